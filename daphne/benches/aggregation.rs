@@ -0,0 +1,176 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Benchmarks for the first round of an aggregation job, in which each Aggregator decrypts and
+//! prepares its report shares. Run with `cargo bench -p daphne`; add `--features parallel` to
+//! prepare the report shares in parallel.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use daphne::{
+    hpke::HpkeReceiverConfig,
+    messages::{
+        AggregateInitializeReq, AggregateInitializeReqRef, DecodeRef, HpkeKemId, Id,
+        PartialBatchSelector, Report,
+    },
+    metrics::DaphneMetrics,
+    DapLeaderTransition, DapMeasurement, DapQueryConfig, DapTaskConfig, DapVersion, Prio3Config,
+    VdafConfig,
+};
+use prio::codec::ParameterizedEncode;
+use rand::prelude::*;
+use std::time::SystemTime;
+use tokio::runtime::{Builder, Runtime};
+use url::Url;
+
+const REPORT_COUNTS: [usize; 2] = [1_000, 10_000];
+
+struct Test {
+    rt: Runtime,
+    now: u64,
+    task_id: Id,
+    agg_job_id: Id,
+    task_config: DapTaskConfig,
+    leader_hpke_receiver_config: HpkeReceiverConfig,
+    helper_hpke_receiver_config: HpkeReceiverConfig,
+    leader_metrics: DaphneMetrics,
+    helper_metrics: DaphneMetrics,
+}
+
+impl Test {
+    fn new(vdaf: VdafConfig) -> Self {
+        let mut rng = thread_rng();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let collector_hpke_receiver_config =
+            HpkeReceiverConfig::gen(rng.gen(), HpkeKemId::X25519HkdfSha256).unwrap();
+        let prometheus_registry = prometheus::Registry::new();
+
+        Self {
+            rt: Builder::new_current_thread().build().unwrap(),
+            now,
+            task_id: Id(rng.gen()),
+            agg_job_id: Id(rng.gen()),
+            task_config: DapTaskConfig {
+                version: DapVersion::Draft03,
+                leader_url: Url::parse("https://leader.example.com/").unwrap(),
+                helper_url: Url::parse("https://helper.example.com/").unwrap(),
+                time_precision: 3600,
+                expiration: now + 3600,
+                min_batch_size: 10,
+                query: DapQueryConfig::TimeInterval,
+                vdaf_verify_key: vdaf.gen_verify_key().unwrap(),
+                vdaf,
+                collector_hpke_config: collector_hpke_receiver_config.config,
+                retired_collector_hpke_configs: Vec::new(),
+                taskprov: None,
+            },
+            leader_hpke_receiver_config: HpkeReceiverConfig::gen(
+                rng.gen(),
+                HpkeKemId::X25519HkdfSha256,
+            )
+            .unwrap(),
+            helper_hpke_receiver_config: HpkeReceiverConfig::gen(
+                rng.gen(),
+                HpkeKemId::X25519HkdfSha256,
+            )
+            .unwrap(),
+            leader_metrics: DaphneMetrics::register(&prometheus_registry, Some("bench_leader"))
+                .unwrap(),
+            helper_metrics: DaphneMetrics::register(&prometheus_registry, Some("bench_helper"))
+                .unwrap(),
+        }
+    }
+
+    fn produce_reports(&self, report_count: usize) -> Vec<Report> {
+        let hpke_config_list = [
+            self.leader_hpke_receiver_config.config.clone(),
+            self.helper_hpke_receiver_config.config.clone(),
+        ];
+        (0..report_count)
+            .map(|i| {
+                self.task_config
+                    .vdaf
+                    .produce_report(
+                        &hpke_config_list,
+                        self.now,
+                        &self.task_id,
+                        DapMeasurement::U64(i as u64 % 1024),
+                        self.task_config.version,
+                    )
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn produce_agg_init_req(
+        &self,
+        reports: Vec<Report>,
+    ) -> DapLeaderTransition<AggregateInitializeReq> {
+        self.rt
+            .block_on(self.task_config.vdaf.produce_agg_init_req(
+                &self.leader_hpke_receiver_config,
+                &self.task_id,
+                &self.task_config,
+                &self.agg_job_id,
+                &PartialBatchSelector::TimeInterval,
+                reports,
+                &self.leader_metrics,
+            ))
+            .unwrap()
+    }
+}
+
+fn prep_init(c: &mut Criterion) {
+    let t = Test::new(VdafConfig::Prio3(Prio3Config::Sum { bits: 10 }));
+    let mut group = c.benchmark_group("prep_init");
+    group.sample_size(10);
+    for report_count in REPORT_COUNTS {
+        let reports = t.produce_reports(report_count);
+
+        group.bench_with_input(
+            BenchmarkId::new("leader", report_count),
+            &reports,
+            |b, reports| {
+                b.iter_batched(
+                    || reports.clone(),
+                    |reports| t.produce_agg_init_req(reports),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+
+        // The Helper decodes the request in place, so include decoding in the measurement.
+        let agg_init_req = match t.produce_agg_init_req(reports) {
+            DapLeaderTransition::Continue(_leader_state, agg_init_req) => {
+                agg_init_req.get_encoded_with_param(&t.task_config.version)
+            }
+            _ => panic!("unexpected transition"),
+        };
+        group.bench_with_input(
+            BenchmarkId::new("helper", report_count),
+            &agg_init_req,
+            |b, agg_init_req| {
+                b.iter(|| {
+                    let agg_init_req = AggregateInitializeReqRef::get_decoded_ref(
+                        &t.task_config.version,
+                        agg_init_req,
+                    )
+                    .unwrap();
+                    t.rt.block_on(t.task_config.vdaf.handle_agg_init_req(
+                        &t.helper_hpke_receiver_config,
+                        &t.task_config,
+                        &agg_init_req,
+                        &t.helper_metrics,
+                    ))
+                    .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, prep_init);
+criterion_main!(benches);
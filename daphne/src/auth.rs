@@ -6,9 +6,10 @@
 use crate::{
     constants::{
         media_type_from_leader, MEDIA_TYPE_AGG_CONT_REQ, MEDIA_TYPE_AGG_INIT_REQ,
+        MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ, MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ,
         MEDIA_TYPE_AGG_SHARE_REQ, MEDIA_TYPE_COLLECT_REQ,
     },
-    messages::{constant_time_eq, Id},
+    messages::{constant_time_eq, taskprov::TaskConfig, Id},
     DapError, DapRequest,
 };
 use async_trait::async_trait;
@@ -70,11 +71,21 @@ pub trait BearerTokenProvider<'a> {
         task_id: &'a Id,
     ) -> Result<Option<Self::WrappedBearerToken>, DapError>;
 
-    /// Returns true if the given bearer token matches the leader token configured for the "taskprov" extension.
-    fn is_taskprov_leader_bearer_token(&self, token: &BearerToken) -> bool;
-
-    /// Returns true if the given bearer token matches the collector token configured for the "taskprov" extension.
-    fn is_taskprov_collector_bearer_token(&self, token: &BearerToken) -> bool;
+    /// Returns true if the given bearer token matches the leader token shared with the peer named
+    /// by the given task configuration of the "taskprov" extension.
+    fn is_taskprov_leader_bearer_token(
+        &self,
+        taskprov_task_config: &TaskConfig,
+        token: &BearerToken,
+    ) -> Result<bool, DapError>;
+
+    /// Returns true if the given bearer token matches the collector token shared with the peer
+    /// named by the given task configuration of the "taskprov" extension.
+    fn is_taskprov_collector_bearer_token(
+        &self,
+        taskprov_task_config: &TaskConfig,
+        token: &BearerToken,
+    ) -> Result<bool, DapError>;
 
     /// Return a bearer token that can be used to authorize a request with the given task ID and
     /// media type.
@@ -99,9 +110,15 @@ pub trait BearerTokenProvider<'a> {
     }
 
     /// Check that the bearer token carried by a request can be used to authorize that request.
+    ///
+    /// If the task is not recognized but its configuration was advertised via taskprov, then
+    /// `taskprov_task_config` is that configuration. In this case the token must be the one shared
+    /// with the peer named by the configuration. This check is done before the task is configured
+    /// so that a request carrying another peer's credentials has no side effects.
     async fn bearer_token_authorized(
         &'a self,
         req: &'a DapRequest<BearerToken>,
+        taskprov_task_config: Option<&TaskConfig>,
     ) -> Result<bool, DapError> {
         if req.task_id.is_none() {
             // Can't authorize request with missing task ID.
@@ -118,12 +135,16 @@ pub trait BearerTokenProvider<'a> {
             Some(MEDIA_TYPE_AGG_INIT_REQ)
                 | Some(MEDIA_TYPE_AGG_CONT_REQ)
                 | Some(MEDIA_TYPE_AGG_SHARE_REQ)
+                | Some(MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ)
+                | Some(MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ)
         ) {
             if let Some(ref got) = req.sender_auth {
                 if let Some(expected) = self.get_leader_bearer_token_for(task_id).await? {
                     return Ok(got == expected.as_ref());
                 }
-                return Ok(self.is_taskprov_leader_bearer_token(got));
+                if let Some(taskprov_task_config) = taskprov_task_config {
+                    return self.is_taskprov_leader_bearer_token(taskprov_task_config, got);
+                }
             }
         }
 
@@ -132,7 +153,9 @@ pub trait BearerTokenProvider<'a> {
                 if let Some(expected) = self.get_collector_bearer_token_for(task_id).await? {
                     return Ok(got == expected.as_ref());
                 }
-                return Ok(self.is_taskprov_collector_bearer_token(got));
+                if let Some(taskprov_task_config) = taskprov_task_config {
+                    return self.is_taskprov_collector_bearer_token(taskprov_task_config, got);
+                }
             }
         }
 
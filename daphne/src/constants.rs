@@ -19,6 +19,18 @@ pub const MEDIA_TYPE_AGG_SHARE_RESP: &str = "application/dap-aggregate-share-res
 pub const MEDIA_TYPE_COLLECT_REQ: &str = "application/dap-collect-req";
 pub const MEDIA_TYPE_COLLECT_RESP: &str = "application/dap-collect-resp";
 
+// Media types for Daphne's extensions to DAP.
+pub const MEDIA_TYPE_BULK_UPLOAD_REQ: &str = "application/x-daphne-bulk-upload-req";
+pub const MEDIA_TYPE_BULK_UPLOAD_RESP: &str = "application/x-daphne-bulk-upload-resp";
+pub const MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ: &str =
+    "application/x-daphne-aggregate-share-reconcile-req";
+pub const MEDIA_TYPE_AGG_SHARE_RECONCILE_RESP: &str =
+    "application/x-daphne-aggregate-share-reconcile-resp";
+pub const MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ: &str =
+    "application/x-daphne-aggregate-share-exclude-req";
+pub const MEDIA_TYPE_AGG_SHARE_EXCLUDE_RESP: &str =
+    "application/x-daphne-aggregate-share-exclude-resp";
+
 /// Check if the provided value for the HTTP Content-Type is valid media type for DAP. If so, then
 /// return a static reference to the media type.
 pub fn media_type_for(content_type: &str) -> Option<&'static str> {
@@ -33,6 +45,12 @@ pub fn media_type_for(content_type: &str) -> Option<&'static str> {
         MEDIA_TYPE_AGG_SHARE_RESP => Some(MEDIA_TYPE_AGG_SHARE_RESP),
         MEDIA_TYPE_COLLECT_REQ => Some(MEDIA_TYPE_COLLECT_REQ),
         MEDIA_TYPE_COLLECT_RESP => Some(MEDIA_TYPE_COLLECT_RESP),
+        MEDIA_TYPE_BULK_UPLOAD_REQ => Some(MEDIA_TYPE_BULK_UPLOAD_REQ),
+        MEDIA_TYPE_BULK_UPLOAD_RESP => Some(MEDIA_TYPE_BULK_UPLOAD_RESP),
+        MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ => Some(MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ),
+        MEDIA_TYPE_AGG_SHARE_RECONCILE_RESP => Some(MEDIA_TYPE_AGG_SHARE_RECONCILE_RESP),
+        MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ => Some(MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ),
+        MEDIA_TYPE_AGG_SHARE_EXCLUDE_RESP => Some(MEDIA_TYPE_AGG_SHARE_EXCLUDE_RESP),
         _ => None,
     }
 }
@@ -41,6 +59,10 @@ pub fn media_type_for(content_type: &str) -> Option<&'static str> {
 pub(crate) fn media_type_from_leader(media_type: &'static str) -> bool {
     matches!(
         media_type,
-        MEDIA_TYPE_AGG_INIT_REQ | MEDIA_TYPE_AGG_CONT_REQ | MEDIA_TYPE_AGG_SHARE_REQ
+        MEDIA_TYPE_AGG_INIT_REQ
+            | MEDIA_TYPE_AGG_CONT_REQ
+            | MEDIA_TYPE_AGG_SHARE_REQ
+            | MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ
+            | MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ
     )
 }
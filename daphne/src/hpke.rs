@@ -113,6 +113,18 @@ pub trait HpkeDecrypter<'a> {
         aad: &[u8],
         ciphertext: &HpkeCiphertext,
     ) -> Result<Vec<u8>, DapError>;
+
+    /// Look up the HPKE receiver config with the given ID, if it can be used to decrypt
+    /// ciphertexts for the task. This allows an Aggregator to decrypt the report shares of an
+    /// aggregation job without calling [`Self::hpke_decrypt`] for each of them, e.g., in parallel.
+    /// Implementations that don't expose their receiver configs return `None`.
+    async fn get_hpke_receiver_config_by_id(
+        &self,
+        _task_id: &Id,
+        _config_id: u8,
+    ) -> Result<Option<HpkeReceiverConfig>, DapError> {
+        Ok(None)
+    }
 }
 
 /// Struct that combines HpkeConfig and HpkeSecretKey
@@ -217,6 +229,14 @@ impl<'a> HpkeDecrypter<'a> for HpkeReceiverConfig {
         Ok(config_id == self.config.id)
     }
 
+    async fn get_hpke_receiver_config_by_id(
+        &self,
+        _task_id: &Id,
+        config_id: u8,
+    ) -> Result<Option<HpkeReceiverConfig>, DapError> {
+        Ok((config_id == self.config.id).then(|| self.clone()))
+    }
+
     async fn hpke_decrypt(
         &self,
         _task_id: &Id,
@@ -231,6 +251,60 @@ impl<'a> HpkeDecrypter<'a> for HpkeReceiverConfig {
     }
 }
 
+/// A Collector's HPKE receiver configurations, e.g., its current configuration and those it used
+/// before rotating its key. Each ciphertext is decrypted with the configuration whose ID matches.
+#[async_trait(?Send)]
+impl<'a> HpkeDecrypter<'a> for Vec<HpkeReceiverConfig> {
+    type WrappedHpkeConfig = HpkeConfig;
+
+    /// The list is only used for decryption, so there is no config to advertise: this method
+    /// always returns an error.
+    async fn get_hpke_config_for(
+        &'a self,
+        _version: DapVersion,
+        _task_id: Option<&Id>,
+    ) -> Result<Self::WrappedHpkeConfig, DapError> {
+        Err(DapError::fatal(
+            "a list of HPKE receiver configs does not advertise an HPKE config",
+        ))
+    }
+
+    async fn can_hpke_decrypt(&self, _task_id: &Id, config_id: u8) -> Result<bool, DapError> {
+        Ok(self
+            .iter()
+            .any(|receiver_config| receiver_config.config.id == config_id))
+    }
+
+    async fn get_hpke_receiver_config_by_id(
+        &self,
+        _task_id: &Id,
+        config_id: u8,
+    ) -> Result<Option<HpkeReceiverConfig>, DapError> {
+        Ok(self
+            .iter()
+            .find(|receiver_config| receiver_config.config.id == config_id)
+            .cloned())
+    }
+
+    async fn hpke_decrypt(
+        &self,
+        _task_id: &Id,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &HpkeCiphertext,
+    ) -> Result<Vec<u8>, DapError> {
+        match self
+            .iter()
+            .find(|receiver_config| receiver_config.config.id == ciphertext.config_id)
+        {
+            Some(receiver_config) => {
+                receiver_config.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)
+            }
+            None => Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId)),
+        }
+    }
+}
+
 impl Encode for HpkeReceiverConfig {
     fn encode(&self, bytes: &mut Vec<u8>) {
         self.config.encode(bytes);
@@ -1,8 +1,12 @@
 // Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use crate::hpke::HpkeReceiverConfig;
-use crate::messages::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId};
+use crate::hpke::{HpkeDecrypter, HpkeReceiverConfig};
+use crate::messages::{
+    HpkeAeadId, HpkeCiphertext, HpkeConfig, HpkeKdfId, HpkeKemId, Id, TransitionFailure,
+};
+use crate::{DapError, DapVersion};
+use assert_matches::assert_matches;
 use hpke_rs::{Hpke, HpkePrivateKey, HpkePublicKey, Mode};
 use hpke_rs_crypto::types::{AeadAlgorithm, KdfAlgorithm, KemAlgorithm};
 use hpke_rs_rust_crypto::HpkeRustCrypto as ImplHpkeCrypto;
@@ -65,3 +69,57 @@ fn hpke_receiver_config_try_from() {
     let bad_private_key = HpkePrivateKey::from(vec![0; 20]);
     assert!(HpkeReceiverConfig::try_from((config, bad_private_key)).is_err());
 }
+
+#[tokio::test]
+async fn decrypt_with_receiver_config_list() {
+    let task_id = Id([0; 32]);
+    let info = b"info string";
+    let aad = b"associated data";
+    let plaintext = b"plaintext";
+    let current = HpkeReceiverConfig::gen(2, HpkeKemId::X25519HkdfSha256).unwrap();
+    let retired = HpkeReceiverConfig::gen(1, HpkeKemId::P256HkdfSha256).unwrap();
+    let receiver_config_list = vec![current, retired.clone()];
+
+    // A ciphertext encrypted under the retired config is decrypted with its key.
+    let (enc, payload) = retired.encrypt(info, aad, plaintext).unwrap();
+    let ciphertext = HpkeCiphertext {
+        config_id: 1,
+        enc,
+        payload,
+    };
+    assert!(receiver_config_list
+        .can_hpke_decrypt(&task_id, 1)
+        .await
+        .unwrap());
+    assert_eq!(
+        receiver_config_list
+            .hpke_decrypt(&task_id, info, aad, &ciphertext)
+            .await
+            .unwrap(),
+        plaintext
+    );
+
+    // A ciphertext with an unknown config ID is rejected.
+    let ciphertext = HpkeCiphertext {
+        config_id: 3,
+        ..ciphertext
+    };
+    assert!(!receiver_config_list
+        .can_hpke_decrypt(&task_id, 3)
+        .await
+        .unwrap());
+    assert_matches!(
+        receiver_config_list
+            .hpke_decrypt(&task_id, info, aad, &ciphertext)
+            .await,
+        Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId))
+    );
+
+    // The list doesn't advertise a config.
+    assert_matches!(
+        receiver_config_list
+            .get_hpke_config_for(DapVersion::Draft02, Some(&task_id))
+            .await,
+        Err(DapError::Fatal(..))
+    );
+}
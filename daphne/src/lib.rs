@@ -41,20 +41,27 @@ use crate::{
         ReportId, ReportMetadata, Time, TransitionFailure,
     },
     vdaf::{
-        prio2::prio2_decode_prepare_state,
-        prio3::{prio3_append_prepare_state, prio3_decode_prepare_state},
-        VdafAggregateShare, VdafError, VdafMessage, VdafState, VdafVerifyKey,
+        registry::try_get_registered_vdaf, VdafAggregateShare, VdafError, VdafMessage, VdafState,
+        VdafVerifyKey,
     },
 };
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm, Key, Nonce,
+};
 use messages::HpkeKemId;
 use prio::{
     codec::{CodecError, Decode, Encode},
-    vdaf::Aggregatable as AggregatableTrait,
+    field::FieldElement,
+    vdaf::{Aggregatable as AggregatableTrait, AggregateShare},
 };
+use rand::prelude::*;
+use ring::hkdf::{Salt, HKDF_SHA256};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
+    io::{Cursor, Read},
 };
 use taskprov::TaskprovVersion;
 use url::Url;
@@ -180,6 +187,26 @@ pub enum DapAbort {
     #[error("staleReport")]
     StaleReport,
 
+    /// Taskprov opt-out. Sent in response to a request for a task provisioned via taskprov that
+    /// the Aggregator declines to opt in to. The detail indicates the reason.
+    #[error("invalidTask")]
+    TaskprovOptOut(String),
+
+    /// Task expired. Sent in response to an upload or aggregation request for a task that has
+    /// expired, or to a collection request for a task whose collection grace period has elapsed.
+    //
+    // TODO spec: Define this error type.
+    #[error("taskExpired")]
+    TaskExpired,
+
+    /// Too many requests. Sent in response to an upload request that exceeds a rate limit or quota
+    /// configured by the Aggregator. The request may be retried after the indicated number of
+    /// seconds. This is conveyed by HTTP status 429 and the `Retry-After` header.
+    //
+    // TODO spec: Define this error type.
+    #[error("tooManyRequests")]
+    TooManyRequests { retry_after: u64 },
+
     /// Unauthorized HTTP request.
     #[error("unauthorizedRequest")]
     UnauthorizedRequest,
@@ -221,12 +248,18 @@ impl DapAbort {
             | Self::ReplayedReport
             | Self::ReportTooLate
             | Self::StaleReport
+            | Self::TaskExpired
             | Self::UnauthorizedRequest
             | Self::UnrecognizedAggregationJob
             | Self::UnrecognizedHpkeConfig
             | Self::UnrecognizedMessage
             | Self::UnrecognizedTask => (self.to_string(), None),
             Self::BadRequest(s) => ("badRequest".to_string(), Some(s.clone())),
+            Self::TaskprovOptOut(s) => ("invalidTask".to_string(), Some(s.clone())),
+            Self::TooManyRequests { retry_after } => (
+                self.to_string(),
+                Some(format!("retry after {retry_after} seconds")),
+            ),
             Self::Internal(e) => ("internalError".to_string(), Some(e.to_string())),
         };
 
@@ -362,6 +395,34 @@ pub struct DapGlobalConfig {
 
     /// Which taskprov draft should be used?
     pub taskprov_version: TaskprovVersion,
+
+    /// Period of time (in seconds) after a task expires during which collection is still
+    /// permitted. Once this period has elapsed, the task's storage may be reclaimed. If not set,
+    /// then collection is permitted for as long as the task exists and expired tasks are not
+    /// reclaimed on this account.
+    #[serde(default)]
+    pub task_expiration_grace_period: Option<Duration>,
+
+    /// If set, then before sending an aggregate-share request, the Leader asks the Helper for the
+    /// report count and checksum of each bucket in the batch and compares them to its own. For
+    /// each bucket that differs, the Aggregators exchange the checksums of the reports in the
+    /// bucket and exclude the reports that only one of them has aggregated.
+    ///
+    /// Setting this also causes each Aggregator to store the output share of each report
+    /// alongside the aggregate share, so that the report can be excluded later. Reports
+    /// aggregated while this is unset cannot be excluded, in which case a mismatched bucket
+    /// causes the collect job to be aborted with a batch mismatch.
+    #[serde(default)]
+    pub reconcile_batch_mismatch: bool,
+
+    /// Maximum number of reports the Leader accepts in a single bulk upload request. Requests
+    /// with more reports are rejected. Defaults to 1000.
+    #[serde(default = "default_max_bulk_upload_reports")]
+    pub max_bulk_upload_reports: u64,
+}
+
+fn default_max_bulk_upload_reports() -> u64 {
+    1000
 }
 
 impl DapGlobalConfig {
@@ -467,11 +528,77 @@ pub struct DapTaskConfig {
     /// VDAF verification key shared by the Aggregators. Used to aggregate reports.
     pub vdaf_verify_key: VdafVerifyKey,
 
-    /// The Collector's HPKE configuration for this task.
+    /// The Collector's current HPKE configuration for this task. Aggregate shares are encrypted
+    /// under this configuration.
     pub collector_hpke_config: HpkeConfig,
+
+    /// HPKE configurations the Collector used for this task before rotating its key, most recent
+    /// first. These are no longer used for encryption, but are kept so that the Collector can
+    /// recognize aggregate shares for collections that were in flight during the rotation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retired_collector_hpke_configs: Vec<HpkeConfig>,
+
+    /// If the task was provisioned via taskprov, then this is the base64url-encoded taskprov task
+    /// configuration. The Leader advertises it to the Helper in the `dap-taskprov` header of each
+    /// request it sends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taskprov: Option<String>,
 }
 
 impl DapTaskConfig {
+    /// Check if the task has expired as of `now`. Uploads and aggregation are not permitted for
+    /// expired tasks.
+    pub fn is_expired(&self, now: Time) -> bool {
+        now >= self.expiration
+    }
+
+    /// Replace the Collector's current HPKE configuration with `hpke_config`. The current
+    /// configuration is retired, unless it is the same as the new one. The config ID of the new
+    /// configuration must differ from that of every other configuration for the task, so that the
+    /// Collector can select its key by config ID.
+    pub fn rotate_collector_hpke_config(
+        &mut self,
+        hpke_config: HpkeConfig,
+    ) -> Result<(), DapError> {
+        if hpke_config == self.collector_hpke_config {
+            return Ok(());
+        }
+
+        if std::iter::once(&self.collector_hpke_config)
+            .chain(self.retired_collector_hpke_configs.iter())
+            .any(|retired| retired.id == hpke_config.id)
+        {
+            return Err(DapError::Fatal(format!(
+                "collector HPKE config ID {} is already in use",
+                hpke_config.id
+            )));
+        }
+
+        let retired = std::mem::replace(&mut self.collector_hpke_config, hpke_config);
+        self.retired_collector_hpke_configs.insert(0, retired);
+        Ok(())
+    }
+
+    /// Check if the collection grace period of the task has elapsed as of `now`. Collection is not
+    /// permitted once the grace period has elapsed. If there is no grace period, then this always
+    /// returns `false`.
+    pub fn is_past_grace_period(&self, now: Time, grace_period: Option<Duration>) -> bool {
+        grace_period.is_some_and(|grace_period| now >= self.expiration.saturating_add(grace_period))
+    }
+
+    /// Return the time until which the task's storage is retained. This is the expiration time
+    /// plus the longer of the collection grace period (if any) and the report storage epoch, so
+    /// that the task is not deleted while collection is still permitted or while reports for the
+    /// task may still be replayed.
+    pub fn retained_until(&self, global_config: &DapGlobalConfig) -> Time {
+        self.expiration.saturating_add(std::cmp::max(
+            global_config
+                .task_expiration_grace_period
+                .unwrap_or_default(),
+            global_config.report_storage_epoch_duration,
+        ))
+    }
+
     /// Convert at timestamp `now` into an [`Interval`] that contains it. The timestamp is the
     /// numbre of seconds since the beginning of UNIX time.
     #[cfg(test)]
@@ -496,13 +623,34 @@ impl DapTaskConfig {
         part_batch_sel: &'a PartialBatchSelector,
         out_shares: Vec<DapOutputShare>,
     ) -> Result<HashMap<DapBatchBucket<'a>, DapAggregateShare>, DapError> {
+        let mut span: HashMap<DapBatchBucket<'a>, DapAggregateShare> = HashMap::new();
+        for (bucket, report_shares) in
+            self.report_shares_for_out_shares(part_batch_sel, out_shares)?
+        {
+            let agg_share = span.entry(bucket).or_default();
+            for report_share in report_shares {
+                agg_share.merge(report_share)?;
+            }
+        }
+
+        Ok(span)
+    }
+
+    /// Compute the "batch span" of a set of output shares and, for each bucket in the span,
+    /// convert each output share into an aggregate share for a single report. This is used to
+    /// keep track of the output share of each report so that it can be excluded later.
+    pub fn report_shares_for_out_shares<'a>(
+        &self,
+        part_batch_sel: &'a PartialBatchSelector,
+        out_shares: Vec<DapOutputShare>,
+    ) -> Result<HashMap<DapBatchBucket<'a>, Vec<DapAggregateShare>>, DapError> {
         if !self.query.is_valid_part_batch_sel(part_batch_sel) {
             return Err(DapError::fatal(
                 "partial batch selector not compatible with task",
             ));
         }
 
-        let mut span: HashMap<DapBatchBucket<'a>, DapAggregateShare> = HashMap::new();
+        let mut span: HashMap<DapBatchBucket<'a>, Vec<DapAggregateShare>> = HashMap::new();
         for out_share in out_shares.into_iter() {
             let bucket = match part_batch_sel {
                 PartialBatchSelector::TimeInterval => DapBatchBucket::TimeInterval {
@@ -513,12 +661,11 @@ impl DapTaskConfig {
                 }
             };
 
-            let agg_share = span.entry(bucket).or_default();
-            agg_share.merge(DapAggregateShare {
+            span.entry(bucket).or_default().push(DapAggregateShare {
                 report_count: 1,
                 checksum: out_share.checksum,
                 data: Some(out_share.data),
-            })?;
+            });
         }
 
         Ok(span)
@@ -553,6 +700,21 @@ impl DapTaskConfig {
         }
     }
 
+    /// Return the batch selector for a single bucket.
+    pub fn batch_sel_for_bucket(&self, bucket: &DapBatchBucket) -> BatchSelector {
+        match bucket {
+            DapBatchBucket::TimeInterval { batch_window } => BatchSelector::TimeInterval {
+                batch_interval: Interval {
+                    start: *batch_window,
+                    duration: self.time_precision,
+                },
+            },
+            DapBatchBucket::FixedSize { batch_id } => BatchSelector::FixedSizeByBatchId {
+                batch_id: (*batch_id).clone(),
+            },
+        }
+    }
+
     /// Return the batch span of a set of reports with the given metadata.
     pub fn batch_span_for_meta<'a>(
         &self,
@@ -611,6 +773,9 @@ impl AsRef<DapTaskConfig> for DapTaskConfig {
 pub enum DapMeasurement {
     U64(u64),
     U32Vec(Vec<u32>),
+
+    /// An encoded measurement for a registered VDAF.
+    Registered(#[serde(with = "hex")] Vec<u8>),
 }
 
 /// The aggregate result computed by the Collector.
@@ -621,6 +786,9 @@ pub enum DapAggregateResult {
     U64(u64),
     U128(u128),
     U128Vec(Vec<u128>),
+
+    /// An encoded aggregate result of a registered VDAF.
+    Registered(#[serde(with = "hex")] Vec<u8>),
 }
 
 /// The Leader's state after sending an AggregateInitReq.
@@ -635,6 +803,47 @@ pub struct DapLeaderUncommitted {
     pub(crate) seq: Vec<(DapOutputShare, ReportId)>,
 }
 
+/// Configuration of the stateless Helper mode. In this mode, the Helper does not store its
+/// aggregation-flow state between rounds. Instead, it seals the state (see
+/// [`DapHelperState::seal`]) and sends it to the Leader, who echoes it back in its next request.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DapStatelessHelperConfig {
+    /// Secret from which the key used to seal the Helper state for each aggregation job is
+    /// derived.
+    #[serde(with = "hex")]
+    pub key: [u8; 32],
+
+    /// Number of seconds for which sealed Helper state is accepted.
+    pub state_lifetime: Duration,
+}
+
+impl DapStatelessHelperConfig {
+    /// Derive the AEAD key for the given aggregation job.
+    fn cipher(&self, task_id: &Id, agg_job_id: &Id) -> Aes256Gcm {
+        let prk = Salt::new(HKDF_SHA256, HELPER_STATE_SALT).extract(&self.key);
+        let mut key = [0; 32];
+        // This expand(), and the associated fill() below can only fail if the length is wrong,
+        // and it won't be, so we unwrap().
+        prk.expand(&[task_id.as_ref(), agg_job_id.as_ref()], HKDF_SHA256)
+            .unwrap()
+            .fill(&mut key)
+            .unwrap();
+        Aes256Gcm::new(Key::from_slice(&key))
+    }
+}
+
+const HELPER_STATE_SALT: &[u8] = b"daphne helper state";
+
+const HELPER_STATE_NONCE_LEN: usize = 12;
+
+/// First byte of a versioned encoding of [`DapHelperState`]. It is followed by the version of the
+/// encoding. Encodings that predate versioning (version 0) begin with the query type of the
+/// partial batch selector, which is never equal to this value.
+const HELPER_STATE_VERSIONED: u8 = 0xff;
+
+/// Version of the encoding of [`DapHelperState`] written by [`DapHelperState::get_encoded`].
+const HELPER_STATE_VERSION: u8 = 1;
+
 /// The Helper's state during the aggregation flow.
 #[derive(Clone, Debug, PartialEq)]
 pub struct DapHelperState {
@@ -649,40 +858,104 @@ impl DapHelperState {
     /// example, it might encrypt the output and add the ciphertext to an outgoing aggregate
     /// response.
     ///
-    /// Note that the encoding format is not specified by the DAP standard.
+    /// Note that the encoding format is not specified by the DAP standard. The encoding is
+    /// versioned so that state written by one release can be read by the next (see
+    /// [`Self::get_decoded`]).
     pub fn get_encoded(&self, vdaf_config: &VdafConfig) -> Result<Vec<u8>, DapError> {
-        let mut bytes = vec![];
+        let vdaf = vdaf_config.vdaf()?;
+        let mut bytes = vec![HELPER_STATE_VERSIONED, HELPER_STATE_VERSION];
         self.part_batch_sel.encode(&mut bytes);
         for (state, time, report_id) in self.seq.iter() {
-            match (vdaf_config, state) {
-                (VdafConfig::Prio3(prio3_config), _) => {
-                    prio3_append_prepare_state(&mut bytes, prio3_config, state)?;
-                }
-                (VdafConfig::Prio2 { .. }, VdafState::Prio2(state)) => {
-                    state.encode(&mut bytes);
-                }
-                _ => return Err(DapError::fatal("VDAF config and prep state mismatch")),
-            }
+            vdaf.encode_prepare_state(state, &mut bytes)?;
             time.encode(&mut bytes);
             report_id.encode(&mut bytes);
         }
         Ok(bytes)
     }
 
-    /// Decode the Helper state from a byte string.
+    /// Seal the Helper state for the given aggregation job so that it can be offloaded to the
+    /// Leader. The state is encrypted and authenticated under a key derived from the task ID and
+    /// aggregation job ID, and it expires `config.state_lifetime` seconds after `now`.
+    pub fn seal(
+        &self,
+        vdaf_config: &VdafConfig,
+        config: &DapStatelessHelperConfig,
+        task_id: &Id,
+        agg_job_id: &Id,
+        now: Time,
+    ) -> Result<Vec<u8>, DapError> {
+        let mut plaintext = Vec::new();
+        now.saturating_add(config.state_lifetime)
+            .encode(&mut plaintext);
+        plaintext.extend_from_slice(&self.get_encoded(vdaf_config)?);
+
+        let nonce: [u8; HELPER_STATE_NONCE_LEN] = thread_rng().gen();
+        let ciphertext = config
+            .cipher(task_id, agg_job_id)
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|_| DapError::fatal("failed to seal helper state"))?;
+
+        let mut sealed = Vec::with_capacity(nonce.len() + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Open Helper state sealed by [`Self::seal`]. State that was tampered with, that was sealed
+    /// for a different task or aggregation job, or that has expired is rejected. Replaying the
+    /// state within the same aggregation job is not detected here; the output shares of an
+    /// aggregation job are only ever aggregated once (see [`put_out_shares`](crate::roles::DapAggregator::put_out_shares)).
+    pub fn open(
+        vdaf_config: &VdafConfig,
+        config: &DapStatelessHelperConfig,
+        task_id: &Id,
+        agg_job_id: &Id,
+        now: Time,
+        sealed: &[u8],
+    ) -> Result<Self, DapAbort> {
+        if sealed.len() < HELPER_STATE_NONCE_LEN {
+            return Err(DapAbort::BadRequest("invalid helper state".into()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(HELPER_STATE_NONCE_LEN);
+        let plaintext = config
+            .cipher(task_id, agg_job_id)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DapAbort::BadRequest("invalid helper state".into()))?;
+
+        let mut r = std::io::Cursor::new(plaintext.as_slice());
+        let expires_at = Time::decode(&mut r)?;
+        if now >= expires_at {
+            return Err(DapAbort::BadRequest("expired helper state".into()));
+        }
+
+        Ok(Self::get_decoded(
+            vdaf_config,
+            &plaintext[r.position() as usize..],
+        )?)
+    }
+
+    /// Decode the Helper state from a byte string. Both the current encoding and the unversioned
+    /// encoding written by previous releases are accepted.
     pub fn get_decoded(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
+        match data {
+            [HELPER_STATE_VERSIONED, HELPER_STATE_VERSION, body @ ..] => {
+                Self::get_decoded_v0(vdaf_config, body)
+            }
+            [HELPER_STATE_VERSIONED, version, ..] => Err(DapError::Fatal(format!(
+                "unsupported helper state version {version}"
+            ))),
+            // Version 1 only added the version tag, so the body is encoded the same way as before.
+            _ => Self::get_decoded_v0(vdaf_config, data),
+        }
+    }
+
+    fn get_decoded_v0(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
+        let vdaf = vdaf_config.vdaf()?;
         let mut r = std::io::Cursor::new(data);
         let part_batch_sel = PartialBatchSelector::decode(&mut r)?;
         let mut seq = vec![];
         while (r.position() as usize) < data.len() {
-            let state = match vdaf_config {
-                VdafConfig::Prio3(ref prio3_config) => {
-                    prio3_decode_prepare_state(prio3_config, 1, &mut r)?
-                }
-                VdafConfig::Prio2 { dimension } => {
-                    prio2_decode_prepare_state(*dimension, 1, &mut r)?
-                }
-            };
+            let state = vdaf.decode_prepare_state(1, &mut r)?;
             let time = Time::decode(&mut r)?;
             let report_id = ReportId::decode(&mut r)?;
             seq.push((state, time, report_id))
@@ -740,6 +1013,17 @@ impl DapAggregateShare {
                 left.merge(&right)
                     .map_err(|e| DapError::Fatal(e.to_string()))?;
             }
+            (
+                Some(VdafAggregateShare::Registered { vdaf, share: left }),
+                Some(VdafAggregateShare::Registered {
+                    vdaf: other_vdaf,
+                    share: right,
+                }),
+            ) if *vdaf == other_vdaf => {
+                *left = try_get_registered_vdaf(vdaf)?
+                    .merge_agg_shares(left, &right)
+                    .map_err(|e| DapError::Fatal(e.to_string()))?;
+            }
 
             _ => return Err(DapError::fatal("invalid aggregate share merge")),
         };
@@ -751,6 +1035,67 @@ impl DapAggregateShare {
         Ok(())
     }
 
+    /// Remove an aggregate share that was previously merged into this one. This is used to exclude
+    /// a set of reports from the aggregate share.
+    pub fn exclude(&mut self, other: DapAggregateShare) -> Result<(), DapError> {
+        if other.report_count > self.report_count {
+            return Err(DapError::fatal(
+                "tried to exclude more reports than were aggregated",
+            ));
+        }
+
+        // Update the aggregate share data.
+        self.data = match (self.data.take(), other.data) {
+            (data, None) => data,
+            (Some(VdafAggregateShare::Field64(left)), Some(VdafAggregateShare::Field64(right))) => {
+                Some(VdafAggregateShare::Field64(subtract_agg_share(
+                    left, right,
+                )?))
+            }
+            (
+                Some(VdafAggregateShare::Field128(left)),
+                Some(VdafAggregateShare::Field128(right)),
+            ) => Some(VdafAggregateShare::Field128(subtract_agg_share(
+                left, right,
+            )?)),
+            (
+                Some(VdafAggregateShare::FieldPrio2(left)),
+                Some(VdafAggregateShare::FieldPrio2(right)),
+            ) => Some(VdafAggregateShare::FieldPrio2(subtract_agg_share(
+                left, right,
+            )?)),
+            (
+                Some(VdafAggregateShare::Registered { vdaf, share: left }),
+                Some(VdafAggregateShare::Registered {
+                    vdaf: other_vdaf,
+                    share: right,
+                }),
+            ) if vdaf == other_vdaf => {
+                let share = try_get_registered_vdaf(&vdaf)?
+                    .subtract_agg_shares(&left, &right)
+                    .map_err(|e| DapError::Fatal(e.to_string()))?;
+                Some(VdafAggregateShare::Registered { vdaf, share })
+            }
+
+            _ => return Err(DapError::fatal("invalid aggregate share exclusion")),
+        };
+
+        self.report_count -= other.report_count;
+        for (x, y) in self.checksum.iter_mut().zip(other.checksum) {
+            *x ^= y;
+        }
+        if self.report_count == 0 {
+            self.data = None;
+        }
+        Ok(())
+    }
+
+    /// Return the checksum of the aggregate share, i.e., the XOR of the checksum of each report
+    /// that was aggregated.
+    pub fn checksum(&self) -> [u8; 32] {
+        self.checksum
+    }
+
     /// Return `true` if the aggregate share contains no reports.
     pub fn empty(&self) -> bool {
         self.report_count == 0
@@ -779,6 +1124,58 @@ impl DapAggregateShare {
     }
 }
 
+/// The encoding of an aggregate share is not part of DAP. It is used to store aggregate shares
+/// compactly, e.g., the output share of each report for batch reconciliation.
+impl Encode for DapAggregateShare {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.report_count.encode(bytes);
+        bytes.extend_from_slice(&self.checksum);
+        match self.data {
+            Some(ref data) => {
+                1_u8.encode(bytes);
+                data.encode_with_type(bytes);
+            }
+            None => 0_u8.encode(bytes),
+        }
+    }
+}
+
+impl Decode for DapAggregateShare {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let report_count = u64::decode(bytes)?;
+        let mut checksum = [0; 32];
+        bytes.read_exact(&mut checksum)?;
+        let data = match u8::decode(bytes)? {
+            0 => None,
+            1 => Some(VdafAggregateShare::decode_with_type(bytes)?),
+            _ => return Err(CodecError::UnexpectedValue),
+        };
+        Ok(Self {
+            report_count,
+            checksum,
+            data,
+        })
+    }
+}
+
+/// Subtract `right` from `left` element-wise.
+fn subtract_agg_share<F: FieldElement>(
+    left: AggregateShare<F>,
+    right: AggregateShare<F>,
+) -> Result<AggregateShare<F>, DapError> {
+    if left.as_ref().len() != right.as_ref().len() {
+        return Err(DapError::fatal("aggregate share length mismatch"));
+    }
+
+    Ok(left
+        .as_ref()
+        .iter()
+        .zip(right.as_ref())
+        .map(|(x, y)| *x - *y)
+        .collect::<Vec<F>>()
+        .into())
+}
+
 /// Leader state transition during the aggregation flow.
 #[derive(Debug)]
 pub enum DapLeaderTransition<M: Debug> {
@@ -812,7 +1209,13 @@ pub enum DapHelperTransition<M: Debug> {
 #[serde(rename_all = "snake_case")]
 pub enum VdafConfig {
     Prio3(Prio3Config),
-    Prio2 { dimension: u32 },
+    Prio2 {
+        dimension: u32,
+    },
+
+    /// A VDAF registered under the given name with
+    /// [`register_vdaf`](crate::vdaf::registry::register_vdaf).
+    Registered(String),
 }
 
 impl std::str::FromStr for VdafConfig {
@@ -849,6 +1252,10 @@ pub struct DapRequest<S> {
     pub payload: Vec<u8>,
     pub url: Url,
     pub sender_auth: Option<S>,
+
+    /// The base64url-encoded taskprov task configuration carried by the `dap-taskprov` header,
+    /// if any.
+    pub taskprov: Option<String>,
 }
 
 impl<S> DapRequest<S> {
@@ -891,6 +1298,19 @@ pub struct DapLeaderProcessTelemetry {
 
     /// The number of reports processed.
     pub reports_processed: u64,
+
+    /// The number of reports whose aggregation was deferred because the deadline passed.
+    #[serde(default)]
+    pub reports_deferred: u64,
+
+    /// The number of reports dropped without being aggregated because their task had expired.
+    #[serde(default)]
+    pub reports_dropped: u64,
+
+    /// The number of collect jobs left pending, either because the batch was not ready or
+    /// because the deadline passed.
+    #[serde(default)]
+    pub collect_jobs_deferred: u64,
 }
 
 pub mod auth;
@@ -900,6 +1320,8 @@ pub mod hpke;
 mod hpke_test;
 pub mod messages;
 pub mod metrics;
+#[cfg(test)]
+mod metrics_test;
 pub mod roles;
 #[cfg(test)]
 mod roles_test;
@@ -15,7 +15,8 @@ use std::{
     collections::HashSet,
     convert::{TryFrom, TryInto},
     fmt,
-    io::{Cursor, Read},
+    io::{self, Cursor, Read},
+    num::TryFromIntError,
 };
 
 // Various algorithm constants
@@ -34,6 +35,7 @@ const FIXED_SIZE_QUERY_TYPE_CURRENT_BATCH: u8 = 0x01;
 
 // Known extension types.
 const EXTENSION_TASKPROV: u16 = 0xff00;
+const EXTENSION_HELPER_STATE: u16 = 0xff01;
 
 /// The identifier for a DAP task.
 #[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Eq, Serialize)]
@@ -126,6 +128,7 @@ impl fmt::Display for ReportId {
 #[serde(rename_all = "snake_case")]
 pub enum Extension {
     Taskprov { payload: Vec<u8> }, // Not a TaskConfig to make computing the expected task id more efficient
+    HelperState { payload: Vec<u8> }, // Sealed Helper state, see `DapHelperState::seal()`
     Unhandled { typ: u16, payload: Vec<u8> },
 }
 
@@ -134,6 +137,7 @@ impl Extension {
     fn type_code(&self) -> u16 {
         match self {
             Self::Taskprov { .. } => EXTENSION_TASKPROV,
+            Self::HelperState { .. } => EXTENSION_HELPER_STATE,
             Self::Unhandled { typ, .. } => *typ,
         }
     }
@@ -146,6 +150,10 @@ impl Encode for Extension {
                 EXTENSION_TASKPROV.encode(bytes);
                 encode_u16_bytes(bytes, payload);
             }
+            Self::HelperState { payload } => {
+                EXTENSION_HELPER_STATE.encode(bytes);
+                encode_u16_bytes(bytes, payload);
+            }
             Self::Unhandled { typ, payload } => {
                 typ.encode(bytes);
                 encode_u16_bytes(bytes, payload);
@@ -160,6 +168,7 @@ impl Decode for Extension {
         let payload = decode_u16_bytes(bytes)?;
         match typ {
             EXTENSION_TASKPROV => Ok(Self::Taskprov { payload }),
+            EXTENSION_HELPER_STATE => Ok(Self::HelperState { payload }),
             _ => Ok(Self::Unhandled { typ, payload }),
         }
     }
@@ -207,7 +216,7 @@ impl ParameterizedDecode<DapVersion> for ReportMetadata {
             if !seen.insert(extension.type_code()) {
                 return Err(CodecError::UnexpectedValue);
             }
-            if matches!(extension, Extension::Unhandled { .. }) {
+            if !matches!(extension, Extension::Taskprov { .. }) {
                 // Unrecognized extensions are an error.
                 return Err(CodecError::UnexpectedValue);
             }
@@ -280,6 +289,36 @@ impl ParameterizedDecode<DapVersion> for ReportShare {
     }
 }
 
+/// A [`ReportShare`] that borrows the public share and encrypted input share from the encoded
+/// message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct ReportShareRef<'a> {
+    pub metadata: ReportMetadata,
+    pub public_share: &'a [u8],
+    pub encrypted_input_share: HpkeCiphertextRef<'a>,
+}
+
+impl From<ReportShareRef<'_>> for ReportShare {
+    fn from(report_share: ReportShareRef<'_>) -> Self {
+        Self {
+            metadata: report_share.metadata,
+            public_share: report_share.public_share.to_vec(),
+            encrypted_input_share: report_share.encrypted_input_share.into(),
+        }
+    }
+}
+
+impl<'a> DecodeRef<'a, DapVersion> for ReportShareRef<'a> {
+    fn decode_ref(version: &DapVersion, bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            metadata: ReportMetadata::decode_with_param(version, bytes)?,
+            public_share: decode_u32_bytes_ref(bytes)?,
+            encrypted_input_share: HpkeCiphertextRef::decode_ref(&(), bytes)?,
+        })
+    }
+}
+
 /// Batch parameter conveyed to the Helper by the Leader in the aggregation sub-protocol. Used to
 /// identify which batch the reports in the [`AggregateInitializeReq`] are intended for.
 #[derive(Clone, Debug, Eq, Deserialize, Hash, PartialEq, Serialize)]
@@ -424,12 +463,46 @@ impl ParameterizedDecode<DapVersion> for AggregateInitializeReq {
     }
 }
 
+/// An [`AggregateInitializeReq`] that borrows the aggregation parameter and the variable-length
+/// fields of its report shares from the encoded message, so that the Helper does not copy the
+/// public shares and input shares of a large request. The report shares themselves are decoded up
+/// front (see [`ItemsRef`]).
+#[derive(Clone, Debug)]
+#[allow(missing_docs)]
+pub struct AggregateInitializeReqRef<'a> {
+    pub task_id: Id,
+    pub agg_job_id: Id,
+    pub agg_param: &'a [u8],
+    pub part_batch_sel: PartialBatchSelector,
+    pub report_shares: ItemsRef<ReportShareRef<'a>>,
+}
+
+impl<'a> DecodeRef<'a, DapVersion> for AggregateInitializeReqRef<'a> {
+    fn decode_ref(version: &DapVersion, bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            task_id: Id::decode(bytes)?,
+            agg_job_id: Id::decode(bytes)?,
+            agg_param: match version {
+                DapVersion::Draft02 => decode_u16_bytes_ref(bytes)?,
+                DapVersion::Draft03 => decode_u32_bytes_ref(bytes)?,
+                _ => unreachable!("unimplemented version"),
+            },
+            part_batch_sel: PartialBatchSelector::decode(bytes)?,
+            report_shares: ItemsRef::decode_ref(version, bytes)?,
+        })
+    }
+}
+
 /// Aggregate continuation request.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct AggregateContinueReq {
     pub task_id: Id,
     pub agg_job_id: Id,
     pub transitions: Vec<Transition>,
+
+    /// Extensions echoed from the Helper's previous response. This is not part of DAP; see
+    /// [`AggregateResp::extensions`].
+    pub extensions: Vec<Extension>,
 }
 
 impl Encode for AggregateContinueReq {
@@ -437,6 +510,7 @@ impl Encode for AggregateContinueReq {
         self.task_id.encode(bytes);
         self.agg_job_id.encode(bytes);
         encode_u32_items(bytes, &(), &self.transitions);
+        encode_trailing_extensions(bytes, &self.extensions);
     }
 }
 
@@ -446,6 +520,7 @@ impl Decode for AggregateContinueReq {
             task_id: Id::decode(bytes)?,
             agg_job_id: Id::decode(bytes)?,
             transitions: decode_u32_items(&(), bytes)?,
+            extensions: decode_trailing_extensions(bytes)?,
         })
     }
 }
@@ -477,6 +552,36 @@ impl Decode for Transition {
     }
 }
 
+/// A [`Transition`] that borrows the VDAF message from the encoded message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct TransitionRef<'a> {
+    pub report_id: ReportId,
+    pub var: TransitionVarRef<'a>,
+}
+
+impl From<TransitionRef<'_>> for Transition {
+    fn from(transition: TransitionRef<'_>) -> Self {
+        Self {
+            report_id: transition.report_id,
+            var: match transition.var {
+                TransitionVarRef::Continued(message) => TransitionVar::Continued(message.to_vec()),
+                TransitionVarRef::Finished => TransitionVar::Finished,
+                TransitionVarRef::Failed(failure) => TransitionVar::Failed(failure),
+            },
+        }
+    }
+}
+
+impl<'a> DecodeRef<'a, ()> for TransitionRef<'a> {
+    fn decode_ref(_: &(), bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            report_id: ReportId::decode(bytes)?,
+            var: TransitionVarRef::decode_ref(&(), bytes)?,
+        })
+    }
+}
+
 /// Transition message variant.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TransitionVar {
@@ -514,6 +619,26 @@ impl Decode for TransitionVar {
     }
 }
 
+/// A [`TransitionVar`] that borrows the VDAF message from the encoded message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum TransitionVarRef<'a> {
+    Continued(&'a [u8]),
+    Finished,
+    Failed(TransitionFailure),
+}
+
+impl<'a> DecodeRef<'a, ()> for TransitionVarRef<'a> {
+    fn decode_ref(_: &(), bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        match u8::decode(bytes)? {
+            0 => Ok(Self::Continued(decode_u32_bytes_ref(bytes)?)),
+            1 => Ok(Self::Finished),
+            2 => Ok(Self::Failed(TransitionFailure::decode(bytes)?)),
+            _ => Err(CodecError::UnexpectedValue),
+        }
+    }
+}
+
 /// Transition error.
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -584,11 +709,17 @@ impl std::fmt::Display for TransitionFailure {
 #[allow(missing_docs)]
 pub struct AggregateResp {
     pub transitions: Vec<Transition>,
+
+    /// Extensions sent by the Helper, e.g., its sealed state. This is not part of DAP: the
+    /// extensions are appended to the message only if there are any, so that the message is
+    /// otherwise encoded as specified.
+    pub extensions: Vec<Extension>,
 }
 
 impl Encode for AggregateResp {
     fn encode(&self, bytes: &mut Vec<u8>) {
         encode_u32_items(bytes, &(), &self.transitions);
+        encode_trailing_extensions(bytes, &self.extensions);
     }
 }
 
@@ -596,10 +727,44 @@ impl Decode for AggregateResp {
     fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
         Ok(Self {
             transitions: decode_u32_items(&(), bytes)?,
+            extensions: decode_trailing_extensions(bytes)?,
         })
     }
 }
 
+/// An [`AggregateResp`] whose transitions borrow their VDAF messages from the encoded message.
+#[derive(Clone, Debug)]
+#[allow(missing_docs)]
+pub struct AggregateRespRef<'a> {
+    pub transitions: ItemsRef<TransitionRef<'a>>,
+    pub extensions: Vec<Extension>,
+}
+
+impl<'a> DecodeRef<'a, ()> for AggregateRespRef<'a> {
+    fn decode_ref(_: &(), bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            transitions: ItemsRef::decode_ref(&(), bytes)?,
+            extensions: decode_trailing_extensions(bytes)?,
+        })
+    }
+}
+
+/// Encode a list of extensions at the end of a message, unless the list is empty.
+fn encode_trailing_extensions(bytes: &mut Vec<u8>, extensions: &[Extension]) {
+    if !extensions.is_empty() {
+        encode_u16_items(bytes, &(), extensions);
+    }
+}
+
+/// Decode the list of extensions at the end of a message, if any.
+fn decode_trailing_extensions(bytes: &mut Cursor<&[u8]>) -> Result<Vec<Extension>, CodecError> {
+    if (bytes.position() as usize) < bytes.get_ref().len() {
+        decode_u16_items(&(), bytes)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
 /// A batch interval.
 #[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[allow(missing_docs)]
@@ -841,6 +1006,230 @@ impl Decode for AggregateShareResp {
     }
 }
 
+/// Daphne extension: Request sent by the Leader to the Helper for the report count and checksum
+/// of each bucket in a batch. This is used to pinpoint the buckets responsible for a batch
+/// mismatch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AggregateShareReconcileReq {
+    pub task_id: Id,
+    pub batch_sel: BatchSelector,
+}
+
+impl Encode for AggregateShareReconcileReq {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.task_id.encode(bytes);
+        self.batch_sel.encode(bytes);
+    }
+}
+
+impl Decode for AggregateShareReconcileReq {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            task_id: Id::decode(bytes)?,
+            batch_sel: BatchSelector::decode(bytes)?,
+        })
+    }
+}
+
+/// Daphne extension: The report count and checksum of the aggregate share for a single bucket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketDigest {
+    pub batch_sel: BatchSelector,
+    pub report_count: u64,
+    pub checksum: [u8; 32],
+}
+
+impl Encode for BucketDigest {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.batch_sel.encode(bytes);
+        self.report_count.encode(bytes);
+        bytes.extend_from_slice(&self.checksum);
+    }
+}
+
+impl Decode for BucketDigest {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            batch_sel: BatchSelector::decode(bytes)?,
+            report_count: u64::decode(bytes)?,
+            checksum: {
+                let mut checksum = [0u8; 32];
+                bytes.read_exact(&mut checksum[..])?;
+                checksum
+            },
+        })
+    }
+}
+
+/// Daphne extension: Response to an [`AggregateShareReconcileReq`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AggregateShareReconcileResp {
+    pub buckets: Vec<BucketDigest>,
+}
+
+impl Encode for AggregateShareReconcileResp {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        encode_u32_items(bytes, &(), &self.buckets);
+    }
+}
+
+impl Decode for AggregateShareReconcileResp {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            buckets: decode_u32_items(&(), bytes)?,
+        })
+    }
+}
+
+/// Daphne extension: Request sent by the Leader to exclude from a batch the reports that only one
+/// of the Aggregators has aggregated. The request lists the checksum of each report the Leader has
+/// aggregated into the batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregateShareExcludeReq {
+    pub task_id: Id,
+    pub batch_sel: BatchSelector,
+    pub checksums: Vec<[u8; 32]>,
+}
+
+impl Encode for AggregateShareExcludeReq {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.task_id.encode(bytes);
+        self.batch_sel.encode(bytes);
+        encode_u32_bytes(bytes, &self.checksums.concat());
+    }
+}
+
+impl Decode for AggregateShareExcludeReq {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            task_id: Id::decode(bytes)?,
+            batch_sel: BatchSelector::decode(bytes)?,
+            checksums: decode_checksums(bytes)?,
+        })
+    }
+}
+
+/// Daphne extension: Response to an [`AggregateShareExcludeReq`]. The response lists the checksum
+/// of each report in the Leader's request that the Helper has not aggregated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AggregateShareExcludeResp {
+    pub checksums: Vec<[u8; 32]>,
+}
+
+impl Encode for AggregateShareExcludeResp {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        encode_u32_bytes(bytes, &self.checksums.concat());
+    }
+}
+
+impl Decode for AggregateShareExcludeResp {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            checksums: decode_checksums(bytes)?,
+        })
+    }
+}
+
+fn decode_checksums(bytes: &mut Cursor<&[u8]>) -> Result<Vec<[u8; 32]>, CodecError> {
+    let checksums = decode_u32_bytes(bytes)?;
+    if checksums.len() % 32 != 0 {
+        return Err(CodecError::UnexpectedValue);
+    }
+    Ok(checksums
+        .chunks_exact(32)
+        .map(|checksum| checksum.try_into().unwrap())
+        .collect())
+}
+
+/// Daphne extension: A sequence of reports for the same task uploaded in a single request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BulkUploadReq {
+    pub task_id: Id,
+    pub reports: Vec<Report>,
+}
+
+impl ParameterizedEncode<DapVersion> for BulkUploadReq {
+    fn encode_with_param(&self, version: &DapVersion, bytes: &mut Vec<u8>) {
+        self.task_id.encode(bytes);
+        encode_u32_items(bytes, version, &self.reports);
+    }
+}
+
+impl ParameterizedDecode<DapVersion> for BulkUploadReq {
+    fn decode_with_param(
+        version: &DapVersion,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<Self, CodecError> {
+        Ok(Self {
+            task_id: Id::decode(bytes)?,
+            reports: decode_u32_items(version, bytes)?,
+        })
+    }
+}
+
+/// Daphne extension: The outcome of uploading a single report in a [`BulkUploadReq`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportUploadResult {
+    pub report_id: ReportId,
+
+    /// If the report was rejected, the type of the problem details document that would have been
+    /// sent in response to uploading the report by itself (e.g.,
+    /// "urn:ietf:params:ppm:dap:error:reportTooLate").
+    pub rejection: Option<String>,
+}
+
+impl Encode for ReportUploadResult {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.report_id.encode(bytes);
+        match self.rejection {
+            None => 0_u8.encode(bytes),
+            Some(ref problem_type) => {
+                1_u8.encode(bytes);
+                encode_u16_bytes(bytes, problem_type.as_bytes());
+            }
+        }
+    }
+}
+
+impl Decode for ReportUploadResult {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let report_id = ReportId::decode(bytes)?;
+        let rejection = match u8::decode(bytes)? {
+            0 => None,
+            1 => Some(
+                String::from_utf8(decode_u16_bytes(bytes)?)
+                    .map_err(|_| CodecError::UnexpectedValue)?,
+            ),
+            _ => return Err(CodecError::UnexpectedValue),
+        };
+        Ok(Self {
+            report_id,
+            rejection,
+        })
+    }
+}
+
+/// Daphne extension: Response to a [`BulkUploadReq`], listing the outcome for each report in the
+/// order in which the reports appeared in the request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BulkUploadResp {
+    pub results: Vec<ReportUploadResult>,
+}
+
+impl Encode for BulkUploadResp {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        encode_u32_items(bytes, &(), &self.results);
+    }
+}
+
+impl Decode for BulkUploadResp {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            results: decode_u32_items(&(), bytes)?,
+        })
+    }
+}
+
 /// Codepoint for KEM schemes compatible with HPKE.
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -1029,6 +1418,45 @@ impl Decode for HpkeCiphertext {
     }
 }
 
+/// An [`HpkeCiphertext`] that borrows the encapsulated key and payload from the encoded message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct HpkeCiphertextRef<'a> {
+    pub config_id: u8,
+    pub enc: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> From<&'a HpkeCiphertext> for HpkeCiphertextRef<'a> {
+    fn from(ciphertext: &'a HpkeCiphertext) -> Self {
+        Self {
+            config_id: ciphertext.config_id,
+            enc: &ciphertext.enc,
+            payload: &ciphertext.payload,
+        }
+    }
+}
+
+impl From<HpkeCiphertextRef<'_>> for HpkeCiphertext {
+    fn from(ciphertext: HpkeCiphertextRef<'_>) -> Self {
+        Self {
+            config_id: ciphertext.config_id,
+            enc: ciphertext.enc.to_vec(),
+            payload: ciphertext.payload.to_vec(),
+        }
+    }
+}
+
+impl<'a> DecodeRef<'a, ()> for HpkeCiphertextRef<'a> {
+    fn decode_ref(_: &(), bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            config_id: u8::decode(bytes)?,
+            enc: decode_u16_bytes_ref(bytes)?,
+            payload: decode_u32_bytes_ref(bytes)?,
+        })
+    }
+}
+
 /// A plaintext input share.
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[allow(missing_docs)]
@@ -1056,7 +1484,7 @@ impl Decode for PlaintextInputShare {
             if !seen.insert(extension.type_code()) {
                 return Err(CodecError::UnexpectedValue);
             }
-            if matches!(extension, Extension::Unhandled { .. }) {
+            if !matches!(extension, Extension::Taskprov { .. }) {
                 return Err(CodecError::UnexpectedValue);
             }
         }
@@ -1106,6 +1534,116 @@ pub(crate) fn decode_u32_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Vec<u8>, Cod
     Ok(out)
 }
 
+/// Like [`decode_u16_bytes`], but borrows the bytes from the input.
+fn decode_u16_bytes_ref<'a>(bytes: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = u16::decode(bytes)? as usize;
+    take_bytes_ref(bytes, len)
+}
+
+/// Like [`decode_u32_bytes`], but borrows the bytes from the input.
+fn decode_u32_bytes_ref<'a>(bytes: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = u32::decode(bytes)? as usize;
+    take_bytes_ref(bytes, len)
+}
+
+fn take_bytes_ref<'a>(bytes: &mut Cursor<&'a [u8]>, len: usize) -> Result<&'a [u8], CodecError> {
+    let inner: &'a [u8] = bytes.get_ref();
+    let start = bytes.position() as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= inner.len() => {
+            bytes.set_position(end as u64);
+            Ok(&inner[start..end])
+        }
+        // Fail the same way as `Read::read_exact()`.
+        _ => Err(CodecError::Io(io::ErrorKind::UnexpectedEof.into())),
+    }
+}
+
+/// Decoding of a message that borrows its variable-length fields from the encoded bytes rather
+/// than copying them. This is the borrowed analogue of [`ParameterizedDecode`] and fails with the
+/// same [`CodecError`] on the same input.
+pub trait DecodeRef<'a, P>: Sized {
+    /// Decode an object from `bytes` and advance `bytes` by its encoded size.
+    fn decode_ref(param: &P, bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError>;
+
+    /// Decode an object from `bytes`. Returns an error if there are any bytes left over.
+    fn get_decoded_ref(param: &P, bytes: &'a [u8]) -> Result<Self, CodecError> {
+        let mut cursor = Cursor::new(bytes);
+        let decoded = Self::decode_ref(param, &mut cursor)?;
+        if cursor.position() as usize != bytes.len() {
+            return Err(CodecError::BytesLeftOver(
+                bytes.len() - cursor.position() as usize,
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+/// A sequence of items with a 32-bit length prefix whose items borrow their variable-length
+/// fields from the encoded message.
+///
+/// The items are decoded once, when the sequence is decoded, so that a malformed message is
+/// rejected up front with the same error as [`decode_u32_items`]. Only their fixed-size fields are
+/// copied.
+///
+/// NOTE This is borrowed decoding, not streaming: every item is decoded before the first one is
+/// processed. The Helper makes several passes over the report shares of an aggregation job (to
+/// resolve a taskprov task, to check for early rejections, and to prepare them), so decoding them
+/// lazily would decode each one once per pass. Processing the report shares as they are parsed is
+/// out of scope for now.
+#[derive(Clone, Debug)]
+pub struct ItemsRef<T> {
+    items: Vec<T>,
+}
+
+impl<T> ItemsRef<T> {
+    /// The number of items in the sequence.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Whether the sequence is empty.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Iterate over the items.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.items.iter()
+    }
+}
+
+impl<T> IntoIterator for ItemsRef<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a, P, T: DecodeRef<'a, P>> DecodeRef<'a, P> for ItemsRef<T> {
+    fn decode_ref(param: &P, bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        let len: usize = u32::decode(bytes)?
+            .try_into()
+            .map_err(|e: TryFromIntError| CodecError::Other(e.into()))?;
+        let inner: &'a [u8] = bytes.get_ref();
+        let start = bytes.position() as usize;
+        let end = match start.checked_add(len) {
+            Some(end) if end <= inner.len() => end,
+            _ => return Err(CodecError::LengthPrefixTooBig(len)),
+        };
+
+        let mut encoded = Cursor::new(&inner[start..end]);
+        let mut items = Vec::new();
+        while (encoded.position() as usize) < end - start {
+            items.push(T::decode_ref(param, &mut encoded)?);
+        }
+        bytes.set_position(end as u64);
+        Ok(Self { items })
+    }
+}
+
 /// Encode the input bytes as a URL-safe, base64 string.
 pub fn encode_base64url<T: AsRef<[u8]>>(input: T) -> String {
     URL_SAFE_NO_PAD.encode(input)
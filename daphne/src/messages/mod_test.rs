@@ -5,16 +5,19 @@ use crate::messages::taskprov::{
     DpConfig, QueryConfig, QueryConfigVar, TaskConfig, UrlBytes, VdafConfig, VdafTypeVar,
 };
 use crate::messages::{
-    decode_base64url, decode_base64url_vec, encode_base64url, AggregateContinueReq,
-    AggregateInitializeReq, AggregateResp, AggregateShareReq, BatchSelector, DapVersion, Extension,
-    HpkeAeadId, HpkeCiphertext, HpkeConfig, HpkeKdfId, HpkeKemId, Id, PartialBatchSelector, Report,
-    ReportId, ReportMetadata, ReportShare, Transition, TransitionVar,
+    decode_base64url, decode_base64url_vec, encode_base64url, encode_u32_bytes,
+    AggregateContinueReq, AggregateInitializeReq, AggregateInitializeReqRef, AggregateResp,
+    AggregateRespRef, AggregateShareExcludeReq, AggregateShareExcludeResp,
+    AggregateShareReconcileReq, AggregateShareReconcileResp, AggregateShareReq, BatchSelector,
+    BucketDigest, BulkUploadReq, BulkUploadResp, DapVersion, DecodeRef, Extension, HpkeAeadId,
+    HpkeCiphertext, HpkeConfig, HpkeKdfId, HpkeKemId, Id, Interval, PartialBatchSelector, Report,
+    ReportId, ReportMetadata, ReportShare, ReportUploadResult, Transition, TransitionVar,
 };
 use crate::taskprov::{compute_task_id, TaskprovVersion};
 use crate::{test_version, test_versions};
 use hpke_rs::HpkePublicKey;
 use paste::paste;
-use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+use prio::codec::{CodecError, Decode, Encode, ParameterizedDecode, ParameterizedEncode};
 use rand::prelude::*;
 
 fn read_report(version: DapVersion) {
@@ -136,6 +139,94 @@ fn read_agg_init_req() {
     )
     .unwrap();
     assert_eq!(got, want);
+
+    for version in [DapVersion::Draft02, DapVersion::Draft03] {
+        let encoded = want.get_encoded_with_param(&version);
+        let got = AggregateInitializeReqRef::get_decoded_ref(&version, &encoded).unwrap();
+        assert_eq!(got.task_id, want.task_id);
+        assert_eq!(got.agg_job_id, want.agg_job_id);
+        assert_eq!(got.agg_param, want.agg_param);
+        assert_eq!(got.part_batch_sel, want.part_batch_sel);
+        assert_eq!(got.report_shares.len(), want.report_shares.len());
+        let got_report_shares = got
+            .report_shares
+            .iter()
+            .cloned()
+            .map(ReportShare::from)
+            .collect::<Vec<_>>();
+        assert_eq!(got_report_shares, want.report_shares);
+    }
+}
+
+/// Reduce a decoding error to what can be compared between decoders.
+fn codec_error_kind(e: CodecError) -> String {
+    match e {
+        CodecError::Io(e) => format!("io: {:?}", e.kind()),
+        e => format!("{e:?}"),
+    }
+}
+
+#[test]
+fn read_agg_init_req_ref_errors() {
+    let version = DapVersion::Draft03;
+    let agg_init_req = AggregateInitializeReq {
+        task_id: Id([23; 32]),
+        agg_job_id: Id([1; 32]),
+        agg_param: Vec::new(),
+        part_batch_sel: PartialBatchSelector::TimeInterval,
+        report_shares: vec![
+            ReportShare {
+                metadata: ReportMetadata {
+                    id: ReportId([99; 16]),
+                    time: 1637361337,
+                    extensions: Vec::default(),
+                },
+                public_share: b"public share".to_vec(),
+                encrypted_input_share: HpkeCiphertext {
+                    config_id: 23,
+                    enc: b"encapsulated key".to_vec(),
+                    payload: b"ciphertext".to_vec(),
+                },
+            };
+            2
+        ],
+    };
+    let encoded = agg_init_req.get_encoded_with_param(&version);
+
+    // Truncate the message at every position, including in the middle of the report shares.
+    for len in 0..encoded.len() {
+        let want = AggregateInitializeReq::get_decoded_with_param(&version, &encoded[..len])
+            .map(|_| ())
+            .map_err(codec_error_kind);
+        let got = AggregateInitializeReqRef::get_decoded_ref(&version, &encoded[..len])
+            .map(|_| ())
+            .map_err(codec_error_kind);
+        assert!(want.is_err());
+        assert_eq!(got, want, "truncated to {len} bytes");
+    }
+
+    // Trailing bytes.
+    let mut trailing = encoded.clone();
+    trailing.push(0);
+    assert_eq!(
+        AggregateInitializeReqRef::get_decoded_ref(&version, &trailing)
+            .map(|_| ())
+            .map_err(codec_error_kind),
+        Err(codec_error_kind(CodecError::BytesLeftOver(1)))
+    );
+
+    // Length prefix of the report shares is too long.
+    let mut too_long = encoded;
+    let pos = 32 + 32 + 4 + 1;
+    too_long[pos..pos + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert_eq!(
+        AggregateInitializeReqRef::get_decoded_ref(&version, &too_long)
+            .map(|_| ())
+            .map_err(codec_error_kind),
+        AggregateInitializeReq::get_decoded_with_param(&version, &too_long)
+            .map(|_| ())
+            .map_err(codec_error_kind),
+    );
 }
 
 #[test]
@@ -155,6 +246,25 @@ fn read_agg_cont_req() {
                 ),
             },
         ],
+        extensions: Vec::new(),
+    };
+
+    let got = AggregateContinueReq::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+}
+
+#[test]
+fn read_agg_cont_req_with_extensions() {
+    let want = AggregateContinueReq {
+        task_id: Id([23; 32]),
+        agg_job_id: Id([1; 32]),
+        transitions: vec![Transition {
+            report_id: ReportId([0; 16]),
+            var: TransitionVar::Continued(b"this is a VDAF-specific message".to_vec()),
+        }],
+        extensions: vec![Extension::HelperState {
+            payload: b"sealed helper state".to_vec(),
+        }],
     };
 
     let got = AggregateContinueReq::get_decoded(&want.get_encoded()).unwrap();
@@ -187,6 +297,111 @@ fn read_agg_share_req() {
     assert_eq!(got, want);
 }
 
+fn read_bulk_upload(version: DapVersion) {
+    let report = |id: u8| Report {
+        task_id: Id([23; 32]),
+        metadata: ReportMetadata {
+            id: ReportId([id; 16]),
+            time: 1637364244,
+            extensions: Vec::new(),
+        },
+        public_share: b"public share".to_vec(),
+        encrypted_input_shares: vec![HpkeCiphertext {
+            config_id: 23,
+            enc: b"encapsulated key".to_vec(),
+            payload: b"ciphertext".to_vec(),
+        }],
+    };
+    let want = BulkUploadReq {
+        task_id: Id([23; 32]),
+        reports: vec![report(1), report(2)],
+    };
+    let got =
+        BulkUploadReq::get_decoded_with_param(&version, &want.get_encoded_with_param(&version))
+            .unwrap();
+    assert_eq!(got, want);
+
+    let want = BulkUploadResp {
+        results: vec![
+            ReportUploadResult {
+                report_id: ReportId([1; 16]),
+                rejection: None,
+            },
+            ReportUploadResult {
+                report_id: ReportId([2; 16]),
+                rejection: Some("urn:ietf:params:ppm:dap:error:replayedReport".to_string()),
+            },
+        ],
+    };
+    let got = BulkUploadResp::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+}
+
+test_versions! { read_bulk_upload }
+
+#[test]
+fn read_agg_share_reconcile() {
+    let want = AggregateShareReconcileReq {
+        task_id: Id([23; 32]),
+        batch_sel: BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1637361337,
+                duration: 7200,
+            },
+        },
+    };
+    let got = AggregateShareReconcileReq::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+
+    let want = AggregateShareReconcileResp {
+        buckets: vec![
+            BucketDigest {
+                batch_sel: BatchSelector::TimeInterval {
+                    batch_interval: Interval {
+                        start: 1637361337,
+                        duration: 3600,
+                    },
+                },
+                report_count: 100,
+                checksum: [1; 32],
+            },
+            BucketDigest {
+                batch_sel: BatchSelector::FixedSizeByBatchId {
+                    batch_id: Id([23; 32]),
+                },
+                report_count: 0,
+                checksum: [0; 32],
+            },
+        ],
+    };
+    let got = AggregateShareReconcileResp::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+}
+
+#[test]
+fn read_agg_share_exclude() {
+    let want = AggregateShareExcludeReq {
+        task_id: Id([23; 32]),
+        batch_sel: BatchSelector::FixedSizeByBatchId {
+            batch_id: Id([17; 32]),
+        },
+        checksums: vec![[1; 32], [2; 32], [3; 32]],
+    };
+    let got = AggregateShareExcludeReq::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+
+    let want = AggregateShareExcludeResp {
+        checksums: vec![[4; 32]],
+    };
+    let got = AggregateShareExcludeResp::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+
+    // The checksums must be a multiple of 32 bytes long.
+    let mut bytes = Vec::new();
+    encode_u32_bytes(&mut bytes, &[1; 33]);
+    assert!(AggregateShareExcludeResp::get_decoded(&bytes).is_err());
+}
+
 #[test]
 fn read_agg_resp() {
     let want = AggregateResp {
@@ -202,10 +417,44 @@ fn read_agg_resp() {
                 ),
             },
         ],
+        extensions: Vec::new(),
     };
 
     let got = AggregateResp::get_decoded(&want.get_encoded()).unwrap();
     assert_eq!(got, want);
+
+    // Extensions are appended to the message.
+    let want = AggregateResp {
+        extensions: vec![Extension::HelperState {
+            payload: b"sealed helper state".to_vec(),
+        }],
+        ..want
+    };
+    let got = AggregateResp::get_decoded(&want.get_encoded()).unwrap();
+    assert_eq!(got, want);
+
+    let encoded = want.get_encoded();
+    let got = AggregateRespRef::get_decoded_ref(&(), &encoded).unwrap();
+    assert_eq!(got.extensions, want.extensions);
+    let got_transitions = got
+        .transitions
+        .into_iter()
+        .map(Transition::from)
+        .collect::<Vec<_>>();
+    assert_eq!(got_transitions, want.transitions);
+
+    // The borrowed decoder fails the same way on a truncated message.
+    for len in 0..encoded.len() {
+        assert_eq!(
+            AggregateRespRef::get_decoded_ref(&(), &encoded[..len])
+                .map(|_| ())
+                .map_err(codec_error_kind),
+            AggregateResp::get_decoded(&encoded[..len])
+                .map(|_| ())
+                .map_err(codec_error_kind),
+            "truncated to {len} bytes"
+        );
+    }
 }
 
 #[test]
@@ -319,6 +568,96 @@ fn read_task_config_taskprov_draft02() {
     );
 }
 
+#[test]
+fn read_task_config_taskprov_draft03() {
+    // As of draft 03, the query type follows the minimum batch size.
+    let data = [
+        0x02, 0x48, 0x69, 0x00, 0x0e, 0x00, 0x0c, 0x68, 0x74, 0x74, 0x70, 0x73, 0x3a, 0x2f, 0x2f,
+        0x74, 0x65, 0x73, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x80, 0x00,
+        0x00, 0x04, 0x00, 0x02, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x63, 0x52, 0xf9,
+        0xa5, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x18, 0x01, 0x02, 0x03, 0x04, 0x04, 0x03,
+        0x02, 0x01, 0x02, 0x02, 0x03, 0x04, 0x04, 0x03, 0x02, 0x02, 0x03, 0x02, 0x03, 0x04, 0x04,
+        0x03, 0x02, 0x03,
+    ];
+
+    let buckets = vec![0x0102030404030201, 0x0202030404030202, 0x0302030404030203];
+    let task_config = TaskConfig::get_decoded_with_param(&TaskprovVersion::Draft03, &data).unwrap();
+    assert_eq!(
+        task_config,
+        TaskConfig {
+            task_info: "Hi".as_bytes().to_vec(),
+            aggregator_endpoints: vec![UrlBytes {
+                bytes: "https://test".as_bytes().to_vec()
+            }],
+            query_config: QueryConfig {
+                time_precision: 0x01,
+                max_batch_query_count: 128,
+                min_batch_size: 1024,
+                var: QueryConfigVar::FixedSize {
+                    max_batch_size: 2048
+                },
+            },
+            task_expiration: 0x6352f9a5,
+            vdaf_config: VdafConfig {
+                dp_config: DpConfig::None,
+                var: VdafTypeVar::Prio3Aes128Histogram { buckets },
+            },
+        }
+    );
+
+    assert_eq!(
+        compute_task_id(
+            TaskprovVersion::Draft03,
+            &task_config.get_encoded_with_param(&TaskprovVersion::Draft03)
+        )
+        .unwrap()
+        .to_hex(),
+        "2e5eb17cb12faaf122fca963b8e4cca3f11737f31fc83a02cf9d72019d2b2081",
+    );
+
+    assert_eq!(
+        task_config.get_encoded_with_param(&TaskprovVersion::Draft03),
+        &data
+    );
+
+    // The encoding differs from draft 02.
+    assert_ne!(
+        task_config.get_encoded_with_param(&TaskprovVersion::Draft02),
+        &data
+    );
+}
+
+#[test]
+fn read_task_config_taskprov_draft03_empty_task_info() {
+    let task_config = TaskConfig {
+        task_info: Vec::new(),
+        aggregator_endpoints: vec![UrlBytes {
+            bytes: "https://test".as_bytes().to_vec(),
+        }],
+        query_config: QueryConfig {
+            time_precision: 0x01,
+            max_batch_query_count: 128,
+            min_batch_size: 1024,
+            var: QueryConfigVar::TimeInterval,
+        },
+        task_expiration: 0x6352f9a5,
+        vdaf_config: VdafConfig {
+            dp_config: DpConfig::None,
+            var: VdafTypeVar::Prio3Aes128Count,
+        },
+    };
+
+    // The task info may be empty in draft 02, but not in draft 03.
+    let data = task_config.get_encoded_with_param(&TaskprovVersion::Draft02);
+    assert_eq!(
+        TaskConfig::get_decoded_with_param(&TaskprovVersion::Draft02, &data).unwrap(),
+        task_config
+    );
+
+    let data = task_config.get_encoded_with_param(&TaskprovVersion::Draft03);
+    assert!(TaskConfig::get_decoded_with_param(&TaskprovVersion::Draft03, &data).is_err());
+}
+
 #[test]
 fn test_base64url() {
     let mut rng = thread_rng();
@@ -2,26 +2,27 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 //! Messages in the taskprov extension to the DAP protocol, as
-//! defined in draft-wang-ppm-dap-taskprov-02.
+//! defined in draft-wang-ppm-dap-taskprov-02 and draft-wang-ppm-dap-taskprov-03.
 
 use crate::messages::{
     decode_u16_bytes, encode_u16_bytes, Duration, Time, QUERY_TYPE_FIXED_SIZE,
     QUERY_TYPE_TIME_INTERVAL,
 };
 use crate::taskprov::TaskprovVersion;
+use crate::vdaf::registry::get_registered_vdaf_by_taskprov_code;
+use crate::{DapAbort, DapError};
 use prio::codec::{
     decode_u16_items, decode_u24_items, decode_u8_items, encode_u16_items, encode_u24_items,
     encode_u8_items, CodecError, Decode, Encode, ParameterizedDecode, ParameterizedEncode,
 };
-use ring::hkdf::KeyType;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 
 // VDAF type codes.
-const VDAF_TYPE_PRIO3_AES128_COUNT: u32 = 0x00000000;
-const VDAF_TYPE_PRIO3_AES128_SUM: u32 = 0x00000001;
-const VDAF_TYPE_PRIO3_AES128_HISTOGRAM: u32 = 0x00000002;
-const VDAF_TYPE_POPLAR1_AES128: u32 = 0x00001000; // The gap from the previous constant is intentional
+pub(crate) const VDAF_TYPE_PRIO3_AES128_COUNT: u32 = 0x00000000;
+pub(crate) const VDAF_TYPE_PRIO3_AES128_SUM: u32 = 0x00000001;
+pub(crate) const VDAF_TYPE_PRIO3_AES128_HISTOGRAM: u32 = 0x00000002;
+pub(crate) const VDAF_TYPE_POPLAR1_AES128: u32 = 0x00001000; // The gap from the previous constant is intentional
 
 // Differential privacy mechanism types.
 const DP_MECHANISM_NONE: u8 = 0x01;
@@ -34,17 +35,24 @@ pub(crate) enum VdafType {
     Prio3Aes128Sum,
     Prio3Aes128Histogram,
     Poplar1Aes128,
+    Registered(u32),
     NotImplemented(u32),
 }
 
-impl KeyType for VdafType {
-    fn len(&self) -> usize {
+impl VdafType {
+    /// Length of the VDAF verification key in bytes. This fails if the VDAF is not supported.
+    pub(crate) fn verify_key_len(&self) -> Result<usize, DapError> {
         match self {
-            VdafType::Prio3Aes128Count => 16,
-            VdafType::Prio3Aes128Sum => 16,
-            VdafType::Prio3Aes128Histogram => 16,
-            VdafType::Poplar1Aes128 => 16,
-            _ => panic!("tried to get key length for undefined VDAF"),
+            VdafType::Prio3Aes128Count => Ok(16),
+            VdafType::Prio3Aes128Sum => Ok(16),
+            VdafType::Prio3Aes128Histogram => Ok(16),
+            VdafType::Registered(x) => match get_registered_vdaf_by_taskprov_code(*x) {
+                Some((_name, vdaf)) => Ok(vdaf.verify_key_len()),
+                None => Err(DapError::Abort(DapAbort::InvalidTask)),
+            },
+            VdafType::Poplar1Aes128 | VdafType::NotImplemented(..) => {
+                Err(DapError::Abort(DapAbort::InvalidTask))
+            }
         }
     }
 }
@@ -53,9 +61,18 @@ impl KeyType for VdafType {
 #[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub enum VdafTypeVar {
     Prio3Aes128Count,
-    Prio3Aes128Sum { bit_length: u8 },
-    Prio3Aes128Histogram { buckets: Vec<u64> },
-    Poplar1Aes128 { bit_length: u16 },
+    Prio3Aes128Sum {
+        bit_length: u8,
+    },
+    Prio3Aes128Histogram {
+        buckets: Vec<u64>,
+    },
+    Poplar1Aes128 {
+        bit_length: u16,
+    },
+    /// A VDAF registered with [`register_vdaf`](crate::vdaf::registry::register_vdaf). It has no
+    /// type-specific data.
+    Registered(u32),
     NotImplemented(u32),
 }
 
@@ -75,7 +92,7 @@ impl Encode for VdafTypeVar {
                 VDAF_TYPE_POPLAR1_AES128.encode(bytes);
                 bit_length.encode(bytes);
             }
-            VdafTypeVar::NotImplemented(x) => {
+            VdafTypeVar::Registered(x) | VdafTypeVar::NotImplemented(x) => {
                 x.encode(bytes);
             }
         }
@@ -96,6 +113,7 @@ impl Decode for VdafTypeVar {
             VDAF_TYPE_POPLAR1_AES128 => Ok(Self::Poplar1Aes128 {
                 bit_length: u16::decode(bytes)?,
             }),
+            _ if get_registered_vdaf_by_taskprov_code(x).is_some() => Ok(Self::Registered(x)),
             _ => Err(CodecError::UnexpectedValue),
         }
     }
@@ -108,6 +126,7 @@ impl From<VdafTypeVar> for VdafType {
             VdafTypeVar::Prio3Aes128Histogram { .. } => VdafType::Prio3Aes128Histogram,
             VdafTypeVar::Prio3Aes128Sum { .. } => VdafType::Prio3Aes128Sum,
             VdafTypeVar::Poplar1Aes128 { .. } => VdafType::Poplar1Aes128,
+            VdafTypeVar::Registered(x) => VdafType::Registered(x),
             VdafTypeVar::NotImplemented(x) => VdafType::NotImplemented(x),
         }
     }
@@ -189,9 +208,8 @@ pub enum QueryConfigVar {
 }
 
 // There is no Encode or Decode for QueryConfigVar as we have to split the query type and
-// the associated configuration data in the message format, so we must do all of the work
-// in QueryConfig's Encode and Decode.  If the spec is revised to allow these fields
-// to be encoded and decoded contiguously, then we will revise this code.
+// the associated configuration data in the draft 02 message format, so we must do all of the
+// work in QueryConfig's Encode and Decode. As of draft 03 these fields are contiguous.
 
 /// A query configuration.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -216,11 +234,21 @@ impl QueryConfig {
 }
 
 impl ParameterizedEncode<TaskprovVersion> for QueryConfig {
-    fn encode_with_param(&self, _encoding_parameter: &TaskprovVersion, bytes: &mut Vec<u8>) {
-        self.encode_query_type(bytes);
-        self.time_precision.encode(bytes);
-        self.max_batch_query_count.encode(bytes);
-        self.min_batch_size.encode(bytes);
+    fn encode_with_param(&self, encoding_parameter: &TaskprovVersion, bytes: &mut Vec<u8>) {
+        match encoding_parameter {
+            TaskprovVersion::Draft02 | TaskprovVersion::Unknown => {
+                self.encode_query_type(bytes);
+                self.time_precision.encode(bytes);
+                self.max_batch_query_count.encode(bytes);
+                self.min_batch_size.encode(bytes);
+            }
+            TaskprovVersion::Draft03 => {
+                self.time_precision.encode(bytes);
+                self.max_batch_query_count.encode(bytes);
+                self.min_batch_size.encode(bytes);
+                self.encode_query_type(bytes);
+            }
+        }
         match &self.var {
             QueryConfigVar::TimeInterval => (),
             QueryConfigVar::FixedSize { max_batch_size } => {
@@ -232,13 +260,29 @@ impl ParameterizedEncode<TaskprovVersion> for QueryConfig {
 
 impl ParameterizedDecode<TaskprovVersion> for QueryConfig {
     fn decode_with_param(
-        _decoding_parameter: &TaskprovVersion,
+        decoding_parameter: &TaskprovVersion,
         bytes: &mut Cursor<&[u8]>,
     ) -> Result<Self, CodecError> {
-        let query_type = u8::decode(bytes)?;
-        let time_precision = Duration::decode(bytes)?;
-        let max_batch_query_count = u16::decode(bytes)?;
-        let min_batch_size = u32::decode(bytes)?;
+        let (query_type, time_precision, max_batch_query_count, min_batch_size) =
+            match decoding_parameter {
+                TaskprovVersion::Draft02 | TaskprovVersion::Unknown => (
+                    u8::decode(bytes)?,
+                    Duration::decode(bytes)?,
+                    u16::decode(bytes)?,
+                    u32::decode(bytes)?,
+                ),
+                TaskprovVersion::Draft03 => {
+                    let time_precision = Duration::decode(bytes)?;
+                    let max_batch_query_count = u16::decode(bytes)?;
+                    let min_batch_size = u32::decode(bytes)?;
+                    (
+                        u8::decode(bytes)?,
+                        time_precision,
+                        max_batch_query_count,
+                        min_batch_size,
+                    )
+                }
+            };
         let var = match query_type {
             QUERY_TYPE_TIME_INTERVAL => Ok(QueryConfigVar::TimeInterval),
             QUERY_TYPE_FIXED_SIZE => Ok(QueryConfigVar::FixedSize {
@@ -281,8 +325,13 @@ impl ParameterizedDecode<TaskprovVersion> for TaskConfig {
         decoding_parameter: &TaskprovVersion,
         bytes: &mut Cursor<&[u8]>,
     ) -> Result<Self, CodecError> {
+        let task_info = decode_u8_items(&(), bytes)?;
+        if matches!(decoding_parameter, TaskprovVersion::Draft03) && task_info.is_empty() {
+            // As of draft 03, the task info must not be empty.
+            return Err(CodecError::UnexpectedValue);
+        }
         Ok(TaskConfig {
-            task_info: decode_u8_items(&(), bytes)?,
+            task_info,
             aggregator_endpoints: decode_u16_items(&(), bytes)?,
             query_config: QueryConfig::decode_with_param(decoding_parameter, bytes)?,
             task_expiration: Time::decode(bytes)?,
@@ -3,19 +3,57 @@
 
 //! Daphne metrics.
 
-use crate::DapError;
+use crate::{messages::Id, taskprov::TaskprovOptOutReason, DapError};
 use prometheus::{
-    register_int_counter_vec_with_registry, register_int_gauge_with_registry, IntCounterVec,
-    IntGauge, Registry,
+    register_gauge_vec_with_registry, register_histogram_with_registry,
+    register_int_counter_vec_with_registry, register_int_counter_with_registry,
+    register_int_gauge_vec_with_registry, GaugeVec, Histogram, IntCounter, IntCounterVec,
+    IntGaugeVec, Registry,
 };
+use std::{collections::HashSet, sync::Mutex};
+
+/// Maximum number of distinct task IDs that are used as metric labels. Once this limit is reached,
+/// any other task is recorded under the label [`TASK_LABEL_OTHER`]. This bounds the cardinality of
+/// the per-task metrics in deployments with many tasks (e.g., those that use taskprov).
+pub const MAX_TASK_LABELS: usize = 64;
+
+/// Label used for tasks that exceed [`MAX_TASK_LABELS`].
+pub const TASK_LABEL_OTHER: &str = "other";
 
 pub struct DaphneMetrics {
     /// Report metrics. How many reports have been rejected, aggregated, and collected. When
     /// a report is rejected, the failure type is recorded.
     pub(crate) report_counter: IntCounterVec,
 
+    /// Report metrics per task. The same as `report_counter`, except that the task ID is also
+    /// recorded. See [`MAX_TASK_LABELS`].
+    pub(crate) report_task_counter: IntCounterVec,
+
+    /// Number of reports whose input share could not be decrypted.
+    pub(crate) hpke_decrypt_failure_counter: IntCounter,
+
     /// Helper: Number of running aggregation jobs.
-    pub(crate) aggregation_job_gauge: IntGauge,
+    ///
+    /// NOTE The unlabeled gauges are registered as vectors without labels so that they are only
+    /// gathered once they have been set, even if they are set to zero.
+    pub(crate) aggregation_job_gauge: IntGaugeVec,
+
+    /// Leader: Time (in seconds) taken to run an aggregation job, including round trips to the
+    /// Helper.
+    pub(crate) aggregation_job_duration: Histogram,
+
+    /// Leader: Number of collect jobs waiting to be processed.
+    pub(crate) collect_job_queue_gauge: IntGaugeVec,
+
+    /// Leader: Fill level of the fixed-size batch currently being filled for each task, i.e., the
+    /// number of reports assigned to the batch divided by the minimum batch size.
+    pub(crate) batch_fill_gauge: GaugeVec,
+
+    /// Number of tasks provisioned via taskprov that were opted out of, by reason.
+    pub(crate) taskprov_opt_out_counter: IntCounterVec,
+
+    /// Task IDs that have been assigned a label so far.
+    task_labels: Mutex<HashSet<Id>>,
 }
 
 impl DaphneMetrics {
@@ -35,15 +73,113 @@ impl DaphneMetrics {
             registry
         )?;
 
-        let aggregation_job_gauge = register_int_gauge_with_registry!(
+        let report_task_counter = register_int_counter_vec_with_registry!(
+            format!("{front}report_task_counter"),
+            "Total number reports rejected, aggregated, and collected for each task.",
+            &["task", "status"],
+            registry
+        )?;
+
+        let hpke_decrypt_failure_counter = register_int_counter_with_registry!(
+            format!("{front}hpke_decrypt_failure_counter"),
+            "Total number of input shares that failed to decrypt.",
+            registry
+        )?;
+
+        let aggregation_job_gauge = register_int_gauge_vec_with_registry!(
             format!("{front}aggregation_job_gauge"),
             "Number of running aggregation jobs.",
+            &[],
+            registry
+        )?;
+
+        let aggregation_job_duration = register_histogram_with_registry!(
+            format!("{front}aggregation_job_duration"),
+            "Time (in seconds) taken to run an aggregation job.",
+            registry
+        )?;
+
+        let collect_job_queue_gauge = register_int_gauge_vec_with_registry!(
+            format!("{front}collect_job_queue_gauge"),
+            "Number of pending collect jobs.",
+            &[],
+            registry
+        )?;
+
+        let batch_fill_gauge = register_gauge_vec_with_registry!(
+            format!("{front}batch_fill_gauge"),
+            "Fill level of the fixed-size batch currently being filled.",
+            &["task"],
+            registry
+        )?;
+
+        let taskprov_opt_out_counter = register_int_counter_vec_with_registry!(
+            format!("{front}taskprov_opt_out_counter"),
+            "Total number of taskprov tasks opted out of.",
+            &["reason"],
             registry
         )?;
 
         Ok(Self {
             report_counter,
+            report_task_counter,
+            hpke_decrypt_failure_counter,
             aggregation_job_gauge,
+            aggregation_job_duration,
+            collect_job_queue_gauge,
+            batch_fill_gauge,
+            taskprov_opt_out_counter,
+            task_labels: Mutex::new(HashSet::new()),
         })
     }
+
+    /// Return the label used for the given task in per-task metrics. Each of the first
+    /// [`MAX_TASK_LABELS`] tasks are labeled by their task ID; every other task is labeled with
+    /// [`TASK_LABEL_OTHER`].
+    pub fn task_label(&self, task_id: &Id) -> String {
+        let mut task_labels = self.task_labels.lock().expect("task_labels: lock failed");
+        if task_labels.contains(task_id) {
+            return task_id.to_base64url();
+        }
+
+        if task_labels.len() < MAX_TASK_LABELS {
+            task_labels.insert(task_id.clone());
+            return task_id.to_base64url();
+        }
+
+        TASK_LABEL_OTHER.into()
+    }
+
+    /// Increment the report counters for the given task and status by `val`.
+    pub fn report_inc_by(&self, task_id: &Id, status: &str, val: u64) {
+        self.report_counter.with_label_values(&[status]).inc_by(val);
+        self.report_task_counter
+            .with_label_values(&[&self.task_label(task_id), status])
+            .inc_by(val);
+    }
+
+    /// Increment the report counter for the given status by `val`. This is for reports that can't
+    /// be attributed to a task; otherwise use [`Self::report_inc_by`].
+    pub fn report_inc_by_status(&self, status: &str, val: u64) {
+        self.report_counter.with_label_values(&[status]).inc_by(val);
+    }
+
+    /// Leader: Record the number of reports assigned to the fixed-size batch that is currently
+    /// being filled for the given task.
+    pub fn set_batch_fill_level(&self, task_id: &Id, report_count: u64, min_batch_size: u64) {
+        if min_batch_size == 0 {
+            return;
+        }
+
+        self.batch_fill_gauge
+            .with_label_values(&[&self.task_label(task_id)])
+            .set(report_count as f64 / min_batch_size as f64);
+    }
+
+    /// Record that a task provisioned via taskprov was opted out of for the given reason.
+    pub fn taskprov_opt_out_inc(&self, reason: TaskprovOptOutReason) {
+        self.taskprov_opt_out_counter
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
 }
@@ -0,0 +1,59 @@
+// Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::{
+    assert_metrics_include, assert_metrics_include_auxiliary_function,
+    messages::Id,
+    metrics::{DaphneMetrics, MAX_TASK_LABELS, TASK_LABEL_OTHER},
+};
+use prometheus::Registry;
+
+#[test]
+fn task_label_cardinality() {
+    let registry = Registry::new();
+    let metrics = DaphneMetrics::register(&registry, Some("test")).unwrap();
+
+    let task_ids: Vec<Id> = (0..MAX_TASK_LABELS + 1)
+        .map(|i| Id([u8::try_from(i).unwrap(); 32]))
+        .collect();
+    for task_id in task_ids.iter().take(MAX_TASK_LABELS) {
+        assert_eq!(metrics.task_label(task_id), task_id.to_base64url());
+    }
+
+    // Once the limit is reached, new tasks are grouped together.
+    let task_id = &task_ids[MAX_TASK_LABELS];
+    assert_eq!(metrics.task_label(task_id), TASK_LABEL_OTHER);
+
+    // Tasks that were labeled before the limit was reached keep their label.
+    assert_eq!(metrics.task_label(&task_ids[0]), task_ids[0].to_base64url());
+}
+
+#[test]
+fn report_inc_by() {
+    let registry = Registry::new();
+    let metrics = DaphneMetrics::register(&registry, Some("test")).unwrap();
+    let task_id = Id([1; 32]);
+
+    metrics.report_inc_by(&task_id, "aggregated", 3);
+    metrics.report_inc_by(&task_id, "collected", 2);
+
+    assert_metrics_include!(registry, {
+        r#"test_report_counter{status="aggregated"}"#: 3,
+        r#"test_report_counter{status="collected"}"#: 2,
+        (format!(r#"test_report_task_counter{{status="aggregated",task="{}"}}"#, task_id.to_base64url())): 3,
+        (format!(r#"test_report_task_counter{{status="collected",task="{}"}}"#, task_id.to_base64url())): 2,
+    });
+}
+
+#[test]
+fn set_batch_fill_level() {
+    let registry = Registry::new();
+    let metrics = DaphneMetrics::register(&registry, Some("test")).unwrap();
+    let task_id = Id([1; 32]);
+
+    metrics.set_batch_fill_level(&task_id, 5, 10);
+
+    assert_metrics_include!(registry, {
+        (format!(r#"test_batch_fill_gauge{{task="{}"}}"#, task_id.to_base64url())): 0.5,
+    });
+}
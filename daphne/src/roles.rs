@@ -6,27 +6,35 @@
 use crate::{
     constants::{
         DRAFT02_MEDIA_TYPE_HPKE_CONFIG, MEDIA_TYPE_AGG_CONT_REQ, MEDIA_TYPE_AGG_CONT_RESP,
-        MEDIA_TYPE_AGG_INIT_REQ, MEDIA_TYPE_AGG_INIT_RESP, MEDIA_TYPE_AGG_SHARE_REQ,
-        MEDIA_TYPE_AGG_SHARE_RESP, MEDIA_TYPE_HPKE_CONFIG_LIST,
+        MEDIA_TYPE_AGG_INIT_REQ, MEDIA_TYPE_AGG_INIT_RESP, MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ,
+        MEDIA_TYPE_AGG_SHARE_EXCLUDE_RESP, MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ,
+        MEDIA_TYPE_AGG_SHARE_RECONCILE_RESP, MEDIA_TYPE_AGG_SHARE_REQ, MEDIA_TYPE_AGG_SHARE_RESP,
+        MEDIA_TYPE_BULK_UPLOAD_RESP, MEDIA_TYPE_HPKE_CONFIG_LIST,
     },
     hpke::HpkeDecrypter,
     messages::{
-        constant_time_eq, decode_base64url, AggregateContinueReq, AggregateInitializeReq,
-        AggregateResp, AggregateShareReq, AggregateShareResp, BatchSelector, CollectReq,
-        CollectResp, HpkeConfigList, Id, PartialBatchSelector, Query, Report, ReportId,
-        ReportMetadata, Time, TransitionFailure, TransitionVar,
+        constant_time_eq, decode_base64url,
+        taskprov::{DpConfig, TaskConfig},
+        AggregateContinueReq, AggregateInitializeReqRef, AggregateRespRef,
+        AggregateShareExcludeReq, AggregateShareExcludeResp, AggregateShareReconcileReq,
+        AggregateShareReconcileResp, AggregateShareReq, AggregateShareResp, BatchSelector,
+        BucketDigest, BulkUploadReq, BulkUploadResp, CollectReq, CollectResp, DecodeRef, Extension,
+        HpkeConfigList, Id, PartialBatchSelector, Query, Report, ReportId, ReportMetadata,
+        ReportUploadResult, Time, TransitionFailure, TransitionVar,
     },
     metrics::DaphneMetrics,
+    taskprov::resolve_advertised_task_config,
     DapAbort, DapAggregateShare, DapCollectJob, DapError, DapGlobalConfig, DapHelperState,
     DapHelperTransition, DapLeaderProcessTelemetry, DapLeaderTransition, DapOutputShare,
-    DapQueryConfig, DapRequest, DapResponse, DapTaskConfig, DapVersion,
+    DapQueryConfig, DapRequest, DapResponse, DapStatelessHelperConfig, DapTaskConfig, DapVersion,
 };
 use async_trait::async_trait;
+use futures::future::try_join_all;
 use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
 use rand::prelude::*;
 use std::borrow::Cow;
-use std::collections::HashMap;
-use tracing::debug;
+use std::collections::{HashMap, HashSet, VecDeque};
+use tracing::{debug, field, info_span, warn, Instrument, Span};
 use url::Url;
 
 /// A party in the DAP protocol who is authorized to send requests to another party.
@@ -51,23 +59,40 @@ where
     type WrappedDapTaskConfig: AsRef<DapTaskConfig>;
 
     /// Decide whether the given DAP request is authorized.
-    async fn authorized(&self, req: &DapRequest<S>) -> Result<bool, DapError>;
+    ///
+    /// If the request advertises the configuration of a task via taskprov, then
+    /// `taskprov_task_config` is that configuration (see [`resolve_advertised_task_config`]). If
+    /// the task is not yet configured, then the request is authorized only if its credentials are
+    /// those of the peer named by `taskprov_task_config`. This must be checked before calling
+    /// [`Self::get_task_config_considering_taskprov`] so that an unauthorized request has no side
+    /// effects.
+    async fn authorized(
+        &self,
+        req: &DapRequest<S>,
+        taskprov_task_config: Option<&TaskConfig>,
+    ) -> Result<bool, DapError>;
 
     /// Look up the DAP global configuration.
     fn get_global_config(&self) -> &DapGlobalConfig;
 
-    /// Decide whether to opt-in or out-out of a task provisioned via taskprov.
+    /// Decide whether to opt-in or out-out of a task provisioned via taskprov. `dp_config` is the
+    /// differential privacy mechanism indicated by the taskprov extension.
     ///
-    /// Returning `Ok(true)` opts in, returning `Ok(false)` opts out, and any error is
-    /// also an opt out, but that error code is used instead of InvalidTask.
-    fn taskprov_opt_in_decision(&self, task_config: &DapTaskConfig) -> Result<bool, DapError>;
+    /// Returning `Ok(())` opts in. Opting out is indicated by [`DapAbort::TaskprovOptOut`]; any
+    /// other error is also an opt out, but that error is returned instead.
+    async fn taskprov_opt_in_decision(
+        &self,
+        task_config: &DapTaskConfig,
+        dp_config: &DpConfig,
+    ) -> Result<(), DapError>;
 
     /// Look up the DAP task configuration for the given task ID.
     ///
-    /// If a `report` has been provided, then look for the draft-wang-ppm-dap-taskprov-<nn> extension
-    /// in the report.  If a taskprov task configuration is successfully read from the report,
-    /// [`DapAggregator::taskprov_opt_in_decision`] will be called, and if it returns Ok(true) the server will opt-in to the task.
-    /// if it returns Ok(false) or an error then the server will opt-out or return an appropriate error.
+    /// If the task is not recognized and a draft-wang-ppm-dap-taskprov-<nn> task configuration
+    /// has been provided (see [`resolve_advertised_task_config`]), then the task is configured
+    /// from it. [`DapAggregator::taskprov_opt_in_decision`] will be called, and if it returns
+    /// Ok(()) the server will opt-in to the task. if it returns an error then the server will
+    /// opt-out and return the error.
     ///
     /// The DAP version must be specified because we may create a DapTaskConfig via taskprov, and we want it
     /// to have the same version as the API entry point the client is using.
@@ -75,7 +100,7 @@ where
         &'srv self,
         version: DapVersion,
         task_id: Cow<'req, Id>,
-        report: Option<&ReportMetadata>,
+        taskprov_task_config: Option<&TaskConfig>,
     ) -> Result<Option<Self::WrappedDapTaskConfig>, DapError>;
 
     /// Look up the DAP task configuration for the given task ID.
@@ -92,6 +117,13 @@ where
     /// Get the current time (number of seconds since the beginning of UNIX time).
     fn get_current_time(&self) -> Time;
 
+    /// Get the current time in milliseconds. This is used to measure latency and is not required
+    /// to be synchronized with [`Self::get_current_time`]. The default implementation has a
+    /// resolution of one second.
+    fn get_current_time_millis(&self) -> u64 {
+        self.get_current_time() * 1000
+    }
+
     /// Check whether the batch determined by the collect request would overlap with a previous
     /// batch.
     async fn is_batch_overlapping(
@@ -104,10 +136,16 @@ where
     /// (resp. Helper) in response to a CollectReq (resp. AggregateShareReq) for fixed-size tasks.
     async fn batch_exists(&self, task_id: &Id, batch_id: &Id) -> Result<bool, DapError>;
 
-    /// Store a set of output shares.
+    /// Store a set of output shares produced by the given aggregation job. The output shares of
+    /// an aggregation job must be merged into each bucket at most once, so that retrying this call
+    /// has no effect on buckets that were already updated. The output shares must not be merged
+    /// into a bucket that has been collected, in which case [`DapAbort::BatchOverlap`] is
+    /// returned. Every bucket is checked before any of them is updated, so that the output
+    /// shares are not merged into some buckets but not others.
     async fn put_out_shares(
         &self,
         task_id: &Id,
+        agg_job_id: &Id,
         part_batch_sel: &PartialBatchSelector,
         out_shares: Vec<DapOutputShare>,
     ) -> Result<(), DapError>;
@@ -119,6 +157,26 @@ where
         batch_sel: &BatchSelector,
     ) -> Result<DapAggregateShare, DapError>;
 
+    /// Daphne extension: Return the checksum of each report aggregated into the given batch. The
+    /// checksums are only known for reports aggregated while
+    /// [`DapGlobalConfig::reconcile_batch_mismatch`] was set, so there may be fewer checksums than
+    /// there are reports in the batch.
+    async fn get_report_checksums(
+        &self,
+        task_id: &Id,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<[u8; 32]>, DapError>;
+
+    /// Daphne extension: Remove the output shares of the reports with the given checksums from
+    /// the aggregate share for the given batch. Checksums of reports that are not in the batch
+    /// are ignored. Return the number of reports that were excluded.
+    async fn exclude_reports(
+        &self,
+        task_id: &Id,
+        batch_sel: &BatchSelector,
+        checksums: &[[u8; 32]],
+    ) -> Result<u64, DapError>;
+
     /// Ensure a set of reorts can be aggregated. Return a transition failure for each report
     /// that must be rejected early, due to the repot being replayed, the bucket that contains the
     /// report being collected, etc.
@@ -217,6 +275,7 @@ macro_rules! leader_post {
             payload: $req_data,
             url,
             sender_auth: Some($role.authorize(&$task_id, $media_type, &$req_data).await?),
+            taskprov: $task_config.taskprov.clone(),
         };
         $role.send_http_post(req).await?
     }};
@@ -234,13 +293,52 @@ where
     /// Store a report for use later on.
     async fn put_report(&self, report: &Report) -> Result<(), DapError>;
 
+    /// Store a sequence of reports for use later on. Return the set of reports that were rejected
+    /// (for example, because they were replayed).
+    ///
+    /// The default implementation stores each report with [`Self::put_report`]. Implementations
+    /// may override it in order to group storage writes.
+    async fn put_reports(
+        &self,
+        reports: &[Report],
+    ) -> Result<HashMap<ReportId, TransitionFailure>, DapError> {
+        let mut rejected = HashMap::new();
+        for report in reports.iter() {
+            match self.put_report(report).await {
+                Ok(()) => (),
+                Err(DapError::Transition(failure)) => {
+                    rejected.insert(report.metadata.id.clone(), failure);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(rejected)
+    }
+
     /// Fetch a sequence of reports to aggregate, grouped by task ID, then by partial batch
-    /// selector. The reports returned are removed from persistent storage.
+    /// selector. Each report returned is later either acknowledged with [`Self::ack_reports`] or
+    /// handed back with [`Self::put_back_reports`]. Implementations may keep the reports in
+    /// persistent storage until then, so that they are not lost if processing fails.
     async fn get_reports(
         &self,
         selector: &Self::ReportSelector,
     ) -> Result<HashMap<Id, HashMap<PartialBatchSelector, Vec<Report>>>, DapError>;
 
+    /// Return reports fetched by [`Self::get_reports`] to persistent storage so that they are
+    /// aggregated later on. This is used to defer aggregation jobs when processing runs out of
+    /// time. It is only called for time-interval queries.
+    async fn put_back_reports(
+        &self,
+        task_id: &Id,
+        part_batch_sel: &PartialBatchSelector,
+        reports: Vec<Report>,
+    ) -> Result<(), DapError>;
+
+    /// Acknowledge reports fetched by [`Self::get_reports`] once they no longer need to be kept:
+    /// they have either been aggregated (or rejected) by an aggregation job or dropped because
+    /// the task has expired.
+    async fn ack_reports(&self, task_id: &Id, report_ids: &[ReportId]) -> Result<(), DapError>;
+
     /// Create a collect job.
     //
     // TODO spec: Figure out if the hostname for the collect URI needs to match the Leader.
@@ -280,44 +378,126 @@ where
 
         let report = Report::get_decoded_with_param(&req.version, req.payload.as_ref())?;
         debug!("report id is {}", report.metadata.id);
-        let task_config = self
-            .get_task_config_considering_taskprov(
-                req.version,
-                Cow::Borrowed(req.task_id()?),
-                Some(&report.metadata),
-            )
-            .await?
-            .ok_or(DapAbort::UnrecognizedTask)?;
+        let task_config =
+            resolve_task_config_for_upload(self, req.version, req.task_id()?, &report).await?;
+        check_report_for_upload(self, &task_config, &report, &mut HashMap::new()).await?;
 
-        // Check whether the DAP version in the request matches the task config.
-        if task_config.as_ref().version != req.version {
+        // Store the report for future processing. At this point, the report may be rejected if
+        // the Leader detects that the report was replayed or pertains to a batch that has already
+        // been collected.
+        Ok(self.put_report(&report).await?)
+    }
+
+    /// Handle HTTP POST to `/upload` with a [`BulkUploadReq`](crate::messages::BulkUploadReq).
+    /// Each report is checked and stored as if it were uploaded by itself; the response is a
+    /// [`BulkUploadResp`](crate::messages::BulkUploadResp) indicating whether each report was
+    /// accepted. The request is aborted only if it can't be parsed, it contains more than
+    /// [`DapGlobalConfig::max_bulk_upload_reports`] reports, or an internal error occurs.
+    ///
+    /// This is a Daphne extension to DAP intended for high-volume clients.
+    async fn http_post_upload_bulk(
+        &'srv self,
+        req: &'req DapRequest<S>,
+    ) -> Result<DapResponse, DapAbort> {
+        // Check whether the DAP version indicated by the sender is supported.
+        if req.version == DapVersion::Unknown {
             return Err(DapAbort::InvalidProtocolVersion);
         }
 
-        if report.encrypted_input_shares.len() != 2 {
-            // TODO spec: Decide if this behavior should be specified.
+        let task_id = req.task_id()?;
+        let bulk_upload_req =
+            BulkUploadReq::get_decoded_with_param(&req.version, req.payload.as_ref())?;
+        if bulk_upload_req.task_id != *task_id {
             return Err(DapAbort::UnrecognizedMessage);
         }
+        debug!(
+            "bulk upload of {} reports for task {task_id}",
+            bulk_upload_req.reports.len(),
+        );
+        let max_reports = self.get_global_config().max_bulk_upload_reports;
+        if bulk_upload_req.reports.len() as u64 > max_reports {
+            return Err(DapAbort::BadRequest(format!(
+                "bulk upload contains more than {max_reports} reports"
+            )));
+        }
 
-        // Check that the indicated HpkeConfig is present.
+        // Check each report. Those that pass are stored together. A report whose ID appears
+        // earlier in the same request is rejected as a replay.
         //
-        // TODO spec: It's not clear if this behavior is MUST, SHOULD, or MAY.
-        if !self
-            .can_hpke_decrypt(&report.task_id, report.encrypted_input_shares[0].config_id)
-            .await?
-        {
-            return Err(DapAbort::UnrecognizedHpkeConfig);
+        // Every report belongs to the same task, so the task configuration is resolved once, by
+        // the first report for which this succeeds.
+        let mut task_config: Option<DapTaskConfig> = None;
+        let mut hpke_config_ids = HashMap::new();
+        let mut rejections: Vec<Option<DapAbort>> =
+            Vec::with_capacity(bulk_upload_req.reports.len());
+        let mut accepted = Vec::with_capacity(bulk_upload_req.reports.len());
+        let mut accepted_index = HashMap::new();
+        for (i, report) in bulk_upload_req.reports.iter().enumerate() {
+            let res = if report.task_id != *task_id {
+                Err(DapAbort::BadRequest(
+                    "report does not belong to the task of the bulk upload".to_string(),
+                ))
+            } else if accepted_index.contains_key(&report.metadata.id) {
+                Err(DapAbort::ReplayedReport)
+            } else {
+                match task_config {
+                    Some(ref task_config) => {
+                        check_report_for_upload(self, task_config, report, &mut hpke_config_ids)
+                            .await
+                    }
+                    None => {
+                        match resolve_task_config_for_upload(self, req.version, task_id, report)
+                            .await
+                        {
+                            Ok(resolved) => {
+                                let task_config = task_config.insert(resolved);
+                                check_report_for_upload(
+                                    self,
+                                    task_config,
+                                    report,
+                                    &mut hpke_config_ids,
+                                )
+                                .await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                }
+            };
+
+            match res {
+                Ok(()) => {
+                    accepted_index.insert(report.metadata.id.clone(), i);
+                    accepted.push(report.clone());
+                    rejections.push(None);
+                }
+                Err(e @ DapAbort::Internal(..)) => return Err(e),
+                Err(e) => rejections.push(Some(e)),
+            }
         }
 
-        // Check that the task has not expired.
-        if report.metadata.time >= task_config.as_ref().expiration {
-            return Err(DapAbort::ReportTooLate);
+        for (report_id, failure) in self.put_reports(&accepted).await?.into_iter() {
+            if let Some(i) = accepted_index.get(&report_id) {
+                rejections[*i] = Some(DapAbort::from(failure));
+            }
         }
 
-        // Store the report for future processing. At this point, the report may be rejected if
-        // the Leader detects that the report was replayed or pertains to a batch that has already
-        // been collected.
-        Ok(self.put_report(&report).await?)
+        let bulk_upload_resp = BulkUploadResp {
+            results: bulk_upload_req
+                .reports
+                .iter()
+                .zip(rejections.into_iter())
+                .map(|(report, rejection)| ReportUploadResult {
+                    report_id: report.metadata.id.clone(),
+                    rejection: rejection.map(|e| e.to_problem_details().typ),
+                })
+                .collect(),
+        };
+
+        Ok(DapResponse {
+            media_type: Some(MEDIA_TYPE_BULK_UPLOAD_RESP),
+            payload: bulk_upload_resp.get_encoded(),
+        })
     }
 
     /// Handle HTTP POST to `/collect`. The input is a [`CollectReq`](crate::messages::CollectReq).
@@ -332,7 +512,16 @@ where
             return Err(DapAbort::InvalidProtocolVersion);
         }
 
-        if !self.authorized(req).await? {
+        // The Collector may provision the task via taskprov by advertising its configuration in
+        // the request.
+        let taskprov_task_config = resolve_advertised_task_config(
+            self.get_global_config().taskprov_version,
+            req.task_id()?,
+            req.taskprov.as_deref(),
+            None,
+        )?;
+
+        if !self.authorized(req, taskprov_task_config.as_ref()).await? {
             debug!("aborted unathorized collect request");
             return Err(DapAbort::UnauthorizedRequest);
         }
@@ -340,7 +529,11 @@ where
         let mut collect_req =
             CollectReq::get_decoded_with_param(&req.version, req.payload.as_ref())?;
         let wrapped_task_config = self
-            .get_task_config_for(Cow::Borrowed(req.task_id()?))
+            .get_task_config_considering_taskprov(
+                req.version,
+                Cow::Borrowed(req.task_id()?),
+                taskprov_task_config.as_ref(),
+            )
             .await?
             .ok_or(DapAbort::UnrecognizedTask)?;
         let task_config = wrapped_task_config.as_ref();
@@ -350,6 +543,13 @@ where
             return Err(DapAbort::InvalidProtocolVersion);
         }
 
+        // Collection is permitted until the task's grace period has elapsed.
+        if task_config
+            .is_past_grace_period(now, self.get_global_config().task_expiration_grace_period)
+        {
+            return Err(DapAbort::TaskExpired);
+        }
+
         if collect_req.query == Query::FixedSizeCurrentBatch {
             // This is where we assign the current batch, and convert the
             // Query::FixedSizeCurrentBatch into a Query::FixedSizeByBatchId.
@@ -414,9 +614,7 @@ where
             .filter(|report| {
                 if let Some(failure) = early_rejects.get(&report.metadata.id) {
                     self.metrics()
-                        .report_counter
-                        .with_label_values(&[&format!("rejected_{failure}")])
-                        .inc();
+                        .report_inc_by(task_id, &format!("rejected_{failure}"), 1);
                     return false;
                 }
                 true
@@ -425,6 +623,7 @@ where
 
         // Prepare AggregateInitializeReq.
         let agg_job_id = Id(rng.gen());
+        Span::current().record("agg_job_id", field::display(&agg_job_id));
         let transition = task_config
             .vdaf
             .produce_agg_init_req(
@@ -454,14 +653,14 @@ where
             MEDIA_TYPE_AGG_INIT_REQ,
             agg_init_req.get_encoded_with_param(&task_config.version)
         );
-        let agg_resp = AggregateResp::get_decoded(&resp.payload)?;
+        let agg_resp = AggregateRespRef::get_decoded_ref(&(), &resp.payload)?;
 
         // Prepare AggreagteContinueReq.
         let transition = task_config.vdaf.handle_agg_resp(
             task_id,
             &agg_job_id,
             state,
-            agg_resp,
+            &agg_resp,
             self.metrics(),
         )?;
         let (uncommited, agg_cont_req) = match transition {
@@ -483,21 +682,21 @@ where
             MEDIA_TYPE_AGG_CONT_REQ,
             agg_cont_req.get_encoded()
         );
-        let agg_resp = AggregateResp::get_decoded(&resp.payload)?;
+        let agg_resp = AggregateRespRef::get_decoded_ref(&(), &resp.payload)?;
 
         // Commit the output shares.
-        let out_shares =
-            task_config
-                .vdaf
-                .handle_final_agg_resp(uncommited, agg_resp, self.metrics())?;
+        let out_shares = task_config.vdaf.handle_final_agg_resp(
+            task_id,
+            uncommited,
+            &agg_resp,
+            self.metrics(),
+        )?;
         let out_shares_count = out_shares.len() as u64;
-        self.put_out_shares(task_id, part_batch_sel, out_shares)
+        self.put_out_shares(task_id, &agg_job_id, part_batch_sel, out_shares)
             .await?;
 
         self.metrics()
-            .report_counter
-            .with_label_values(&["aggregated"])
-            .inc_by(out_shares_count);
+            .report_inc_by(task_id, "aggregated", out_shares_count);
 
         Ok(out_shares_count)
     }
@@ -513,9 +712,10 @@ where
     ) -> Result<u64, DapAbort> {
         debug!("collecting id {collect_id}");
         let batch_selector = BatchSelector::try_from(collect_req.query.clone())?;
-        let leader_agg_share = self
+        let mut leader_agg_share = self
             .get_agg_share(&collect_req.task_id, &batch_selector)
             .await?;
+        Span::current().record("report_count", leader_agg_share.report_count);
 
         // Check the batch size. If not not ready, then return early.
         //
@@ -524,7 +724,20 @@ where
             return Ok(0);
         }
 
-        let batch_selector = BatchSelector::try_from(collect_req.query.clone())?;
+        // If enabled, compare the report count and checksum of each bucket with the Helper's
+        // before asking for its aggregate share. The reports responsible for a mismatch are
+        // excluded from the batch, in which case the aggregate share is fetched again.
+        if self.get_global_config().reconcile_batch_mismatch
+            && reconcile_batch(self, task_config, &collect_req.task_id, &batch_selector).await? > 0
+        {
+            leader_agg_share = self
+                .get_agg_share(&collect_req.task_id, &batch_selector)
+                .await?;
+            Span::current().record("report_count", leader_agg_share.report_count);
+            if !task_config.is_report_count_compatible(leader_agg_share.report_count)? {
+                return Ok(0);
+            }
+        }
 
         // Prepare the Leader's aggregate share.
         let leader_enc_agg_share = task_config.vdaf.produce_leader_encrypted_agg_share(
@@ -568,36 +781,95 @@ where
         self.mark_collected(&agg_share_req.task_id, &agg_share_req.batch_sel)
             .await?;
 
-        self.metrics()
-            .report_counter
-            .with_label_values(&["collected"])
-            .inc_by(agg_share_req.report_count);
+        self.metrics().report_inc_by(
+            &agg_share_req.task_id,
+            "collected",
+            agg_share_req.report_count,
+        );
 
         Ok(agg_share_req.report_count)
     }
 
-    /// Fetch a set of reports grouped by task, then run an aggregation job for each task. once all
-    /// jobs completed, process the collect job queue. It is not safe to run multiple instances of
-    /// this function in parallel.
+    /// Run an aggregation job for each set of reports, grouped by task and then by partial batch
+    /// selector as returned by [`Self::get_reports`]. Reports for expired tasks are skipped. The
+    /// number of reports processed, aggregated, and deferred is added to `telem`.
     ///
-    /// This method is geared primarily towards testing. It also demonstrates how to properly
-    /// synchronize collect and aggregation jobs. If used in a large DAP deployment, it is likely
-    /// create a bottleneck. Such deployments can improve throughput by running many aggregation
-    /// jobs in parallel.
-    async fn process(
+    /// Jobs are interleaved across tasks so that a task with a large backlog does not starve the
+    /// others: each round runs at most one job per task, starting with the task that has the
+    /// fewest reports. Once `deadline` (UNIX time in milliseconds) has passed, the remaining jobs
+    /// for time-interval queries are handed back with [`Self::put_back_reports`].
+    ///
+    /// The deadline does not apply to jobs for fixed-size queries: [`Self::get_reports`] has
+    /// already assigned their reports to a batch, and handing them back would leave the batch
+    /// waiting for reports that may never be aggregated into it. A run may therefore overshoot
+    /// the deadline by the time it takes to run these jobs; this can be bounded by limiting the
+    /// number of reports fetched at once by the report selector.
+    async fn run_agg_jobs(
         &'srv self,
-        selector: &Self::ReportSelector,
-    ) -> Result<DapLeaderProcessTelemetry, DapAbort> {
-        let mut telem = DapLeaderProcessTelemetry::default();
-
-        // Fetch reports and run an aggregation job for each task.
-        for (task_id, reports) in self.get_reports(selector).await?.into_iter() {
+        reports: HashMap<Id, HashMap<PartialBatchSelector, Vec<Report>>>,
+        deadline: Option<u64>,
+        telem: &mut DapLeaderProcessTelemetry,
+    ) -> Result<(), DapAbort> {
+        let mut task_queues = Vec::with_capacity(reports.len());
+        for (task_id, reports) in reports.into_iter() {
             let task_config = self
                 .get_task_config_for(Cow::Owned(task_id.clone()))
                 .await?
                 .ok_or(DapAbort::UnrecognizedTask)?;
 
-            for (part_batch_sel, reports) in reports.into_iter() {
+            // Reports for expired tasks are not aggregated. They are acknowledged so that they
+            // are removed from storage.
+            if task_config.as_ref().is_expired(self.get_current_time()) {
+                let report_ids: Vec<ReportId> = reports
+                    .values()
+                    .flatten()
+                    .map(|report| report.metadata.id.clone())
+                    .collect();
+                let dropped = report_ids.len() as u64;
+                debug!("dropping {dropped} reports for expired task {task_id}");
+                self.ack_reports(&task_id, &report_ids).await?;
+                self.metrics()
+                    .report_inc_by(&task_id, "dropped_task_expired", dropped);
+                telem.reports_dropped += dropped;
+                continue;
+            }
+
+            let jobs: VecDeque<(PartialBatchSelector, Vec<Report>)> = reports
+                .into_iter()
+                .filter(|(_part_batch_sel, reports)| !reports.is_empty())
+                .collect();
+            task_queues.push((task_id, task_config, jobs));
+        }
+
+        loop {
+            let mut round: Vec<(usize, PartialBatchSelector, Vec<Report>)> = task_queues
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, (_task_id, _task_config, jobs))| {
+                    jobs.pop_front()
+                        .map(|(part_batch_sel, reports)| (i, part_batch_sel, reports))
+                })
+                .collect();
+            if round.is_empty() {
+                break;
+            }
+            round.sort_by_key(|(_i, _part_batch_sel, reports)| reports.len());
+
+            for (i, part_batch_sel, reports) in round.into_iter() {
+                let (task_id, task_config, _jobs) = &task_queues[i];
+                if matches!(part_batch_sel, PartialBatchSelector::TimeInterval)
+                    && deadline_passed(self, deadline)
+                {
+                    debug!(
+                        "deferring {} reports for task {task_id}: deadline passed",
+                        reports.len()
+                    );
+                    telem.reports_deferred += reports.len() as u64;
+                    self.put_back_reports(task_id, &part_batch_sel, reports)
+                        .await?;
+                    continue;
+                }
+
                 // TODO Consider splitting reports into smaller chunks.
                 // TODO Consider handling tasks in parallel.
                 telem.reports_processed += reports.len() as u64;
@@ -605,28 +877,115 @@ where
                     "process {} reports for task {task_id} with selector {part_batch_sel:?}",
                     reports.len()
                 );
-                if !reports.is_empty() {
-                    telem.reports_aggregated += self
-                        .run_agg_job(&task_id, task_config.as_ref(), &part_batch_sel, reports)
-                        .await?;
-                }
+                let span = info_span!(
+                    "run_agg_job",
+                    task_id = %task_id,
+                    agg_job_id = field::Empty,
+                    report_count = reports.len()
+                );
+                let report_ids: Vec<ReportId> = reports
+                    .iter()
+                    .map(|report| report.metadata.id.clone())
+                    .collect();
+                let start = self.get_current_time_millis();
+                telem.reports_aggregated += self
+                    .run_agg_job(task_id, task_config.as_ref(), &part_batch_sel, reports)
+                    .instrument(span)
+                    .await?;
+                self.ack_reports(task_id, &report_ids).await?;
+                let end = self.get_current_time_millis();
+                self.metrics()
+                    .aggregation_job_duration
+                    .observe(end.saturating_sub(start) as f64 / 1000.0);
             }
         }
 
-        // Process pending collect jobs. We wait until all aggregation jobs are finished before
-        // proceeding to this step. This is to prevent a race condition involving an aggregate
-        // share computed during a collect job and any output shares computed during an aggregation
-        // job.
-        for (collect_id, collect_req) in self.get_pending_collect_jobs().await? {
+        Ok(())
+    }
+
+    /// Run the collect jobs in `collect_jobs` until `deadline` (UNIX time in milliseconds) passes.
+    /// Return the jobs that are not yet complete, either because the batch is not ready or
+    /// because there was no time left. The number of reports collected is added to `telem`.
+    async fn run_collect_jobs(
+        &'srv self,
+        collect_jobs: Vec<(Id, CollectReq)>,
+        deadline: Option<u64>,
+        telem: &mut DapLeaderProcessTelemetry,
+    ) -> Result<Vec<(Id, CollectReq)>, DapAbort> {
+        let mut incomplete = Vec::new();
+        for (collect_id, collect_req) in collect_jobs {
+            if deadline_passed(self, deadline) {
+                incomplete.push((collect_id, collect_req));
+                continue;
+            }
+
             let task_config = self
                 .get_task_config_for(Cow::Owned(collect_req.task_id.clone()))
                 .await?
                 .ok_or(DapAbort::UnrecognizedTask)?;
 
-            telem.reports_collected += self
+            let span = info_span!(
+                "run_collect_job",
+                task_id = %collect_req.task_id,
+                collect_id = %collect_id,
+                report_count = field::Empty
+            );
+            let reports_collected = self
                 .run_collect_job(&collect_id, task_config.as_ref(), &collect_req)
+                .instrument(span)
                 .await?;
+            if reports_collected == 0 {
+                incomplete.push((collect_id, collect_req));
+            }
+            telem.reports_collected += reports_collected;
         }
+        Ok(incomplete)
+    }
+
+    /// Process pending collect and aggregation jobs until `deadline` (UNIX time in milliseconds)
+    /// passes. It is not safe to run multiple instances of this function in parallel.
+    ///
+    /// Collect jobs whose batches are ready are completed first. Next, a set of reports is
+    /// fetched and an aggregation job is run for each task, as described in
+    /// [`Self::run_agg_jobs`]. Finally, the collect jobs that were not ready are tried again. Once
+    /// the deadline has passed, any remaining work is left queued for the next call, except for
+    /// aggregation jobs for fixed-size queries (see [`Self::run_agg_jobs`]); the amount of deferred
+    /// work is reported in the returned telemetry. If `deadline` is `None`, then all work is done.
+    ///
+    /// This method is geared primarily towards testing. If used in a large DAP deployment, it is
+    /// likely create a bottleneck. Such deployments can improve throughput by running many
+    /// aggregation jobs in parallel.
+    async fn process(
+        &'srv self,
+        selector: &Self::ReportSelector,
+        deadline: Option<u64>,
+    ) -> Result<DapLeaderProcessTelemetry, DapAbort> {
+        let mut telem = DapLeaderProcessTelemetry::default();
+
+        // Complete the collect jobs that are ready. Running them before aggregation does not race
+        // with the aggregation jobs below: reports for a batch that has been collected are
+        // rejected when aggregated.
+        let collect_jobs = self.get_pending_collect_jobs().await?;
+        self.metrics()
+            .collect_job_queue_gauge
+            .with_label_values(&[])
+            .set(collect_jobs.len() as i64);
+        let collect_jobs = self
+            .run_collect_jobs(collect_jobs, deadline, &mut telem)
+            .await?;
+
+        // Fetch reports and run an aggregation job for each task.
+        if !deadline_passed(self, deadline) {
+            let reports = self.get_reports(selector).await?;
+            self.run_agg_jobs(reports, deadline, &mut telem).await?;
+        }
+
+        // Try again the collect jobs that were not ready, as they may have become ready as a
+        // result of aggregation.
+        let collect_jobs = self
+            .run_collect_jobs(collect_jobs, deadline, &mut telem)
+            .await?;
+        telem.collect_jobs_deferred = collect_jobs.len() as u64;
 
         Ok(telem)
     }
@@ -654,6 +1013,13 @@ where
         agg_job_id: &Id,
     ) -> Result<Option<DapHelperState>, DapError>;
 
+    /// Return the configuration for the stateless Helper mode, if enabled. In this mode, the
+    /// Helper's aggregation-flow state is sealed and offloaded to the Leader instead of being
+    /// stored with [`Self::put_helper_state`].
+    fn get_stateless_helper_config(&self) -> Option<&DapStatelessHelperConfig> {
+        None
+    }
+
     /// Handle an HTTP POST to `/aggregate`. The input is either an AggregateInitializeReq or
     /// AggregateContinueReq and the response is an AggregateResp.
     ///
@@ -667,7 +1033,9 @@ where
             return Err(DapAbort::InvalidProtocolVersion);
         }
 
-        if !self.authorized(req).await? {
+        // An aggregation job may provision its task via taskprov, so initialization requests are
+        // authorized once the advertised task configuration has been resolved.
+        if req.media_type != Some(MEDIA_TYPE_AGG_INIT_REQ) && !self.authorized(req, None).await? {
             debug!("aborted unathorized aggregate request");
             return Err(DapAbort::UnauthorizedRequest);
         }
@@ -675,69 +1043,108 @@ where
         match req.media_type {
             Some(MEDIA_TYPE_AGG_INIT_REQ) => {
                 let agg_init_req =
-                    AggregateInitializeReq::get_decoded_with_param(&req.version, &req.payload)?;
+                    AggregateInitializeReqRef::get_decoded_ref(&req.version, &req.payload)?;
+                record_agg_job_fields(
+                    &agg_init_req.task_id,
+                    &agg_init_req.agg_job_id,
+                    agg_init_req.report_shares.len(),
+                );
 
-                let mut first_metadata: Option<&ReportMetadata> = None;
+                let mut first_metadata: Option<ReportMetadata> = None;
 
                 // If taskprov is allowed, ensure that either all of the shares have it or none of them
                 // do (section 6 of draft-wang-ppm-dap-taskprov-02).
                 let global_config = self.get_global_config();
                 if global_config.allow_taskprov {
                     let task_id = req.task_id()?;
-                    let using_taskprov = agg_init_req
-                        .report_shares
-                        .iter()
-                        .filter(|share| {
-                            share
-                                .metadata
-                                .is_taskprov(global_config.taskprov_version, task_id)
-                        })
-                        .count();
+                    let mut using_taskprov = 0;
+                    for report_share in agg_init_req.report_shares.iter() {
+                        if report_share
+                            .metadata
+                            .is_taskprov(global_config.taskprov_version, task_id)
+                        {
+                            using_taskprov += 1;
+                        }
+                    }
 
                     if using_taskprov == agg_init_req.report_shares.len() {
                         // All the extensions use taskprov and look ok, so compute first_metadata.
                         // Note this will always be Some().
                         first_metadata = agg_init_req
                             .report_shares
-                            .first()
-                            .map(|report_share| &report_share.metadata);
+                            .iter()
+                            .next()
+                            .map(|report_share| report_share.metadata.clone());
                     } else if using_taskprov != 0 {
                         // It's not all taskprov or no taskprov, so it's an error.
                         return Err(DapAbort::UnrecognizedMessage);
                     }
                 }
 
+                // The Leader may also advertise the task configuration in the request.
+                let taskprov_task_config = resolve_advertised_task_config(
+                    global_config.taskprov_version,
+                    req.task_id()?,
+                    req.taskprov.as_deref(),
+                    first_metadata.as_ref(),
+                )?;
+
+                if !self.authorized(req, taskprov_task_config.as_ref()).await? {
+                    debug!("aborted unathorized aggregate request");
+                    return Err(DapAbort::UnauthorizedRequest);
+                }
+
                 let wrapped_task_config = self
                     .get_task_config_considering_taskprov(
                         req.version,
                         Cow::Borrowed(req.task_id()?),
-                        first_metadata,
+                        taskprov_task_config.as_ref(),
                     )
                     .await?
                     .ok_or(DapAbort::UnrecognizedTask)?;
                 let task_config = wrapped_task_config.as_ref();
-                let helper_state =
-                    self.get_helper_state(&agg_init_req.task_id, &agg_init_req.agg_job_id);
+
+                // In stateless mode, the Helper has no storage in which to check for an existing
+                // aggregation job.
+                let stateless_helper_config = self.get_stateless_helper_config();
+                let helper_state = async {
+                    if stateless_helper_config.is_some() {
+                        Ok(None)
+                    } else {
+                        self.get_helper_state(&agg_init_req.task_id, &agg_init_req.agg_job_id)
+                            .await
+                    }
+                };
 
                 // Check whether the DAP version in the request matches the task config.
                 if task_config.version != req.version {
                     return Err(DapAbort::InvalidProtocolVersion);
                 }
 
+                // Check that the task has not expired. Aggregation jobs that were initialized
+                // before the task expired may still be continued.
+                if task_config.is_expired(self.get_current_time()) {
+                    return Err(DapAbort::TaskExpired);
+                }
+
                 // Ensure we know which batch the request pertains to.
                 check_part_batch(
                     task_config,
                     &agg_init_req.part_batch_sel,
-                    &agg_init_req.agg_param,
+                    agg_init_req.agg_param,
                 )?;
 
+                // The report metadata is small, so it is copied out of the request. The public and
+                // input shares are decoded in place as they are processed.
+                let report_metadata = agg_init_req
+                    .report_shares
+                    .iter()
+                    .map(|report_share| report_share.metadata.clone())
+                    .collect::<Vec<_>>();
                 let early_rejects_future = self.check_early_reject(
                     &agg_init_req.task_id,
                     &agg_init_req.part_batch_sel,
-                    agg_init_req
-                        .report_shares
-                        .iter()
-                        .map(|report_share| &report_share.metadata),
+                    report_metadata.iter(),
                 );
 
                 let transition = task_config
@@ -787,21 +1194,33 @@ where
                                 // rejection metrics, the latter rejections take precedence. The
                                 // Leader has the opposite behavior: Early rejections are resolved
                                 // first, so take precedence.
-                                self.metrics()
-                                    .report_counter
-                                    .with_label_values(&[&format!("rejected_{failure}")])
-                                    .inc();
+                                self.metrics().report_inc_by(
+                                    &agg_init_req.task_id,
+                                    &format!("rejected_{failure}"),
+                                    1,
+                                );
                             } else {
                                 state_index += 1;
                             }
                         }
 
-                        self.put_helper_state(
-                            &agg_init_req.task_id,
-                            &agg_init_req.agg_job_id,
-                            &state,
-                        )
-                        .await?;
+                        if let Some(config) = stateless_helper_config {
+                            let payload = state.seal(
+                                &task_config.vdaf,
+                                config,
+                                &agg_init_req.task_id,
+                                &agg_init_req.agg_job_id,
+                                self.get_current_time(),
+                            )?;
+                            agg_resp.extensions.push(Extension::HelperState { payload });
+                        } else {
+                            self.put_helper_state(
+                                &agg_init_req.task_id,
+                                &agg_init_req.agg_job_id,
+                                &state,
+                            )
+                            .await?;
+                        }
                         agg_resp
                     }
                     DapHelperTransition::Finish(..) => {
@@ -809,7 +1228,10 @@ where
                     }
                 };
 
-                self.metrics().aggregation_job_gauge.inc();
+                self.metrics()
+                    .aggregation_job_gauge
+                    .with_label_values(&[])
+                    .inc();
 
                 Ok(DapResponse {
                     media_type: Some(MEDIA_TYPE_AGG_INIT_RESP),
@@ -818,6 +1240,11 @@ where
             }
             Some(MEDIA_TYPE_AGG_CONT_REQ) => {
                 let agg_cont_req = AggregateContinueReq::get_decoded(&req.payload)?;
+                record_agg_job_fields(
+                    &agg_cont_req.task_id,
+                    &agg_cont_req.agg_job_id,
+                    agg_cont_req.transitions.len(),
+                );
                 let wrapped_task_config = self
                     .get_task_config_for(Cow::Borrowed(req.task_id()?))
                     .await?
@@ -829,10 +1256,33 @@ where
                     return Err(DapAbort::InvalidProtocolVersion);
                 }
 
-                let state = self
-                    .get_helper_state(&agg_cont_req.task_id, &agg_cont_req.agg_job_id)
-                    .await?
-                    .ok_or(DapAbort::UnrecognizedAggregationJob)?;
+                // If the Helper offloaded its state to the Leader, then the state is echoed back
+                // in the request. Otherwise it is in storage.
+                let sealed_state =
+                    agg_cont_req
+                        .extensions
+                        .iter()
+                        .find_map(|extension| match extension {
+                            Extension::HelperState { payload } => Some(payload),
+                            _ => None,
+                        });
+                let state = match (sealed_state, self.get_stateless_helper_config()) {
+                    (Some(sealed_state), Some(config)) => DapHelperState::open(
+                        &task_config.vdaf,
+                        config,
+                        &agg_cont_req.task_id,
+                        &agg_cont_req.agg_job_id,
+                        self.get_current_time(),
+                        sealed_state,
+                    )?,
+                    (Some(..), None) => {
+                        return Err(DapAbort::BadRequest("unexpected helper state".into()));
+                    }
+                    (None, _) => self
+                        .get_helper_state(&agg_cont_req.task_id, &agg_cont_req.agg_job_id)
+                        .await?
+                        .ok_or(DapAbort::UnrecognizedAggregationJob)?,
+                };
                 let part_batch_sel = state.part_batch_sel.clone();
                 let transition =
                     task_config
@@ -845,18 +1295,24 @@ where
                     }
                     DapHelperTransition::Finish(out_shares, agg_resp) => {
                         let out_shares_count = u64::try_from(out_shares.len()).unwrap();
-                        self.put_out_shares(&agg_cont_req.task_id, &part_batch_sel, out_shares)
-                            .await?;
+                        self.put_out_shares(
+                            &agg_cont_req.task_id,
+                            &agg_cont_req.agg_job_id,
+                            &part_batch_sel,
+                            out_shares,
+                        )
+                        .await?;
                         (agg_resp, out_shares_count)
                     }
                 };
 
                 self.metrics()
-                    .report_counter
-                    .with_label_values(&["aggregated"])
-                    .inc_by(out_shares_count);
+                    .report_inc_by(&agg_cont_req.task_id, "aggregated", out_shares_count);
 
-                self.metrics().aggregation_job_gauge.dec();
+                self.metrics()
+                    .aggregation_job_gauge
+                    .with_label_values(&[])
+                    .dec();
 
                 Ok(DapResponse {
                     media_type: Some(MEDIA_TYPE_AGG_CONT_RESP),
@@ -883,11 +1339,14 @@ where
             return Err(DapAbort::InvalidProtocolVersion);
         }
 
-        if !self.authorized(req).await? {
+        if !self.authorized(req, None).await? {
             return Err(DapAbort::UnauthorizedRequest);
         }
 
         let agg_share_req = AggregateShareReq::get_decoded_with_param(&req.version, &req.payload)?;
+        Span::current()
+            .record("task_id", field::display(&agg_share_req.task_id))
+            .record("report_count", agg_share_req.report_count);
         let wrapped_task_config = self
             .get_task_config_for(Cow::Borrowed(req.task_id()?))
             .await?
@@ -899,6 +1358,13 @@ where
             return Err(DapAbort::InvalidProtocolVersion);
         }
 
+        // Collection is permitted until the task's grace period has elapsed.
+        if task_config
+            .is_past_grace_period(now, self.get_global_config().task_expiration_grace_period)
+        {
+            return Err(DapAbort::TaskExpired);
+        }
+
         // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
         // collected batches.
         check_batch(
@@ -946,16 +1412,426 @@ where
             encrypted_agg_share,
         };
 
-        self.metrics()
-            .report_counter
-            .with_label_values(&["collected"])
-            .inc_by(agg_share_req.report_count);
+        self.metrics().report_inc_by(
+            &agg_share_req.task_id,
+            "collected",
+            agg_share_req.report_count,
+        );
 
         Ok(DapResponse {
             media_type: Some(MEDIA_TYPE_AGG_SHARE_RESP),
             payload: agg_share_resp.get_encoded(),
         })
     }
+
+    /// Handle an HTTP POST to `/aggregate_share/reconcile`. The input is an
+    /// AggregateShareReconcileReq and the response is an AggregateShareReconcileResp listing the
+    /// report count and checksum of each bucket in the batch.
+    ///
+    /// This is a Daphne extension to DAP that is called by the Leader during the Collection phase
+    /// in order to pinpoint the source of a batch mismatch. It does not mark anything as
+    /// collected.
+    async fn http_post_aggregate_share_reconcile(
+        &'srv self,
+        req: &'req DapRequest<S>,
+    ) -> Result<DapResponse, DapAbort> {
+        let now = self.get_current_time();
+
+        // Check whether the DAP version indicated by the sender is supported.
+        if req.version == DapVersion::Unknown {
+            return Err(DapAbort::InvalidProtocolVersion);
+        }
+
+        if !self.authorized(req, None).await? {
+            return Err(DapAbort::UnauthorizedRequest);
+        }
+
+        let reconcile_req = AggregateShareReconcileReq::get_decoded(&req.payload)?;
+        Span::current().record("task_id", field::display(&reconcile_req.task_id));
+        let wrapped_task_config = self
+            .get_task_config_for(Cow::Borrowed(req.task_id()?))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+        let task_config = wrapped_task_config.as_ref();
+
+        // Check whether the DAP version in the request matches the task config.
+        if task_config.version != req.version {
+            return Err(DapAbort::InvalidProtocolVersion);
+        }
+
+        // Collection is permitted until the task's grace period has elapsed.
+        if task_config
+            .is_past_grace_period(now, self.get_global_config().task_expiration_grace_period)
+        {
+            return Err(DapAbort::TaskExpired);
+        }
+
+        if !task_config
+            .query
+            .is_valid_batch_sel(&reconcile_req.batch_sel)
+        {
+            return Err(DapAbort::QueryMismatch);
+        }
+
+        let reconcile_resp = AggregateShareReconcileResp {
+            buckets: bucket_digests(
+                self,
+                task_config,
+                &reconcile_req.task_id,
+                &reconcile_req.batch_sel,
+            )
+            .await?,
+        };
+
+        Ok(DapResponse {
+            media_type: Some(MEDIA_TYPE_AGG_SHARE_RECONCILE_RESP),
+            payload: reconcile_resp.get_encoded(),
+        })
+    }
+
+    /// Handle an HTTP POST to `/aggregate_share/exclude`. The input is an
+    /// AggregateShareExcludeReq listing the reports the Leader has aggregated into a batch. The
+    /// Helper excludes from the batch each report the Leader has not aggregated and responds with
+    /// an AggregateShareExcludeResp listing each report the Helper has not aggregated.
+    ///
+    /// This is a Daphne extension to DAP that is called by the Leader during the Collection phase
+    /// in order to resolve a batch mismatch. It is only supported if
+    /// [`DapGlobalConfig::reconcile_batch_mismatch`] is set.
+    async fn http_post_aggregate_share_exclude(
+        &'srv self,
+        req: &'req DapRequest<S>,
+    ) -> Result<DapResponse, DapAbort> {
+        let now = self.get_current_time();
+
+        // Check whether the DAP version indicated by the sender is supported.
+        if req.version == DapVersion::Unknown {
+            return Err(DapAbort::InvalidProtocolVersion);
+        }
+
+        if !self.authorized(req, None).await? {
+            return Err(DapAbort::UnauthorizedRequest);
+        }
+
+        if !self.get_global_config().reconcile_batch_mismatch {
+            return Err(DapAbort::BadRequest(
+                "batch reconciliation is not enabled".into(),
+            ));
+        }
+
+        let exclude_req = AggregateShareExcludeReq::get_decoded(&req.payload)?;
+        Span::current().record("task_id", field::display(&exclude_req.task_id));
+        let wrapped_task_config = self
+            .get_task_config_for(Cow::Borrowed(req.task_id()?))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+        let task_config = wrapped_task_config.as_ref();
+
+        // Check whether the DAP version in the request matches the task config.
+        if task_config.version != req.version {
+            return Err(DapAbort::InvalidProtocolVersion);
+        }
+
+        // Collection is permitted until the task's grace period has elapsed.
+        if task_config
+            .is_past_grace_period(now, self.get_global_config().task_expiration_grace_period)
+        {
+            return Err(DapAbort::TaskExpired);
+        }
+
+        if !task_config.query.is_valid_batch_sel(&exclude_req.batch_sel) {
+            return Err(DapAbort::QueryMismatch);
+        }
+
+        // Reports can't be excluded from a batch that has already been collected.
+        if self
+            .is_batch_overlapping(&exclude_req.task_id, &exclude_req.batch_sel)
+            .await?
+        {
+            return Err(DapAbort::BatchOverlap);
+        }
+
+        let (helper_checksums, leader_only) = diff_report_checksums(
+            self,
+            &exclude_req.task_id,
+            &exclude_req.batch_sel,
+            &exclude_req.checksums,
+        )
+        .await?;
+
+        let helper_only: Vec<[u8; 32]> = helper_checksums
+            .difference(&exclude_req.checksums.iter().copied().collect())
+            .copied()
+            .collect();
+        let excluded = self
+            .exclude_reports(&exclude_req.task_id, &exclude_req.batch_sel, &helper_only)
+            .await?;
+        self.metrics()
+            .report_inc_by(&exclude_req.task_id, "rejected_batch_mismatch", excluded);
+
+        let exclude_resp = AggregateShareExcludeResp {
+            checksums: leader_only,
+        };
+
+        Ok(DapResponse {
+            media_type: Some(MEDIA_TYPE_AGG_SHARE_EXCLUDE_RESP),
+            payload: exclude_resp.get_encoded(),
+        })
+    }
+}
+
+/// Resolve the configuration of the task for which a report was uploaded. If the report
+/// advertises the task via taskprov, then the task may be provisioned.
+async fn resolve_task_config_for_upload<'srv, 'req, S>(
+    leader: &'srv impl DapLeader<'srv, 'req, S>,
+    version: DapVersion,
+    task_id: &'req Id,
+    report: &Report,
+) -> Result<DapTaskConfig, DapAbort>
+where
+    'srv: 'req,
+{
+    let taskprov_task_config = resolve_advertised_task_config(
+        leader.get_global_config().taskprov_version,
+        task_id,
+        None,
+        Some(&report.metadata),
+    )?;
+    let task_config = leader
+        .get_task_config_considering_taskprov(
+            version,
+            Cow::Borrowed(task_id),
+            taskprov_task_config.as_ref(),
+        )
+        .await?
+        .ok_or(DapAbort::UnrecognizedTask)?;
+
+    // Check whether the DAP version in the request matches the task config.
+    if task_config.as_ref().version != version {
+        return Err(DapAbort::InvalidProtocolVersion);
+    }
+
+    Ok(task_config.as_ref().clone())
+}
+
+/// Check that a report uploaded for the given task can be stored for aggregation. Whether we can
+/// decrypt under the report's HPKE config is looked up once per config ID and recorded in
+/// `hpke_config_ids`, so that the lookup can be shared by the reports of a bulk upload.
+async fn check_report_for_upload<'srv, 'req, S>(
+    leader: &'srv impl DapLeader<'srv, 'req, S>,
+    task_config: &DapTaskConfig,
+    report: &Report,
+    hpke_config_ids: &mut HashMap<u8, bool>,
+) -> Result<(), DapAbort>
+where
+    'srv: 'req,
+{
+    if report.encrypted_input_shares.len() != 2 {
+        // TODO spec: Decide if this behavior should be specified.
+        return Err(DapAbort::UnrecognizedMessage);
+    }
+
+    // Check that the indicated HpkeConfig is present.
+    //
+    // TODO spec: It's not clear if this behavior is MUST, SHOULD, or MAY.
+    let config_id = report.encrypted_input_shares[0].config_id;
+    let can_hpke_decrypt = match hpke_config_ids.get(&config_id) {
+        Some(can_hpke_decrypt) => *can_hpke_decrypt,
+        None => {
+            let can_hpke_decrypt = leader.can_hpke_decrypt(&report.task_id, config_id).await?;
+            hpke_config_ids.insert(config_id, can_hpke_decrypt);
+            can_hpke_decrypt
+        }
+    };
+    if !can_hpke_decrypt {
+        return Err(DapAbort::UnrecognizedHpkeConfig);
+    }
+
+    // Check that the task has not expired.
+    if task_config.is_expired(leader.get_current_time()) {
+        return Err(DapAbort::TaskExpired);
+    }
+
+    // Check that the report was not generated after the task expired.
+    if report.metadata.time >= task_config.expiration {
+        return Err(DapAbort::ReportTooLate);
+    }
+
+    Ok(())
+}
+
+/// Compute the report count and checksum of each bucket in the given batch.
+async fn bucket_digests<'srv, 'req, S>(
+    agg: &impl DapAggregator<'srv, 'req, S>,
+    task_config: &DapTaskConfig,
+    task_id: &Id,
+    batch_sel: &BatchSelector,
+) -> Result<Vec<BucketDigest>, DapAbort>
+where
+    'srv: 'req,
+{
+    let bucket_sels: Vec<BatchSelector> = task_config
+        .batch_span_for_sel(batch_sel)?
+        .iter()
+        .map(|bucket| task_config.batch_sel_for_bucket(bucket))
+        .collect();
+    let agg_shares = try_join_all(
+        bucket_sels
+            .iter()
+            .map(|bucket_sel| agg.get_agg_share(task_id, bucket_sel)),
+    )
+    .await?;
+    Ok(bucket_sels
+        .into_iter()
+        .zip(agg_shares)
+        .map(|(bucket_sel, agg_share)| BucketDigest {
+            batch_sel: bucket_sel,
+            report_count: agg_share.report_count,
+            checksum: agg_share.checksum,
+        })
+        .collect())
+}
+
+/// Fetch the checksum of each report we have aggregated into the given batch and compare them to
+/// the peer's. Return our checksums and the peer's checksums that we don't have. Abort with a
+/// batch mismatch if we don't know the checksum of every report in the batch, as the batch can't
+/// be reconciled in that case.
+async fn diff_report_checksums<'srv, 'req, S>(
+    agg: &impl DapAggregator<'srv, 'req, S>,
+    task_id: &Id,
+    batch_sel: &BatchSelector,
+    peer_checksums: &[[u8; 32]],
+) -> Result<(HashSet<[u8; 32]>, Vec<[u8; 32]>), DapAbort>
+where
+    'srv: 'req,
+{
+    let (agg_share, checksums) = futures::try_join!(
+        agg.get_agg_share(task_id, batch_sel),
+        agg.get_report_checksums(task_id, batch_sel),
+    )?;
+    let checksums: HashSet<[u8; 32]> = checksums.into_iter().collect();
+    if checksums.len() as u64 != agg_share.report_count {
+        warn!(
+            "task {task_id}: batch {batch_sel:?} can't be reconciled: {} reports were aggregated, but only {} can be excluded",
+            agg_share.report_count,
+            checksums.len(),
+        );
+        return Err(DapAbort::BatchMismatch);
+    }
+
+    let peer_only = peer_checksums
+        .iter()
+        .filter(|checksum| !checksums.contains(*checksum))
+        .copied()
+        .collect();
+    Ok((checksums, peer_only))
+}
+
+/// Ask the Helper for the report count and checksum of each bucket in the batch and compare them
+/// to our own. For each bucket that differs, exchange the checksums of the reports in the bucket
+/// with the Helper so that the reports only one of us has aggregated are excluded. Return the
+/// number of reports we excluded.
+async fn reconcile_batch<'srv, 'req, S>(
+    leader: &impl DapLeader<'srv, 'req, S>,
+    task_config: &DapTaskConfig,
+    task_id: &Id,
+    batch_sel: &BatchSelector,
+) -> Result<u64, DapAbort>
+where
+    'srv: 'req,
+{
+    let reconcile_req = AggregateShareReconcileReq {
+        task_id: task_id.clone(),
+        batch_sel: batch_sel.clone(),
+    };
+    let resp = leader_post!(
+        leader,
+        task_id,
+        task_config,
+        "aggregate_share/reconcile",
+        MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ,
+        reconcile_req.get_encoded()
+    );
+    let reconcile_resp = AggregateShareReconcileResp::get_decoded(&resp.payload)?;
+
+    let mut helper_digests: HashMap<BatchSelector, BucketDigest> = reconcile_resp
+        .buckets
+        .into_iter()
+        .map(|digest| (digest.batch_sel.clone(), digest))
+        .collect();
+
+    let mut mismatched = Vec::new();
+    for leader_digest in bucket_digests(leader, task_config, task_id, batch_sel).await? {
+        let (helper_report_count, helper_checksum) =
+            match helper_digests.remove(&leader_digest.batch_sel) {
+                Some(helper_digest) => (helper_digest.report_count, helper_digest.checksum),
+                None => (0, [0; 32]),
+            };
+
+        if leader_digest.report_count != helper_report_count
+            || !constant_time_eq(&leader_digest.checksum, &helper_checksum)
+        {
+            warn!(
+                "task {task_id}: bucket {:?} mismatched: leader has {} reports with checksum {}, helper has {} reports with checksum {}",
+                leader_digest.batch_sel,
+                leader_digest.report_count,
+                hex::encode(leader_digest.checksum),
+                helper_report_count,
+                hex::encode(helper_checksum),
+            );
+            mismatched.push(leader_digest.batch_sel);
+        }
+    }
+
+    // Any buckets remaining are ones the Helper reported but we didn't expect.
+    for (bucket_sel, helper_digest) in helper_digests.into_iter() {
+        warn!(
+            "task {task_id}: helper reported unexpected bucket {bucket_sel:?} with {} reports",
+            helper_digest.report_count
+        );
+        mismatched.push(bucket_sel);
+    }
+
+    let mut excluded = 0;
+    for bucket_sel in mismatched {
+        let (leader_checksums, _) =
+            diff_report_checksums(leader, task_id, &bucket_sel, &[]).await?;
+        let exclude_req = AggregateShareExcludeReq {
+            task_id: task_id.clone(),
+            batch_sel: bucket_sel,
+            checksums: leader_checksums.into_iter().collect(),
+        };
+        let resp = leader_post!(
+            leader,
+            task_id,
+            task_config,
+            "aggregate_share/exclude",
+            MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ,
+            exclude_req.get_encoded()
+        );
+        let exclude_resp = AggregateShareExcludeResp::get_decoded(&resp.payload)?;
+        excluded += leader
+            .exclude_reports(task_id, &exclude_req.batch_sel, &exclude_resp.checksums)
+            .await?;
+    }
+
+    if excluded > 0 {
+        warn!("task {task_id}: excluded {excluded} reports to reconcile batch {batch_sel:?}");
+        leader
+            .metrics()
+            .report_inc_by(task_id, "rejected_batch_mismatch", excluded);
+    }
+    Ok(excluded)
+}
+
+/// Check whether `deadline` (UNIX time in milliseconds) has passed.
+fn deadline_passed<'srv, 'req, S>(
+    aggregator: &impl DapAggregator<'srv, 'req, S>,
+    deadline: Option<u64>,
+) -> bool
+where
+    'srv: 'req,
+{
+    deadline.is_some_and(|deadline| aggregator.get_current_time_millis() >= deadline)
 }
 
 fn check_part_batch(
@@ -976,6 +1852,15 @@ fn check_part_batch(
     Ok(())
 }
 
+/// Record the task ID, aggregation job ID, and number of reports of an aggregation job in the
+/// current span. The fields are only recorded if they were declared when the span was created.
+fn record_agg_job_fields(task_id: &Id, agg_job_id: &Id, report_count: usize) {
+    Span::current()
+        .record("task_id", field::display(task_id))
+        .record("agg_job_id", field::display(agg_job_id))
+        .record("report_count", report_count);
+}
+
 async fn check_batch<'srv, 'req, S>(
     agg: &impl DapAggregator<'srv, 'req, S>,
     task_config: &DapTaskConfig,
@@ -7,32 +7,35 @@ use crate::{
     auth::BearerToken,
     constants::{
         DRAFT02_MEDIA_TYPE_HPKE_CONFIG, MEDIA_TYPE_AGG_CONT_REQ, MEDIA_TYPE_AGG_INIT_REQ,
-        MEDIA_TYPE_AGG_SHARE_REQ, MEDIA_TYPE_COLLECT_REQ, MEDIA_TYPE_REPORT,
+        MEDIA_TYPE_AGG_SHARE_REQ, MEDIA_TYPE_BULK_UPLOAD_REQ, MEDIA_TYPE_BULK_UPLOAD_RESP,
+        MEDIA_TYPE_COLLECT_REQ, MEDIA_TYPE_REPORT,
     },
     hpke::{HpkeDecrypter, HpkeReceiverConfig},
     messages::{
-        taskprov, AggregateContinueReq, AggregateInitializeReq, AggregateResp, AggregateShareReq,
-        BatchSelector, CollectReq, CollectResp, Extension, HpkeKemId, Id, Interval,
-        PartialBatchSelector, Query, Report, ReportId, ReportMetadata, ReportShare, Time,
-        Transition, TransitionFailure, TransitionVar,
+        encode_base64url, taskprov, AggregateContinueReq, AggregateInitializeReq, AggregateResp,
+        AggregateShareReq, BatchSelector, BulkUploadReq, BulkUploadResp, CollectReq, CollectResp,
+        Extension, HpkeKemId, Id, Interval, PartialBatchSelector, Query, Report, ReportId,
+        ReportMetadata, ReportShare, ReportUploadResult, Time, Transition, TransitionFailure,
+        TransitionVar,
     },
     metrics::DaphneMetrics,
     roles::{early_metadata_check, DapAggregator, DapAuthorizedSender, DapHelper, DapLeader},
-    taskprov::TaskprovVersion,
+    taskprov::{TaskprovOptInPolicy, TaskprovVersion},
     test_version, test_versions,
     testing::{AggStore, DapBatchBucketOwned, MockAggregator, MockAggregatorReportSelector},
-    vdaf::VdafVerifyKey,
-    DapAbort, DapAggregateShare, DapCollectJob, DapGlobalConfig, DapMeasurement, DapQueryConfig,
-    DapRequest, DapTaskConfig, DapVersion, Prio3Config, VdafConfig,
+    vdaf::{VdafAggregateShare, VdafVerifyKey},
+    DapAbort, DapAggregateShare, DapCollectJob, DapError, DapGlobalConfig, DapHelperState,
+    DapLeaderProcessTelemetry, DapMeasurement, DapOutputShare, DapQueryConfig, DapRequest,
+    DapStatelessHelperConfig, DapTaskConfig, DapVersion, Prio3Config, VdafConfig,
 };
 use assert_matches::assert_matches;
 use matchit::Router;
 use paste::paste;
-use prio::codec::{Decode, Encode, ParameterizedEncode};
+use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
 use rand::{thread_rng, Rng};
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
     time::SystemTime,
     vec,
@@ -64,6 +67,26 @@ struct Test {
 
 impl Test {
     fn new(version: DapVersion) -> Self {
+        Self::new_with_stateless_helper_config(version, None)
+    }
+
+    fn new_with_stateless_helper_config(
+        version: DapVersion,
+        stateless_helper_config: Option<DapStatelessHelperConfig>,
+    ) -> Self {
+        Self::build(version, stateless_helper_config, false)
+    }
+
+    /// Like [`Self::new`], except that both Aggregators reconcile batch mismatches.
+    fn new_with_reconcile_batch_mismatch(version: DapVersion) -> Self {
+        Self::build(version, None, true)
+    }
+
+    fn build(
+        version: DapVersion,
+        stateless_helper_config: Option<DapStatelessHelperConfig>,
+        reconcile_batch_mismatch: bool,
+    ) -> Self {
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -81,6 +104,9 @@ impl Test {
             supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
             allow_taskprov: true,
             taskprov_version: TaskprovVersion::Draft02,
+            task_expiration_grace_period: Some(604800),
+            reconcile_batch_mismatch,
+            max_bulk_upload_reports: 1000,
         };
 
         // Task Parameters that the Leader and Helper must agree on.
@@ -109,6 +135,8 @@ impl Test {
                 query: DapQueryConfig::TimeInterval,
                 vdaf: vdaf_config.clone(),
                 vdaf_verify_key: VdafVerifyKey::Prio3(rng.gen()),
+                retired_collector_hpke_configs: Vec::new(),
+                taskprov: None,
             },
         );
         tasks.insert(
@@ -124,6 +152,8 @@ impl Test {
                 query: DapQueryConfig::FixedSize { max_batch_size: 2 },
                 vdaf: vdaf_config.clone(),
                 vdaf_verify_key: VdafVerifyKey::Prio3(rng.gen()),
+                retired_collector_hpke_configs: Vec::new(),
+                taskprov: None,
             },
         );
         tasks.insert(
@@ -139,6 +169,8 @@ impl Test {
                 query: DapQueryConfig::TimeInterval,
                 vdaf: vdaf_config,
                 vdaf_verify_key: VdafVerifyKey::Prio3(rng.gen()),
+                retired_collector_hpke_configs: Vec::new(),
+                taskprov: None,
             },
         );
 
@@ -167,7 +199,9 @@ impl Test {
             agg_store: Arc::new(Mutex::new(HashMap::new())),
             collector_hpke_config: collector_hpke_receiver_config.config.clone(),
             taskprov_vdaf_verify_key_init,
+            taskprov_opt_in_policy: TaskprovOptInPolicy::default(),
             metrics: DaphneMetrics::register(&prometheus_registry, Some("test_helper")).unwrap(),
+            stateless_helper_config,
             peer: None,
         });
 
@@ -187,7 +221,9 @@ impl Test {
             agg_store: Arc::new(Mutex::new(HashMap::new())),
             collector_hpke_config: collector_hpke_receiver_config.config,
             taskprov_vdaf_verify_key_init,
+            taskprov_opt_in_policy: TaskprovOptInPolicy::default(),
             metrics: DaphneMetrics::register(&prometheus_registry, Some("test_leader")).unwrap(),
+            stateless_helper_config: None,
             peer: Some(Arc::clone(&helper)),
         });
 
@@ -215,6 +251,7 @@ impl Test {
             payload: report.get_encoded_with_param(&version),
             url: task_config.leader_url.join("upload").unwrap(),
             sender_auth: None,
+            taskprov: None,
         }
     }
 
@@ -264,6 +301,7 @@ impl Test {
                 task_id: task_id.clone(),
                 agg_job_id,
                 transitions,
+                extensions: Vec::new(),
             },
             task_config.helper_url.join("aggregate").unwrap(),
         )
@@ -403,6 +441,7 @@ impl Test {
             payload,
             url,
             sender_auth,
+            taskprov: None,
         }
     }
 
@@ -428,6 +467,7 @@ impl Test {
             payload,
             url,
             sender_auth,
+            taskprov: None,
         }
     }
 
@@ -446,6 +486,7 @@ impl Test {
             payload: msg.get_encoded_with_param(&version),
             url,
             sender_auth: Some(self.collector_token.clone()),
+            taskprov: None,
         }
     }
 }
@@ -506,7 +547,7 @@ async fn http_post_aggregate_init_unauthorized_request(version: DapVersion) {
 
 async_test_versions! { http_post_aggregate_init_unauthorized_request }
 
-// Test that the Helper rejects reports past the expiration date.
+// Test that the Helper rejects aggregation jobs for tasks that have expired.
 async fn http_post_aggregate_init_expired_task(version: DapVersion) {
     let t = Test::new(version);
 
@@ -520,12 +561,9 @@ async fn http_post_aggregate_init_expired_task(version: DapVersion) {
         .gen_test_agg_init_req(&t.expired_task_id, vec![report_share])
         .await;
 
-    let resp = t.helper.http_post_aggregate(&req).await.unwrap();
-    let agg_resp = AggregateResp::get_decoded(&resp.payload).unwrap();
-    assert_eq!(agg_resp.transitions.len(), 1);
     assert_matches!(
-        agg_resp.transitions[0].var,
-        TransitionVar::Failed(TransitionFailure::TaskExpired)
+        t.helper.http_post_aggregate(&req).await,
+        Err(DapAbort::TaskExpired)
     );
 }
 
@@ -546,6 +584,7 @@ async fn http_get_hpke_config_unrecognized_task(version: DapVersion) {
         ))
         .unwrap(),
         sender_auth: None,
+        taskprov: None,
     };
 
     assert_matches!(
@@ -565,6 +604,7 @@ async fn http_get_hpke_config_missing_task_id(version: DapVersion) {
         payload: Vec::new(),
         url: Url::parse("http://aggregator.biz/v02/hpke_config").unwrap(),
         sender_auth: None,
+        taskprov: None,
     };
 
     // An Aggregator is permitted to abort an HPKE config request if the task ID is missing. Note
@@ -699,6 +739,7 @@ async fn http_post_collect_unauthorized_request(version: DapVersion) {
         .get_encoded_with_param(&task_config.version),
         url: task_config.leader_url.join("collect").unwrap(),
         sender_auth: None, // Unauthorized request.
+        taskprov: None,
     };
 
     // Expect failure due to missing bearer token.
@@ -775,6 +816,137 @@ async fn http_post_aggregate_transition_continue(version: DapVersion) {
 
 async_test_versions! { http_post_aggregate_transition_continue }
 
+async fn stateless_helper_run_agg_job(version: DapVersion) {
+    let t = Test::new_with_stateless_helper_config(
+        version,
+        Some(DapStatelessHelperConfig {
+            key: thread_rng().gen(),
+            state_lifetime: 3600,
+        }),
+    );
+    let task_id = &t.time_interval_task_id;
+    let report_sel = MockAggregatorReportSelector(task_id.clone());
+
+    let report = t.gen_test_report(task_id).await;
+    let req = t.gen_test_upload_req(report).await;
+    t.leader.http_post_upload(&req).await.unwrap();
+
+    // The Leader carries the Helper's state from the first round to the second.
+    let telem = t.leader.process(&report_sel, None).await.unwrap();
+    assert_eq!(telem.reports_aggregated, 1);
+    assert!(t
+        .helper
+        .helper_state_store
+        .lock()
+        .expect("helper_state_store: failed to lock")
+        .is_empty());
+}
+
+async_test_versions! { stateless_helper_run_agg_job }
+
+async fn stateless_helper_rejects_bad_state(version: DapVersion) {
+    let stateless_helper_config = DapStatelessHelperConfig {
+        key: thread_rng().gen(),
+        state_lifetime: 3600,
+    };
+    let t = Test::new_with_stateless_helper_config(version, Some(stateless_helper_config.clone()));
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.helper.unchecked_get_task_config(task_id).await;
+
+    let report = t.gen_test_report(task_id).await;
+    let report_shares = vec![ReportShare {
+        metadata: report.metadata.clone(),
+        public_share: report.public_share,
+        encrypted_input_share: report.encrypted_input_shares[1].clone(),
+    }];
+    let req = t.gen_test_agg_init_req(task_id, report_shares).await;
+    let agg_job_id = AggregateInitializeReq::get_decoded_with_param(&version, &req.payload)
+        .unwrap()
+        .agg_job_id;
+
+    // The Helper returns its state to the Leader rather than storing it.
+    let agg_resp =
+        AggregateResp::get_decoded(&t.helper.http_post_aggregate(&req).await.unwrap().payload)
+            .unwrap();
+    assert!(t
+        .helper
+        .helper_state_store
+        .lock()
+        .expect("helper_state_store: failed to lock")
+        .is_empty());
+    let sealed = match &agg_resp.extensions[..] {
+        [Extension::HelperState { payload }] => payload.clone(),
+        _ => panic!("unexpected extensions: {:?}", agg_resp.extensions),
+    };
+
+    // The Helper keeps no record of the aggregation job, but the reports of a replayed request are
+    // rejected. Replayed continuations are likewise harmless, since the output shares of an
+    // aggregation job are only aggregated once.
+    let agg_resp =
+        AggregateResp::get_decoded(&t.helper.http_post_aggregate(&req).await.unwrap().payload)
+            .unwrap();
+    assert_matches!(
+        agg_resp.transitions[0].var,
+        TransitionVar::Failed(TransitionFailure::ReportReplayed)
+    );
+
+    let cont_req = |agg_job_id: Id, payload: Vec<u8>| {
+        t.leader_authorized_req(
+            task_id,
+            version,
+            MEDIA_TYPE_AGG_CONT_REQ,
+            AggregateContinueReq {
+                task_id: task_id.clone(),
+                agg_job_id,
+                transitions: Vec::new(),
+                extensions: vec![Extension::HelperState { payload }],
+            },
+            task_config.helper_url.join("aggregate").unwrap(),
+        )
+    };
+
+    // Tampered state.
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    let req = cont_req(agg_job_id.clone(), tampered).await;
+    assert_matches!(
+        t.helper.http_post_aggregate(&req).await.unwrap_err(),
+        DapAbort::BadRequest(s) => assert_eq!(s, "invalid helper state")
+    );
+
+    // State replayed for another aggregation job.
+    let req = cont_req(Id(thread_rng().gen()), sealed.clone()).await;
+    assert_matches!(
+        t.helper.http_post_aggregate(&req).await.unwrap_err(),
+        DapAbort::BadRequest(s) => assert_eq!(s, "invalid helper state")
+    );
+
+    // Expired state.
+    assert_matches!(
+        DapHelperState::open(
+            &task_config.vdaf,
+            &stateless_helper_config,
+            task_id,
+            &agg_job_id,
+            t.now + stateless_helper_config.state_lifetime + 1,
+            &sealed,
+        )
+        .unwrap_err(),
+        DapAbort::BadRequest(s) => assert_eq!(s, "expired helper state")
+    );
+    DapHelperState::open(
+        &task_config.vdaf,
+        &stateless_helper_config,
+        task_id,
+        &agg_job_id,
+        t.now,
+        &sealed,
+    )
+    .unwrap();
+}
+
+async_test_versions! { stateless_helper_rejects_bad_state }
+
 async fn http_post_aggregate_failure_report_replayed(version: DapVersion) {
     let t = Test::new(version);
     let task_id = &t.time_interval_task_id;
@@ -851,6 +1023,8 @@ async fn http_post_aggregate_failure_batch_collected(version: DapVersion) {
             AggStore {
                 agg_share: DapAggregateShare::default(),
                 collected: true,
+                agg_job_ids: HashSet::default(),
+                report_shares: HashMap::default(),
             },
         );
     }
@@ -875,6 +1049,146 @@ async fn http_post_aggregate_failure_batch_collected(version: DapVersion) {
 
 async_test_versions! { http_post_aggregate_failure_batch_collected }
 
+async fn put_out_shares_idempotent(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.helper.unchecked_get_task_config(task_id).await;
+    let bucket = DapBatchBucketOwned::TimeInterval {
+        batch_window: task_config.truncate_time(t.now),
+    };
+    let out_share = || DapOutputShare {
+        time: t.now,
+        checksum: [1; 32],
+        data: VdafAggregateShare::Field64(vec![1.into()].into()),
+    };
+    let get_report_count = || {
+        t.helper
+            .agg_store
+            .lock()
+            .expect("agg_store: failed to lock")
+            .get(task_id)
+            .and_then(|agg_store| agg_store.get(&bucket))
+            .map(|agg_store| agg_store.agg_share.report_count)
+    };
+
+    // Merging the output shares of the same aggregation job twice has no effect.
+    let agg_job_id = Id(thread_rng().gen());
+    for _ in 0..2 {
+        t.helper
+            .put_out_shares(
+                task_id,
+                &agg_job_id,
+                &PartialBatchSelector::TimeInterval,
+                vec![out_share()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(get_report_count(), Some(1));
+    }
+
+    // The output shares of another aggregation job are merged.
+    t.helper
+        .put_out_shares(
+            task_id,
+            &Id(thread_rng().gen()),
+            &PartialBatchSelector::TimeInterval,
+            vec![out_share()],
+        )
+        .await
+        .unwrap();
+    assert_eq!(get_report_count(), Some(2));
+
+    // Once the bucket is collected, no more output shares are merged.
+    t.helper
+        .agg_store
+        .lock()
+        .expect("agg_store: failed to lock")
+        .get_mut(task_id)
+        .unwrap()
+        .get_mut(&bucket)
+        .unwrap()
+        .collected = true;
+    assert_matches!(
+        t.helper
+            .put_out_shares(
+                task_id,
+                &Id(thread_rng().gen()),
+                &PartialBatchSelector::TimeInterval,
+                vec![out_share()],
+            )
+            .await,
+        Err(DapError::Abort(DapAbort::BatchOverlap))
+    );
+    assert_eq!(get_report_count(), Some(2));
+}
+
+async_test_versions! { put_out_shares_idempotent }
+
+// Test that if any of the buckets spanned by the output shares has been collected, then the output
+// shares are not merged into any of them.
+async fn put_out_shares_batch_overlap_merges_nothing(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.helper.unchecked_get_task_config(task_id).await;
+    let earlier = t.now - task_config.time_precision;
+    let out_share = |time| DapOutputShare {
+        time,
+        checksum: [1; 32],
+        data: VdafAggregateShare::Field64(vec![1.into()].into()),
+    };
+    let get_report_count = |time| {
+        let bucket = DapBatchBucketOwned::TimeInterval {
+            batch_window: task_config.truncate_time(time),
+        };
+        t.helper
+            .agg_store
+            .lock()
+            .expect("agg_store: failed to lock")
+            .get(task_id)
+            .and_then(|agg_store| agg_store.get(&bucket))
+            .map_or(0, |agg_store| agg_store.agg_share.report_count)
+    };
+
+    // Populate and collect the bucket for the current batch window.
+    t.helper
+        .put_out_shares(
+            task_id,
+            &Id(thread_rng().gen()),
+            &PartialBatchSelector::TimeInterval,
+            vec![out_share(t.now)],
+        )
+        .await
+        .unwrap();
+    t.helper
+        .agg_store
+        .lock()
+        .expect("agg_store: failed to lock")
+        .get_mut(task_id)
+        .unwrap()
+        .get_mut(&DapBatchBucketOwned::TimeInterval {
+            batch_window: task_config.truncate_time(t.now),
+        })
+        .unwrap()
+        .collected = true;
+
+    // The output shares span the collected bucket and the one before it.
+    assert_matches!(
+        t.helper
+            .put_out_shares(
+                task_id,
+                &Id(thread_rng().gen()),
+                &PartialBatchSelector::TimeInterval,
+                vec![out_share(earlier), out_share(t.now)],
+            )
+            .await,
+        Err(DapError::Abort(DapAbort::BatchOverlap))
+    );
+    assert_eq!(get_report_count(earlier), 0);
+    assert_eq!(get_report_count(t.now), 1);
+}
+
+async_test_versions! { put_out_shares_batch_overlap_merges_nothing }
+
 async fn http_post_aggregate_abort_helper_state_overwritten(version: DapVersion) {
     let t = Test::new(version);
     let task_id = &t.time_interval_task_id;
@@ -931,6 +1245,7 @@ async fn http_post_upload_fail_send_invalid_report(version: DapVersion) {
         payload: report_invalid_task_id.get_encoded_with_param(&task_config.version),
         url: task_config.leader_url.join("upload").unwrap(),
         sender_auth: None,
+        taskprov: None,
     };
 
     // Expect failure due to invalid task ID in report.
@@ -954,7 +1269,7 @@ async fn http_post_upload_fail_send_invalid_report(version: DapVersion) {
 
 async_test_versions! { http_post_upload_fail_send_invalid_report }
 
-// Test that the Leader rejects reports past the expiration date.
+// Test that the Leader rejects uploads for tasks that have expired.
 async fn http_post_upload_task_expired(version: DapVersion) {
     let t = Test::new(version);
     let task_id = &t.expired_task_id;
@@ -968,16 +1283,130 @@ async fn http_post_upload_task_expired(version: DapVersion) {
         payload: report.get_encoded_with_param(&version),
         url: task_config.leader_url.join("upload").unwrap(),
         sender_auth: None,
+        taskprov: None,
     };
 
     assert_matches!(
         t.leader.http_post_upload(&req).await.unwrap_err(),
-        DapAbort::ReportTooLate
+        DapAbort::TaskExpired
     );
 }
 
 async_test_versions! { http_post_upload_task_expired }
 
+async fn http_post_upload_bulk(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    // Upload and aggregate a report so that uploading it again is detected as a replay.
+    let replayed_report = t.gen_test_report(task_id).await;
+    let req = t.gen_test_upload_req(replayed_report.clone()).await;
+    t.leader.http_post_upload(&req).await.unwrap();
+    t.run_agg_job(task_id).await.unwrap();
+
+    let report = t.gen_test_report(task_id).await;
+    let mut late_report = t.gen_test_report(task_id).await;
+    late_report.metadata.time = task_config.expiration;
+    let other_task_report = t.gen_test_report(&t.fixed_size_task_id).await;
+
+    let bulk_upload_req = BulkUploadReq {
+        task_id: task_id.clone(),
+        reports: vec![
+            report.clone(),
+            replayed_report.clone(),
+            late_report.clone(),
+            other_task_report.clone(),
+            report.clone(), // Repeated within the request
+        ],
+    };
+    let req = DapRequest {
+        version,
+        media_type: Some(MEDIA_TYPE_BULK_UPLOAD_REQ),
+        task_id: Some(task_id.clone()),
+        payload: bulk_upload_req.get_encoded_with_param(&version),
+        url: task_config.leader_url.join("upload").unwrap(),
+        sender_auth: None,
+        taskprov: None,
+    };
+
+    let resp = t.leader.http_post_upload_bulk(&req).await.unwrap();
+    assert_eq!(resp.media_type, Some(MEDIA_TYPE_BULK_UPLOAD_RESP));
+    let bulk_upload_resp = BulkUploadResp::get_decoded(&resp.payload).unwrap();
+    let rejection = |typ: &str| Some(format!("urn:ietf:params:ppm:dap:error:{typ}"));
+    assert_eq!(
+        bulk_upload_resp.results,
+        vec![
+            ReportUploadResult {
+                report_id: report.metadata.id.clone(),
+                rejection: None,
+            },
+            ReportUploadResult {
+                report_id: replayed_report.metadata.id.clone(),
+                rejection: rejection("replayedReport"),
+            },
+            ReportUploadResult {
+                report_id: late_report.metadata.id.clone(),
+                rejection: rejection("reportTooLate"),
+            },
+            ReportUploadResult {
+                report_id: other_task_report.metadata.id.clone(),
+                rejection: rejection("badRequest"),
+            },
+            ReportUploadResult {
+                report_id: report.metadata.id.clone(),
+                rejection: rejection("replayedReport"),
+            },
+        ]
+    );
+
+    // Only the accepted report is stored.
+    let report_sel = MockAggregatorReportSelector(task_id.clone());
+    let (_task_id, _part_batch_sel, reports) = get_reports!(t.leader, &report_sel);
+    assert_eq!(reports, vec![report]);
+}
+
+async_test_versions! { http_post_upload_bulk }
+
+async fn http_post_upload_bulk_too_many_reports(version: DapVersion) {
+    let mut t = Test::new(version);
+    Arc::get_mut(&mut t.leader)
+        .unwrap()
+        .global_config
+        .max_bulk_upload_reports = 2;
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    let mut reports = Vec::new();
+    for _ in 0..3 {
+        reports.push(t.gen_test_report(task_id).await);
+    }
+    let bulk_upload_req = BulkUploadReq {
+        task_id: task_id.clone(),
+        reports,
+    };
+    let req = DapRequest {
+        version,
+        media_type: Some(MEDIA_TYPE_BULK_UPLOAD_REQ),
+        task_id: Some(task_id.clone()),
+        payload: bulk_upload_req.get_encoded_with_param(&version),
+        url: task_config.leader_url.join("upload").unwrap(),
+        sender_auth: None,
+        taskprov: None,
+    };
+
+    // The request is rejected as a whole and nothing is stored.
+    assert_matches!(
+        t.leader.http_post_upload_bulk(&req).await,
+        Err(DapAbort::BadRequest(..))
+    );
+    let report_sel = MockAggregatorReportSelector(task_id.clone());
+    let (_task_id, _part_batch_sel, reports) = get_reports!(t.leader, &report_sel);
+    assert!(reports.is_empty());
+}
+
+async_test_versions! { http_post_upload_bulk_too_many_reports }
+
 async fn get_reports_empty_response(version: DapVersion) {
     let t = Test::new(version);
     let task_id = &t.time_interval_task_id;
@@ -1009,6 +1438,111 @@ async fn get_reports_empty_response(version: DapVersion) {
 
 async_test_versions! { get_reports_empty_response }
 
+async fn run_agg_jobs_defers_reports_after_deadline(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.time_interval_task_id;
+    let report_sel = MockAggregatorReportSelector(task_id.clone());
+
+    let report = t.gen_test_report(task_id).await;
+    let req = t.gen_test_upload_req(report.clone()).await;
+    t.leader
+        .http_post_upload(&req)
+        .await
+        .expect("upload failed unexpectedly");
+
+    // The deadline has passed, so the report is put back instead of being aggregated.
+    let reports = t.leader.get_reports(&report_sel).await.unwrap();
+    let mut telem = DapLeaderProcessTelemetry::default();
+    t.leader
+        .run_agg_jobs(reports, Some(0), &mut telem)
+        .await
+        .unwrap();
+    assert_eq!(telem.reports_deferred, 1);
+    assert_eq!(telem.reports_processed, 0);
+    assert_eq!(telem.reports_aggregated, 0);
+    assert!(!is_acked(&t.leader, task_id, &report.metadata.id));
+
+    // The report is aggregated by the next run, after which it is acknowledged.
+    let telem = t.leader.process(&report_sel, None).await.unwrap();
+    assert_eq!(telem.reports_deferred, 0);
+    assert_eq!(telem.reports_aggregated, 1);
+    assert!(is_acked(&t.leader, task_id, &report.metadata.id));
+}
+
+/// Check whether the Leader has acknowledged the given report.
+fn is_acked(leader: &MockAggregator, task_id: &Id, report_id: &ReportId) -> bool {
+    leader
+        .report_store
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .is_some_and(|report_store| report_store.acked.contains(report_id))
+}
+
+async_test_versions! { run_agg_jobs_defers_reports_after_deadline }
+
+// Test that the jobs for fixed-size queries are run even if the deadline has passed, since their
+// reports have already been assigned to a batch.
+async fn run_agg_jobs_runs_fixed_size_jobs_after_deadline(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.fixed_size_task_id;
+    let report_sel = MockAggregatorReportSelector(task_id.clone());
+
+    let report = t.gen_test_report(task_id).await;
+    let req = t.gen_test_upload_req(report).await;
+    t.leader
+        .http_post_upload(&req)
+        .await
+        .expect("upload failed unexpectedly");
+
+    let reports = t.leader.get_reports(&report_sel).await.unwrap();
+    let mut telem = DapLeaderProcessTelemetry::default();
+    t.leader
+        .run_agg_jobs(reports, Some(0), &mut telem)
+        .await
+        .unwrap();
+    assert_eq!(telem.reports_deferred, 0);
+    assert_eq!(telem.reports_processed, 1);
+    assert_eq!(telem.reports_aggregated, 1);
+}
+
+async_test_versions! { run_agg_jobs_runs_fixed_size_jobs_after_deadline }
+
+// Test that the Leader drops, rather than aggregates, reports for a task that expired after they
+// were uploaded.
+async fn process_drops_reports_for_expired_task(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.time_interval_task_id;
+    let report_sel = MockAggregatorReportSelector(task_id.clone());
+
+    let report = t.gen_test_report(task_id).await;
+    let report_id = report.metadata.id.clone();
+    let req = t.gen_test_upload_req(report).await;
+    t.leader
+        .http_post_upload(&req)
+        .await
+        .expect("upload failed unexpectedly");
+
+    t.leader
+        .tasks
+        .lock()
+        .unwrap()
+        .get_mut(task_id)
+        .unwrap()
+        .expiration = t.now;
+
+    let telem = t.leader.process(&report_sel, None).await.unwrap();
+    assert_eq!(telem.reports_dropped, 1);
+    assert_eq!(telem.reports_aggregated, 0);
+    assert!(is_acked(&t.leader, task_id, &report_id));
+
+    // The report is not kept around.
+    let (_task_id, _part_batch_sel, reports) = get_reports!(t.leader, &report_sel);
+    assert_eq!(reports.len(), 0);
+}
+
+async_test_versions! { process_drops_reports_for_expired_task }
+
 async fn poll_collect_job_test_results(version: DapVersion) {
     let t = Test::new(version);
     let task_id = &t.time_interval_task_id;
@@ -1280,6 +1814,83 @@ async fn http_post_collect_success(version: DapVersion) {
 
 async_test_versions! { http_post_collect_success }
 
+// Test that the Leader permits collection for an expired task until the grace period has elapsed.
+async fn http_post_collect_task_expired(version: DapVersion) {
+    let t = Test::new(version);
+    let task_id = &t.expired_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    let collect_req = CollectReq {
+        task_id: task_id.clone(),
+        query: task_config.query_for_current_batch_window(t.now),
+        agg_param: Vec::default(),
+    };
+    let req = t
+        .collector_authorized_req(
+            task_config.version,
+            MEDIA_TYPE_COLLECT_REQ,
+            task_id,
+            collect_req,
+            task_config.leader_url.join("collect").unwrap(),
+        )
+        .await;
+
+    // Within the grace period.
+    t.leader.http_post_collect(&req).await.unwrap();
+
+    // After the grace period.
+    t.leader
+        .tasks
+        .lock()
+        .unwrap()
+        .get_mut(task_id)
+        .unwrap()
+        .expiration = t.now - t.leader.global_config.task_expiration_grace_period.unwrap() - 1;
+    assert_matches!(
+        t.leader.http_post_collect(&req).await.unwrap_err(),
+        DapAbort::TaskExpired
+    );
+}
+
+async_test_versions! { http_post_collect_task_expired }
+
+// Test that collection is permitted after a task expires if there is no grace period.
+async fn http_post_collect_task_expired_no_grace_period(version: DapVersion) {
+    let mut t = Test::new(version);
+    Arc::get_mut(&mut t.leader)
+        .unwrap()
+        .global_config
+        .task_expiration_grace_period = None;
+    let task_id = &t.expired_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    let collect_req = CollectReq {
+        task_id: task_id.clone(),
+        query: task_config.query_for_current_batch_window(t.now),
+        agg_param: Vec::default(),
+    };
+    let req = t
+        .collector_authorized_req(
+            task_config.version,
+            MEDIA_TYPE_COLLECT_REQ,
+            task_id,
+            collect_req,
+            task_config.leader_url.join("collect").unwrap(),
+        )
+        .await;
+
+    t.leader
+        .tasks
+        .lock()
+        .unwrap()
+        .get_mut(task_id)
+        .unwrap()
+        .expiration = 0;
+    t.leader.http_post_collect(&req).await.unwrap();
+}
+
+async_test_versions! { http_post_collect_task_expired_no_grace_period }
+
 // Test that the Leader handles queries from the Collector properly.
 async fn http_post_collect_invalid_query(version: DapVersion) {
     let mut rng = thread_rng();
@@ -1394,6 +2005,10 @@ async fn e2e_time_interval(version: DapVersion) {
         r#"test_helper_report_counter{status="aggregated"}"#: 1,
         r#"test_leader_report_counter{status="collected"}"#: 1,
         r#"test_helper_report_counter{status="collected"}"#: 1,
+        (format!(r#"test_leader_report_task_counter{{status="aggregated",task="{}"}}"#, task_id.to_base64url())): 1,
+        (format!(r#"test_helper_report_task_counter{{status="aggregated",task="{}"}}"#, task_id.to_base64url())): 1,
+        (format!(r#"test_leader_report_task_counter{{status="collected",task="{}"}}"#, task_id.to_base64url())): 1,
+        (format!(r#"test_helper_report_task_counter{{status="collected",task="{}"}}"#, task_id.to_base64url())): 1,
         r#"test_helper_aggregation_job_gauge"#: 0,
     });
 }
@@ -1431,6 +2046,123 @@ async fn e2e_fixed_size(version: DapVersion) {
 
 async_test_versions! { e2e_fixed_size }
 
+async fn e2e_reconcile_batch(version: DapVersion) {
+    let t = Test::new_with_reconcile_batch_mismatch(version);
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    let report = t.gen_test_report(task_id).await;
+    let req = t.gen_test_upload_req(report).await;
+    t.leader.http_post_upload(&req).await.unwrap();
+    t.run_agg_job(task_id).await.unwrap();
+
+    // The Leader and Helper agree on every bucket, so collection succeeds.
+    let query = task_config.query_for_current_batch_window(t.now);
+    t.run_col_job(task_id, &query).await.unwrap();
+}
+
+async_test_versions! { e2e_reconcile_batch }
+
+async fn e2e_reconcile_batch_exclude_reports(version: DapVersion) {
+    let t = Test::new_with_reconcile_batch_mismatch(version);
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    for _ in 0..3 {
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report).await;
+        t.leader.http_post_upload(&req).await.unwrap();
+        t.run_agg_job(task_id).await.unwrap();
+    }
+
+    // Remove a different report from each Aggregator's bucket, as if each had failed to aggregate
+    // a report that the other aggregated.
+    {
+        let mut leader_guard = t.leader.agg_store.lock().unwrap();
+        let leader_agg_store = leader_guard
+            .get_mut(task_id)
+            .unwrap()
+            .values_mut()
+            .next()
+            .unwrap();
+        let checksums: Vec<[u8; 32]> = leader_agg_store.report_shares.keys().copied().collect();
+        let report_share = leader_agg_store
+            .report_shares
+            .remove(&checksums[0])
+            .unwrap();
+        leader_agg_store.agg_share.exclude(report_share).unwrap();
+
+        let mut helper_guard = t.helper.agg_store.lock().unwrap();
+        let helper_agg_store = helper_guard
+            .get_mut(task_id)
+            .unwrap()
+            .values_mut()
+            .next()
+            .unwrap();
+        let report_share = helper_agg_store
+            .report_shares
+            .remove(&checksums[1])
+            .unwrap();
+        helper_agg_store.agg_share.exclude(report_share).unwrap();
+    }
+
+    // Each Aggregator excludes the report the other is missing, so the batch is collected with
+    // the one report both have aggregated.
+    let query = task_config.query_for_current_batch_window(t.now);
+    t.run_col_job(task_id, &query).await.unwrap();
+
+    assert_metrics_include!(t.prometheus_registry, {
+        r#"test_leader_report_counter{status="rejected_batch_mismatch"}"#: 1,
+        r#"test_helper_report_counter{status="rejected_batch_mismatch"}"#: 1,
+        r#"test_leader_report_counter{status="collected"}"#: 1,
+        r#"test_helper_report_counter{status="collected"}"#: 1,
+    });
+}
+
+async_test_versions! { e2e_reconcile_batch_exclude_reports }
+
+async fn e2e_reconcile_batch_mismatch(version: DapVersion) {
+    let t = Test::new_with_reconcile_batch_mismatch(version);
+    let task_id = &t.time_interval_task_id;
+    let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+    let report = t.gen_test_report(task_id).await;
+    let req = t.gen_test_upload_req(report).await;
+    t.leader.http_post_upload(&req).await.unwrap();
+    t.run_agg_job(task_id).await.unwrap();
+
+    // Tamper with the Leader's report count for the bucket, so that it differs from the number of
+    // reports whose output shares are known.
+    {
+        let mut guard = t.leader.agg_store.lock().unwrap();
+        for agg_store in guard.get_mut(task_id).unwrap().values_mut() {
+            agg_store.agg_share.report_count += 1;
+        }
+    }
+
+    // The Leader detects the mismatch, but can't exclude the offending report.
+    let query = task_config.query_for_current_batch_window(t.now);
+    assert_matches!(
+        t.run_col_job(task_id, &query).await,
+        Err(DapAbort::BatchMismatch)
+    );
+
+    // Nothing has been collected by either Aggregator.
+    let batch_sel = BatchSelector::try_from(query).unwrap();
+    assert!(!t
+        .leader
+        .is_batch_overlapping(task_id, &batch_sel)
+        .await
+        .unwrap());
+    assert!(!t
+        .helper
+        .is_batch_overlapping(task_id, &batch_sel)
+        .await
+        .unwrap());
+}
+
+async_test_versions! { e2e_reconcile_batch_mismatch }
+
 async fn e2e_taskprov(version: DapVersion) {
     let t = Test::new(version);
     let vdaf = VdafConfig::Prio3(Prio3Config::Count);
@@ -1501,6 +2233,7 @@ async fn e2e_taskprov(version: DapVersion) {
         payload: report.get_encoded_with_param(&version),
         url: Url::parse("https://cool.biz/upload").unwrap(),
         sender_auth: None,
+        taskprov: None,
     };
     t.leader.http_post_upload(&req).await.unwrap();
 
@@ -1527,6 +2260,204 @@ async fn e2e_taskprov(version: DapVersion) {
 
 async_test_version! { e2e_taskprov, Draft02 }
 
+// Test that the Collector can provision a task via taskprov by advertising it in its collect
+// request to the Leader, and that the Leader advertises it to the Helper in turn.
+async fn e2e_taskprov_collector_provisioned(version: DapVersion) {
+    let t = Test::new(version);
+    let vdaf = VdafConfig::Prio3(Prio3Config::Count);
+
+    let taskprov_task_config = taskprov::TaskConfig {
+        task_info: "cool task".as_bytes().to_vec(),
+        aggregator_endpoints: vec![
+            taskprov::UrlBytes {
+                bytes: b"https://cool.biz/".to_vec(),
+            },
+            taskprov::UrlBytes {
+                bytes: b"http://cool.com:8788/".to_vec(),
+            },
+        ],
+        query_config: taskprov::QueryConfig {
+            time_precision: 3600,
+            max_batch_query_count: 1,
+            min_batch_size: 1,
+            var: taskprov::QueryConfigVar::TimeInterval,
+        },
+        task_expiration: t.now + 86400 * 14,
+        vdaf_config: taskprov::VdafConfig {
+            dp_config: taskprov::DpConfig::None,
+            var: taskprov::VdafTypeVar::Prio3Aes128Count,
+        },
+    }
+    .get_encoded_with_param(&t.leader.global_config.taskprov_version);
+    let task_id = crate::taskprov::compute_task_id(
+        t.leader.global_config.taskprov_version,
+        &taskprov_task_config,
+    )
+    .unwrap();
+
+    // Collector: Send the collect request to the Leader, advertising the task configuration.
+    let mut req = t
+        .collector_authorized_req(
+            version,
+            MEDIA_TYPE_COLLECT_REQ,
+            &task_id,
+            CollectReq {
+                task_id: task_id.clone(),
+                query: Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: t.now - (t.now % 3600),
+                        duration: 3600,
+                    },
+                },
+                agg_param: Vec::default(),
+            },
+            Url::parse("https://cool.biz/collect").unwrap(),
+        )
+        .await;
+    req.taskprov = Some(encode_base64url(&taskprov_task_config));
+    t.leader.http_post_collect(&req).await.unwrap();
+
+    // The Leader is now configured with the task, but the Helper is not.
+    let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+    assert!(t.helper.tasks.lock().unwrap().get(&task_id).is_none());
+
+    // Client: Send upload request to Leader. The report does not carry the taskprov extension.
+    let hpke_config_list = [
+        t.leader
+            .get_hpke_config_for(version, Some(&task_id))
+            .await
+            .unwrap()
+            .as_ref()
+            .clone(),
+        t.helper
+            .get_hpke_config_for(version, Some(&task_id))
+            .await
+            .unwrap()
+            .as_ref()
+            .clone(),
+    ];
+    let report = vdaf
+        .produce_report(
+            &hpke_config_list,
+            t.now,
+            &task_id,
+            DapMeasurement::U64(1),
+            version,
+        )
+        .unwrap();
+    let req = t.gen_test_upload_req(report).await;
+    t.leader.http_post_upload(&req).await.unwrap();
+
+    // Leader: Run aggregation job. The Helper is configured with the task from the Leader's
+    // request.
+    t.run_agg_job(&task_id).await.unwrap();
+    assert!(t.helper.tasks.lock().unwrap().get(&task_id).is_some());
+
+    // Leader: Complete the collection job.
+    let resp = t.leader.get_pending_collect_jobs().await.unwrap();
+    let (collect_id, collect_req) = &resp[0];
+    t.leader
+        .run_collect_job(collect_id, &task_config, collect_req)
+        .await
+        .unwrap();
+
+    assert_metrics_include!(t.prometheus_registry, {
+        r#"test_leader_report_counter{status="aggregated"}"#: 1,
+        r#"test_helper_report_counter{status="aggregated"}"#: 1,
+        r#"test_leader_report_counter{status="collected"}"#: 1,
+        r#"test_helper_report_counter{status="collected"}"#: 1,
+    });
+}
+
+async_test_version! { e2e_taskprov_collector_provisioned, Draft02 }
+
+// Test that a request advertising a task via taskprov, but carrying credentials other than those
+// shared with the task's peer, is rejected before the task is provisioned.
+async fn taskprov_unauthorized_not_provisioned(version: DapVersion) {
+    let t = Test::new(version);
+    let mut rng = thread_rng();
+    let taskprov_task_config = taskprov::TaskConfig {
+        task_info: "cool task".as_bytes().to_vec(),
+        aggregator_endpoints: vec![
+            taskprov::UrlBytes {
+                bytes: b"https://cool.biz/".to_vec(),
+            },
+            taskprov::UrlBytes {
+                bytes: b"http://cool.com:8788/".to_vec(),
+            },
+        ],
+        query_config: taskprov::QueryConfig {
+            time_precision: 3600,
+            max_batch_query_count: 1,
+            min_batch_size: 1,
+            var: taskprov::QueryConfigVar::TimeInterval,
+        },
+        task_expiration: t.now + 86400 * 14,
+        vdaf_config: taskprov::VdafConfig {
+            dp_config: taskprov::DpConfig::None,
+            var: taskprov::VdafTypeVar::Prio3Aes128Count,
+        },
+    }
+    .get_encoded_with_param(&t.leader.global_config.taskprov_version);
+    let task_id = crate::taskprov::compute_task_id(
+        t.leader.global_config.taskprov_version,
+        &taskprov_task_config,
+    )
+    .unwrap();
+    let wrong_token = BearerToken::from("not the token shared with the peer");
+
+    // Collector -> Leader
+    let req = DapRequest {
+        version,
+        media_type: Some(MEDIA_TYPE_COLLECT_REQ),
+        task_id: Some(task_id.clone()),
+        payload: CollectReq {
+            task_id: task_id.clone(),
+            query: Query::TimeInterval {
+                batch_interval: Interval {
+                    start: t.now - (t.now % 3600),
+                    duration: 3600,
+                },
+            },
+            agg_param: Vec::default(),
+        }
+        .get_encoded_with_param(&version),
+        url: Url::parse("https://cool.biz/collect").unwrap(),
+        sender_auth: Some(wrong_token.clone()),
+        taskprov: Some(encode_base64url(&taskprov_task_config)),
+    };
+    assert_matches!(
+        t.leader.http_post_collect(&req).await.unwrap_err(),
+        DapAbort::UnauthorizedRequest
+    );
+    assert!(t.leader.tasks.lock().unwrap().get(&task_id).is_none());
+
+    // Leader -> Helper
+    let req = DapRequest {
+        version,
+        media_type: Some(MEDIA_TYPE_AGG_INIT_REQ),
+        task_id: Some(task_id.clone()),
+        payload: AggregateInitializeReq {
+            task_id: task_id.clone(),
+            agg_job_id: Id(rng.gen()),
+            agg_param: Vec::default(),
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_shares: Vec::new(),
+        }
+        .get_encoded_with_param(&version),
+        url: Url::parse("http://cool.com:8788/aggregate").unwrap(),
+        sender_auth: Some(wrong_token),
+        taskprov: Some(encode_base64url(&taskprov_task_config)),
+    };
+    assert_matches!(
+        t.helper.http_post_aggregate(&req).await.unwrap_err(),
+        DapAbort::UnauthorizedRequest
+    );
+    assert!(t.helper.tasks.lock().unwrap().get(&task_id).is_none());
+}
+
+async_test_version! { taskprov_unauthorized_not_provisioned, Draft02 }
+
 fn early_metadata_checks(version: DapVersion) {
     let t = Test::new(version);
     let mut rng = thread_rng();
@@ -3,19 +3,20 @@
 
 use crate::{
     messages::{
-        taskprov::{QueryConfigVar, TaskConfig, VdafType, VdafTypeVar},
-        Extension, HpkeConfig, Id, ReportMetadata,
+        decode_base64url_vec, encode_base64url,
+        taskprov::{DpConfig, QueryConfigVar, TaskConfig, VdafType, VdafTypeVar},
+        Duration, Extension, HpkeConfig, Id, ReportMetadata, Time,
     },
-    vdaf::VdafVerifyKey,
+    vdaf::{registry::get_registered_vdaf_by_taskprov_code, VdafVerifyKey},
     DapAbort, DapError, DapQueryConfig, DapTaskConfig, DapVersion, Prio3Config, VdafConfig,
 };
-use prio::codec::ParameterizedDecode;
+use prio::codec::{ParameterizedDecode, ParameterizedEncode};
 use ring::{
     digest,
-    hkdf::{Prk, Salt, HKDF_SHA256},
+    hkdf::{KeyType, Prk, Salt, HKDF_SHA256},
 };
 use serde::{Deserialize, Serialize};
-use std::str;
+use std::{fmt, str};
 use url::Url;
 
 /// DAP taskprov version.
@@ -24,17 +25,31 @@ pub enum TaskprovVersion {
     #[serde(rename = "v02")]
     Draft02,
 
+    #[serde(rename = "v03")]
+    Draft03,
+
     #[serde(other)]
     Unknown,
 }
 
+/// HTTP header used to advertise a taskprov task configuration: by the Collector in collect
+/// requests to the Leader, and by the Leader in requests to the Helper. The value is the
+/// base64url-encoded task configuration.
+pub const TASKPROV_HEADER: &str = "dap-taskprov";
+
 /// SHA-256 of "dap-taskprov"
-#[allow(dead_code)]
 pub(crate) const TASK_PROV_SALT_DRAFT02: [u8; 32] = [
     0x28, 0xb9, 0xbb, 0x4f, 0x62, 0x4f, 0x67, 0x9a, 0xc1, 0x98, 0xd9, 0x68, 0xf4, 0xb0, 0x9e, 0xec,
     0x74, 0x01, 0x7a, 0x52, 0xcb, 0x4c, 0xf6, 0x39, 0xfb, 0x83, 0xe0, 0x47, 0x72, 0x3a, 0x0f, 0xfe,
 ];
 
+/// SHA-256 of "dap-taskprov-03"
+pub(crate) const TASK_PROV_SALT_DRAFT03: [u8; 32] = [
+    0x63, 0xf2, 0x66, 0x5c, 0xe8, 0x27, 0xe5, 0x44, 0x79, 0xce, 0x8b, 0xe2, 0xd0, 0xf0, 0x09, 0xa4,
+    0x55, 0xfd, 0x6d, 0x0d, 0x90, 0xc3, 0xc8, 0xc9, 0x0c, 0x6b, 0x49, 0x52, 0xbf, 0xb9, 0x48, 0x66,
+];
+
+/// The task ID is the SHA-256 hash of the serialized task config. This is unchanged since draft 02.
 fn compute_task_id_draft02(serialized: &[u8]) -> Id {
     let d = digest::digest(&digest::SHA256, serialized);
     let dref = d.as_ref();
@@ -46,7 +61,9 @@ fn compute_task_id_draft02(serialized: &[u8]) -> Id {
 /// Compute the task id of a serialized task config.
 pub fn compute_task_id(version: TaskprovVersion, serialized: &[u8]) -> Result<Id, DapError> {
     match version {
-        TaskprovVersion::Draft02 => Ok(compute_task_id_draft02(serialized)),
+        TaskprovVersion::Draft02 | TaskprovVersion::Draft03 => {
+            Ok(compute_task_id_draft02(serialized))
+        }
         TaskprovVersion::Unknown => Err(DapError::fatal(
             "attempted to resolve taskprov task with unknown version",
         )),
@@ -61,33 +78,56 @@ pub fn compute_task_id(version: TaskprovVersion, serialized: &[u8]) -> Result<Id
 pub(crate) fn extract_prk_from_verify_key_init(
     version: TaskprovVersion,
     verify_key_init: &[u8; 32],
-) -> Prk {
+) -> Result<Prk, DapError> {
     // The documentation says computing the Salt is expensive, and we use the same PRK all the
     // time, so we compute it once.
     let value = match version {
         TaskprovVersion::Draft02 => &TASK_PROV_SALT_DRAFT02,
-        _ => panic!("unimplemented taskprov version"),
+        TaskprovVersion::Draft03 => &TASK_PROV_SALT_DRAFT03,
+        TaskprovVersion::Unknown => {
+            return Err(DapError::fatal(
+                "attempted to derive VDAF verify key with unknown taskprov version",
+            ))
+        }
     };
-    Salt::new(HKDF_SHA256, value).extract(verify_key_init)
+    Ok(Salt::new(HKDF_SHA256, value).extract(verify_key_init))
 }
 
-/// Expand a pseudorandom key into the VDAF verification key for a given task.
+/// Length of the output of an HKDF expansion.
+struct OutputLen(usize);
+
+impl KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Expand a pseudorandom key into the VDAF verification key for a given task. This fails if the
+/// VDAF is not supported.
 pub(crate) fn expand_prk_into_verify_key(
     prk: &Prk,
     task_id: &Id,
     vdaf_type: VdafType,
-) -> VdafVerifyKey {
+) -> Result<VdafVerifyKey, DapError> {
+    let len = vdaf_type.verify_key_len()?;
     let info = [task_id.as_ref()];
     // This expand(), and the associated fill() below can only fail if the length is wrong,
     // and it won't be, so we unwrap().
-    let okm = prk.expand(&info, vdaf_type).unwrap();
+    let okm = prk.expand(&info, OutputLen(len)).unwrap();
     match &vdaf_type {
         VdafType::Prio3Aes128Count | VdafType::Prio3Aes128Sum | VdafType::Prio3Aes128Histogram => {
             let mut bytes = [0u8; 16];
             okm.fill(&mut bytes[..]).unwrap();
-            VdafVerifyKey::Prio3(bytes)
+            Ok(VdafVerifyKey::Prio3(bytes))
+        }
+        VdafType::Registered(..) => {
+            let mut bytes = vec![0; len];
+            okm.fill(&mut bytes[..]).unwrap();
+            Ok(VdafVerifyKey::Registered(bytes))
+        }
+        VdafType::Poplar1Aes128 | VdafType::NotImplemented(..) => {
+            Err(DapError::Abort(DapAbort::InvalidTask))
         }
-        _ => panic!("Unknown VDAF type"),
     }
 }
 
@@ -98,15 +138,14 @@ pub(crate) fn expand_prk_into_verify_key(
 /// compute_vdaf_verify_key_from_prk(). Callers reusing the same PRK frequently
 /// should consider computing the prk once and then calling compute_vdaf_verify_key_from_prk()
 /// directly.
-#[allow(dead_code)]
 pub(crate) fn compute_vdaf_verify_key(
     version: TaskprovVersion,
     verify_key_init: &[u8; 32],
     task_id: &Id,
     vdaf_type: VdafType,
-) -> VdafVerifyKey {
+) -> Result<VdafVerifyKey, DapError> {
     expand_prk_into_verify_key(
-        &extract_prk_from_verify_key_init(version, verify_key_init),
+        &extract_prk_from_verify_key_init(version, verify_key_init)?,
         task_id,
         vdaf_type,
     )
@@ -149,6 +188,48 @@ pub fn get_taskprov_task_config(
     }
 }
 
+/// Decode the taskprov task configuration advertised in the `dap-taskprov` header and check that
+/// it matches the task ID.
+pub fn get_taskprov_task_config_from_header(
+    version: TaskprovVersion,
+    task_id: &Id,
+    header: &str,
+) -> Result<TaskConfig, DapError> {
+    let payload = decode_base64url_vec(header).ok_or_else(|| bad_request("bad taskprov header"))?;
+    if compute_task_id(version, &payload)? != *task_id {
+        return Err(DapError::Abort(DapAbort::UnrecognizedTask));
+    }
+    TaskConfig::get_decoded_with_param(&version, &payload)
+        .map_err(|_| DapError::Abort(DapAbort::UnrecognizedMessage))
+}
+
+/// Resolve the taskprov task configuration advertised for a task, if any. The configuration may
+/// be advertised in the `dap-taskprov` header, given by `header`, or in the taskprov extension of
+/// a report, whose metadata is given by `metadata`. The header takes precedence.
+pub fn resolve_advertised_task_config(
+    version: TaskprovVersion,
+    task_id: &Id,
+    header: Option<&str>,
+    metadata: Option<&ReportMetadata>,
+) -> Result<Option<TaskConfig>, DapError> {
+    // Don't check for taskprov usage if we don't know the version.
+    if matches!(version, TaskprovVersion::Unknown) {
+        return Ok(None);
+    }
+
+    if let Some(header) = header {
+        return Ok(Some(get_taskprov_task_config_from_header(
+            version, task_id, header,
+        )?));
+    }
+
+    if let Some(metadata) = metadata {
+        return get_taskprov_task_config(version, task_id, metadata);
+    }
+
+    Ok(None)
+}
+
 fn url_from_bytes(bytes: &[u8]) -> Result<Url, DapError> {
     let s = str::from_utf8(bytes).map_err(|_| bad_request("bad URL UTF8"))?;
     Url::parse(s).map_err(|_| bad_request("bad URL syntax"))
@@ -165,23 +246,44 @@ impl From<QueryConfigVar> for DapQueryConfig {
     }
 }
 
-impl From<VdafTypeVar> for VdafConfig {
-    fn from(var: VdafTypeVar) -> Self {
+/// Unsupported VDAFs, including registered VDAFs that are no longer registered, are rejected with
+/// [`DapAbort::InvalidTask`].
+impl TryFrom<VdafTypeVar> for VdafConfig {
+    type Error = DapError;
+
+    fn try_from(var: VdafTypeVar) -> Result<Self, DapError> {
         match var {
-            VdafTypeVar::Prio3Aes128Count => VdafConfig::Prio3(Prio3Config::Count),
+            VdafTypeVar::Prio3Aes128Count => Ok(VdafConfig::Prio3(Prio3Config::Count)),
             VdafTypeVar::Prio3Aes128Histogram { buckets } => {
-                VdafConfig::Prio3(Prio3Config::Histogram { buckets })
+                Ok(VdafConfig::Prio3(Prio3Config::Histogram { buckets }))
             }
-            VdafTypeVar::Prio3Aes128Sum { bit_length } => VdafConfig::Prio3(Prio3Config::Sum {
+            VdafTypeVar::Prio3Aes128Sum { bit_length } => Ok(VdafConfig::Prio3(Prio3Config::Sum {
                 bits: bit_length.into(),
-            }),
+            })),
+            VdafTypeVar::Registered(x) => match get_registered_vdaf_by_taskprov_code(x) {
+                Some((name, _vdaf)) => Ok(VdafConfig::Registered(name)),
+                None => Err(DapError::Abort(DapAbort::InvalidTask)),
+            },
             VdafTypeVar::Poplar1Aes128 { .. } | VdafTypeVar::NotImplemented(..) => {
-                unreachable!("VDAF not implemented")
+                Err(DapError::Abort(DapAbort::InvalidTask))
             }
         }
     }
 }
 
+impl TaskConfig {
+    /// Return the URLs of the Leader and Helper, in that order.
+    pub fn aggregator_urls(&self) -> Result<(Url, Url), DapError> {
+        if self.aggregator_endpoints.len() != 2 {
+            return Err(bad_request("number of aggregator endpoints is not 2"));
+        }
+        Ok((
+            url_from_bytes(&self.aggregator_endpoints[0].bytes)?,
+            url_from_bytes(&self.aggregator_endpoints[1].bytes)?,
+        ))
+    }
+}
+
 impl DapTaskConfig {
     pub fn try_from_taskprov(
         dap_version: DapVersion,
@@ -191,26 +293,27 @@ impl DapTaskConfig {
         vdaf_verify_key_init: &[u8; 32],
         collector_hpke_config: &HpkeConfig,
     ) -> Result<DapTaskConfig, DapError> {
-        if task_config.aggregator_endpoints.len() != 2 {
-            return Err(bad_request("number of aggregator endpoints is not 2"));
-        }
+        let (leader_url, helper_url) = task_config.aggregator_urls()?;
         let vdaf_type = VdafType::from(task_config.vdaf_config.var.clone());
+        let taskprov = encode_base64url(task_config.get_encoded_with_param(&taskprov_version));
         Ok(DapTaskConfig {
             version: dap_version,
-            leader_url: url_from_bytes(&task_config.aggregator_endpoints[0].bytes)?,
-            helper_url: url_from_bytes(&task_config.aggregator_endpoints[1].bytes)?,
+            leader_url,
+            helper_url,
             time_precision: task_config.query_config.time_precision,
             expiration: task_config.task_expiration,
             min_batch_size: task_config.query_config.min_batch_size.into(),
             query: DapQueryConfig::from(task_config.query_config.var),
-            vdaf: VdafConfig::from(task_config.vdaf_config.var),
+            vdaf: VdafConfig::try_from(task_config.vdaf_config.var)?,
             vdaf_verify_key: compute_vdaf_verify_key(
                 taskprov_version,
                 vdaf_verify_key_init,
                 task_id,
                 vdaf_type,
-            ),
+            )?,
             collector_hpke_config: collector_hpke_config.clone(),
+            retired_collector_hpke_configs: Vec::new(),
+            taskprov: Some(taskprov),
         })
     }
 }
@@ -231,3 +334,189 @@ impl ReportMetadata {
         });
     }
 }
+
+/// A VDAF permitted by a [`TaskprovOptInPolicy`], along with the range of parameters permitted for
+/// it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskprovVdafPolicy {
+    /// Prio3Count.
+    Prio3Count,
+
+    /// Prio3Sum with a bit length of at most `max_bits`.
+    Prio3Sum { max_bits: u32 },
+
+    /// Prio3Histogram with at most `max_buckets` bucket boundaries.
+    Prio3Histogram { max_buckets: usize },
+
+    /// The VDAF registered under `name`.
+    Registered { name: String },
+}
+
+impl TaskprovVdafPolicy {
+    fn permits(&self, vdaf: &VdafConfig) -> bool {
+        match (self, vdaf) {
+            (Self::Prio3Count, VdafConfig::Prio3(Prio3Config::Count)) => true,
+            (Self::Prio3Sum { max_bits }, VdafConfig::Prio3(Prio3Config::Sum { bits })) => {
+                bits <= max_bits
+            }
+            (
+                Self::Prio3Histogram { max_buckets },
+                VdafConfig::Prio3(Prio3Config::Histogram { buckets }),
+            ) => buckets.len() <= *max_buckets,
+            (Self::Registered { name }, VdafConfig::Registered(registered_name)) => {
+                name == registered_name
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The reason for opting out of a task provisioned via taskprov.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TaskprovOptOutReason {
+    /// The VDAF or its parameters are not permitted.
+    Vdaf,
+
+    /// The minimum batch size is out of range.
+    MinBatchSize,
+
+    /// The task expires too far in the future.
+    Expiration,
+
+    /// The peer Aggregator's URL does not match any permitted pattern.
+    PeerUrl,
+
+    /// The task does not use the required differential privacy mechanism.
+    DpMechanism,
+
+    /// The peer Aggregator has already provisioned the maximum number of tasks.
+    PeerTaskLimit,
+}
+
+impl TaskprovOptOutReason {
+    /// Return the reason as a string suitable for use as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Vdaf => "vdaf",
+            Self::MinBatchSize => "min_batch_size",
+            Self::Expiration => "expiration",
+            Self::PeerUrl => "peer_url",
+            Self::DpMechanism => "dp_mechanism",
+            Self::PeerTaskLimit => "peer_task_limit",
+        }
+    }
+}
+
+impl fmt::Display for TaskprovOptOutReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Policy for deciding whether to opt in to a task provisioned via taskprov. Each constraint is
+/// optional; the default policy opts in to every task.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TaskprovOptInPolicy {
+    /// VDAFs that may be used. If empty, then every VDAF is permitted.
+    #[serde(default)]
+    pub vdafs: Vec<TaskprovVdafPolicy>,
+
+    /// Lower bound for the task's minimum batch size.
+    pub min_batch_size_lower_bound: Option<u64>,
+
+    /// Upper bound for the task's minimum batch size.
+    pub min_batch_size_upper_bound: Option<u64>,
+
+    /// Maximum amount of time (in seconds) from now until the task expires.
+    pub max_expiration_horizon: Option<Duration>,
+
+    /// Patterns that the peer Aggregator's URL must match. The character `*` matches any sequence
+    /// of characters, e.g., `https://*.example.com/*`. If empty, then every URL is permitted.
+    #[serde(default)]
+    pub peer_url_patterns: Vec<String>,
+
+    /// Differential privacy mechanism the task is required to use.
+    pub dp_mechanism: Option<DpConfig>,
+
+    /// Maximum number of tasks each peer Aggregator may provision.
+    pub max_tasks_per_peer: Option<u64>,
+}
+
+impl TaskprovOptInPolicy {
+    /// Decide whether to opt in to a task provisioned via taskprov. `dp_config` is the
+    /// differential privacy mechanism indicated by the taskprov extension, `peer_url` is the URL
+    /// of the peer Aggregator, and `peer_task_count` is the number of tasks the peer has
+    /// provisioned so far.
+    pub fn check(
+        &self,
+        task_config: &DapTaskConfig,
+        dp_config: &DpConfig,
+        peer_url: &Url,
+        peer_task_count: u64,
+        now: Time,
+    ) -> Result<(), TaskprovOptOutReason> {
+        if !self.vdafs.is_empty()
+            && !self
+                .vdafs
+                .iter()
+                .any(|vdaf_policy| vdaf_policy.permits(&task_config.vdaf))
+        {
+            return Err(TaskprovOptOutReason::Vdaf);
+        }
+
+        if matches!(self.min_batch_size_lower_bound, Some(lower_bound) if task_config.min_batch_size < lower_bound)
+            || matches!(self.min_batch_size_upper_bound, Some(upper_bound) if task_config.min_batch_size > upper_bound)
+        {
+            return Err(TaskprovOptOutReason::MinBatchSize);
+        }
+
+        if matches!(self.max_expiration_horizon, Some(horizon) if task_config.expiration > now.saturating_add(horizon))
+        {
+            return Err(TaskprovOptOutReason::Expiration);
+        }
+
+        if !self.peer_url_patterns.is_empty()
+            && !self
+                .peer_url_patterns
+                .iter()
+                .any(|pattern| url_pattern_matches(pattern, peer_url.as_str()))
+        {
+            return Err(TaskprovOptOutReason::PeerUrl);
+        }
+
+        if matches!(self.dp_mechanism, Some(ref dp_mechanism) if dp_mechanism != dp_config) {
+            return Err(TaskprovOptOutReason::DpMechanism);
+        }
+
+        if matches!(self.max_tasks_per_peer, Some(max_tasks) if peer_task_count >= max_tasks) {
+            return Err(TaskprovOptOutReason::PeerTaskLimit);
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if `url` matches `pattern`, where `*` matches any sequence of characters.
+fn url_pattern_matches(pattern: &str, url: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let mut rest = match url.strip_prefix(parts.next().unwrap_or_default()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // The pattern contains no wildcard.
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
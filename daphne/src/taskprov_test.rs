@@ -2,11 +2,17 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use crate::{
-    messages::taskprov::VdafType,
-    messages::Id,
-    taskprov::{compute_vdaf_verify_key, TaskprovVersion},
+    hpke::HpkeReceiverConfig,
+    messages::taskprov::{DpConfig, VdafType},
+    messages::{HpkeKemId, Id},
+    taskprov::{
+        compute_vdaf_verify_key, TaskprovOptInPolicy, TaskprovOptOutReason, TaskprovVdafPolicy,
+        TaskprovVersion,
+    },
     vdaf::VdafVerifyKey,
+    DapQueryConfig, DapTaskConfig, DapVersion, Prio3Config, VdafConfig,
 };
+use url::Url;
 
 #[test]
 fn check_vdaf_key_computation() {
@@ -25,7 +31,8 @@ fn check_vdaf_key_computation() {
         &verify_key_init,
         &task_id,
         VdafType::Prio3Aes128Count,
-    );
+    )
+    .unwrap();
     let expected: [u8; 16] = [
         0xfb, 0xd1, 0x7d, 0xb5, 0x39, 0x0f, 0x94, 0x9e, 0xe3, 0x2d, 0x26, 0x34, 0xdc, 0x49, 0x9f,
         0x5b,
@@ -35,3 +42,217 @@ fn check_vdaf_key_computation() {
         _ => unreachable!(),
     }
 }
+
+#[test]
+fn check_vdaf_key_computation_draft03() {
+    let task_id = Id([
+        0xb4, 0x76, 0x9b, 0xb0, 0x63, 0xa8, 0xb3, 0x31, 0x2a, 0xf7, 0x42, 0x97, 0xf3, 0x0f, 0xdb,
+        0xf8, 0xe0, 0xb7, 0x1c, 0x2e, 0xb2, 0x48, 0x1f, 0x59, 0x1d, 0x1d, 0x7d, 0xe6, 0x6a, 0x4c,
+        0xe3, 0x4f,
+    ]);
+    let verify_key_init: [u8; 32] = [
+        0x1a, 0x2a, 0x3f, 0x1b, 0xeb, 0xb4, 0xbb, 0xe4, 0x55, 0xea, 0xac, 0xee, 0x29, 0x1a, 0x0f,
+        0x32, 0xd7, 0xe1, 0xbc, 0x6c, 0x75, 0x10, 0x05, 0x60, 0x7b, 0x81, 0xda, 0xc3, 0xa7, 0xda,
+        0x76, 0x1d,
+    ];
+    let vk = compute_vdaf_verify_key(
+        TaskprovVersion::Draft03,
+        &verify_key_init,
+        &task_id,
+        VdafType::Prio3Aes128Count,
+    )
+    .unwrap();
+    let expected: [u8; 16] = [
+        0xc7, 0x01, 0x4d, 0xc0, 0xf9, 0xcf, 0x37, 0x41, 0xfd, 0x27, 0xf1, 0xef, 0xfe, 0xce, 0x0d,
+        0xd9,
+    ];
+    match &vk {
+        VdafVerifyKey::Prio3(bytes) => assert_eq!(*bytes, expected),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn check_vdaf_key_computation_unknown_version() {
+    assert!(compute_vdaf_verify_key(
+        TaskprovVersion::Unknown,
+        &[0; 32],
+        &Id([0; 32]),
+        VdafType::Prio3Aes128Count,
+    )
+    .is_err());
+}
+
+fn policy_test_task_config(
+    vdaf: VdafConfig,
+    min_batch_size: u64,
+    expiration: u64,
+) -> DapTaskConfig {
+    DapTaskConfig {
+        version: DapVersion::Draft02,
+        leader_url: Url::parse("https://leader.example.com/v02/").unwrap(),
+        helper_url: Url::parse("https://helper.example.com/v02/").unwrap(),
+        time_precision: 3600,
+        expiration,
+        min_batch_size,
+        query: DapQueryConfig::TimeInterval,
+        vdaf,
+        vdaf_verify_key: VdafVerifyKey::Prio3([0; 16]),
+        collector_hpke_config: HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config,
+        retired_collector_hpke_configs: Vec::new(),
+        taskprov: None,
+    }
+}
+
+#[test]
+fn opt_in_policy_default_permits_all() {
+    let now = 1_000_000;
+    let task_config = policy_test_task_config(
+        VdafConfig::Prio3(Prio3Config::Sum { bits: 64 }),
+        1,
+        now + 86400 * 365,
+    );
+    assert_eq!(
+        TaskprovOptInPolicy::default().check(
+            &task_config,
+            &DpConfig::None,
+            &task_config.leader_url,
+            u64::MAX,
+            now
+        ),
+        Ok(())
+    );
+}
+
+#[test]
+fn opt_in_policy() {
+    let now = 1_000_000;
+    let policy: TaskprovOptInPolicy = serde_json::from_str(
+        r#"{
+            "vdafs": ["prio3_count", { "prio3_sum": { "max_bits": 8 } }],
+            "min_batch_size_lower_bound": 10,
+            "min_batch_size_upper_bound": 1000,
+            "max_expiration_horizon": 86400,
+            "peer_url_patterns": ["https://*.example.com/*"],
+            "dp_mechanism": "None",
+            "max_tasks_per_peer": 2
+        }"#,
+    )
+    .unwrap();
+    let check = |task_config: &DapTaskConfig, peer_url: &str, peer_task_count: u64| {
+        policy.check(
+            task_config,
+            &DpConfig::None,
+            &Url::parse(peer_url).unwrap(),
+            peer_task_count,
+            now,
+        )
+    };
+    let peer_url = "https://leader.example.com/v02/";
+
+    let ok = policy_test_task_config(VdafConfig::Prio3(Prio3Config::Sum { bits: 8 }), 10, now);
+    assert_eq!(check(&ok, peer_url, 1), Ok(()));
+
+    let task_config =
+        policy_test_task_config(VdafConfig::Prio3(Prio3Config::Sum { bits: 9 }), 10, now);
+    assert_eq!(
+        check(&task_config, peer_url, 0),
+        Err(TaskprovOptOutReason::Vdaf)
+    );
+
+    let task_config = policy_test_task_config(
+        VdafConfig::Prio3(Prio3Config::Histogram { buckets: vec![1] }),
+        10,
+        now,
+    );
+    assert_eq!(
+        check(&task_config, peer_url, 0),
+        Err(TaskprovOptOutReason::Vdaf)
+    );
+
+    let task_config = policy_test_task_config(VdafConfig::Prio3(Prio3Config::Count), 9, now);
+    assert_eq!(
+        check(&task_config, peer_url, 0),
+        Err(TaskprovOptOutReason::MinBatchSize)
+    );
+
+    let task_config = policy_test_task_config(VdafConfig::Prio3(Prio3Config::Count), 1001, now);
+    assert_eq!(
+        check(&task_config, peer_url, 0),
+        Err(TaskprovOptOutReason::MinBatchSize)
+    );
+
+    let task_config =
+        policy_test_task_config(VdafConfig::Prio3(Prio3Config::Count), 10, now + 86401);
+    assert_eq!(
+        check(&task_config, peer_url, 0),
+        Err(TaskprovOptOutReason::Expiration)
+    );
+
+    assert_eq!(
+        check(&ok, "https://leader.example.org/v02/", 0),
+        Err(TaskprovOptOutReason::PeerUrl)
+    );
+    assert_eq!(
+        check(&ok, "http://leader.example.com/v02/", 0),
+        Err(TaskprovOptOutReason::PeerUrl)
+    );
+
+    assert_eq!(
+        check(&ok, peer_url, 2),
+        Err(TaskprovOptOutReason::PeerTaskLimit)
+    );
+}
+
+#[test]
+fn opt_in_policy_peer_url_patterns() {
+    let now = 1_000_000;
+    let task_config = policy_test_task_config(VdafConfig::Prio3(Prio3Config::Count), 1, now);
+    let permits = |pattern: &str, peer_url: &str| {
+        TaskprovOptInPolicy {
+            peer_url_patterns: vec![pattern.into()],
+            ..Default::default()
+        }
+        .check(
+            &task_config,
+            &DpConfig::None,
+            &Url::parse(peer_url).unwrap(),
+            0,
+            now,
+        )
+        .is_ok()
+    };
+
+    assert!(permits("https://leader.com/", "https://leader.com/"));
+    assert!(!permits("https://leader.com/", "https://leader.com/v02/"));
+    assert!(permits("https://leader.com/*", "https://leader.com/v02/"));
+    assert!(permits("*", "https://leader.com/v02/"));
+    assert!(permits(
+        "https://*.leader.com/*/",
+        "https://a.b.leader.com/v02/"
+    ));
+    assert!(!permits(
+        "https://*.leader.com/*/",
+        "https://leader.com/v02/"
+    ));
+    assert!(!permits(
+        "https://*.leader.com/",
+        "https://evil.com/.leader.com/x"
+    ));
+}
+
+#[test]
+fn opt_in_policy_vdaf_policy_serialization() {
+    let vdafs: Vec<TaskprovVdafPolicy> =
+        serde_json::from_str(r#"["prio3_count", { "prio3_histogram": { "max_buckets": 10 } }]"#)
+            .unwrap();
+    assert_eq!(
+        vdafs,
+        vec![
+            TaskprovVdafPolicy::Prio3Count,
+            TaskprovVdafPolicy::Prio3Histogram { max_buckets: 10 }
+        ]
+    );
+}
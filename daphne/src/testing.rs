@@ -8,14 +8,16 @@ use crate::{
     constants,
     hpke::{HpkeDecrypter, HpkeReceiverConfig},
     messages::{
+        taskprov::{DpConfig, TaskConfig},
         BatchSelector, CollectReq, CollectResp, HpkeCiphertext, HpkeConfig, Id,
         PartialBatchSelector, Report, ReportId, ReportMetadata, Time, TransitionFailure,
     },
     metrics::DaphneMetrics,
     roles::{DapAggregator, DapAuthorizedSender, DapHelper, DapLeader},
-    taskprov, DapAbort, DapAggregateShare, DapBatchBucket, DapCollectJob, DapError,
-    DapGlobalConfig, DapHelperState, DapOutputShare, DapQueryConfig, DapRequest, DapResponse,
-    DapTaskConfig, DapVersion,
+    taskprov::TaskprovOptInPolicy,
+    DapAbort, DapAggregateShare, DapBatchBucket, DapCollectJob, DapError, DapGlobalConfig,
+    DapHelperState, DapOutputShare, DapQueryConfig, DapRequest, DapResponse,
+    DapStatelessHelperConfig, DapTaskConfig, DapVersion,
 };
 use assert_matches::assert_matches;
 use async_trait::async_trait;
@@ -76,8 +78,12 @@ pub(crate) struct MockAggregator {
     pub(crate) agg_store: Arc<Mutex<HashMap<Id, HashMap<DapBatchBucketOwned, AggStore>>>>,
     pub(crate) collector_hpke_config: HpkeConfig,
     pub(crate) taskprov_vdaf_verify_key_init: [u8; 32],
+    pub(crate) taskprov_opt_in_policy: TaskprovOptInPolicy,
     pub(crate) metrics: DaphneMetrics,
 
+    // Helper: If set, then the Helper offloads its state to the Leader. Not set by the Leader.
+    pub(crate) stateless_helper_config: Option<DapStatelessHelperConfig>,
+
     // Leader: Reference to peer. Used to simulate HTTP requests from Leader to Helper, i.e.,
     // implement `DapLeader::send_http_post()` for `MockAggregator`. Not set by the Helper.
     pub(crate) peer: Option<Arc<MockAggregator>>,
@@ -187,6 +193,19 @@ impl MockAggregator {
             .map(|(batch_id, _report_count)| batch_id)
     }
 
+    fn is_task_configured(&self, task_id: &Id) -> bool {
+        self.tasks
+            .lock()
+            .expect("tasks: lock failed")
+            .contains_key(task_id)
+    }
+
+    fn unchecked_collector_token(&self) -> Result<&BearerToken, DapError> {
+        self.collector_token.as_ref().ok_or_else(|| {
+            DapError::fatal("MockAggregator not configured with Collector bearer token")
+        })
+    }
+
     pub(crate) async fn unchecked_get_task_config(&self, task_id: &Id) -> DapTaskConfig {
         self.get_task_config_for(Cow::Borrowed(task_id))
             .await
@@ -199,38 +218,43 @@ impl MockAggregator {
 impl<'a> BearerTokenProvider<'a> for MockAggregator {
     type WrappedBearerToken = &'a BearerToken;
 
+    // MockAggregator uses the same tokens for all tasks, including those provisioned via taskprov.
+    // The tokens are only returned for tasks that are configured, so that requests for other tasks
+    // are authorized as taskprov requests.
     async fn get_leader_bearer_token_for(
         &'a self,
-        _task_id: &'a Id,
+        task_id: &'a Id,
     ) -> Result<Option<&'a BearerToken>, DapError> {
+        if !self.is_task_configured(task_id) {
+            return Ok(None);
+        }
         Ok(Some(&self.leader_token))
     }
 
     async fn get_collector_bearer_token_for(
         &'a self,
-        _task_id: &'a Id,
+        task_id: &'a Id,
     ) -> Result<Option<&'a BearerToken>, DapError> {
-        if let Some(ref collector_token) = self.collector_token {
-            Ok(Some(collector_token))
-        } else {
-            Err(DapError::fatal(
-                "MockAggregator not configured with Collector bearer token",
-            ))
+        if !self.is_task_configured(task_id) {
+            return Ok(None);
         }
+        Ok(Some(self.unchecked_collector_token()?))
     }
 
-    fn is_taskprov_leader_bearer_token(&self, _token: &BearerToken) -> bool {
-        // MockAggregator currently uses the same token for all tasks, regardless of how the task
-        // is configured. As a result, we don't expect BearerTokenProver::bearer_token_authorized()
-        // to ever reach this point.
-        unreachable!("did not expect to check bearer token");
+    fn is_taskprov_leader_bearer_token(
+        &self,
+        _taskprov_task_config: &TaskConfig,
+        token: &BearerToken,
+    ) -> Result<bool, DapError> {
+        Ok(self.global_config.allow_taskprov && *token == self.leader_token)
     }
 
-    fn is_taskprov_collector_bearer_token(&self, _token: &BearerToken) -> bool {
-        // MockAggregator currently uses the same token for all tasks, regardless of how the task
-        // is configured. As a result, we don't expect BearerTokenProver::bearer_token_authorized()
-        // to ever reach this point.
-        unreachable!("did not expect to check bearer token");
+    fn is_taskprov_collector_bearer_token(
+        &self,
+        _taskprov_task_config: &TaskConfig,
+        token: &BearerToken,
+    ) -> Result<bool, DapError> {
+        Ok(self.global_config.allow_taskprov && token == self.unchecked_collector_token()?)
     }
 }
 
@@ -264,6 +288,14 @@ impl<'a> HpkeDecrypter<'a> for MockAggregator {
         Ok(self.get_hpke_receiver_config_for(config_id).is_some())
     }
 
+    async fn get_hpke_receiver_config_by_id(
+        &self,
+        _task_id: &Id,
+        config_id: u8,
+    ) -> Result<Option<HpkeReceiverConfig>, DapError> {
+        Ok(self.get_hpke_receiver_config_for(config_id).cloned())
+    }
+
     async fn hpke_decrypt(
         &self,
         _task_id: &Id,
@@ -305,64 +337,99 @@ where
     // clones the task config as needed.
     type WrappedDapTaskConfig = DapTaskConfig;
 
-    async fn authorized(&self, req: &DapRequest<BearerToken>) -> Result<bool, DapError> {
-        self.bearer_token_authorized(req).await
+    async fn authorized(
+        &self,
+        req: &DapRequest<BearerToken>,
+        taskprov_task_config: Option<&TaskConfig>,
+    ) -> Result<bool, DapError> {
+        self.bearer_token_authorized(req, taskprov_task_config)
+            .await
     }
 
     fn get_global_config(&self) -> &DapGlobalConfig {
         &self.global_config
     }
 
-    fn taskprov_opt_in_decision(&self, _task_config: &DapTaskConfig) -> Result<bool, DapError> {
-        Ok(true)
+    async fn taskprov_opt_in_decision(
+        &self,
+        task_config: &DapTaskConfig,
+        dp_config: &DpConfig,
+    ) -> Result<(), DapError> {
+        // The Leader's peer is the Helper and vice versa.
+        let peer_url_for = |task_config: &DapTaskConfig| {
+            if self.peer.is_some() {
+                task_config.helper_url.clone()
+            } else {
+                task_config.leader_url.clone()
+            }
+        };
+        let peer_url = peer_url_for(task_config);
+
+        let peer_task_count = self
+            .tasks
+            .lock()
+            .expect("tasks: lock failed")
+            .values()
+            .filter(|other| peer_url_for(other).origin() == peer_url.origin())
+            .count() as u64;
+
+        self.taskprov_opt_in_policy
+            .check(
+                task_config,
+                dp_config,
+                &peer_url,
+                peer_task_count,
+                self.get_current_time(),
+            )
+            .map_err(|reason| {
+                self.metrics.taskprov_opt_out_inc(reason);
+                DapError::Abort(DapAbort::TaskprovOptOut(format!(
+                    "task rejected by opt-in policy: {reason}"
+                )))
+            })
     }
 
     async fn get_task_config_considering_taskprov(
         &'srv self,
         version: DapVersion,
         task_id: Cow<'req, Id>,
-        metadata: Option<&ReportMetadata>,
+        taskprov_task_config: Option<&TaskConfig>,
     ) -> Result<Option<DapTaskConfig>, DapError> {
-        let taskprov_version = self.global_config.taskprov_version;
-
-        // Before looking up the task configuration, first check if it needs to be configured from
-        // the current request.
-        if self.get_global_config().allow_taskprov
-            && metadata.is_some()
-            && metadata.unwrap().is_taskprov(taskprov_version, &task_id)
+        if let Some(task_config) = self
+            .tasks
+            .lock()
+            .expect("tasks: lock failed")
+            .get(task_id.as_ref())
         {
-            if let Some(taskprov_task_config) = taskprov::get_taskprov_task_config(
-                taskprov_version,
-                task_id.as_ref(),
-                metadata.unwrap(),
-            )? {
-                let task_config = DapTaskConfig::try_from_taskprov(
-                    version,
-                    self.global_config.taskprov_version,
-                    task_id.as_ref(),
-                    taskprov_task_config,
-                    &self.taskprov_vdaf_verify_key_init,
-                    &self.collector_hpke_config,
-                )?;
-
-                let mut tasks = self.tasks.lock().expect("tasks: lock failed");
-                if tasks.get(task_id.as_ref()).is_none() {
-                    // Decide whether to opt-in to the task.
-                    if !self.taskprov_opt_in_decision(&task_config)? {
-                        return Err(DapError::Abort(DapAbort::InvalidTask));
-                    }
+            return Ok(Some(task_config.clone()));
+        }
 
-                    tasks
-                        .deref_mut()
-                        .insert(task_id.into_owned(), task_config.clone());
-                }
+        // The task is not recognized, so check if it needs to be configured from the current
+        // request.
+        let taskprov_task_config = match taskprov_task_config {
+            Some(taskprov_task_config) if self.global_config.allow_taskprov => taskprov_task_config,
+            _ => return Ok(None),
+        };
 
-                return Ok(Some(task_config));
-            }
-        }
+        let task_config = DapTaskConfig::try_from_taskprov(
+            version,
+            self.global_config.taskprov_version,
+            task_id.as_ref(),
+            taskprov_task_config.clone(),
+            &self.taskprov_vdaf_verify_key_init,
+            &self.collector_hpke_config,
+        )?;
 
-        let tasks = self.tasks.lock().expect("tasks: lock failed");
-        Ok(tasks.get(task_id.as_ref()).cloned())
+        // Decide whether to opt-in to the task.
+        self.taskprov_opt_in_decision(&task_config, &taskprov_task_config.vdaf_config.dp_config)
+            .await?;
+
+        self.tasks
+            .lock()
+            .expect("tasks: lock failed")
+            .deref_mut()
+            .insert(task_id.into_owned(), task_config.clone());
+        Ok(Some(task_config))
     }
 
     fn get_current_time(&self) -> Time {
@@ -372,6 +439,15 @@ where
             .as_secs()
     }
 
+    fn get_current_time_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            .try_into()
+            .unwrap()
+    }
+
     async fn is_batch_overlapping(
         &self,
         task_id: &Id,
@@ -416,6 +492,7 @@ where
     async fn put_out_shares(
         &self,
         task_id: &Id,
+        agg_job_id: &Id,
         part_batch_sel: &PartialBatchSelector,
         out_shares: Vec<DapOutputShare>,
     ) -> Result<(), DapError> {
@@ -424,19 +501,88 @@ where
             .await?
             .ok_or_else(|| DapError::fatal("task not found"))?;
 
+        let span = task_config.report_shares_for_out_shares(part_batch_sel, out_shares)?;
         let mut guard = self.agg_store.lock().expect("agg_store: failed to lock");
         let agg_store = guard.entry(task_id.clone()).or_default();
-        for (bucket, agg_share_delta) in task_config
-            .batch_span_for_out_shares(part_batch_sel, out_shares)?
-            .into_iter()
-        {
+
+        // Check every bucket before merging into any of them.
+        if span.keys().any(|bucket| {
+            agg_store
+                .get(&bucket.to_owned_bucket())
+                .map_or(false, |inner_agg_store| inner_agg_store.collected)
+        }) {
+            return Err(DapError::Abort(DapAbort::BatchOverlap));
+        }
+
+        for (bucket, report_shares) in span.into_iter() {
             let inner_agg_store = agg_store.entry(bucket.to_owned_bucket()).or_default();
-            inner_agg_store.agg_share.merge(agg_share_delta)?;
+            if inner_agg_store.agg_job_ids.insert(agg_job_id.clone()) {
+                for report_share in report_shares {
+                    if self.global_config.reconcile_batch_mismatch {
+                        inner_agg_store
+                            .report_shares
+                            .insert(report_share.checksum, report_share.clone());
+                    }
+                    inner_agg_store.agg_share.merge(report_share)?;
+                }
+            }
         }
 
         Ok(())
     }
 
+    async fn get_report_checksums(
+        &self,
+        task_id: &Id,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<[u8; 32]>, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let guard = self.agg_store.lock().expect("agg_store: failed to lock");
+        let agg_store = if let Some(agg_store) = guard.get(task_id) {
+            agg_store
+        } else {
+            return Ok(Vec::new());
+        };
+
+        let mut checksums = Vec::new();
+        for bucket in task_config.batch_span_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) = agg_store.get(&bucket.to_owned_bucket()) {
+                checksums.extend(inner_agg_store.report_shares.keys().copied());
+            }
+        }
+
+        Ok(checksums)
+    }
+
+    async fn exclude_reports(
+        &self,
+        task_id: &Id,
+        batch_sel: &BatchSelector,
+        checksums: &[[u8; 32]],
+    ) -> Result<u64, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let mut guard = self.agg_store.lock().expect("agg_store: failed to lock");
+        let agg_store = guard.entry(task_id.clone()).or_default();
+
+        let mut excluded = 0;
+        for bucket in task_config.batch_span_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) = agg_store.get_mut(&bucket.to_owned_bucket()) {
+                if inner_agg_store.collected {
+                    return Err(DapError::Abort(DapAbort::BatchOverlap));
+                }
+
+                for checksum in checksums {
+                    if let Some(report_share) = inner_agg_store.report_shares.remove(checksum) {
+                        inner_agg_store.agg_share.exclude(report_share)?;
+                        excluded += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(excluded)
+    }
+
     async fn get_agg_share(
         &self,
         task_id: &Id,
@@ -598,6 +744,10 @@ where
 
         Ok(None)
     }
+
+    fn get_stateless_helper_config(&self) -> Option<&DapStatelessHelperConfig> {
+        self.stateless_helper_config.as_ref()
+    }
 }
 
 #[async_trait(?Send)]
@@ -686,6 +836,50 @@ where
         }
     }
 
+    async fn put_back_reports(
+        &self,
+        task_id: &Id,
+        part_batch_sel: &PartialBatchSelector,
+        reports: Vec<Report>,
+    ) -> Result<(), DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let mut guard = self
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock");
+        let report_store = guard.entry(task_id.clone()).or_default();
+
+        // Put the reports back at the front of the queue so that they are aggregated next.
+        for report in reports.into_iter().rev() {
+            let bucket = match part_batch_sel {
+                PartialBatchSelector::TimeInterval => DapBatchBucketOwned::TimeInterval {
+                    batch_window: task_config.truncate_time(report.metadata.time),
+                },
+                PartialBatchSelector::FixedSizeByBatchId { batch_id } => {
+                    DapBatchBucketOwned::FixedSize {
+                        batch_id: batch_id.clone(),
+                    }
+                }
+            };
+            report_store
+                .pending
+                .entry(bucket)
+                .or_default()
+                .push_front(report);
+        }
+        Ok(())
+    }
+
+    async fn ack_reports(&self, task_id: &Id, report_ids: &[ReportId]) -> Result<(), DapError> {
+        let mut guard = self
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock");
+        let report_store = guard.entry(task_id.clone()).or_default();
+        report_store.acked.extend(report_ids.iter().cloned());
+        Ok(())
+    }
+
     // Called after receiving a CollectReq from Collector.
     async fn init_collect_job(&self, collect_req: &CollectReq) -> Result<Url, DapError> {
         let mut rng = thread_rng();
@@ -840,6 +1034,20 @@ where
                 .http_post_aggregate_share(&req)
                 .await
                 .expect("peer aborted unexpectedly")),
+            constants::MEDIA_TYPE_AGG_SHARE_RECONCILE_REQ => Ok(self
+                .peer
+                .as_ref()
+                .expect("peer not configured")
+                .http_post_aggregate_share_reconcile(&req)
+                .await
+                .expect("peer aborted unexpectedly")),
+            constants::MEDIA_TYPE_AGG_SHARE_EXCLUDE_REQ => Ok(self
+                .peer
+                .as_ref()
+                .expect("peer not configured")
+                .http_post_aggregate_share_exclude(&req)
+                .await
+                .expect("peer aborted unexpectedly")),
             s => unreachable!("unhandled media type: {}", s),
         }
     }
@@ -857,6 +1065,9 @@ pub(crate) struct HelperStateInfo {
 pub(crate) struct ReportStore {
     pub(crate) pending: HashMap<DapBatchBucketOwned, VecDeque<Report>>,
     pub(crate) processed: HashSet<ReportId>,
+
+    /// Reports that have been fetched for aggregation and acknowledged.
+    pub(crate) acked: HashSet<ReportId>,
 }
 
 /// Stores the state of the collect job.
@@ -878,10 +1089,14 @@ pub(crate) struct LeaderState {
 /// AggStore keeps track of the following:
 /// * Aggregate share
 /// * Whether this aggregate share has been collected
+/// * The aggregation jobs whose output shares have been merged into the aggregate share
+/// * The output share of each report, indexed by checksum, if batch reconciliation is enabled
 #[derive(Default)]
 pub(crate) struct AggStore {
     pub(crate) agg_share: DapAggregateShare,
     pub(crate) collected: bool,
+    pub(crate) agg_job_ids: HashSet<Id>,
+    pub(crate) report_shares: HashMap<[u8; 32], DapAggregateShare>,
 }
 
 // These are declarative macros which let us generate a test point for
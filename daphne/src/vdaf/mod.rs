@@ -7,37 +7,38 @@
 use crate::{
     hpke::HpkeDecrypter,
     messages::{
-        encode_u32_bytes, AggregateContinueReq, AggregateInitializeReq, AggregateResp,
-        BatchSelector, Extension, HpkeCiphertext, HpkeConfig, Id, PartialBatchSelector,
-        PlaintextInputShare, Report, ReportId, ReportMetadata, ReportShare, Time, Transition,
-        TransitionFailure, TransitionVar,
+        encode_u32_bytes, AggregateContinueReq, AggregateInitializeReq, AggregateInitializeReqRef,
+        AggregateResp, AggregateRespRef, BatchSelector, Extension, HpkeCiphertext,
+        HpkeCiphertextRef, HpkeConfig, Id, PartialBatchSelector, PlaintextInputShare, Report,
+        ReportId, ReportMetadata, ReportShare, Time, Transition, TransitionFailure, TransitionVar,
+        TransitionVarRef,
     },
     metrics::DaphneMetrics,
-    vdaf::{
-        prio2::{
-            prio2_encode_prepare_message, prio2_helper_prepare_finish, prio2_leader_prepare_finish,
-            prio2_prepare_init, prio2_shard, prio2_unshard,
-        },
-        prio3::{
-            prio3_encode_prepare_message, prio3_helper_prepare_finish, prio3_leader_prepare_finish,
-            prio3_prepare_init, prio3_shard, prio3_unshard,
-        },
-    },
+    vdaf::{prio2::Prio2Vdaf, prio3::Prio3Vdaf},
     DapAbort, DapAggregateResult, DapAggregateShare, DapError, DapHelperState, DapHelperTransition,
     DapLeaderState, DapLeaderTransition, DapLeaderUncommitted, DapMeasurement, DapOutputShare,
     DapTaskConfig, DapVersion, VdafConfig,
 };
 use prio::{
-    codec::{CodecError, Decode, Encode, ParameterizedEncode},
+    codec::{
+        decode_u16_items, decode_u32_items, encode_u16_items, encode_u32_items, CodecError, Decode,
+        Encode, ParameterizedEncode,
+    },
     field::{Field128, Field64, FieldPrio2},
     vdaf::{
-        prio2::{Prio2PrepareShare, Prio2PrepareState},
+        prio2::{Prio2, Prio2PrepareShare, Prio2PrepareState},
         prio3::{Prio3PrepareShare, Prio3PrepareState},
     },
 };
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, convert::TryInto};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    convert::TryInto,
+    io::Cursor,
+    ops::Deref,
+    sync::Arc,
+};
 
 const CTX_INPUT_SHARE_DRAFT02: &[u8] = b"dap-02 input share";
 const CTX_INPUT_SHARE_DRAFT03: &[u8] = b"dap-03 input share";
@@ -48,20 +49,32 @@ const CTX_ROLE_CLIENT: u8 = 1;
 const CTX_ROLE_LEADER: u8 = 2;
 const CTX_ROLE_HELPER: u8 = 3;
 
+/// An error that occurred while running a VDAF.
 #[derive(Debug, thiserror::Error)]
-pub(crate) enum VdafError {
+pub enum VdafError {
     #[error("{0}")]
     Codec(#[from] CodecError),
     #[error("{0}")]
     Vdaf(#[from] prio::vdaf::VdafError),
 }
 
+impl VdafError {
+    /// An input to the VDAF, such as a measurement or a prepare state, does not have the type
+    /// expected by the VDAF.
+    pub(crate) fn unexpected_type(what: &str) -> Self {
+        Self::Vdaf(prio::vdaf::VdafError::Uncategorized(format!(
+            "unexpected {what} type"
+        )))
+    }
+}
+
 /// A VDAF verification key.
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum VdafVerifyKey {
     Prio3(#[serde(with = "hex")] [u8; 16]),
     Prio2(#[serde(with = "hex")] [u8; 32]),
+    Registered(#[serde(with = "hex")] Vec<u8>),
 }
 
 impl AsRef<[u8]> for VdafVerifyKey {
@@ -69,30 +82,46 @@ impl AsRef<[u8]> for VdafVerifyKey {
         match self {
             Self::Prio3(ref bytes) => &bytes[..],
             Self::Prio2(ref bytes) => &bytes[..],
+            Self::Registered(ref bytes) => &bytes[..],
         }
     }
 }
 
+/// An Aggregator's prepare state for a report. The state of a registered VDAF is opaque to Daphne.
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) enum VdafState {
+pub enum VdafState {
     Prio2(Prio2PrepareState),
     Prio3Field64(Prio3PrepareState<Field64, 16>),
     Prio3Field128(Prio3PrepareState<Field128, 16>),
+    Registered(Vec<u8>),
 }
 
+/// An Aggregator's prepare share for a report. The share of a registered VDAF is opaque to Daphne.
 #[derive(Clone, Debug)]
-pub(crate) enum VdafMessage {
+pub enum VdafMessage {
     Prio2Share(Prio2PrepareShare),
     Prio3ShareField64(Prio3PrepareShare<Field64, 16>),
     Prio3ShareField128(Prio3PrepareShare<Field128, 16>),
+    Registered(Vec<u8>),
 }
 
+/// An output share or aggregate share. Registered VDAFs may aggregate over one of the fields, in
+/// which case their shares are merged like those of the built-in VDAFs, or use an encoding of
+/// their own.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
-pub(crate) enum VdafAggregateShare {
+pub enum VdafAggregateShare {
     Field64(prio::vdaf::AggregateShare<Field64>),
     Field128(prio::vdaf::AggregateShare<Field128>),
     FieldPrio2(prio::vdaf::AggregateShare<FieldPrio2>),
+
+    /// An encoded share of the VDAF registered under the name `vdaf`. Shares are merged with
+    /// [`DapVdaf::merge_agg_shares`].
+    Registered {
+        vdaf: String,
+        #[serde(with = "hex")]
+        share: Vec<u8>,
+    },
 }
 
 impl Encode for VdafAggregateShare {
@@ -101,6 +130,158 @@ impl Encode for VdafAggregateShare {
             VdafAggregateShare::Field64(agg_share) => bytes.append(&mut agg_share.into()),
             VdafAggregateShare::Field128(agg_share) => bytes.append(&mut agg_share.into()),
             VdafAggregateShare::FieldPrio2(agg_share) => bytes.append(&mut agg_share.into()),
+            VdafAggregateShare::Registered { share, .. } => bytes.extend_from_slice(share),
+        }
+    }
+}
+
+impl VdafAggregateShare {
+    /// Encode the share along with its type, so that it can be decoded without knowing the VDAF.
+    /// (The encoding used in DAP messages only consists of the share itself.)
+    pub(crate) fn encode_with_type(&self, bytes: &mut Vec<u8>) {
+        match self {
+            VdafAggregateShare::Field64(agg_share) => {
+                0_u8.encode(bytes);
+                encode_u32_items(bytes, &(), agg_share.as_ref());
+            }
+            VdafAggregateShare::Field128(agg_share) => {
+                1_u8.encode(bytes);
+                encode_u32_items(bytes, &(), agg_share.as_ref());
+            }
+            VdafAggregateShare::FieldPrio2(agg_share) => {
+                2_u8.encode(bytes);
+                encode_u32_items(bytes, &(), agg_share.as_ref());
+            }
+            VdafAggregateShare::Registered { vdaf, share } => {
+                3_u8.encode(bytes);
+                encode_u16_items(bytes, &(), vdaf.as_bytes());
+                encode_u32_items(bytes, &(), share);
+            }
+        }
+    }
+
+    /// Decode a share encoded with [`Self::encode_with_type`].
+    pub(crate) fn decode_with_type(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        match u8::decode(bytes)? {
+            0 => Ok(Self::Field64(
+                decode_u32_items::<_, Field64>(&(), bytes)?.into(),
+            )),
+            1 => Ok(Self::Field128(
+                decode_u32_items::<_, Field128>(&(), bytes)?.into(),
+            )),
+            2 => Ok(Self::FieldPrio2(
+                decode_u32_items::<_, FieldPrio2>(&(), bytes)?.into(),
+            )),
+            3 => Ok(Self::Registered {
+                vdaf: String::from_utf8(decode_u16_items(&(), bytes)?)
+                    .map_err(|e| CodecError::Other(Box::new(e)))?,
+                share: decode_u32_items(&(), bytes)?,
+            }),
+            _ => Err(CodecError::UnexpectedValue),
+        }
+    }
+}
+
+/// A VDAF that can be run by the Clients, Aggregators, and Collector of a task. Prio2 and Prio3
+/// are built in; other VDAFs can be plugged in with [`register_vdaf`](registry::register_vdaf)
+/// and used by tasks configured with [`VdafConfig::Registered`].
+///
+/// The states and messages passed to each method are those produced by the same VDAF. For
+/// example, the `state` passed to [`Self::helper_prepare_finish`] was returned by
+/// [`Self::prepare_init`].
+pub trait DapVdaf: Send + Sync {
+    /// Length of the verification key in bytes.
+    fn verify_key_len(&self) -> usize;
+
+    /// Checks if the provided aggregation parameter is valid.
+    fn is_valid_agg_param(&self, agg_param: &[u8]) -> bool {
+        agg_param.is_empty()
+    }
+
+    /// Split a measurement into a public share and a sequence of encoded input shares, one for
+    /// each Aggregator.
+    fn shard(&self, measurement: DapMeasurement) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError>;
+
+    /// Consume an input share and return the Aggregator's prepare state and prepare share.
+    fn prepare_init(
+        &self,
+        verify_key: &[u8],
+        agg_id: usize,
+        nonce: &[u8],
+        public_share: &[u8],
+        input_share: &[u8],
+    ) -> Result<(VdafState, VdafMessage), VdafError>;
+
+    /// Encode a prepare share returned by [`Self::prepare_init`].
+    fn encode_prepare_message(&self, message: &VdafMessage) -> Vec<u8>;
+
+    /// Consume the Leader's prepare state and share and the Helper's encoded prepare share.
+    /// Return the Leader's output share and the message to send to the Helper.
+    fn leader_prepare_finish(
+        &self,
+        state: VdafState,
+        share: VdafMessage,
+        helper_share: &[u8],
+    ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError>;
+
+    /// Consume the Helper's prepare state and the message sent by the Leader. Return the Helper's
+    /// output share.
+    fn helper_prepare_finish(
+        &self,
+        state: VdafState,
+        leader_message: &[u8],
+    ) -> Result<VdafAggregateShare, VdafError>;
+
+    /// Append the encoding of a prepare state to `bytes`. The encoding must be self-delimiting,
+    /// as the Helper's state is encoded as a sequence of prepare states.
+    fn encode_prepare_state(&self, state: &VdafState, bytes: &mut Vec<u8>)
+        -> Result<(), VdafError>;
+
+    /// Decode a prepare state from the front of `bytes`.
+    fn decode_prepare_state(
+        &self,
+        agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafState, VdafError>;
+
+    /// Interpret `agg_shares` as a sequence of encoded aggregate shares and unshard them.
+    fn unshard(
+        &self,
+        num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError>;
+
+    /// Merge two encoded aggregate shares of the [`VdafAggregateShare::Registered`] variant. VDAFs
+    /// that aggregate over one of the built-in fields need not implement this.
+    fn merge_agg_shares(&self, agg_share: &[u8], other: &[u8]) -> Result<Vec<u8>, VdafError> {
+        let _ = (agg_share, other);
+        Err(VdafError::unexpected_type("aggregate share"))
+    }
+
+    /// Remove the encoded aggregate share `other` from `agg_share`, into which it was previously
+    /// merged. Like [`Self::merge_agg_shares`], this is only used for the
+    /// [`VdafAggregateShare::Registered`] variant.
+    fn subtract_agg_shares(&self, agg_share: &[u8], other: &[u8]) -> Result<Vec<u8>, VdafError> {
+        let _ = (agg_share, other);
+        Err(VdafError::unexpected_type("aggregate share"))
+    }
+}
+
+/// The implementation of the VDAF specified by a [`VdafConfig`].
+pub(crate) enum VdafRef {
+    Prio3(Prio3Vdaf),
+    Prio2(Prio2Vdaf),
+    Registered(Arc<dyn DapVdaf>),
+}
+
+impl Deref for VdafRef {
+    type Target = dyn DapVdaf;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Prio3(vdaf) => vdaf,
+            Self::Prio2(vdaf) => vdaf,
+            Self::Registered(vdaf) => vdaf.as_ref(),
         }
     }
 }
@@ -114,6 +295,23 @@ fn unimplemented_version() -> DapError {
 }
 
 impl VdafConfig {
+    /// Return the implementation of the VDAF. This fails if the VDAF is not registered or if its
+    /// parameters are invalid.
+    pub(crate) fn vdaf(&self) -> Result<VdafRef, DapError> {
+        let invalid = |e| DapError::Fatal(format!("invalid VDAF config: {e}"));
+        match self {
+            Self::Prio3(prio3_config) => Ok(VdafRef::Prio3(
+                Prio3Vdaf::new(prio3_config).map_err(invalid)?,
+            )),
+            Self::Prio2 { dimension } => Ok(VdafRef::Prio2(Prio2Vdaf(
+                Prio2::new(*dimension as usize).map_err(|e| invalid(e.into()))?,
+            ))),
+            Self::Registered(name) => {
+                registry::try_get_registered_vdaf(name).map(VdafRef::Registered)
+            }
+        }
+    }
+
     /// Parse a verification key from raw bytes.
     pub fn get_decoded_verify_key(&self, bytes: &[u8]) -> Result<VdafVerifyKey, DapError> {
         match self {
@@ -123,23 +321,36 @@ impl VdafConfig {
             Self::Prio2 { .. } => Ok(VdafVerifyKey::Prio2(
                 <[u8; 32]>::try_from(bytes).map_err(|e| CodecError::Other(Box::new(e)))?,
             )),
+            Self::Registered(..) => {
+                if bytes.len() != self.vdaf()?.verify_key_len() {
+                    return Err(CodecError::UnexpectedValue.into());
+                }
+                Ok(VdafVerifyKey::Registered(bytes.to_vec()))
+            }
         }
     }
 
     /// Checks if the provided aggregation parameter is valid for the underling VDAF being
     /// executed.
     pub fn is_valid_agg_param(&self, agg_param: &[u8]) -> bool {
-        match self {
-            Self::Prio3(..) | Self::Prio2 { .. } => agg_param.is_empty(),
+        match self.vdaf() {
+            Ok(vdaf) => vdaf.is_valid_agg_param(agg_param),
+            Err(..) => false,
         }
     }
 
-    /// Generate the Aggregators' shared verification parameters.
-    pub fn gen_verify_key(&self) -> VdafVerifyKey {
+    /// Generate the Aggregators' shared verification parameters. This fails if the VDAF is not
+    /// registered.
+    pub fn gen_verify_key(&self) -> Result<VdafVerifyKey, DapError> {
         let mut rng = thread_rng();
         match self {
-            Self::Prio3(..) => VdafVerifyKey::Prio3(rng.gen()),
-            Self::Prio2 { .. } => VdafVerifyKey::Prio2(rng.gen()),
+            Self::Prio3(..) => Ok(VdafVerifyKey::Prio3(rng.gen())),
+            Self::Prio2 { .. } => Ok(VdafVerifyKey::Prio2(rng.gen())),
+            Self::Registered(..) => {
+                let mut bytes = vec![0; self.vdaf()?.verify_key_len()];
+                rng.fill(&mut bytes[..]);
+                Ok(VdafVerifyKey::Registered(bytes))
+            }
         }
     }
 
@@ -276,12 +487,7 @@ impl VdafConfig {
         &self,
         measurement: DapMeasurement,
     ) -> Result<(Vec<u8>, Vec<Vec<u8>>), DapError> {
-        let public_share = Vec::new();
-        let input_shares = match self {
-            Self::Prio3(prio3_config) => prio3_shard(prio3_config, measurement)?,
-            Self::Prio2 { dimension } => prio2_shard(*dimension, measurement)?,
-        };
-        Ok((public_share, input_shares))
+        Ok(self.vdaf()?.shard(measurement)?)
     }
 
     /// Generate a report for a measurement. This method is run by the Client.
@@ -319,39 +525,37 @@ impl VdafConfig {
         )
     }
 
-    /// Consume a report share sent by the Client and return the initial Prepare step. This is run
-    /// by each Aggregator.
-    ///
-    /// # Inputs
+    /// Consume a sequence of report shares sent by Clients and return the initial Prepare step for
+    /// each. This is run by each Aggregator.
     ///
-    /// * `decryptor` is used to decrypt the input share.
+    /// The VDAF is instantiated once for the whole sequence. If the `parallel` feature is enabled,
+    /// then the report shares are decrypted and prepared in parallel. A report share can only be
+    /// decrypted in parallel if `decrypter` provides the receiver config for it (see
+    /// [`HpkeDecrypter::get_hpke_receiver_config_by_id`]); otherwise it is decrypted with
+    /// [`HpkeDecrypter::hpke_decrypt`] beforehand.
     ///
-    /// * `verify_key` is the secret VDAF verification key shared by the Aggregators.
+    /// An error is returned if the report shares can't be processed at all. Otherwise the result
+    /// for each report share is returned in order.
     ///
-    /// * `task_id` is the DAP task ID indicated by the report.
+    /// # Inputs
     ///
-    /// * `report_id` is the report ID.
+    /// * `decryptor` is used to decrypt the input shares.
     ///
-    /// * `encrypted_input_share` is the encrypted input share.
+    /// * `task_id` is the DAP task ID indicated by the reports.
     ///
-    /// * `version` is the DapVersion to use.
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) async fn consume_report_share(
+    /// * `report_shares` is the sequence of report metadata, public shares, and encrypted input
+    /// shares.
+    pub(crate) async fn consume_report_shares(
         &self,
         decrypter: &impl HpkeDecrypter<'_>,
         is_leader: bool,
         task_id: &Id,
         task_config: &DapTaskConfig,
-        metadata: &ReportMetadata,
-        public_share: &[u8],
-        encrypted_input_share: &HpkeCiphertext,
-    ) -> Result<(VdafState, VdafMessage), DapError> {
-        if metadata.time >= task_config.expiration {
-            return Err(DapError::Transition(TransitionFailure::TaskExpired));
-        }
-
-        if !public_share.is_empty() {
-            return Err(DapError::Transition(TransitionFailure::VdafPrepError));
+        report_shares: Vec<(&ReportMetadata, &[u8], HpkeCiphertextRef<'_>)>,
+    ) -> Result<Vec<Result<(VdafState, VdafMessage), DapError>>, DapError> {
+        let vdaf = self.vdaf()?;
+        if task_config.vdaf_verify_key.as_ref().len() != vdaf.verify_key_len() {
+            return Err(DapError::fatal("VDAF verify key does not match config"));
         }
 
         let input_share_text = match task_config.version {
@@ -370,48 +574,87 @@ impl VdafConfig {
             CTX_ROLE_HELPER
         }); // Receiver role
 
-        let mut aad = Vec::with_capacity(58);
-        task_id.encode(&mut aad);
-        metadata.encode_with_param(&task_config.version, &mut aad);
-        // TODO spec: Consider folding the public share into a field called "header".
-        encode_u32_bytes(&mut aad, public_share);
+        // Decrypt the report shares whose receiver config is not available. The rest are decrypted
+        // along with preparation below.
+        let mut receiver_configs = HashMap::new();
+        let mut pending = Vec::with_capacity(report_shares.len());
+        for (metadata, public_share, encrypted_input_share) in report_shares {
+            if metadata.time >= task_config.expiration {
+                pending.push(Err(DapError::Transition(TransitionFailure::TaskExpired)));
+                continue;
+            }
 
-        let encoded_input_share = decrypter
-            .hpke_decrypt(task_id, &info, &aad, encrypted_input_share)
-            .await?;
-        // For Draft02, the encoded input share is the VDAF-specific payload, but for Draft03 and
-        // later it is a serialized PlaintextInputShare.  For simplicity in later code, we wrap the Draft02
-        // payload into a PlaintextInputShare.
-        let input_share = match task_config.version {
-            DapVersion::Draft02 => PlaintextInputShare {
-                extensions: vec![],
-                payload: encoded_input_share,
-            },
-            _ => PlaintextInputShare::get_decoded(&encoded_input_share)?,
-        };
+            let mut aad = Vec::with_capacity(58);
+            task_id.encode(&mut aad);
+            metadata.encode_with_param(&task_config.version, &mut aad);
+            // TODO spec: Consider folding the public share into a field called "header".
+            encode_u32_bytes(&mut aad, public_share);
+
+            let config_id = encrypted_input_share.config_id;
+            let receiver_config = match receiver_configs.entry(config_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    decrypter
+                        .get_hpke_receiver_config_by_id(task_id, config_id)
+                        .await?,
+                ),
+            };
 
-        let agg_id = usize::from(!is_leader);
-        match (self, &task_config.vdaf_verify_key) {
-            (Self::Prio3(ref prio3_config), VdafVerifyKey::Prio3(ref verify_key)) => {
-                Ok(prio3_prepare_init(
-                    prio3_config,
-                    verify_key,
-                    agg_id,
-                    metadata.id.as_ref(),
-                    &input_share.payload,
-                )?)
-            }
-            (Self::Prio2 { dimension }, VdafVerifyKey::Prio2(ref verify_key)) => {
-                Ok(prio2_prepare_init(
-                    *dimension,
-                    verify_key,
-                    agg_id,
-                    metadata.id.as_ref(),
-                    &input_share.payload,
-                )?)
-            }
-            _ => Err(DapError::fatal("VDAF verify key does not match config")),
+            let input_share = if receiver_config.is_some() {
+                EncodedInputShare::Encrypted(aad, encrypted_input_share)
+            } else {
+                match decrypter
+                    .hpke_decrypt(
+                        task_id,
+                        &info,
+                        &aad,
+                        &HpkeCiphertext::from(encrypted_input_share),
+                    )
+                    .await
+                {
+                    Ok(encoded_input_share) => EncodedInputShare::Decrypted(encoded_input_share),
+                    Err(e) => {
+                        pending.push(Err(e));
+                        continue;
+                    }
+                }
+            };
+            pending.push(Ok((metadata, public_share, input_share)));
         }
+
+        let agg_id = usize::from(!is_leader);
+        let vdaf = &*vdaf;
+        let prepare = |pending| -> Result<(VdafState, VdafMessage), DapError> {
+            let (metadata, public_share, input_share): (&ReportMetadata, &[u8], _) = pending?;
+            let encoded_input_share = match input_share {
+                EncodedInputShare::Decrypted(encoded_input_share) => encoded_input_share,
+                EncodedInputShare::Encrypted(aad, ciphertext) => {
+                    let receiver_config = receiver_configs[&ciphertext.config_id].as_ref().unwrap();
+                    receiver_config.decrypt(&info, &aad, ciphertext.enc, ciphertext.payload)?
+                }
+            };
+
+            // For Draft02, the encoded input share is the VDAF-specific payload, but for Draft03
+            // and later it is a serialized PlaintextInputShare. For simplicity in later code, we
+            // wrap the Draft02 payload into a PlaintextInputShare.
+            let input_share = match task_config.version {
+                DapVersion::Draft02 => PlaintextInputShare {
+                    extensions: vec![],
+                    payload: encoded_input_share,
+                },
+                _ => PlaintextInputShare::get_decoded(&encoded_input_share)?,
+            };
+
+            Ok(vdaf.prepare_init(
+                task_config.vdaf_verify_key.as_ref(),
+                agg_id,
+                metadata.id.as_ref(),
+                public_share,
+                &input_share.payload,
+            )?)
+        };
+
+        Ok(map_reports(pending, prepare))
     }
 
     /// Initialize the aggregation flow for a sequence of reports. The outputs are the Leader's
@@ -432,8 +675,11 @@ impl VdafConfig {
     /// * `reports` is the set of reports uploaded by Clients.
     ///
     /// * `version` is the DapVersion to use.
+    //
+    // This method is public only so that it can be benchmarked.
+    #[doc(hidden)]
     #[allow(clippy::too_many_arguments)]
-    pub(crate) async fn produce_agg_init_req(
+    pub async fn produce_agg_init_req(
         &self,
         decrypter: &impl HpkeDecrypter<'_>,
         task_id: &Id,
@@ -444,8 +690,7 @@ impl VdafConfig {
         metrics: &DaphneMetrics,
     ) -> Result<DapLeaderTransition<AggregateInitializeReq>, DapAbort> {
         let mut processed = HashSet::with_capacity(reports.len());
-        let mut states = Vec::with_capacity(reports.len());
-        let mut seq = Vec::with_capacity(reports.len());
+        let mut split = Vec::with_capacity(reports.len());
         for report in reports.into_iter() {
             if processed.contains(&report.metadata.id) {
                 return Err(DapError::fatal(
@@ -465,38 +710,49 @@ impl VdafConfig {
                 let mut it = report.encrypted_input_shares.into_iter();
                 (it.next().unwrap(), it.next().unwrap())
             };
+            split.push((
+                report.metadata,
+                report.public_share,
+                leader_share,
+                helper_share,
+            ));
+        }
 
-            match self
-                .consume_report_share(
-                    decrypter,
-                    true, // is_leader
-                    task_id,
-                    task_config,
-                    &report.metadata,
-                    &report.public_share,
-                    &leader_share,
-                )
-                .await
-            {
+        let results = self
+            .consume_report_shares(
+                decrypter,
+                true, // is_leader
+                task_id,
+                task_config,
+                split
+                    .iter()
+                    .map(|(metadata, public_share, leader_share, _)| {
+                        (metadata, public_share.as_slice(), leader_share.into())
+                    })
+                    .collect(),
+            )
+            .await?;
+
+        let mut states = Vec::with_capacity(split.len());
+        let mut seq = Vec::with_capacity(split.len());
+        for (result, (metadata, public_share, _, helper_share)) in results.into_iter().zip(split) {
+            match result {
                 Ok((step, message)) => {
-                    states.push((
-                        step,
-                        message,
-                        report.metadata.time,
-                        report.metadata.id.clone(),
-                    ));
+                    states.push((step, message, metadata.time, metadata.id.clone()));
                     seq.push(ReportShare {
-                        metadata: report.metadata,
-                        public_share: report.public_share,
+                        metadata,
+                        public_share,
                         encrypted_input_share: helper_share,
                     });
                 }
 
                 // Skip report that can't be processed any further.
-                Err(DapError::Transition(failure)) => metrics
-                    .report_counter
-                    .with_label_values(&[&format!("rejected_{failure}")])
-                    .inc(),
+                Err(DapError::Transition(failure)) => {
+                    if matches!(failure, TransitionFailure::HpkeDecryptError) {
+                        metrics.hpke_decrypt_failure_counter.inc();
+                    }
+                    metrics.report_inc_by(task_id, &format!("rejected_{failure}"), 1);
+                }
 
                 Err(e) => return Err(DapAbort::Internal(Box::new(e))),
             };
@@ -539,40 +795,53 @@ impl VdafConfig {
     /// * `agg_init_req` is the request sent by the Leader.
     ///
     /// * `version` is the DapVersion to use.
-    pub(crate) async fn handle_agg_init_req(
+    //
+    // This method is public only so that it can be benchmarked.
+    #[doc(hidden)]
+    pub async fn handle_agg_init_req(
         &self,
         decrypter: &impl HpkeDecrypter<'_>,
         task_config: &DapTaskConfig,
-        agg_init_req: &AggregateInitializeReq,
+        agg_init_req: &AggregateInitializeReqRef<'_>,
         metrics: &DaphneMetrics,
     ) -> Result<DapHelperTransition<AggregateResp>, DapAbort> {
+        let vdaf = self.vdaf()?;
         let num_reports = agg_init_req.report_shares.len();
         let mut processed = HashSet::with_capacity(num_reports);
-        let mut states = Vec::with_capacity(num_reports);
-        let mut transitions = Vec::with_capacity(num_reports);
+        let mut report_shares = Vec::with_capacity(num_reports);
         for report_share in agg_init_req.report_shares.iter() {
             if processed.contains(&report_share.metadata.id) {
                 return Err(DapAbort::UnrecognizedMessage);
             }
             processed.insert(report_share.metadata.id.clone());
+            report_shares.push(report_share);
+        }
 
-            let var = match self
-                .consume_report_share(
-                    decrypter,
-                    false, // is_leader
-                    &agg_init_req.task_id,
-                    task_config,
-                    &report_share.metadata,
-                    &report_share.public_share,
-                    &report_share.encrypted_input_share,
-                )
-                .await
-            {
+        let results = self
+            .consume_report_shares(
+                decrypter,
+                false, // is_leader
+                &agg_init_req.task_id,
+                task_config,
+                report_shares
+                    .iter()
+                    .map(|report_share| {
+                        (
+                            &report_share.metadata,
+                            report_share.public_share,
+                            report_share.encrypted_input_share,
+                        )
+                    })
+                    .collect(),
+            )
+            .await?;
+
+        let mut states = Vec::with_capacity(num_reports);
+        let mut transitions = Vec::with_capacity(num_reports);
+        for (result, report_share) in results.into_iter().zip(report_shares) {
+            let var = match result {
                 Ok((step, message)) => {
-                    let message_data = match self {
-                        Self::Prio3(..) => prio3_encode_prepare_message(&message),
-                        Self::Prio2 { .. } => prio2_encode_prepare_message(&message),
-                    };
+                    let message_data = vdaf.encode_prepare_message(&message);
                     states.push((
                         step,
                         report_share.metadata.time,
@@ -582,10 +851,10 @@ impl VdafConfig {
                 }
 
                 Err(DapError::Transition(failure)) => {
-                    metrics
-                        .report_counter
-                        .with_label_values(&[&format!("rejected_{failure}")])
-                        .inc();
+                    if matches!(failure, TransitionFailure::HpkeDecryptError) {
+                        metrics.hpke_decrypt_failure_counter.inc();
+                    }
+                    metrics.report_inc_by(&agg_init_req.task_id, &format!("rejected_{failure}"), 1);
                     TransitionVar::Failed(failure)
                 }
 
@@ -603,7 +872,10 @@ impl VdafConfig {
                 part_batch_sel: agg_init_req.part_batch_sel.clone(),
                 seq: states,
             },
-            AggregateResp { transitions },
+            AggregateResp {
+                transitions,
+                extensions: Vec::new(),
+            },
         ))
     }
 
@@ -624,55 +896,38 @@ impl VdafConfig {
         task_id: &Id,
         agg_job_id: &Id,
         state: DapLeaderState,
-        agg_resp: AggregateResp,
+        agg_resp: &AggregateRespRef<'_>,
         metrics: &DaphneMetrics,
     ) -> Result<DapLeaderTransition<AggregateContinueReq>, DapAbort> {
         if agg_resp.transitions.len() != state.seq.len() {
             return Err(DapAbort::UnrecognizedMessage);
         }
 
+        let vdaf = self.vdaf()?;
         let mut seq = Vec::with_capacity(state.seq.len());
         let mut states = Vec::with_capacity(state.seq.len());
         for (helper, (leader_step, leader_message, leader_time, leader_report_id)) in
-            agg_resp.transitions.into_iter().zip(state.seq.into_iter())
+            agg_resp.transitions.iter().zip(state.seq.into_iter())
         {
             // TODO spec: Consider removing the report ID from the AggregateResp.
             if helper.report_id != leader_report_id {
                 return Err(DapAbort::UnrecognizedMessage);
             }
 
-            let helper_message = match &helper.var {
-                TransitionVar::Continued(message) => message,
+            let helper_message = match helper.var {
+                TransitionVarRef::Continued(message) => message,
 
                 // Skip report that can't be processed any further.
-                TransitionVar::Failed(failure) => {
-                    metrics
-                        .report_counter
-                        .with_label_values(&[&format!("rejected_{failure}")])
-                        .inc();
+                TransitionVarRef::Failed(failure) => {
+                    metrics.report_inc_by(task_id, &format!("rejected_{failure}"), 1);
                     continue;
                 }
 
                 // TODO Log the fact that the helper sent an unexpected message.
-                TransitionVar::Finished => return Err(DapAbort::UnrecognizedMessage),
-            };
-
-            let res = match self {
-                Self::Prio3(prio3_config) => prio3_leader_prepare_finish(
-                    prio3_config,
-                    leader_step,
-                    leader_message,
-                    helper_message,
-                ),
-                Self::Prio2 { dimension } => prio2_leader_prepare_finish(
-                    *dimension,
-                    leader_step,
-                    leader_message,
-                    helper_message,
-                ),
+                TransitionVarRef::Finished => return Err(DapAbort::UnrecognizedMessage),
             };
 
-            match res {
+            match vdaf.leader_prepare_finish(leader_step, leader_message, helper_message) {
                 Ok((data, message)) => {
                     let checksum = ring::digest::digest(
                         &ring::digest::SHA256,
@@ -697,10 +952,7 @@ impl VdafConfig {
                 // Skip report that can't be processed any further.
                 Err(VdafError::Codec(..)) | Err(VdafError::Vdaf(..)) => {
                     let failure = TransitionFailure::VdafPrepError;
-                    metrics
-                        .report_counter
-                        .with_label_values(&[&format!("rejected_{failure}")])
-                        .inc();
+                    metrics.report_inc_by(task_id, &format!("rejected_{failure}"), 1);
                 }
             };
         }
@@ -709,12 +961,21 @@ impl VdafConfig {
             return Ok(DapLeaderTransition::Skip);
         }
 
+        // Echo the Helper's state back to it, if it offloaded it to us.
+        let extensions = agg_resp
+            .extensions
+            .iter()
+            .filter(|extension| matches!(extension, Extension::HelperState { .. }))
+            .cloned()
+            .collect();
+
         Ok(DapLeaderTransition::Uncommitted(
             DapLeaderUncommitted { seq: states },
             AggregateContinueReq {
                 task_id: task_id.clone(),
                 agg_job_id: agg_job_id.clone(),
                 transitions: seq,
+                extensions,
             },
         ))
     }
@@ -735,6 +996,7 @@ impl VdafConfig {
         agg_cont_req: &AggregateContinueReq,
         metrics: &DaphneMetrics,
     ) -> Result<DapHelperTransition<AggregateResp>, DapAbort> {
+        let vdaf = self.vdaf()?;
         let mut processed = HashSet::with_capacity(state.seq.len());
         let mut recognized = HashSet::with_capacity(state.seq.len());
         for (_, _, report_id) in state.seq.iter() {
@@ -773,16 +1035,7 @@ impl VdafConfig {
                     _ => return Err(DapAbort::UnrecognizedMessage),
                 };
 
-                let res = match self {
-                    Self::Prio3(prio3_config) => {
-                        prio3_helper_prepare_finish(prio3_config, helper_step, leader_message)
-                    }
-                    Self::Prio2 { dimension } => {
-                        prio2_helper_prepare_finish(*dimension, helper_step, leader_message)
-                    }
-                };
-
-                let var = match res {
+                let var = match vdaf.helper_prepare_finish(helper_step, leader_message) {
                     Ok(data) => {
                         let checksum = ring::digest::digest(
                             &ring::digest::SHA256,
@@ -799,10 +1052,11 @@ impl VdafConfig {
 
                     Err(VdafError::Codec(..)) | Err(VdafError::Vdaf(..)) => {
                         let failure = TransitionFailure::VdafPrepError;
-                        metrics
-                            .report_counter
-                            .with_label_values(&[&format!("rejected_{failure}")])
-                            .inc();
+                        metrics.report_inc_by(
+                            &agg_cont_req.task_id,
+                            &format!("rejected_{failure}"),
+                            1,
+                        );
                         TransitionVar::Failed(failure)
                     }
                 };
@@ -818,7 +1072,10 @@ impl VdafConfig {
 
         Ok(DapHelperTransition::Finish(
             out_shares,
-            AggregateResp { transitions },
+            AggregateResp {
+                transitions,
+                extensions: Vec::new(),
+            },
         ))
     }
 
@@ -837,8 +1094,9 @@ impl VdafConfig {
     /// * `agg_resp` is the previous aggregate response sent by the Helper.
     pub(crate) fn handle_final_agg_resp(
         &self,
+        task_id: &Id,
         uncommitted: DapLeaderUncommitted,
-        agg_resp: AggregateResp,
+        agg_resp: &AggregateRespRef<'_>,
         metrics: &DaphneMetrics,
     ) -> Result<Vec<DapOutputShare>, DapAbort> {
         if agg_resp.transitions.len() != uncommitted.seq.len() {
@@ -846,30 +1104,25 @@ impl VdafConfig {
         }
 
         let mut out_shares = Vec::with_capacity(uncommitted.seq.len());
-        for (helper, (out_share, leader_report_id)) in agg_resp
-            .transitions
-            .into_iter()
-            .zip(uncommitted.seq.into_iter())
+        for (helper, (out_share, leader_report_id)) in
+            agg_resp.transitions.iter().zip(uncommitted.seq.into_iter())
         {
             // TODO spec: Consider removing the report ID from the AggregateResp.
             if helper.report_id != leader_report_id {
                 return Err(DapAbort::UnrecognizedMessage);
             }
 
-            match &helper.var {
+            match helper.var {
                 // TODO Log the fact that the helper sent an unexpected message.
-                TransitionVar::Continued(..) => return Err(DapAbort::UnrecognizedMessage),
+                TransitionVarRef::Continued(..) => return Err(DapAbort::UnrecognizedMessage),
 
                 // Skip report that can't be processed any further.
-                TransitionVar::Failed(failure) => {
-                    metrics
-                        .report_counter
-                        .with_label_values(&[&format!("rejected_{failure}")])
-                        .inc();
+                TransitionVarRef::Failed(failure) => {
+                    metrics.report_inc_by(task_id, &format!("rejected_{failure}"), 1);
                     continue;
                 }
 
-                TransitionVar::Finished => out_shares.push(out_share),
+                TransitionVarRef::Finished => out_shares.push(out_share),
             };
         }
 
@@ -931,8 +1184,11 @@ impl VdafConfig {
     /// Aggregators. The first encrypted aggregate shares must be the Leader's.
     ///
     /// * `version` is the DapVersion to use.
-    //
-    // TODO spec: Allow the collector to have multiple HPKE public keys (the way Aggregators do).
+    ///
+    /// If the Collector has rotated its HPKE key during the collection, the aggregate shares may be
+    /// encrypted under different configurations. In this case, `decrypter` is expected to select
+    /// the key by the config ID of each ciphertext (see the implementation of
+    /// [`HpkeDecrypter`] for `Vec<HpkeReceiverConfig>`).
     pub async fn consume_encrypted_agg_shares(
         &self,
         decrypter: &impl HpkeDecrypter<'_>,
@@ -979,17 +1235,30 @@ impl VdafConfig {
         }
 
         let num_measurements = usize::try_from(report_count).unwrap();
-        match self {
-            Self::Prio3(prio3_config) => {
-                Ok(prio3_unshard(prio3_config, num_measurements, agg_shares)?)
-            }
-            Self::Prio2 { dimension } => {
-                Ok(prio2_unshard(*dimension, num_measurements, agg_shares)?)
-            }
-        }
+        Ok(self.vdaf()?.unshard(num_measurements, agg_shares)?)
     }
 }
 
+/// An input share that is either decrypted or encrypted, along with its AAD, under a receiver
+/// config that is available without I/O.
+enum EncodedInputShare<'a> {
+    Decrypted(Vec<u8>),
+    Encrypted(Vec<u8>, HpkeCiphertextRef<'a>),
+}
+
+/// Apply `f` to each report. The results are returned in the same order as the reports.
+#[cfg(feature = "parallel")]
+fn map_reports<T: Send, U: Send>(reports: Vec<T>, f: impl Fn(T) -> U + Send + Sync) -> Vec<U> {
+    use rayon::prelude::*;
+    reports.into_par_iter().map(f).collect()
+}
+
+/// Apply `f` to each report. The results are returned in the same order as the reports.
+#[cfg(not(feature = "parallel"))]
+fn map_reports<T, U>(reports: Vec<T>, f: impl Fn(T) -> U) -> Vec<U> {
+    reports.into_iter().map(f).collect()
+}
+
 fn produce_encrypted_agg_share(
     is_leader: bool,
     hpke_config: &HpkeConfig,
@@ -1043,3 +1312,6 @@ mod prio2_test;
 pub mod prio3;
 #[cfg(test)]
 mod prio3_test;
+pub mod registry;
+#[cfg(test)]
+mod registry_test;
@@ -6,9 +6,10 @@ use crate::{
     async_test_versions,
     hpke::HpkeReceiverConfig,
     messages::{
-        AggregateContinueReq, AggregateInitializeReq, AggregateResp, BatchSelector, HpkeAeadId,
-        HpkeCiphertext, HpkeConfig, HpkeKdfId, HpkeKemId, Id, Interval, PartialBatchSelector,
-        Report, ReportId, ReportShare, Time, Transition, TransitionFailure, TransitionVar,
+        AggregateContinueReq, AggregateInitializeReq, AggregateInitializeReqRef, AggregateResp,
+        AggregateRespRef, BatchSelector, DecodeRef, HpkeAeadId, HpkeCiphertext, HpkeCiphertextRef,
+        HpkeConfig, HpkeKdfId, HpkeKemId, Id, Interval, PartialBatchSelector, Report, ReportId,
+        ReportShare, Time, Transition, TransitionFailure, TransitionVar,
     },
     metrics::DaphneMetrics,
     test_version, test_versions, DapAbort, DapAggregateResult, DapAggregateShare, DapError,
@@ -19,9 +20,12 @@ use crate::{
 use assert_matches::assert_matches;
 use hpke_rs::HpkePublicKey;
 use paste::paste;
-use prio::vdaf::{
-    prio3::Prio3, Aggregatable, Aggregator as VdafAggregator, Collector as VdafCollector,
-    PrepareTransition,
+use prio::{
+    codec::{Encode, ParameterizedEncode},
+    vdaf::{
+        prio3::Prio3, Aggregatable, Aggregator as VdafAggregator, Collector as VdafCollector,
+        PrepareTransition,
+    },
 };
 use rand::prelude::*;
 use std::{fmt::Debug, time::SystemTime};
@@ -85,29 +89,39 @@ async fn roundtrip_report(version: DapVersion) {
         .unwrap();
 
     let (leader_step, leader_share) = TEST_VDAF
-        .consume_report_share(
+        .consume_report_shares(
             &t.leader_hpke_receiver_config,
             true, // is_leader
             &t.task_id,
             &t.task_config,
-            &report.metadata,
-            &report.public_share,
-            &report.encrypted_input_shares[0],
+            vec![(
+                &report.metadata,
+                &report.public_share,
+                HpkeCiphertextRef::from(&report.encrypted_input_shares[0]),
+            )],
         )
         .await
+        .unwrap()
+        .pop()
+        .unwrap()
         .unwrap();
 
     let (helper_step, helper_share) = TEST_VDAF
-        .consume_report_share(
+        .consume_report_shares(
             &t.helper_hpke_receiver_config,
             false, // is_leader
             &t.task_id,
             &t.task_config,
-            &report.metadata,
-            &report.public_share,
-            &report.encrypted_input_shares[1],
+            vec![(
+                &report.metadata,
+                &report.public_share,
+                HpkeCiphertextRef::from(&report.encrypted_input_shares[1]),
+            )],
         )
         .await
+        .unwrap()
+        .pop()
+        .unwrap()
         .unwrap();
 
     match (leader_step, helper_step, leader_share, helper_share) {
@@ -219,6 +233,7 @@ async fn produce_agg_init_req_skip_hpke_decrypt_err(version: DapVersion) {
 
     assert_metrics_include!(t.prometheus_registry, {
         r#"test_leader_report_counter{status="rejected_hpke_decrypt_error"}"#: 1,
+        r#"test_leader_hpke_decrypt_failure_counter"#: 1,
     });
 }
 
@@ -283,6 +298,7 @@ async fn handle_agg_init_req_hpke_decrypt_err(version: DapVersion) {
 
     assert_metrics_include!(t.prometheus_registry, {
         r#"test_helper_report_counter{status="rejected_hpke_decrypt_error"}"#: 1,
+        r#"test_helper_hpke_decrypt_failure_counter"#: 1,
     });
 }
 
@@ -661,6 +677,34 @@ async fn helper_state_serialization(version: DapVersion) {
 
 async_test_versions! { helper_state_serialization }
 
+// Helper state may be written by one release and read by the next. If this test fails, then a
+// previous encoding can no longer be read.
+#[test]
+fn helper_state_previous_encodings() {
+    // Written before the encoding was versioned.
+    let v0 = hex::decode(
+        "01bc74bbaff4bba269b623e975eb2d4759000000006ad5ba5601f2a966836abea475bd95cc2532214d",
+    )
+    .unwrap();
+    let v1 = hex::decode(
+        "ff0101bc74bbaff4bba269b623e975eb2d4759000000006ad5ba5601f2a966836abea475bd95cc2532214d",
+    )
+    .unwrap();
+
+    let state = DapHelperState::get_decoded(TEST_VDAF, &v0).unwrap();
+    assert_eq!(state.seq.len(), 1);
+    assert_eq!(DapHelperState::get_decoded(TEST_VDAF, &v1).unwrap(), state);
+    assert_eq!(state.get_encoded(TEST_VDAF).unwrap(), v1);
+
+    // Encodings from a later release are rejected.
+    let mut v2 = v1;
+    v2[1] = 2;
+    assert_matches!(
+        DapHelperState::get_decoded(TEST_VDAF, &v2),
+        Err(DapError::Fatal(s)) => assert_eq!(s, "unsupported helper state version 2")
+    );
+}
+
 pub(crate) struct Test {
     now: Time,
     task_id: Id,
@@ -684,7 +728,7 @@ impl Test {
             .as_secs();
         let task_id = Id(rng.gen());
         let agg_job_id = Id(rng.gen());
-        let vdaf_verify_key = vdaf.gen_verify_key();
+        let vdaf_verify_key = vdaf.gen_verify_key().unwrap();
         let leader_hpke_receiver_config =
             HpkeReceiverConfig::gen(rng.gen(), HpkeKemId::X25519HkdfSha256).unwrap();
         let helper_hpke_receiver_config =
@@ -719,6 +763,8 @@ impl Test {
                 vdaf: vdaf.clone(),
                 vdaf_verify_key,
                 collector_hpke_config,
+                retired_collector_hpke_configs: Vec::new(),
+                taskprov: None,
             },
             prometheus_registry,
             leader_metrics,
@@ -848,6 +894,9 @@ impl Test {
         &mut self,
         agg_init_req: AggregateInitializeReq,
     ) -> DapHelperTransition<AggregateResp> {
+        let version = self.task_config.version;
+        let encoded = agg_init_req.get_encoded_with_param(&version);
+        let agg_init_req = AggregateInitializeReqRef::get_decoded_ref(&version, &encoded).unwrap();
         self.task_config
             .vdaf
             .handle_agg_init_req(
@@ -865,13 +914,15 @@ impl Test {
         leader_state: DapLeaderState,
         agg_resp: AggregateResp,
     ) -> DapLeaderTransition<AggregateContinueReq> {
+        let encoded = agg_resp.get_encoded();
+        let agg_resp = AggregateRespRef::get_decoded_ref(&(), &encoded).unwrap();
         self.task_config
             .vdaf
             .handle_agg_resp(
                 &self.task_id,
                 &self.agg_job_id,
                 leader_state,
-                agg_resp,
+                &agg_resp,
                 &self.leader_metrics,
             )
             .unwrap()
@@ -882,13 +933,15 @@ impl Test {
         leader_state: DapLeaderState,
         agg_resp: AggregateResp,
     ) -> DapAbort {
+        let encoded = agg_resp.get_encoded();
+        let agg_resp = AggregateRespRef::get_decoded_ref(&(), &encoded).unwrap();
         self.task_config
             .vdaf
             .handle_agg_resp(
                 &self.task_id,
                 &self.agg_job_id,
                 leader_state,
-                agg_resp,
+                &agg_resp,
                 &self.leader_metrics,
             )
             .expect_err("handle_agg_resp() succeeded; expected failure")
@@ -921,9 +974,16 @@ impl Test {
         leader_uncommitted: DapLeaderUncommitted,
         agg_resp: AggregateResp,
     ) -> Vec<DapOutputShare> {
+        let encoded = agg_resp.get_encoded();
+        let agg_resp = AggregateRespRef::get_decoded_ref(&(), &encoded).unwrap();
         self.task_config
             .vdaf
-            .handle_final_agg_resp(leader_uncommitted, agg_resp, &self.leader_metrics)
+            .handle_final_agg_resp(
+                &self.task_id,
+                leader_uncommitted,
+                &agg_resp,
+                &self.leader_metrics,
+            )
             .unwrap()
     }
 
@@ -5,7 +5,8 @@
 //! [VDAF](https://datatracker.ietf.org/doc/draft-patton-cfrg-vdaf/).
 
 use crate::{
-    vdaf::VdafError, DapAggregateResult, DapMeasurement, VdafAggregateShare, VdafMessage, VdafState,
+    vdaf::{DapVdaf, VdafError},
+    DapAggregateResult, DapMeasurement, VdafAggregateShare, VdafMessage, VdafState,
 };
 use prio::{
     codec::{CodecError, Decode, Encode, ParameterizedDecode},
@@ -19,13 +20,12 @@ use std::{convert::TryFrom, io::Cursor};
 
 /// Split the given measurement into a sequence of encoded input shares.
 pub(crate) fn prio2_shard(
-    dimension: u32,
+    vdaf: &Prio2,
     measurement: DapMeasurement,
 ) -> Result<Vec<Vec<u8>>, VdafError> {
-    let vdaf = Prio2::new(dimension as usize)?;
     let (_public_share, input_shares) = match measurement {
         DapMeasurement::U32Vec(ref data) => vdaf.shard(data)?,
-        _ => panic!("prio2_shard: unexpected measurement type"),
+        _ => return Err(VdafError::unexpected_type("measurement")),
     };
 
     Ok(input_shares
@@ -36,15 +36,14 @@ pub(crate) fn prio2_shard(
 
 /// Consume an input share and return the corresponding VDAF step and message.
 pub(crate) fn prio2_prepare_init(
-    dimension: u32,
+    vdaf: &Prio2,
     verify_key: &[u8; 32],
     agg_id: usize,
     nonce_data: &[u8],
     input_share_data: &[u8],
 ) -> Result<(VdafState, VdafMessage), VdafError> {
-    let vdaf = Prio2::new(dimension as usize)?;
     let input_share: Share<FieldPrio2, 32> =
-        Share::get_decoded_with_param(&(&vdaf, agg_id), input_share_data)?;
+        Share::get_decoded_with_param(&(vdaf, agg_id), input_share_data)?;
     let (state, share) =
         vdaf.prepare_init(verify_key, agg_id, &(), nonce_data, &(), &input_share)?;
     Ok((VdafState::Prio2(state), VdafMessage::Prio2Share(share)))
@@ -52,12 +51,11 @@ pub(crate) fn prio2_prepare_init(
 
 /// Consume the verifier shares and return the output share and serialized outbound message.
 pub(crate) fn prio2_leader_prepare_finish(
-    dimension: u32,
+    vdaf: &Prio2,
     leader_state: VdafState,
     leader_share: VdafMessage,
     helper_share_data: &[u8],
 ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
-    let vdaf = Prio2::new(dimension as usize)?;
     let (out_share, outbound) = match (leader_state, leader_share) {
         (VdafState::Prio2(state), VdafMessage::Prio2Share(share)) => {
             let helper_share =
@@ -78,11 +76,10 @@ pub(crate) fn prio2_leader_prepare_finish(
 
 /// Consume the peer's prepare message and return an output share.
 pub(crate) fn prio2_helper_prepare_finish(
-    dimension: u32,
+    vdaf: &Prio2,
     helper_state: VdafState,
     leader_message_data: &[u8],
 ) -> Result<VdafAggregateShare, VdafError> {
-    let vdaf = Prio2::new(dimension as usize)?;
     <()>::get_decoded(leader_message_data)?;
     let out_share = match helper_state {
         VdafState::Prio2(state) => match vdaf.prepare_step(state, ())? {
@@ -99,13 +96,12 @@ pub(crate) fn prio2_helper_prepare_finish(
 
 /// Parse a prio2 prepare message from the front of `reader` whose type is compatible with `param`.
 pub(crate) fn prio2_decode_prepare_state(
-    dimension: u32,
+    vdaf: &Prio2,
     agg_id: usize,
     bytes: &mut Cursor<&[u8]>,
 ) -> Result<VdafState, VdafError> {
-    let vdaf = Prio2::new(dimension as usize)?;
     Ok(VdafState::Prio2(Prio2PrepareState::decode_with_param(
-        &(&vdaf, agg_id),
+        &(vdaf, agg_id),
         bytes,
     )?))
 }
@@ -120,11 +116,10 @@ pub(crate) fn prio2_encode_prepare_message(message: &VdafMessage) -> Vec<u8> {
 
 /// Interpret `encoded_agg_shares` as a sequence of encoded aggregate shares and unshard them.
 pub(crate) fn prio2_unshard<M: IntoIterator<Item = Vec<u8>>>(
-    dimension: u32,
+    vdaf: &Prio2,
     num_measurements: usize,
     encoded_agg_shares: M,
 ) -> Result<DapAggregateResult, VdafError> {
-    let vdaf = Prio2::new(dimension as usize)?;
     let mut agg_shares = Vec::with_capacity(vdaf.num_aggregators());
     for encoded in encoded_agg_shares.into_iter() {
         let agg_share = AggregateShare::try_from(encoded.as_ref())
@@ -134,3 +129,83 @@ pub(crate) fn prio2_unshard<M: IntoIterator<Item = Vec<u8>>>(
     let agg_res = vdaf.unshard(&(), agg_shares, num_measurements)?;
     Ok(DapAggregateResult::U32Vec(agg_res))
 }
+
+/// An instance of Prio2. The Aggregators construct it once for each aggregation job and reuse it
+/// for every report.
+pub(crate) struct Prio2Vdaf(pub(crate) Prio2);
+
+impl DapVdaf for Prio2Vdaf {
+    fn verify_key_len(&self) -> usize {
+        32
+    }
+
+    fn shard(&self, measurement: DapMeasurement) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        Ok((Vec::new(), prio2_shard(&self.0, measurement)?))
+    }
+
+    fn prepare_init(
+        &self,
+        verify_key: &[u8],
+        agg_id: usize,
+        nonce: &[u8],
+        public_share: &[u8],
+        input_share: &[u8],
+    ) -> Result<(VdafState, VdafMessage), VdafError> {
+        if !public_share.is_empty() {
+            return Err(CodecError::UnexpectedValue.into());
+        }
+        let verify_key = verify_key
+            .try_into()
+            .map_err(|e| CodecError::Other(Box::new(e)))?;
+        prio2_prepare_init(&self.0, verify_key, agg_id, nonce, input_share)
+    }
+
+    fn encode_prepare_message(&self, message: &VdafMessage) -> Vec<u8> {
+        prio2_encode_prepare_message(message)
+    }
+
+    fn leader_prepare_finish(
+        &self,
+        state: VdafState,
+        share: VdafMessage,
+        helper_share: &[u8],
+    ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
+        prio2_leader_prepare_finish(&self.0, state, share, helper_share)
+    }
+
+    fn helper_prepare_finish(
+        &self,
+        state: VdafState,
+        leader_message: &[u8],
+    ) -> Result<VdafAggregateShare, VdafError> {
+        prio2_helper_prepare_finish(&self.0, state, leader_message)
+    }
+
+    fn encode_prepare_state(
+        &self,
+        state: &VdafState,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), VdafError> {
+        match state {
+            VdafState::Prio2(state) => state.encode(bytes),
+            _ => return Err(VdafError::unexpected_type("prepare state")),
+        }
+        Ok(())
+    }
+
+    fn decode_prepare_state(
+        &self,
+        agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafState, VdafError> {
+        prio2_decode_prepare_state(&self.0, agg_id, bytes)
+    }
+
+    fn unshard(
+        &self,
+        num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        prio2_unshard(&self.0, num_measurements, agg_shares)
+    }
+}
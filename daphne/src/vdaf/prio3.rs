@@ -4,14 +4,15 @@
 //! Parameters for the [Prio3 VDAF](https://datatracker.ietf.org/doc/draft-patton-cfrg-vdaf/).
 
 use crate::{
-    vdaf::VdafError, DapAggregateResult, DapMeasurement, Prio3Config, VdafAggregateShare,
-    VdafMessage, VdafState,
+    vdaf::{DapVdaf, VdafError},
+    DapAggregateResult, DapMeasurement, Prio3Config, VdafAggregateShare, VdafMessage, VdafState,
 };
 use prio::{
     codec::{CodecError, Encode, ParameterizedDecode},
     vdaf::{
         prio3::{
-            Prio3, Prio3InputShare, Prio3PrepareMessage, Prio3PrepareShare, Prio3PrepareState,
+            Prio3, Prio3Aes128Count, Prio3Aes128Histogram, Prio3Aes128Sum, Prio3InputShare,
+            Prio3PrepareMessage, Prio3PrepareShare, Prio3PrepareState,
         },
         AggregateShare, Aggregator, Client, Collector, PrepareTransition, Vdaf,
     },
@@ -21,6 +22,27 @@ use std::{convert::TryFrom, io::Cursor};
 const ERR_EXPECT_FINISH: &str = "unexpected transition (continued)";
 const ERR_FIELD_TYPE: &str = "unexpected field type for step or message";
 
+/// An instance of Prio3 for a [`Prio3Config`]. The Aggregators construct it once for each
+/// aggregation job and reuse it for every report.
+#[derive(Clone, Debug)]
+pub(crate) enum Prio3Vdaf {
+    Count(Prio3Aes128Count),
+    Histogram(Prio3Aes128Histogram),
+    Sum(Prio3Aes128Sum),
+}
+
+impl Prio3Vdaf {
+    pub(crate) fn new(config: &Prio3Config) -> Result<Self, VdafError> {
+        Ok(match config {
+            Prio3Config::Count => Self::Count(Prio3::new_aes128_count(2)?),
+            Prio3Config::Histogram { buckets } => {
+                Self::Histogram(Prio3::new_aes128_histogram(2, buckets)?)
+            }
+            Prio3Config::Sum { bits } => Self::Sum(Prio3::new_aes128_sum(2, *bits)?),
+        })
+    }
+}
+
 macro_rules! shard {
     (
         $vdaf:ident,
@@ -39,23 +61,20 @@ macro_rules! shard {
 
 /// Split the given measurement into a sequence of encoded input shares.
 pub(crate) fn prio3_shard(
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     measurement: DapMeasurement,
 ) -> Result<Vec<Vec<u8>>, VdafError> {
-    match (&config, measurement) {
-        (Prio3Config::Count, DapMeasurement::U64(measurement)) => {
-            let vdaf = Prio3::new_aes128_count(2)?;
+    match (vdaf, measurement) {
+        (Prio3Vdaf::Count(vdaf), DapMeasurement::U64(measurement)) => {
             Ok(shard!(vdaf, &measurement))
         }
-        (Prio3Config::Histogram { buckets }, DapMeasurement::U64(measurement)) => {
-            let vdaf = Prio3::new_aes128_histogram(2, buckets)?;
+        (Prio3Vdaf::Histogram(vdaf), DapMeasurement::U64(measurement)) => {
             Ok(shard!(vdaf, &(measurement as u128)))
         }
-        (Prio3Config::Sum { bits }, DapMeasurement::U64(measurement)) => {
-            let vdaf = Prio3::new_aes128_sum(2, *bits)?;
+        (Prio3Vdaf::Sum(vdaf), DapMeasurement::U64(measurement)) => {
             Ok(shard!(vdaf, &(measurement as u128)))
         }
-        _ => panic!("prio3_shard: unexpected VDAF config"),
+        _ => Err(VdafError::unexpected_type("measurement")),
     }
 }
 
@@ -69,7 +88,7 @@ macro_rules! prep_init {
     ) => {{
         // Parse the input share.
         let input_share =
-            Prio3InputShare::get_decoded_with_param(&(&$vdaf, $agg_id), $input_share_data)?;
+            Prio3InputShare::get_decoded_with_param(&($vdaf, $agg_id), $input_share_data)?;
 
         // Run the prepare-init algorithm, returning the initial state.
         $vdaf.prepare_init($verify_key, $agg_id, &(), $nonce_data, &(), &input_share)?
@@ -78,31 +97,28 @@ macro_rules! prep_init {
 
 /// Consume an input share and return the corresponding VDAF step and message.
 pub(crate) fn prio3_prepare_init(
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     verify_key: &[u8; 16],
     agg_id: usize,
     nonce_data: &[u8],
     input_share_data: &[u8],
 ) -> Result<(VdafState, VdafMessage), VdafError> {
-    match &config {
-        Prio3Config::Count => {
-            let vdaf = Prio3::new_aes128_count(2)?;
+    match vdaf {
+        Prio3Vdaf::Count(vdaf) => {
             let (state, share) = prep_init!(vdaf, verify_key, agg_id, nonce_data, input_share_data);
             Ok((
                 VdafState::Prio3Field64(state),
                 VdafMessage::Prio3ShareField64(share),
             ))
         }
-        Prio3Config::Histogram { buckets } => {
-            let vdaf = Prio3::new_aes128_histogram(2, buckets)?;
+        Prio3Vdaf::Histogram(vdaf) => {
             let (state, share) = prep_init!(vdaf, verify_key, agg_id, nonce_data, input_share_data);
             Ok((
                 VdafState::Prio3Field128(state),
                 VdafMessage::Prio3ShareField128(share),
             ))
         }
-        Prio3Config::Sum { bits } => {
-            let vdaf = Prio3::new_aes128_sum(2, *bits)?;
+        Prio3Vdaf::Sum(vdaf) => {
             let (state, share) = prep_init!(vdaf, verify_key, agg_id, nonce_data, input_share_data);
             Ok((
                 VdafState::Prio3Field128(state),
@@ -139,38 +155,35 @@ macro_rules! leader_prep_fin {
 
 /// Consume the verifier shares and return the output share and serialized outbound message.
 pub(crate) fn prio3_leader_prepare_finish(
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     leader_state: VdafState,
     leader_share: VdafMessage,
     helper_share_data: &[u8],
 ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
-    let (agg_share, outbound) = match (&config, leader_state, leader_share) {
+    let (agg_share, outbound) = match (vdaf, leader_state, leader_share) {
         (
-            Prio3Config::Count,
+            Prio3Vdaf::Count(vdaf),
             VdafState::Prio3Field64(state),
             VdafMessage::Prio3ShareField64(share),
         ) => {
-            let vdaf = Prio3::new_aes128_count(2)?;
             let (out_share, outbound) = leader_prep_fin!(vdaf, state, share, helper_share_data);
             let agg_share = VdafAggregateShare::Field64(vdaf.aggregate(&(), [out_share])?);
             (agg_share, outbound)
         }
         (
-            Prio3Config::Histogram { buckets },
+            Prio3Vdaf::Histogram(vdaf),
             VdafState::Prio3Field128(state),
             VdafMessage::Prio3ShareField128(share),
         ) => {
-            let vdaf = Prio3::new_aes128_histogram(2, buckets)?;
             let (out_share, outbound) = leader_prep_fin!(vdaf, state, share, helper_share_data);
             let agg_share = VdafAggregateShare::Field128(vdaf.aggregate(&(), [out_share])?);
             (agg_share, outbound)
         }
         (
-            Prio3Config::Sum { bits },
+            Prio3Vdaf::Sum(vdaf),
             VdafState::Prio3Field128(state),
             VdafMessage::Prio3ShareField128(share),
         ) => {
-            let vdaf = Prio3::new_aes128_sum(2, *bits)?;
             let (out_share, outbound) = leader_prep_fin!(vdaf, state, share, helper_share_data);
             let agg_share = VdafAggregateShare::Field128(vdaf.aggregate(&(), [out_share])?);
             (agg_share, outbound)
@@ -204,23 +217,20 @@ macro_rules! helper_prep_fin {
 
 /// Consume the peer's prepare message and return an output share.
 pub(crate) fn prio3_helper_prepare_finish(
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     state: VdafState,
     peer_message_data: &[u8],
 ) -> Result<VdafAggregateShare, VdafError> {
-    let agg_share = match (&config, state) {
-        (Prio3Config::Count, VdafState::Prio3Field64(state)) => {
-            let vdaf = Prio3::new_aes128_count(2)?;
+    let agg_share = match (vdaf, state) {
+        (Prio3Vdaf::Count(vdaf), VdafState::Prio3Field64(state)) => {
             let out_share = helper_prep_fin!(vdaf, state, peer_message_data);
             VdafAggregateShare::Field64(vdaf.aggregate(&(), [out_share])?)
         }
-        (Prio3Config::Histogram { buckets }, VdafState::Prio3Field128(state)) => {
-            let vdaf = Prio3::new_aes128_histogram(2, buckets)?;
+        (Prio3Vdaf::Histogram(vdaf), VdafState::Prio3Field128(state)) => {
             let out_share = helper_prep_fin!(vdaf, state, peer_message_data);
             VdafAggregateShare::Field128(vdaf.aggregate(&(), [out_share])?)
         }
-        (Prio3Config::Sum { bits }, VdafState::Prio3Field128(state)) => {
-            let vdaf = Prio3::new_aes128_sum(2, *bits)?;
+        (Prio3Vdaf::Sum(vdaf), VdafState::Prio3Field128(state)) => {
             let out_share = helper_prep_fin!(vdaf, state, peer_message_data);
             VdafAggregateShare::Field128(vdaf.aggregate(&(), [out_share])?)
         }
@@ -234,47 +244,38 @@ pub(crate) fn prio3_helper_prepare_finish(
 /// the `step` is not compatible with `param`.
 pub(crate) fn prio3_append_prepare_state(
     bytes: &mut Vec<u8>,
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     state: &VdafState,
 ) -> Result<(), VdafError> {
-    match (&config, state) {
-        (Prio3Config::Count, VdafState::Prio3Field64(state)) => {
+    match (vdaf, state) {
+        (Prio3Vdaf::Count(_), VdafState::Prio3Field64(state)) => {
             state.encode(bytes);
         }
-        (Prio3Config::Histogram { buckets: _ }, VdafState::Prio3Field128(state))
-        | (Prio3Config::Sum { bits: _ }, VdafState::Prio3Field128(state)) => {
+        (Prio3Vdaf::Histogram(_), VdafState::Prio3Field128(state))
+        | (Prio3Vdaf::Sum(_), VdafState::Prio3Field128(state)) => {
             state.encode(bytes);
         }
-        _ => panic!("prio3_append_prepare_state: {ERR_FIELD_TYPE}"),
+        _ => return Err(VdafError::unexpected_type("prepare state")),
     }
     Ok(())
 }
 
 /// Parse a prio3 prepare message from the front of `reader` whose type is compatible with `param`.
 pub(crate) fn prio3_decode_prepare_state(
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     agg_id: usize,
     bytes: &mut Cursor<&[u8]>,
 ) -> Result<VdafState, VdafError> {
-    match config {
-        Prio3Config::Count => {
-            let vdaf = Prio3::new_aes128_count(2)?;
-            Ok(VdafState::Prio3Field64(
-                Prio3PrepareState::decode_with_param(&(&vdaf, agg_id), bytes)?,
-            ))
-        }
-        Prio3Config::Histogram { buckets } => {
-            let vdaf = Prio3::new_aes128_histogram(2, buckets)?;
-            Ok(VdafState::Prio3Field128(
-                Prio3PrepareState::decode_with_param(&(&vdaf, agg_id), bytes)?,
-            ))
-        }
-        Prio3Config::Sum { bits } => {
-            let vdaf = Prio3::new_aes128_sum(2, *bits)?;
-            Ok(VdafState::Prio3Field128(
-                Prio3PrepareState::decode_with_param(&(&vdaf, agg_id), bytes)?,
-            ))
-        }
+    match vdaf {
+        Prio3Vdaf::Count(vdaf) => Ok(VdafState::Prio3Field64(
+            Prio3PrepareState::decode_with_param(&(vdaf, agg_id), bytes)?,
+        )),
+        Prio3Vdaf::Histogram(vdaf) => Ok(VdafState::Prio3Field128(
+            Prio3PrepareState::decode_with_param(&(vdaf, agg_id), bytes)?,
+        )),
+        Prio3Vdaf::Sum(vdaf) => Ok(VdafState::Prio3Field128(
+            Prio3PrepareState::decode_with_param(&(vdaf, agg_id), bytes)?,
+        )),
     }
 }
 
@@ -305,25 +306,94 @@ macro_rules! unshard {
 
 /// Interpret `agg_shares` as a sequence of encoded aggregate shares and unshard them.
 pub(crate) fn prio3_unshard<M: IntoIterator<Item = Vec<u8>>>(
-    config: &Prio3Config,
+    vdaf: &Prio3Vdaf,
     num_measurements: usize,
     agg_shares: M,
 ) -> Result<DapAggregateResult, VdafError> {
-    match &config {
-        Prio3Config::Count => {
-            let vdaf = Prio3::new_aes128_count(2)?;
+    match vdaf {
+        Prio3Vdaf::Count(vdaf) => {
             let agg_res = unshard!(vdaf, num_measurements, agg_shares)?;
             Ok(DapAggregateResult::U64(agg_res))
         }
-        Prio3Config::Histogram { buckets } => {
-            let vdaf = Prio3::new_aes128_histogram(2, buckets)?;
+        Prio3Vdaf::Histogram(vdaf) => {
             let agg_res = unshard!(vdaf, num_measurements, agg_shares)?;
             Ok(DapAggregateResult::U128Vec(agg_res))
         }
-        Prio3Config::Sum { bits } => {
-            let vdaf = Prio3::new_aes128_sum(2, *bits)?;
+        Prio3Vdaf::Sum(vdaf) => {
             let agg_res = unshard!(vdaf, num_measurements, agg_shares)?;
             Ok(DapAggregateResult::U128(agg_res))
         }
     }
 }
+
+impl DapVdaf for Prio3Vdaf {
+    fn verify_key_len(&self) -> usize {
+        16
+    }
+
+    fn shard(&self, measurement: DapMeasurement) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        Ok((Vec::new(), prio3_shard(self, measurement)?))
+    }
+
+    fn prepare_init(
+        &self,
+        verify_key: &[u8],
+        agg_id: usize,
+        nonce: &[u8],
+        public_share: &[u8],
+        input_share: &[u8],
+    ) -> Result<(VdafState, VdafMessage), VdafError> {
+        if !public_share.is_empty() {
+            return Err(CodecError::UnexpectedValue.into());
+        }
+        let verify_key = verify_key
+            .try_into()
+            .map_err(|e| CodecError::Other(Box::new(e)))?;
+        prio3_prepare_init(self, verify_key, agg_id, nonce, input_share)
+    }
+
+    fn encode_prepare_message(&self, message: &VdafMessage) -> Vec<u8> {
+        prio3_encode_prepare_message(message)
+    }
+
+    fn leader_prepare_finish(
+        &self,
+        state: VdafState,
+        share: VdafMessage,
+        helper_share: &[u8],
+    ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
+        prio3_leader_prepare_finish(self, state, share, helper_share)
+    }
+
+    fn helper_prepare_finish(
+        &self,
+        state: VdafState,
+        leader_message: &[u8],
+    ) -> Result<VdafAggregateShare, VdafError> {
+        prio3_helper_prepare_finish(self, state, leader_message)
+    }
+
+    fn encode_prepare_state(
+        &self,
+        state: &VdafState,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), VdafError> {
+        prio3_append_prepare_state(bytes, self, state)
+    }
+
+    fn decode_prepare_state(
+        &self,
+        agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafState, VdafError> {
+        prio3_decode_prepare_state(self, agg_id, bytes)
+    }
+
+    fn unshard(
+        &self,
+        num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        prio3_unshard(self, num_measurements, agg_shares)
+    }
+}
@@ -5,7 +5,7 @@ use crate::{
     vdaf::{
         prio3::{
             prio3_encode_prepare_message, prio3_helper_prepare_finish, prio3_leader_prepare_finish,
-            prio3_prepare_init, prio3_shard, prio3_unshard,
+            prio3_prepare_init, prio3_shard, prio3_unshard, Prio3Vdaf,
         },
         VdafError,
     },
@@ -54,28 +54,29 @@ fn test_prepare(
     let mut rng = thread_rng();
     let verify_key = rng.gen();
     let nonce = b"this is a good nonce";
+    let vdaf = &Prio3Vdaf::new(config)?;
 
     // Shard
-    let encoded_input_shares = prio3_shard(config, measurement).unwrap();
+    let encoded_input_shares = prio3_shard(vdaf, measurement).unwrap();
     assert_eq!(encoded_input_shares.len(), 2);
 
     // Prepare
     let (leader_state, leader_share) =
-        prio3_prepare_init(config, &verify_key, 0, nonce, &encoded_input_shares[0])?;
+        prio3_prepare_init(vdaf, &verify_key, 0, nonce, &encoded_input_shares[0])?;
 
     let (helper_state, helper_share) =
-        prio3_prepare_init(config, &verify_key, 1, nonce, &encoded_input_shares[1])?;
+        prio3_prepare_init(vdaf, &verify_key, 1, nonce, &encoded_input_shares[1])?;
 
     let helper_share_data = prio3_encode_prepare_message(&helper_share);
 
     let (leader_out_share, leader_message_data) =
-        prio3_leader_prepare_finish(config, leader_state, leader_share, &helper_share_data)?;
+        prio3_leader_prepare_finish(vdaf, leader_state, leader_share, &helper_share_data)?;
 
-    let helper_out_share = prio3_helper_prepare_finish(config, helper_state, &leader_message_data)?;
+    let helper_out_share = prio3_helper_prepare_finish(vdaf, helper_state, &leader_message_data)?;
 
     // Unshard
     let agg_res = prio3_unshard(
-        config,
+        vdaf,
         1,
         [
             leader_out_share.get_encoded(),
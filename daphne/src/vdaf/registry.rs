@@ -0,0 +1,103 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Registry of VDAFs implemented outside of this crate. A VDAF registered here can be used by a
+//! task by configuring it with [`VdafConfig::Registered`](crate::VdafConfig::Registered), or, if
+//! it is registered with a VDAF type code, by provisioning the task via taskprov.
+
+use crate::{
+    messages::taskprov::{
+        VDAF_TYPE_POPLAR1_AES128, VDAF_TYPE_PRIO3_AES128_COUNT, VDAF_TYPE_PRIO3_AES128_HISTOGRAM,
+        VDAF_TYPE_PRIO3_AES128_SUM,
+    },
+    vdaf::DapVdaf,
+    DapError,
+};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+struct RegisteredVdaf {
+    vdaf: Arc<dyn DapVdaf>,
+    taskprov_code: Option<u32>,
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<String, RegisteredVdaf>> = RwLock::new(HashMap::new());
+}
+
+/// Register `vdaf` under `name`. If `taskprov_code` is set, then tasks provisioned via taskprov
+/// with this VDAF type code use this VDAF. A registered VDAF has no type-specific parameters in
+/// the taskprov encoding of its type; anything that varies between tasks must be registered under
+/// a different name and code.
+///
+/// Returns an error if the name or VDAF type code is already taken. A VDAF cannot be unregistered,
+/// as tasks that use it may be stored anywhere.
+pub fn register_vdaf(
+    name: impl Into<String>,
+    taskprov_code: Option<u32>,
+    vdaf: Arc<dyn DapVdaf>,
+) -> Result<(), DapError> {
+    let name = name.into();
+    let mut registry = REGISTRY
+        .write()
+        .map_err(|_| DapError::fatal("VDAF registry is poisoned"))?;
+    if registry.contains_key(&name) {
+        return Err(DapError::Fatal(format!(
+            "VDAF {name} is already registered"
+        )));
+    }
+
+    if let Some(code) = taskprov_code {
+        let builtin = [
+            VDAF_TYPE_PRIO3_AES128_COUNT,
+            VDAF_TYPE_PRIO3_AES128_SUM,
+            VDAF_TYPE_PRIO3_AES128_HISTOGRAM,
+            VDAF_TYPE_POPLAR1_AES128,
+        ];
+        if builtin.contains(&code)
+            || registry
+                .values()
+                .any(|registered| registered.taskprov_code == Some(code))
+        {
+            return Err(DapError::Fatal(format!(
+                "VDAF type code {code:#010x} is already taken"
+            )));
+        }
+    }
+
+    registry.insert(
+        name,
+        RegisteredVdaf {
+            vdaf,
+            taskprov_code,
+        },
+    );
+    Ok(())
+}
+
+/// Look up the VDAF registered under `name`.
+pub(crate) fn get_registered_vdaf(name: &str) -> Option<Arc<dyn DapVdaf>> {
+    let registry = REGISTRY.read().ok()?;
+    registry.get(name).map(|registered| registered.vdaf.clone())
+}
+
+/// Look up the VDAF registered under `name`, failing if there is none.
+pub(crate) fn try_get_registered_vdaf(name: &str) -> Result<Arc<dyn DapVdaf>, DapError> {
+    get_registered_vdaf(name)
+        .ok_or_else(|| DapError::Fatal(format!("VDAF {name} is not registered")))
+}
+
+/// Look up the name and implementation of the VDAF registered with the given taskprov VDAF type
+/// code.
+pub(crate) fn get_registered_vdaf_by_taskprov_code(
+    code: u32,
+) -> Option<(String, Arc<dyn DapVdaf>)> {
+    let registry = REGISTRY.read().ok()?;
+    registry
+        .iter()
+        .find(|(_name, registered)| registered.taskprov_code == Some(code))
+        .map(|(name, registered)| (name.clone(), registered.vdaf.clone()))
+}
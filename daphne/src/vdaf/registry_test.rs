@@ -0,0 +1,415 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::{
+    async_test_version, async_test_versions,
+    messages::{
+        taskprov::{VdafType, VdafTypeVar},
+        Id,
+    },
+    taskprov::{compute_vdaf_verify_key, TaskprovVersion},
+    vdaf::{
+        mod_test::Test,
+        registry::{get_registered_vdaf_by_taskprov_code, register_vdaf},
+        DapVdaf, VdafAggregateShare, VdafError, VdafMessage, VdafState,
+    },
+    DapAbort, DapAggregateResult, DapAggregateShare, DapError, DapMeasurement, DapVersion,
+    Prio3Config, VdafConfig,
+};
+use assert_matches::assert_matches;
+use paste::paste;
+use prio::{
+    codec::{CodecError, Decode, Encode},
+    field::Field64,
+    vdaf::AggregateShare,
+};
+use rand::prelude::*;
+use std::{
+    io::{Cursor, Read},
+    str::FromStr,
+    sync::Arc,
+    sync::Once,
+};
+
+const TOY_SUM: &str = "toy_sum";
+const TOY_SUM_TASKPROV_CODE: u32 = 0xffff0000;
+
+/// The sum of 64-bit integers, secret shared without any proof of validity.
+struct ToySum;
+
+impl ToySum {
+    fn decode_state(state: VdafState) -> Result<Field64, VdafError> {
+        match state {
+            VdafState::Registered(bytes) => Ok(Field64::get_decoded(&bytes)?),
+            _ => panic!("unexpected state type"),
+        }
+    }
+}
+
+impl DapVdaf for ToySum {
+    fn verify_key_len(&self) -> usize {
+        8
+    }
+
+    fn shard(&self, measurement: DapMeasurement) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        let measurement = match measurement {
+            DapMeasurement::U64(measurement) => Field64::from(measurement),
+            _ => panic!("unexpected measurement type"),
+        };
+        let leader_share = Field64::from(u64::from(thread_rng().gen::<u32>()));
+        let helper_share = measurement - leader_share;
+        Ok((
+            Vec::new(),
+            vec![leader_share.get_encoded(), helper_share.get_encoded()],
+        ))
+    }
+
+    fn prepare_init(
+        &self,
+        verify_key: &[u8],
+        _agg_id: usize,
+        _nonce: &[u8],
+        public_share: &[u8],
+        input_share: &[u8],
+    ) -> Result<(VdafState, VdafMessage), VdafError> {
+        if verify_key.len() != self.verify_key_len() || !public_share.is_empty() {
+            return Err(CodecError::UnexpectedValue.into());
+        }
+        let input_share = Field64::get_decoded(input_share)?;
+        Ok((
+            VdafState::Registered(input_share.get_encoded()),
+            VdafMessage::Registered(Vec::new()),
+        ))
+    }
+
+    fn encode_prepare_message(&self, message: &VdafMessage) -> Vec<u8> {
+        match message {
+            VdafMessage::Registered(bytes) => bytes.clone(),
+            _ => panic!("unexpected message type"),
+        }
+    }
+
+    fn leader_prepare_finish(
+        &self,
+        state: VdafState,
+        _share: VdafMessage,
+        _helper_share: &[u8],
+    ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
+        let out_share = Self::decode_state(state)?;
+        Ok((
+            VdafAggregateShare::Field64(AggregateShare::from(vec![out_share])),
+            Vec::new(),
+        ))
+    }
+
+    fn helper_prepare_finish(
+        &self,
+        state: VdafState,
+        _leader_message: &[u8],
+    ) -> Result<VdafAggregateShare, VdafError> {
+        let out_share = Self::decode_state(state)?;
+        Ok(VdafAggregateShare::Field64(AggregateShare::from(vec![
+            out_share,
+        ])))
+    }
+
+    fn encode_prepare_state(
+        &self,
+        state: &VdafState,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), VdafError> {
+        match state {
+            VdafState::Registered(state) => bytes.extend_from_slice(state),
+            _ => panic!("unexpected state type"),
+        }
+        Ok(())
+    }
+
+    fn decode_prepare_state(
+        &self,
+        _agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafState, VdafError> {
+        Ok(VdafState::Registered(Field64::decode(bytes)?.get_encoded()))
+    }
+
+    fn unshard(
+        &self,
+        _num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        let mut agg_res = Field64::from(0);
+        for agg_share in agg_shares {
+            agg_res += Field64::get_decoded(&agg_share)?;
+        }
+        Ok(DapAggregateResult::U64(u64::from(agg_res)))
+    }
+}
+
+const TOY_XOR: &str = "toy_xor";
+
+/// The XOR of 8-byte strings, secret shared without any proof of validity. Unlike [`ToySum`], its
+/// measurements, aggregate shares, and aggregate result have an encoding of its own.
+struct ToyXor;
+
+impl ToyXor {
+    fn xor(left: &[u8], right: &[u8]) -> Result<Vec<u8>, VdafError> {
+        if left.len() != 8 || right.len() != 8 {
+            return Err(CodecError::UnexpectedValue.into());
+        }
+        Ok(left.iter().zip(right).map(|(x, y)| x ^ y).collect())
+    }
+
+    fn agg_share(state: VdafState) -> Result<VdafAggregateShare, VdafError> {
+        match state {
+            VdafState::Registered(share) => Ok(VdafAggregateShare::Registered {
+                vdaf: TOY_XOR.into(),
+                share,
+            }),
+            _ => Err(VdafError::unexpected_type("prepare state")),
+        }
+    }
+}
+
+impl DapVdaf for ToyXor {
+    fn verify_key_len(&self) -> usize {
+        8
+    }
+
+    fn shard(&self, measurement: DapMeasurement) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        let measurement = match measurement {
+            DapMeasurement::Registered(measurement) => measurement,
+            _ => return Err(VdafError::unexpected_type("measurement")),
+        };
+        let leader_share = thread_rng().gen::<[u8; 8]>().to_vec();
+        let helper_share = Self::xor(&measurement, &leader_share)?;
+        Ok((Vec::new(), vec![leader_share, helper_share]))
+    }
+
+    fn prepare_init(
+        &self,
+        _verify_key: &[u8],
+        _agg_id: usize,
+        _nonce: &[u8],
+        _public_share: &[u8],
+        input_share: &[u8],
+    ) -> Result<(VdafState, VdafMessage), VdafError> {
+        Ok((
+            VdafState::Registered(input_share.to_vec()),
+            VdafMessage::Registered(Vec::new()),
+        ))
+    }
+
+    fn encode_prepare_message(&self, _message: &VdafMessage) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn leader_prepare_finish(
+        &self,
+        state: VdafState,
+        _share: VdafMessage,
+        _helper_share: &[u8],
+    ) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
+        Ok((Self::agg_share(state)?, Vec::new()))
+    }
+
+    fn helper_prepare_finish(
+        &self,
+        state: VdafState,
+        _leader_message: &[u8],
+    ) -> Result<VdafAggregateShare, VdafError> {
+        Self::agg_share(state)
+    }
+
+    fn encode_prepare_state(
+        &self,
+        state: &VdafState,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), VdafError> {
+        match state {
+            VdafState::Registered(state) => bytes.extend_from_slice(state),
+            _ => return Err(VdafError::unexpected_type("prepare state")),
+        }
+        Ok(())
+    }
+
+    fn decode_prepare_state(
+        &self,
+        _agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafState, VdafError> {
+        let mut state = vec![0; 8];
+        bytes
+            .read_exact(&mut state)
+            .map_err(|e| CodecError::Other(Box::new(e)))?;
+        Ok(VdafState::Registered(state))
+    }
+
+    fn unshard(
+        &self,
+        _num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        let mut agg_res = vec![0; 8];
+        for agg_share in agg_shares {
+            agg_res = Self::xor(&agg_res, &agg_share)?;
+        }
+        Ok(DapAggregateResult::Registered(agg_res))
+    }
+
+    fn merge_agg_shares(&self, agg_share: &[u8], other: &[u8]) -> Result<Vec<u8>, VdafError> {
+        Self::xor(agg_share, other)
+    }
+
+    fn subtract_agg_shares(&self, agg_share: &[u8], other: &[u8]) -> Result<Vec<u8>, VdafError> {
+        Self::xor(agg_share, other)
+    }
+}
+
+fn register_toy_sum() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        register_vdaf(TOY_SUM, Some(TOY_SUM_TASKPROV_CODE), Arc::new(ToySum)).unwrap();
+    });
+}
+
+async fn roundtrip(version: DapVersion) {
+    register_toy_sum();
+    let mut t = Test::new(&VdafConfig::Registered(TOY_SUM.into()), version);
+    let got = t
+        .roundtrip(vec![
+            DapMeasurement::U64(1),
+            DapMeasurement::U64(1337),
+            DapMeasurement::U64(0),
+            DapMeasurement::U64(42),
+        ])
+        .await;
+    assert_eq!(got, DapAggregateResult::U64(1380));
+}
+
+async_test_versions! { roundtrip }
+
+async fn roundtrip_registered_encoding(version: DapVersion) {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        register_vdaf(TOY_XOR, None, Arc::new(ToyXor)).unwrap();
+    });
+
+    let mut t = Test::new(&VdafConfig::Registered(TOY_XOR.into()), version);
+    let got = t
+        .roundtrip(vec![
+            DapMeasurement::Registered(vec![1, 0, 0, 0, 0, 0, 0, 0]),
+            DapMeasurement::Registered(vec![3, 0, 0, 0, 0, 0, 0, 7]),
+            DapMeasurement::Registered(vec![0, 0, 0, 0, 0, 0, 0, 1]),
+        ])
+        .await;
+    assert_eq!(
+        got,
+        DapAggregateResult::Registered(vec![2, 0, 0, 0, 0, 0, 0, 6])
+    );
+
+    // Shares of a registered VDAF are merged and excluded by the VDAF.
+    let agg_share = |share: Vec<u8>| DapAggregateShare {
+        report_count: 1,
+        checksum: [0; 32],
+        data: Some(VdafAggregateShare::Registered {
+            vdaf: TOY_XOR.into(),
+            share,
+        }),
+    };
+    let mut merged = agg_share(vec![1; 8]);
+    merged.merge(agg_share(vec![2; 8])).unwrap();
+    assert_matches!(&merged.data, Some(VdafAggregateShare::Registered { share, .. }) if share == &vec![3; 8]);
+    merged.exclude(agg_share(vec![2; 8])).unwrap();
+    assert_matches!(&merged.data, Some(VdafAggregateShare::Registered { share, .. }) if share == &vec![1; 8]);
+
+    // Built-in VDAFs reject measurements of registered VDAFs.
+    assert_matches!(
+        VdafConfig::Prio3(Prio3Config::Count)
+            .vdaf()
+            .unwrap()
+            .shard(DapMeasurement::Registered(vec![1])),
+        Err(VdafError::Vdaf(..))
+    );
+}
+
+async_test_versions! { roundtrip_registered_encoding }
+
+#[test]
+fn register_conflict() {
+    register_toy_sum();
+
+    // The name is taken.
+    assert_matches!(
+        register_vdaf(TOY_SUM, None, Arc::new(ToySum)),
+        Err(DapError::Fatal(..))
+    );
+
+    // The VDAF type code is taken by a registered VDAF.
+    assert_matches!(
+        register_vdaf(
+            "toy_sum_conflict",
+            Some(TOY_SUM_TASKPROV_CODE),
+            Arc::new(ToySum)
+        ),
+        Err(DapError::Fatal(..))
+    );
+
+    // The VDAF type code is taken by a built-in VDAF.
+    assert_matches!(
+        register_vdaf("toy_sum_builtin", Some(0x00000001), Arc::new(ToySum)),
+        Err(DapError::Fatal(..))
+    );
+}
+
+#[test]
+fn unregistered() {
+    let vdaf_config = VdafConfig::Registered("not_registered".into());
+    assert!(!vdaf_config.is_valid_agg_param(&[]));
+    assert!(matches!(
+        vdaf_config.get_decoded_verify_key(&[0; 8]),
+        Err(DapError::Fatal(..))
+    ));
+    assert!(matches!(
+        vdaf_config.gen_verify_key(),
+        Err(DapError::Fatal(..))
+    ));
+    assert_matches!(
+        VdafTypeVar::get_decoded(&0xfffffffeu32.get_encoded()),
+        Err(CodecError::UnexpectedValue)
+    );
+
+    // A task provisioned via taskprov with a VDAF that is not registered is rejected.
+    assert_matches!(
+        VdafConfig::try_from(VdafTypeVar::Registered(0xfffffffe)),
+        Err(DapError::Abort(DapAbort::InvalidTask))
+    );
+    assert!(matches!(
+        compute_vdaf_verify_key(
+            TaskprovVersion::Draft02,
+            &[0; 32],
+            &Id([0; 32]),
+            VdafType::Registered(0xfffffffe),
+        ),
+        Err(DapError::Abort(DapAbort::InvalidTask))
+    ));
+}
+
+#[test]
+fn registered_config() {
+    register_toy_sum();
+
+    let vdaf_config = VdafConfig::from_str(r#"{"registered":"toy_sum"}"#).unwrap();
+    assert_eq!(vdaf_config, VdafConfig::Registered(TOY_SUM.into()));
+    assert_eq!(vdaf_config.gen_verify_key().unwrap().as_ref().len(), 8);
+    assert!(vdaf_config.get_decoded_verify_key(&[0; 8]).is_ok());
+    assert!(vdaf_config.get_decoded_verify_key(&[0; 16]).is_err());
+
+    // A task provisioned via taskprov can refer to the VDAF by its type code.
+    let var = VdafTypeVar::get_decoded(&TOY_SUM_TASKPROV_CODE.get_encoded()).unwrap();
+    assert_eq!(var, VdafTypeVar::Registered(TOY_SUM_TASKPROV_CODE));
+    assert_eq!(var.get_encoded(), TOY_SUM_TASKPROV_CODE.get_encoded());
+    assert_eq!(VdafConfig::try_from(var).unwrap(), vdaf_config);
+    let (name, _vdaf) = get_registered_vdaf_by_taskprov_code(TOY_SUM_TASKPROV_CODE).unwrap();
+    assert_eq!(name, TOY_SUM);
+}
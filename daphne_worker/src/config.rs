@@ -8,61 +8,130 @@
 use crate::{
     dap_err,
     durable::{
-        durable_name_report_store, durable_name_task,
+        durable_name_report_store, durable_name_task, durable_name_upload_rate_limiter,
+        garbage_collector::{
+            GetExpiredTaskprovTasks, TaskprovTaskGarbage, DURABLE_GARBAGE_COLLECTOR_DELETE_TASK,
+            DURABLE_GARBAGE_COLLECTOR_GET_EXPIRED_TASKPROV_TASKS,
+            DURABLE_GARBAGE_COLLECTOR_PUT_TASKPROV_TASK,
+        },
+        leader_agg_job_queue::AggJobLeases,
         leader_batch_queue::{LeaderBatchQueueResult, DURABLE_LEADER_BATCH_QUEUE_CURRENT},
+        metrics_aggregator::{
+            durable_name_metrics_aggregator, DURABLE_METRICS_AGGREGATOR_GET,
+            DURABLE_METRICS_AGGREGATOR_PUT, METRICS_AGGREGATOR_SHARD_COUNT,
+        },
+        upload_rate_limiter::{
+            UploadRateLimit, UploadRateLimiterReq, UploadRateLimiterResult,
+            DURABLE_UPLOAD_RATE_LIMITER_ACQUIRE, DURABLE_UPLOAD_RATE_LIMITER_RELEASE,
+            UPLOAD_RATE_LIMITER_CLIENT_SHARD_COUNT,
+        },
         DurableConnector, BINDING_DAP_GARBAGE_COLLECTOR, BINDING_DAP_LEADER_BATCH_QUEUE,
-        DURABLE_DELETE_ALL,
+        BINDING_DAP_METRICS_AGGREGATOR, BINDING_DAP_UPLOAD_RATE_LIMITER, DURABLE_DELETE_ALL,
     },
     int_err,
-    metrics::DaphneWorkerMetrics,
+    metrics::{DaphneWorkerMetrics, MetricsFamily},
+    now,
+    tracing_utils::TRACEPARENT_FIELD,
+    AdminTaskConfig, AdminTaskList, AdminUpdateTask, DaphneWorkerReportSelector,
     InternalTestAddTask, InternalTestEndpointForTask, InternalTestRole,
 };
 use daphne::{
     auth::BearerToken,
     constants,
     hpke::HpkeReceiverConfig,
-    messages::{decode_base64url_vec, HpkeConfig, Id, ReportMetadata},
-    DapAbort, DapError, DapGlobalConfig, DapQueryConfig, DapRequest, DapTaskConfig, DapVersion,
-    Prio3Config, VdafConfig,
+    messages::{
+        decode_base64url_vec, encode_base64url, taskprov::TaskConfig, HpkeConfig, Id,
+        ReportMetadata, Time,
+    },
+    taskprov::{TaskprovOptInPolicy, TASKPROV_HEADER},
+    DapAbort, DapError, DapGlobalConfig, DapQueryConfig, DapRequest, DapStatelessHelperConfig,
+    DapTaskConfig, DapVersion, Prio3Config, VdafConfig,
 };
+use futures::future::try_join_all;
 use matchit::Router;
 use prio::{
     codec::Decode,
     vdaf::prg::{Prg, PrgAes128, Seed, SeedStream},
 };
 use prometheus::{Encoder, Registry};
+use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
     collections::HashMap,
+    future::Future,
     io::Cursor,
-    sync::{Arc, RwLock, RwLockReadGuard},
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
     time::Duration,
 };
-use tracing::{error, trace};
+use tracing::{error, field, info, trace, Span};
+use wasm_bindgen::{JsCast, JsValue};
 use worker::{kv::KvStore, *};
 
 pub(crate) const KV_KEY_PREFIX_HPKE_RECEIVER_CONFIG: &str = "hpke_receiver_config";
 pub(crate) const KV_KEY_PREFIX_BEARER_TOKEN_LEADER: &str = "bearer_token/leader/task";
 pub(crate) const KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR: &str = "bearer_token/collector/task";
 pub(crate) const KV_KEY_PREFIX_TASK_CONFIG: &str = "config/task";
+pub(crate) const KV_KEY_PREFIX_TASKPROV_PEER_TASK_COUNT: &str = "taskprov/peer_task_count";
 pub(crate) const KV_BINDING_DAP_CONFIG: &str = "DAP_CONFIG";
 
-/// Long-lived parameters for tasks using draft-wang-ppm-dap-taskprov-02 ("taskprov").
-pub(crate) struct TaskprovConfig {
-    /// HPKE collector configuration for all taskprov tasks.
+/// Credentials shared with a peer Aggregator for tasks provisioned via taskprov.
+pub(crate) struct TaskprovCredentials {
+    /// HPKE collector configuration for the peer's taskprov tasks.
     pub(crate) hpke_collector_config: HpkeConfig,
 
     /// VDAF verify key init secret, used to generate the VDAF verification key for a taskprov task.
     pub(crate) vdaf_verify_key_init: [u8; 32],
 
-    /// Leader bearer token for all taskprov tasks
+    /// Leader bearer token for the peer's taskprov tasks.
     pub(crate) leader_bearer_token: BearerToken,
 
-    /// Collector bearer token for all taskprov tasks
+    /// Collector bearer token for the peer's taskprov tasks.
     pub(crate) collector_bearer_token: BearerToken,
 }
 
+/// Taskprov credentials for a specific peer, as configured by `DAP_TASKPROV_PEERS`.
+#[derive(Deserialize)]
+struct TaskprovPeerCredentials {
+    hpke_collector_config: HpkeConfig,
+    #[serde(with = "hex")]
+    vdaf_verify_key_init: [u8; 32],
+    leader_bearer_token: String,
+    collector_bearer_token: String,
+}
+
+impl From<TaskprovPeerCredentials> for TaskprovCredentials {
+    fn from(creds: TaskprovPeerCredentials) -> Self {
+        Self {
+            hpke_collector_config: creds.hpke_collector_config,
+            vdaf_verify_key_init: creds.vdaf_verify_key_init,
+            leader_bearer_token: BearerToken::from(creds.leader_bearer_token),
+            collector_bearer_token: BearerToken::from(creds.collector_bearer_token),
+        }
+    }
+}
+
+/// Long-lived parameters for tasks using draft-wang-ppm-dap-taskprov ("taskprov").
+pub(crate) struct TaskprovConfig {
+    /// Credentials for peers that are not configured with their own.
+    pub(crate) default_credentials: TaskprovCredentials,
+
+    /// Credentials for specific peers, keyed by the ASCII serialization of the peer's origin.
+    pub(crate) peer_credentials: HashMap<String, TaskprovCredentials>,
+
+    /// Policy used to decide whether to opt in to a taskprov task.
+    pub(crate) opt_in_policy: TaskprovOptInPolicy,
+}
+
+impl TaskprovConfig {
+    /// Return the credentials shared with the peer Aggregator with the given URL.
+    pub(crate) fn credentials_for(&self, peer_url: &Url) -> &TaskprovCredentials {
+        self.peer_credentials
+            .get(&peer_url.origin().ascii_serialization())
+            .unwrap_or(&self.default_credentials)
+    }
+}
+
 /// Parameters required for pushing Prometheus metrics.
 struct MetricsPushConfig {
     /// URL of the server to push metrics to.
@@ -72,10 +141,211 @@ struct MetricsPushConfig {
     bearer_token: BearerToken,
 }
 
+/// Leader: Schedule on which reports and collection jobs are processed, as configured by
+/// `DAP_LEADER_PROCESS_SCHEDULE`.
+#[derive(Deserialize)]
+pub(crate) struct LeaderProcessSchedule {
+    /// Cron pattern of the trigger on which to process. If not set, then processing runs on every
+    /// trigger.
+    #[serde(default)]
+    pub(crate) cron: Option<String>,
+
+    /// Report selector passed to `DapLeader::process()`.
+    pub(crate) report_selector: DaphneWorkerReportSelector,
+}
+
+/// Leader: Conditions under which a `ReportsPending` instance runs an aggregation job for its
+/// reports, as configured by `DAP_AGG_JOB_TRIGGERS`.
+#[derive(Deserialize)]
+pub(crate) struct AggJobTrigger {
+    /// Run an aggregation job once the instance holds this many reports.
+    #[serde(default)]
+    pub(crate) min_reports: Option<u64>,
+
+    /// Run an aggregation job once the oldest report in the instance has been waiting for this
+    /// many seconds.
+    #[serde(default)]
+    pub(crate) max_age: Option<u64>,
+
+    /// Maximum number of reports to aggregate in each job.
+    pub(crate) max_reports: u64,
+}
+
+/// Leader: Aggregation job triggers, as configured by `DAP_AGG_JOB_TRIGGERS`.
+#[derive(Default, Deserialize)]
+pub(crate) struct AggJobTriggers {
+    /// Trigger for tasks that are not configured with their own.
+    #[serde(default)]
+    pub(crate) default: Option<AggJobTrigger>,
+
+    /// Triggers for specific tasks, keyed by the base64url-encoded task ID. A task whose trigger
+    /// is `null` is only aggregated by `DapLeader::process()`.
+    #[serde(default)]
+    pub(crate) tasks: HashMap<String, Option<AggJobTrigger>>,
+}
+
+impl AggJobTriggers {
+    /// Return the trigger for the given task, if any.
+    pub(crate) fn for_task(&self, task_id: &Id) -> Option<&AggJobTrigger> {
+        match self.tasks.get(&task_id.to_base64url()) {
+            Some(trigger) => trigger.as_ref(),
+            None => self.default.as_ref(),
+        }
+    }
+}
+
+/// Upload rate limits for a task, as configured by `DAP_UPLOAD_RATE_LIMITS`.
+#[derive(Default, Deserialize)]
+pub(crate) struct UploadRateLimitsForTask {
+    /// Limit on the uploads for the task by all Clients.
+    #[serde(default)]
+    pub(crate) task: Option<UploadRateLimit>,
+
+    /// Limit on the uploads for the task by each Client.
+    #[serde(default)]
+    pub(crate) client: Option<UploadRateLimit>,
+}
+
+/// Leader: Upload rate limits and quotas, as configured by `DAP_UPLOAD_RATE_LIMITS`.
+#[derive(Default, Deserialize)]
+pub(crate) struct UploadRateLimits {
+    /// Limits for tasks that are not configured with their own.
+    #[serde(default)]
+    pub(crate) default: UploadRateLimitsForTask,
+
+    /// Limits for specific tasks, keyed by the base64url-encoded task ID.
+    #[serde(default)]
+    pub(crate) tasks: HashMap<String, UploadRateLimitsForTask>,
+}
+
+impl UploadRateLimits {
+    /// Return the limits for the given task.
+    pub(crate) fn for_task(&self, task_id: &Id) -> &UploadRateLimitsForTask {
+        self.tasks
+            .get(&task_id.to_base64url())
+            .unwrap_or(&self.default)
+    }
+
+    fn all_limits(&self) -> impl Iterator<Item = &UploadRateLimit> {
+        std::iter::once(&self.default)
+            .chain(self.tasks.values())
+            .flat_map(|limits| limits.task.iter().chain(limits.client.iter()))
+    }
+}
+
+/// Parameters describing a change of the report shard count that is still being migrated.
+pub(crate) struct ReportShardMigration {
+    /// Report shard count in use before the change.
+    pub(crate) previous_count: u64,
+
+    /// Time (UNIX seconds) at which the current shard count took effect.
+    pub(crate) changed_at: Time,
+}
+
+/// Parameters used to map each report to the `ReportsPending` and `ReportsProcessed` instances
+/// that store it.
+pub(crate) struct ReportShardConfig {
+    /// Sharding key, used to compute the ReportsPending or ReportsProcessed shard to map a report
+    /// to (based on the report ID).
+    pub(crate) key: Seed<16>,
+
+    /// Shard count, the number of report storage shards. This should be a power of 2.
+    pub(crate) count: u64,
+
+    /// If set, then the report shard count was recently changed. Reports that may have been
+    /// processed under the previous shard count are checked for replay under both mappings.
+    pub(crate) migration: Option<ReportShardMigration>,
+}
+
+impl ReportShardConfig {
+    /// Derive the batch name for a report for the given task and with the given report ID.
+    pub(crate) fn durable_name_report_store(
+        &self,
+        global_config: &DapGlobalConfig,
+        version: &DapVersion,
+        task_id_hex: &str,
+        metadata: &ReportMetadata,
+    ) -> String {
+        self.durable_name_report_store_with_shard_count(
+            global_config,
+            version,
+            task_id_hex,
+            metadata,
+            self.count,
+        )
+    }
+
+    /// Derive the names of the `ReportsProcessed` instances that need to be checked for replay of
+    /// the given report. This is the instance given by [`Self::durable_name_report_store`], unless
+    /// the shard count is being migrated and the report may have been processed under the previous
+    /// shard count, in which case the instance under the previous mapping is also included.
+    ///
+    /// The previous mapping is retired automatically: a report processed before the shard count
+    /// changed can't have a timestamp later than the time of the change plus the maximum clock
+    /// skew, and such reports are rejected as too old one report storage epoch later.
+    pub(crate) fn durable_names_report_processed(
+        &self,
+        global_config: &DapGlobalConfig,
+        version: &DapVersion,
+        task_id_hex: &str,
+        metadata: &ReportMetadata,
+    ) -> Vec<String> {
+        let durable_name =
+            self.durable_name_report_store(global_config, version, task_id_hex, metadata);
+        let mut durable_names = vec![durable_name];
+        if let Some(ref migration) = self.migration {
+            if metadata.time
+                <= migration
+                    .changed_at
+                    .saturating_add(global_config.report_storage_max_future_time_skew)
+            {
+                let previous_durable_name = self.durable_name_report_store_with_shard_count(
+                    global_config,
+                    version,
+                    task_id_hex,
+                    metadata,
+                    migration.previous_count,
+                );
+                if previous_durable_name != durable_names[0] {
+                    durable_names.push(previous_durable_name);
+                }
+            }
+        }
+        durable_names
+    }
+
+    fn durable_name_report_store_with_shard_count(
+        &self,
+        global_config: &DapGlobalConfig,
+        version: &DapVersion,
+        task_id_hex: &str,
+        metadata: &ReportMetadata,
+        shard_count: u64,
+    ) -> String {
+        let mut shard_seed = [0; 8];
+        PrgAes128::seed_stream(&self.key, metadata.id.as_ref()).fill(&mut shard_seed);
+        let shard = u64::from_be_bytes(shard_seed) % shard_count;
+        let epoch = metadata.time - (metadata.time % global_config.report_storage_epoch_duration);
+        durable_name_report_store(version, task_id_hex, epoch, shard)
+    }
+}
+
+/// Parse a report shard count from the value of the environment variable with the given name. The
+/// count must be positive.
+pub(crate) fn parse_report_shard_count(name: &str, value: &str) -> Result<u64> {
+    let count: u64 = value
+        .parse()
+        .map_err(|err| Error::RustError(format!("Failed to parse {name}: {err}")))?;
+    if count == 0 {
+        return Err(Error::RustError(format!("{name} must be positive")));
+    }
+    Ok(count)
+}
+
 /// Daphne-Worker configuration, including long-lived parameters used across DAP tasks.
 pub(crate) struct DaphneWorkerConfig {
     /// Indicates if DaphneWorker is used as the Leader.
-    is_leader: bool,
+    pub(crate) is_leader: bool,
 
     /// Global DAP configuration.
     pub(crate) global: DapGlobalConfig,
@@ -86,17 +356,42 @@ pub(crate) struct DaphneWorkerConfig {
     /// Leader: Key used to derive collection job IDs. This field is not configured by the Helper.
     pub(crate) collect_id_key: Option<Seed<16>>,
 
-    /// Sharding key, used to compute the ReportsPending or ReportsProcessed shard to map a report
-    /// to (based on the report ID).
-    report_shard_key: Seed<16>,
+    /// Parameters used to map each report to the instances of `ReportsPending` and
+    /// `ReportsProcessed` that store it.
+    report_shards: ReportShardConfig,
 
-    /// Shard count, the number of report storage shards. This should be a power of 2.
-    report_shard_count: u64,
+    /// Leader: Number of aggregation job queue shards. Each instance of `ReportsPending` is
+    /// assigned to one of the shards by hashing its ID.
+    pub(crate) agg_job_queue_shard_count: u64,
+
+    /// Leader: Number of seconds for which an aggregation job is leased to a processor. If the
+    /// processor doesn't acknowledge the job within this time, then the job is handed out again.
+    pub(crate) agg_job_lease_secs: u64,
+
+    /// Leader: Schedule on which reports and collection jobs are processed. If not configured, then
+    /// they are only processed when requested via `/internal/process`.
+    pub(crate) leader_process_schedule: Option<LeaderProcessSchedule>,
+
+    /// Cron pattern of the trigger on which tasks whose retention period has elapsed are deleted.
+    /// This lists every task, so it should be scheduled less often than the other triggers. If
+    /// not configured, or if there is no task expiration grace period, then tasks are not swept.
+    pub(crate) task_sweep_cron: Option<String>,
+
+    /// Leader: Number of seconds `DapLeader::process()` may run for before deferring the remaining
+    /// work. If not configured, then processing runs until all work is done.
+    pub(crate) leader_process_time_budget: Option<u64>,
+
+    /// Leader: Conditions under which `ReportsPending` runs aggregation jobs by itself. If not
+    /// configured, then reports are only aggregated by `DapLeader::process()`.
+    pub(crate) agg_job_triggers: Option<AggJobTriggers>,
+
+    /// Leader: Upload rate limits and quotas. If not configured, then uploads are not limited.
+    pub(crate) upload_rate_limits: Option<UploadRateLimits>,
 
     /// Base URL of the Aggregator (unversioned).
     base_url: Url,
 
-    /// Optional: draft-wang-ppm-dap-taskprov-02 configuration. If not configured, then taskprov
+    /// Optional: draft-wang-ppm-dap-taskprov configuration. If not configured, then taskprov
     /// will be disabled.
     pub(crate) taskprov: Option<TaskprovConfig>,
 
@@ -110,15 +405,58 @@ pub(crate) struct DaphneWorkerConfig {
     /// configured by the Leader.
     pub(crate) helper_state_store_garbage_collect_after_secs: Option<Duration>,
 
+    /// Helper: If set, then the Helper seals its state and sends it to the Leader instead of
+    /// storing it in HelperStateStore.
+    pub(crate) stateless_helper: Option<DapStatelessHelperConfig>,
+
     /// Additional time to wait before deletng an instance of ReportsProcessed. Added to the value
     /// of the `report_storage_epoch_duration` field of the global DAP configuration.
     pub(crate) processed_alarm_safety_interval: Duration,
 
     /// Metrics push configuration.
     metrics_push_config: Option<MetricsPushConfig>,
+
+    /// If set, then the metrics collected by each request are aggregated by the
+    /// `MetricsAggregator` durable object and can be scraped from `/internal/metrics`.
+    pub(crate) metrics_pull_enabled: bool,
 }
 
 impl DaphneWorkerConfig {
+    /// Leader: Return the aggregation job trigger for the given task, if any.
+    pub(crate) fn agg_job_trigger(&self, task_id: &Id) -> Option<&AggJobTrigger> {
+        self.agg_job_triggers
+            .as_ref()
+            .and_then(|agg_job_triggers| agg_job_triggers.for_task(task_id))
+    }
+
+    /// Return the URL of the peer Aggregator for the given task: the Leader's peer is the Helper
+    /// and vice versa.
+    pub(crate) fn peer_url<'a>(&self, task_config: &'a DapTaskConfig) -> &'a Url {
+        if self.is_leader {
+            &task_config.helper_url
+        } else {
+            &task_config.leader_url
+        }
+    }
+
+    /// Return the taskprov credentials shared with the peer Aggregator named by the given taskprov
+    /// task configuration, or `None` if taskprov is not configured.
+    pub(crate) fn taskprov_peer_credentials(
+        &self,
+        taskprov_task_config: &TaskConfig,
+    ) -> std::result::Result<Option<&TaskprovCredentials>, DapError> {
+        let taskprov = match self.taskprov {
+            Some(ref taskprov) => taskprov,
+            None => return Ok(None),
+        };
+        let (leader_url, helper_url) = taskprov_task_config.aggregator_urls()?;
+        Ok(Some(taskprov.credentials_for(if self.is_leader {
+            &helper_url
+        } else {
+            &leader_url
+        })))
+    }
+
     pub(crate) fn from_worker_env(env: &Env) -> Result<Self> {
         let is_leader = match env.var("DAP_AGGREGATOR_ROLE")?.to_string().as_str() {
             "leader" => true,
@@ -161,13 +499,161 @@ impl DaphneWorkerConfig {
         )
         .map_err(int_err)?;
 
-        let report_shard_count: u64 = env
-            .var("DAP_REPORT_SHARD_COUNT")?
-            .to_string()
-            .parse()
-            .map_err(|err| {
-                Error::RustError(format!("Failed to parse DAP_REPORT_SHARD_COUNT: {err}"))
-            })?;
+        let report_shard_count = parse_report_shard_count(
+            "DAP_REPORT_SHARD_COUNT",
+            &env.var("DAP_REPORT_SHARD_COUNT")?.to_string(),
+        )?;
+
+        const DAP_REPORT_SHARD_COUNT_PREVIOUS: &str = "DAP_REPORT_SHARD_COUNT_PREVIOUS";
+        const DAP_REPORT_SHARD_COUNT_CHANGED_AT: &str = "DAP_REPORT_SHARD_COUNT_CHANGED_AT";
+        let report_shard_migration = match (
+            env.var(DAP_REPORT_SHARD_COUNT_PREVIOUS),
+            env.var(DAP_REPORT_SHARD_COUNT_CHANGED_AT),
+        ) {
+            (Ok(previous_count), Ok(changed_at)) => Some(ReportShardMigration {
+                previous_count: parse_report_shard_count(
+                    DAP_REPORT_SHARD_COUNT_PREVIOUS,
+                    &previous_count.to_string(),
+                )?,
+                changed_at: changed_at.to_string().parse().map_err(|err| {
+                    Error::RustError(format!(
+                        "Failed to parse {DAP_REPORT_SHARD_COUNT_CHANGED_AT}: {err}"
+                    ))
+                })?,
+            }),
+            (Err(..), Err(..)) => None,
+            (Ok(..), Err(..)) => {
+                return Err(Error::RustError(format!(
+                    "failed to configure report shard migration: missing {DAP_REPORT_SHARD_COUNT_CHANGED_AT}"
+                )))
+            }
+            (Err(..), Ok(..)) => {
+                return Err(Error::RustError(format!(
+                    "failed to configure report shard migration: missing {DAP_REPORT_SHARD_COUNT_PREVIOUS}"
+                )))
+            }
+        };
+
+        const DAP_AGG_JOB_QUEUE_SHARD_COUNT: &str = "DAP_AGG_JOB_QUEUE_SHARD_COUNT";
+        let agg_job_queue_shard_count: u64 = match env.var(DAP_AGG_JOB_QUEUE_SHARD_COUNT) {
+            Ok(raw) => raw.to_string().parse().map_err(|err| {
+                Error::RustError(format!(
+                    "Failed to parse {DAP_AGG_JOB_QUEUE_SHARD_COUNT}: {err}"
+                ))
+            })?,
+            Err(err) => {
+                trace!("{DAP_AGG_JOB_QUEUE_SHARD_COUNT} not configured: {err:?}");
+                1
+            }
+        };
+        if agg_job_queue_shard_count == 0 {
+            return Err(Error::RustError(format!(
+                "Failed to parse {DAP_AGG_JOB_QUEUE_SHARD_COUNT}: must be positive"
+            )));
+        }
+
+        const DAP_AGG_JOB_LEASE_SECS: &str = "DAP_AGG_JOB_LEASE_SECS";
+        let agg_job_lease_secs: u64 = match env.var(DAP_AGG_JOB_LEASE_SECS) {
+            Ok(raw) => raw.to_string().parse().map_err(|err| {
+                Error::RustError(format!("Failed to parse {DAP_AGG_JOB_LEASE_SECS}: {err}"))
+            })?,
+            Err(err) => {
+                trace!("{DAP_AGG_JOB_LEASE_SECS} not configured: {err:?}");
+                60
+            }
+        };
+        if agg_job_lease_secs == 0 {
+            return Err(Error::RustError(format!(
+                "Failed to parse {DAP_AGG_JOB_LEASE_SECS}: must be positive"
+            )));
+        }
+
+        const DAP_AGG_JOB_TRIGGERS: &str = "DAP_AGG_JOB_TRIGGERS";
+        let agg_job_triggers: Option<AggJobTriggers> = match env.var(DAP_AGG_JOB_TRIGGERS) {
+            Ok(raw) => Some(
+                serde_json::from_str(raw.to_string().as_ref()).map_err(|err| {
+                    Error::RustError(format!("Failed to parse {DAP_AGG_JOB_TRIGGERS}: {err}"))
+                })?,
+            ),
+            Err(err) => {
+                trace!("{DAP_AGG_JOB_TRIGGERS} not configured: {err:?}");
+                None
+            }
+        };
+        if let Some(ref agg_job_triggers) = agg_job_triggers {
+            if agg_job_triggers
+                .default
+                .iter()
+                .chain(agg_job_triggers.tasks.values().flatten())
+                .any(|trigger| {
+                    trigger.max_reports == 0 || trigger.min_reports.map_or(false, |n| n == 0)
+                })
+            {
+                return Err(Error::RustError(format!(
+                    "Failed to parse {DAP_AGG_JOB_TRIGGERS}: report counts must be positive"
+                )));
+            }
+        }
+
+        const DAP_UPLOAD_RATE_LIMITS: &str = "DAP_UPLOAD_RATE_LIMITS";
+        let upload_rate_limits: Option<UploadRateLimits> = match env.var(DAP_UPLOAD_RATE_LIMITS) {
+            Ok(raw) => Some(
+                serde_json::from_str(raw.to_string().as_ref()).map_err(|err| {
+                    Error::RustError(format!("Failed to parse {DAP_UPLOAD_RATE_LIMITS}: {err}"))
+                })?,
+            ),
+            Err(err) => {
+                trace!("{DAP_UPLOAD_RATE_LIMITS} not configured: {err:?}");
+                None
+            }
+        };
+        if let Some(ref upload_rate_limits) = upload_rate_limits {
+            if upload_rate_limits
+                .all_limits()
+                .any(|limit| limit.reports_per_sec <= 0.0 || limit.burst == 0)
+            {
+                return Err(Error::RustError(format!(
+                    "Failed to parse {DAP_UPLOAD_RATE_LIMITS}: rate and burst must be positive"
+                )));
+            }
+        }
+
+        const DAP_LEADER_PROCESS_SCHEDULE: &str = "DAP_LEADER_PROCESS_SCHEDULE";
+        let leader_process_schedule = match env.var(DAP_LEADER_PROCESS_SCHEDULE) {
+            Ok(raw) => Some(
+                serde_json::from_str(raw.to_string().as_ref()).map_err(|err| {
+                    Error::RustError(format!(
+                        "Failed to parse {DAP_LEADER_PROCESS_SCHEDULE}: {err}"
+                    ))
+                })?,
+            ),
+            Err(err) => {
+                trace!("{DAP_LEADER_PROCESS_SCHEDULE} not configured: {err:?}");
+                None
+            }
+        };
+
+        const DAP_TASK_SWEEP_CRON: &str = "DAP_TASK_SWEEP_CRON";
+        let task_sweep_cron = match env.var(DAP_TASK_SWEEP_CRON) {
+            Ok(raw) => Some(raw.to_string()),
+            Err(err) => {
+                trace!("{DAP_TASK_SWEEP_CRON} not configured: {err:?}");
+                None
+            }
+        };
+
+        const DAP_LEADER_PROCESS_TIME_BUDGET: &str = "DAP_LEADER_PROCESS_TIME_BUDGET";
+        let leader_process_time_budget = match env.var(DAP_LEADER_PROCESS_TIME_BUDGET) {
+            Ok(raw) => Some(raw.to_string().parse().map_err(|err| {
+                Error::RustError(format!(
+                    "Failed to parse {DAP_LEADER_PROCESS_TIME_BUDGET}: {err}"
+                ))
+            })?),
+            Err(err) => {
+                trace!("{DAP_LEADER_PROCESS_TIME_BUDGET} not configured: {err:?}");
+                None
+            }
+        };
 
         let deployment = if let Ok(deployment) = env.var("DAP_DEPLOYMENT") {
             match deployment.to_string().as_str() {
@@ -216,11 +702,45 @@ impl DaphneWorkerConfig {
                     .to_string(),
             );
 
+            const DAP_TASKPROV_OPT_IN_POLICY: &str = "DAP_TASKPROV_OPT_IN_POLICY";
+            let opt_in_policy = match env.var(DAP_TASKPROV_OPT_IN_POLICY) {
+                Ok(raw) => serde_json::from_str(raw.to_string().as_ref()).map_err(|err| {
+                    Error::RustError(format!(
+                        "Failed to parse {DAP_TASKPROV_OPT_IN_POLICY}: {err}"
+                    ))
+                })?,
+                Err(err) => {
+                    trace!("{DAP_TASKPROV_OPT_IN_POLICY} not configured: {err:?}");
+                    TaskprovOptInPolicy::default()
+                }
+            };
+
+            const DAP_TASKPROV_PEERS: &str = "DAP_TASKPROV_PEERS";
+            let peer_credentials = match env.secret(DAP_TASKPROV_PEERS) {
+                Ok(raw) => serde_json::from_str::<HashMap<Url, TaskprovPeerCredentials>>(
+                    raw.to_string().as_ref(),
+                )
+                .map_err(|err| {
+                    Error::RustError(format!("Failed to parse {DAP_TASKPROV_PEERS}: {err}"))
+                })?
+                .into_iter()
+                .map(|(peer_url, creds)| (peer_url.origin().ascii_serialization(), creds.into()))
+                .collect(),
+                Err(err) => {
+                    trace!("{DAP_TASKPROV_PEERS} not configured: {err:?}");
+                    HashMap::default()
+                }
+            };
+
             Some(TaskprovConfig {
-                hpke_collector_config,
-                vdaf_verify_key_init,
-                leader_bearer_token,
-                collector_bearer_token,
+                default_credentials: TaskprovCredentials {
+                    hpke_collector_config,
+                    vdaf_verify_key_init,
+                    leader_bearer_token,
+                    collector_bearer_token,
+                },
+                peer_credentials,
+                opt_in_policy,
             })
         } else {
             None
@@ -249,6 +769,47 @@ impl DaphneWorkerConfig {
             None
         };
 
+        const DAP_HELPER_STATE_KEY: &str = "DAP_HELPER_STATE_KEY";
+        const DAP_HELPER_STATE_LIFETIME: &str = "DAP_HELPER_STATE_LIFETIME";
+        let stateless_helper = match env.secret(DAP_HELPER_STATE_KEY) {
+            Ok(raw) if !is_leader => {
+                let key = hex::decode(raw.to_string())
+                    .map_err(|err| {
+                        Error::RustError(format!(
+                            "{DAP_HELPER_STATE_KEY}: Failed to decode hex: {err}"
+                        ))
+                    })?
+                    .try_into()
+                    .map_err(|_| {
+                        Error::RustError(format!("{DAP_HELPER_STATE_KEY}: Incorrect length"))
+                    })?;
+                let state_lifetime = match env.var(DAP_HELPER_STATE_LIFETIME) {
+                    Ok(raw) => raw.to_string().parse().map_err(|err| {
+                        Error::RustError(format!(
+                            "Failed to parse {DAP_HELPER_STATE_LIFETIME}: {err}"
+                        ))
+                    })?,
+                    Err(err) => {
+                        trace!("{DAP_HELPER_STATE_LIFETIME} not configured: {err:?}");
+                        3600
+                    }
+                };
+                Some(DapStatelessHelperConfig {
+                    key,
+                    state_lifetime,
+                })
+            }
+            Ok(..) => {
+                return Err(Error::RustError(format!(
+                    "{DAP_HELPER_STATE_KEY} is only used by the Helper"
+                )))
+            }
+            Err(err) => {
+                trace!("{DAP_HELPER_STATE_KEY} not configured: {err:?}");
+                None
+            }
+        };
+
         let processed_alarm_safety_interval = Duration::from_secs(
             env.var("DAP_PROCESSED_ALARM_SAFETY_INTERVAL")?
                 .to_string()
@@ -287,35 +848,84 @@ impl DaphneWorkerConfig {
             }
         };
 
+        const DAP_METRICS_PULL_ENABLED: &str = "DAP_METRICS_PULL_ENABLED";
+        let metrics_pull_enabled = if let Ok(enabled) = env.var(DAP_METRICS_PULL_ENABLED) {
+            enabled.to_string().parse().map_err(|err| {
+                Error::RustError(format!("Failed to parse {DAP_METRICS_PULL_ENABLED}: {err}"))
+            })?
+        } else {
+            false
+        };
+
         Ok(Self {
             global,
             deployment,
             collect_id_key,
-            report_shard_key,
-            report_shard_count,
+            report_shards: ReportShardConfig {
+                key: report_shard_key,
+                count: report_shard_count,
+                migration: report_shard_migration,
+            },
+            agg_job_queue_shard_count,
+            agg_job_lease_secs,
             base_url,
             is_leader,
             taskprov,
             default_version,
             admin_token,
             helper_state_store_garbage_collect_after_secs,
+            stateless_helper,
             processed_alarm_safety_interval,
             metrics_push_config,
+            metrics_pull_enabled,
+            leader_process_schedule,
+            task_sweep_cron,
+            leader_process_time_budget,
+            agg_job_triggers,
+            upload_rate_limits,
         })
     }
 
     /// Derive the batch name for a report for the given task and with the given report ID.
     pub(crate) fn durable_name_report_store(
         &self,
-        task_config: &DapTaskConfig,
+        version: &DapVersion,
         task_id_hex: &str,
         metadata: &ReportMetadata,
     ) -> String {
+        self.report_shards
+            .durable_name_report_store(&self.global, version, task_id_hex, metadata)
+    }
+
+    /// Derive the names of the `ReportsProcessed` instances that need to be checked for replay of
+    /// the given report. (See [`ReportShardConfig::durable_names_report_processed`].)
+    pub(crate) fn durable_names_report_processed(
+        &self,
+        task_config: &DapTaskConfig,
+        task_id_hex: &str,
+        metadata: &ReportMetadata,
+    ) -> Vec<String> {
+        self.report_shards.durable_names_report_processed(
+            &self.global,
+            &task_config.version,
+            task_id_hex,
+            metadata,
+        )
+    }
+
+    /// Derive the aggregation job queue shard for the `ReportsPending` instance with the given ID.
+    pub(crate) fn agg_job_queue_shard(&self, reports_pending_id_hex: &str) -> u64 {
+        let mut shard_seed = [0; 8];
+        PrgAes128::seed_stream(&self.report_shards.key, reports_pending_id_hex.as_bytes())
+            .fill(&mut shard_seed);
+        u64::from_be_bytes(shard_seed) % self.agg_job_queue_shard_count
+    }
+
+    /// Derive the shard of the `UploadRateLimiter` instances that holds the given Client's limit.
+    pub(crate) fn upload_rate_limiter_client_shard(&self, client: &str) -> u64 {
         let mut shard_seed = [0; 8];
-        PrgAes128::seed_stream(&self.report_shard_key, metadata.id.as_ref()).fill(&mut shard_seed);
-        let shard = u64::from_be_bytes(shard_seed) % self.report_shard_count;
-        let epoch = metadata.time - (metadata.time % self.global.report_storage_epoch_duration);
-        durable_name_report_store(&task_config.version, task_id_hex, epoch, shard)
+        PrgAes128::seed_stream(&self.report_shards.key, client.as_bytes()).fill(&mut shard_seed);
+        u64::from_be_bytes(shard_seed) % UPLOAD_RATE_LIMITER_CLIENT_SHARD_COUNT
     }
 }
 
@@ -369,10 +979,20 @@ pub(crate) struct DaphneWorkerRequestState<'srv> {
 
     /// Metrics.
     pub(crate) metrics: DaphneWorkerMetrics,
+
+    /// Address of the client that sent the request, if known. This is used to enforce per-Client
+    /// upload rate limits.
+    pub(crate) client_addr: Option<String>,
+
+    /// Leader: Aggregation jobs leased while processing reports.
+    pub(crate) agg_job_leases: Mutex<AggJobLeases>,
 }
 
 impl<'srv> DaphneWorkerRequestState<'srv> {
-    pub(crate) fn new(isolate_state: &'srv DaphneWorkerIsolateState) -> Result<Self> {
+    pub(crate) fn new(
+        isolate_state: &'srv DaphneWorkerIsolateState,
+        client_addr: Option<String>,
+    ) -> Result<Self> {
         let prometheus_registry = Registry::new();
         let metrics = DaphneWorkerMetrics::register(&prometheus_registry, None)
             .map_err(|e| Error::RustError(format!("failed to register metrics: {e}")))?;
@@ -381,6 +1001,8 @@ impl<'srv> DaphneWorkerRequestState<'srv> {
             isolate_state,
             prometheus_registry,
             metrics,
+            client_addr,
+            agg_job_leases: Mutex::default(),
         })
     }
 
@@ -432,6 +1054,38 @@ impl<'srv> DaphneWorkerRequestState<'srv> {
         }
         Ok(())
     }
+
+    /// If configured, return a future that merges the metrics collected while handling this
+    /// request into the totals maintained by a random shard of the `MetricsAggregator` durable
+    /// object. The future does not borrow the request state, so that it may be run after the
+    /// response is sent (e.g., with `Context::wait_until()`).
+    pub(crate) fn maybe_aggregate_metrics(
+        &self,
+        env: &Env,
+    ) -> Option<impl Future<Output = Result<()>> + 'static> {
+        if !self.isolate_state.config.metrics_pull_enabled {
+            return None;
+        }
+
+        let families = MetricsFamily::gather(&self.prometheus_registry);
+        if families.is_empty() {
+            return None;
+        }
+
+        // `Env` is a handle to a JavaScript object but doesn't implement `Clone`.
+        let env: Env = JsValue::clone(env).unchecked_into();
+        let shard = thread_rng().gen_range(0..METRICS_AGGREGATOR_SHARD_COUNT);
+        Some(async move {
+            DurableConnector::new(&env)
+                .post::<_, ()>(
+                    BINDING_DAP_METRICS_AGGREGATOR,
+                    DURABLE_METRICS_AGGREGATOR_PUT,
+                    durable_name_metrics_aggregator(shard),
+                    families,
+                )
+                .await
+        })
+    }
 }
 
 /// Daphne-Worker, used to handle a DAP request. Constructed from `DaphneWorkerState::handler()`.
@@ -442,7 +1096,7 @@ pub(crate) struct DaphneWorker<'srv> {
 
 impl<'srv> DaphneWorker<'srv> {
     pub(crate) fn durable(&self) -> DurableConnector<'_> {
-        DurableConnector::new(self.env)
+        DurableConnector::new_with_metrics(self.env, &self.state.metrics)
     }
 
     pub(crate) fn kv(&self) -> Result<KvStore> {
@@ -457,6 +1111,16 @@ impl<'srv> DaphneWorker<'srv> {
         self.state.isolate_state
     }
 
+    /// Leader: Deadline (UNIX time in milliseconds) for a call to `DapLeader::process()` starting
+    /// now, as determined by the configured time budget.
+    pub(crate) fn leader_process_deadline(&self) -> Option<u64> {
+        self.state
+            .isolate_state
+            .config
+            .leader_process_time_budget
+            .map(|budget| Date::now().as_millis() + budget * 1000)
+    }
+
     /// Set a key/value pair unless the key already exists. If the key exists, then return the current
     /// value. Otherwise return nothing.
     async fn kv_set_if_not_exists<K, V>(
@@ -481,6 +1145,34 @@ impl<'srv> DaphneWorker<'srv> {
         Ok(None)
     }
 
+    /// Get the value associated with a key directly from KV, bypassing the cache.
+    async fn kv_get<K, V>(&self, kv_key_prefix: &str, kv_key_suffix: &K) -> Result<Option<V>>
+    where
+        K: ToString,
+        V: for<'de> Deserialize<'de>,
+    {
+        let kv_key = format!("{}/{}", kv_key_prefix, kv_key_suffix.to_string());
+        Ok(self.kv()?.get(&kv_key).json().await?)
+    }
+
+    /// Set a key/value pair, overwriting the current value (if any).
+    async fn kv_put<K, V>(&self, kv_key_prefix: &str, kv_key_suffix: &K, kv_value: V) -> Result<()>
+    where
+        K: ToString,
+        V: Serialize,
+    {
+        let kv_key = format!("{}/{}", kv_key_prefix, kv_key_suffix.to_string());
+        self.kv()?.put(&kv_key, kv_value)?.execute().await?;
+        Ok(())
+    }
+
+    /// Delete a key/value pair.
+    async fn kv_delete<K: ToString>(&self, kv_key_prefix: &str, kv_key_suffix: &K) -> Result<()> {
+        let kv_key = format!("{}/{}", kv_key_prefix, kv_key_suffix.to_string());
+        self.kv()?.delete(&kv_key).await?;
+        Ok(())
+    }
+
     async fn kv_get_cached<'req, K, V>(
         &self,
         map: &'srv Arc<RwLock<HashMap<K, V>>>,
@@ -534,6 +1226,17 @@ impl<'srv> DaphneWorker<'srv> {
         }
     }
 
+    /// Remove a value from the cache populated by `kv_get_cached()`.
+    fn evict_cached<K, V>(map: &Arc<RwLock<HashMap<K, V>>>, key: &K) -> Result<()>
+    where
+        K: Eq + std::hash::Hash,
+    {
+        map.write()
+            .map_err(|e| Error::RustError(format!("Failed to lock map for writing: {e}")))?
+            .remove(key);
+        Ok(())
+    }
+
     /// Get a reference to the HPKE receiver configs, ensuring that the config indicated by
     /// `hpke_config_id` is cached (if it exists).
     pub(crate) async fn get_hpke_receiver_config(
@@ -571,6 +1274,75 @@ impl<'srv> DaphneWorker<'srv> {
             .await
     }
 
+    /// Set a collector bearer token for the given task.
+    pub(crate) async fn set_collector_bearer_token(
+        &self,
+        task_id: &Id,
+        token: &BearerToken,
+    ) -> Result<Option<BearerToken>> {
+        self.kv_set_if_not_exists(KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR, task_id, token.clone())
+            .await
+    }
+
+    /// Retrieve from KV the number of tasks the given peer Aggregator has provisioned via
+    /// taskprov. Peers are identified by the origin of their URL.
+    pub(crate) async fn get_taskprov_peer_task_count(&self, peer_url: &Url) -> Result<u64> {
+        Ok(self
+            .kv_get(
+                KV_KEY_PREFIX_TASKPROV_PEER_TASK_COUNT,
+                &peer_url.origin().ascii_serialization(),
+            )
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Increment the number of tasks the given peer Aggregator has provisioned via taskprov.
+    ///
+    /// NOTE KV does not support atomic updates, so concurrent increments may be lost. The count
+    /// is only meant to bound the number of tasks approximately.
+    pub(crate) async fn inc_taskprov_peer_task_count(&self, peer_url: &Url) -> Result<()> {
+        let count = self.get_taskprov_peer_task_count(peer_url).await?;
+        self.kv_put(
+            KV_KEY_PREFIX_TASKPROV_PEER_TASK_COUNT,
+            &peer_url.origin().ascii_serialization(),
+            count + 1,
+        )
+        .await
+    }
+
+    /// Decrement the number of tasks the given peer Aggregator has provisioned via taskprov. The
+    /// same caveat applies as for [`Self::inc_taskprov_peer_task_count`].
+    pub(crate) async fn dec_taskprov_peer_task_count(&self, peer_url: &Url) -> Result<()> {
+        let count = self.get_taskprov_peer_task_count(peer_url).await?;
+        self.kv_put(
+            KV_KEY_PREFIX_TASKPROV_PEER_TASK_COUNT,
+            &peer_url.origin().ascii_serialization(),
+            count.saturating_sub(1),
+        )
+        .await
+    }
+
+    /// Schedule a task provisioned via taskprov for deletion by
+    /// [`Self::sweep_expired_taskprov_tasks`]. The task is deleted once its retention period has
+    /// elapsed (see [`DapTaskConfig::retained_until`]).
+    pub(crate) async fn put_taskprov_task_garbage(
+        &self,
+        task_id: &Id,
+        task_config: &DapTaskConfig,
+    ) -> Result<()> {
+        self.durable()
+            .post(
+                BINDING_DAP_GARBAGE_COLLECTOR,
+                DURABLE_GARBAGE_COLLECTOR_PUT_TASKPROV_TASK,
+                "garbage_collector".to_string(),
+                &TaskprovTaskGarbage {
+                    task_id: task_id.clone(),
+                    delete_after: task_config.retained_until(&self.config().global),
+                },
+            )
+            .await
+    }
+
     /// Retrieve from KV the Collector's bearer token for the given task.
     pub(crate) async fn get_collector_bearer_token<'a>(
         &'a self,
@@ -610,6 +1382,130 @@ impl<'srv> DaphneWorker<'srv> {
             .await
     }
 
+    /// Return the limits that apply to an upload for the given task, along with the label used
+    /// for the `upload_throttled` metric, the name of the `UploadRateLimiter` instance that holds
+    /// each limit, and the request to send it. The Client's limit comes first.
+    fn upload_rate_limits(
+        &self,
+        version: &DapVersion,
+        task_id: &Id,
+        report_count: u64,
+    ) -> Vec<(&'static str, String, UploadRateLimiterReq)> {
+        let limits = match self.config().upload_rate_limits {
+            Some(ref upload_rate_limits) => upload_rate_limits.for_task(task_id),
+            None => return Vec::new(),
+        };
+
+        let task_id_hex = task_id.to_hex();
+        let mut checks = Vec::with_capacity(2);
+        if let (Some(limit), Some(client_addr)) = (&limits.client, &self.state.client_addr) {
+            let client_shard = self.config().upload_rate_limiter_client_shard(client_addr);
+            checks.push((
+                "client",
+                durable_name_upload_rate_limiter(version, &task_id_hex, Some(client_shard)),
+                UploadRateLimiterReq {
+                    limit: limit.clone(),
+                    client: Some(client_addr.clone()),
+                    report_count,
+                },
+            ));
+        }
+        if let Some(limit) = &limits.task {
+            checks.push((
+                "task",
+                durable_name_upload_rate_limiter(version, &task_id_hex, None),
+                UploadRateLimiterReq {
+                    limit: limit.clone(),
+                    client: None,
+                    report_count,
+                },
+            ));
+        }
+        checks
+    }
+
+    /// Leader: Take the tokens for `report_count` reports to be uploaded, subject to the limits
+    /// configured for the task. The Client's limit is taken first. Each limit takes one request to
+    /// the instance that holds it. If a limit is exceeded, then the tokens already taken are
+    /// returned and the upload is rejected with [`DapAbort::TooManyRequests`].
+    pub(crate) async fn acquire_upload_rate_limits(
+        &self,
+        version: &DapVersion,
+        task_id: &Id,
+        report_count: u64,
+    ) -> std::result::Result<(), DapError> {
+        let mut acquired = Vec::new();
+        for (limit_label, durable_name, acquire_req) in
+            self.upload_rate_limits(version, task_id, report_count)
+        {
+            let result: UploadRateLimiterResult = self
+                .durable()
+                .post(
+                    BINDING_DAP_UPLOAD_RATE_LIMITER,
+                    DURABLE_UPLOAD_RATE_LIMITER_ACQUIRE,
+                    durable_name.clone(),
+                    &acquire_req,
+                )
+                .await
+                .map_err(dap_err)?;
+
+            if let UploadRateLimiterResult::Limited { retry_after } = result {
+                self.state
+                    .metrics
+                    .upload_throttled
+                    .with_label_values(&[limit_label])
+                    .inc_by(report_count);
+                for (durable_name, release_req) in acquired {
+                    self.release_upload_rate_limit(durable_name, release_req)
+                        .await?;
+                }
+                return Err(DapError::Abort(DapAbort::TooManyRequests { retry_after }));
+            }
+            acquired.push((durable_name, acquire_req));
+        }
+
+        Ok(())
+    }
+
+    /// Leader: Return the tokens for `report_count` reports taken by
+    /// [`Self::acquire_upload_rate_limits`] that were not accepted, e.g., because they were
+    /// rejected as replays.
+    pub(crate) async fn release_upload_rate_limits(
+        &self,
+        version: &DapVersion,
+        task_id: &Id,
+        report_count: u64,
+    ) -> std::result::Result<(), DapError> {
+        if report_count == 0 {
+            return Ok(());
+        }
+
+        for (_limit_label, durable_name, release_req) in
+            self.upload_rate_limits(version, task_id, report_count)
+        {
+            self.release_upload_rate_limit(durable_name, release_req)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn release_upload_rate_limit(
+        &self,
+        durable_name: String,
+        release_req: UploadRateLimiterReq,
+    ) -> std::result::Result<(), DapError> {
+        self.durable()
+            .post::<_, ()>(
+                BINDING_DAP_UPLOAD_RATE_LIMITER,
+                DURABLE_UPLOAD_RATE_LIMITER_RELEASE,
+                durable_name,
+                release_req,
+            )
+            .await
+            .map_err(dap_err)
+    }
+
     /// Try retrieving from KV the configuration for the given task. Return an error if the
     /// indicated task is not recognized.
     pub(crate) async fn try_get_task_config<'req>(
@@ -656,6 +1552,27 @@ impl<'srv> DaphneWorker<'srv> {
         Ok(())
     }
 
+    /// Render the metrics aggregated by the `MetricsAggregator` durable objects in the Prometheus
+    /// text exposition format.
+    pub(crate) async fn internal_metrics(&self) -> Result<String> {
+        let durable = self.durable();
+        let shards: Vec<Vec<MetricsFamily>> =
+            try_join_all((0..METRICS_AGGREGATOR_SHARD_COUNT).map(|shard| {
+                durable.get(
+                    BINDING_DAP_METRICS_AGGREGATOR,
+                    DURABLE_METRICS_AGGREGATOR_GET,
+                    durable_name_metrics_aggregator(shard),
+                )
+            }))
+            .await?;
+
+        let mut families = Vec::new();
+        for shard in shards {
+            MetricsFamily::merge_all(&mut families, shard);
+        }
+        Ok(MetricsFamily::encode_text(&families))
+    }
+
     /// Get the batch ID for the oldest batch that has not been collected. This method is only
     /// applicable to fixed-size tasks.
     pub(crate) async fn internal_current_batch(
@@ -808,6 +1725,8 @@ impl<'srv> DaphneWorker<'srv> {
                     vdaf,
                     vdaf_verify_key,
                     collector_hpke_config,
+                    retired_collector_hpke_configs: Vec::new(),
+                    taskprov: None,
                 },
             )
             .await?
@@ -822,6 +1741,283 @@ impl<'srv> DaphneWorker<'srv> {
         }
     }
 
+    /// List the tasks configured in KV. At most `limit` task IDs are returned. If there are more
+    /// tasks, then the response includes a cursor for fetching the next page.
+    pub(crate) async fn admin_list_tasks(
+        &self,
+        cursor: Option<String>,
+        limit: u64,
+    ) -> Result<AdminTaskList> {
+        let prefix = format!("{KV_KEY_PREFIX_TASK_CONFIG}/");
+        let mut builder = self.kv()?.list().prefix(prefix.clone()).limit(limit);
+        if let Some(cursor) = cursor {
+            builder = builder.cursor(cursor);
+        }
+        let list = builder.execute().await?;
+
+        let task_ids = list
+            .keys
+            .iter()
+            .filter_map(|key| {
+                let task_id_data = hex::decode(key.name.strip_prefix(&prefix)?).ok()?;
+                Some(encode_base64url(task_id_data))
+            })
+            .collect();
+
+        Ok(AdminTaskList {
+            task_ids,
+            cursor: if list.list_complete {
+                None
+            } else {
+                list.cursor
+            },
+        })
+    }
+
+    /// Get the configuration of the given task, with secrets redacted.
+    pub(crate) async fn admin_get_task(&self, task_id: &Id) -> Result<Response> {
+        match self
+            .kv_get::<_, DapTaskConfig>(KV_KEY_PREFIX_TASK_CONFIG, task_id)
+            .await?
+        {
+            Some(task_config) => Response::from_json(&AdminTaskConfig::from(&task_config)),
+            None => Response::error("task not found", 404),
+        }
+    }
+
+    /// Update the mutable parameters of the given task.
+    ///
+    /// NOTE The task configuration and bearer tokens are cached by each isolate. Only this
+    /// isolate's cache is invalidated; other isolates may continue to use the old values until they
+    /// are restarted.
+    pub(crate) async fn admin_update_task(
+        &self,
+        task_id: &Id,
+        cmd: AdminUpdateTask,
+    ) -> Result<Response> {
+        let mut task_config: DapTaskConfig =
+            match self.kv_get(KV_KEY_PREFIX_TASK_CONFIG, task_id).await? {
+                Some(task_config) => task_config,
+                None => return Response::error("task not found", 404),
+            };
+
+        if let Some(expiration) = cmd.expiration {
+            task_config.expiration = expiration;
+        }
+
+        if let Some(min_batch_size) = cmd.min_batch_size {
+            if min_batch_size == 0 {
+                return Response::error("min batch size must be positive", 400);
+            }
+            if let DapQueryConfig::FixedSize { max_batch_size } = task_config.query {
+                if min_batch_size > max_batch_size {
+                    return Response::error("min batch size exceeds max batch size", 400);
+                }
+            }
+            task_config.min_batch_size = min_batch_size;
+        }
+
+        if let Some(collector_hpke_config) = cmd.collector_hpke_config {
+            let collector_hpke_config = match decode_base64url_vec(collector_hpke_config.as_bytes())
+                .and_then(|data| HpkeConfig::get_decoded(&data).ok())
+            {
+                Some(collector_hpke_config) => collector_hpke_config,
+                None => return Response::error("invalid collector HPKE config", 400),
+            };
+            if let Err(e) = task_config.rotate_collector_hpke_config(collector_hpke_config) {
+                return Response::error(e.to_string(), 400);
+            }
+        }
+
+        if let Some(config_ids) = cmd.remove_collector_hpke_config_ids {
+            if config_ids.contains(&task_config.collector_hpke_config.id) {
+                return Response::error("cannot remove the current collector HPKE config", 400);
+            }
+            task_config
+                .retired_collector_hpke_configs
+                .retain(|hpke_config| !config_ids.contains(&hpke_config.id));
+        }
+
+        if cmd.collector_authentication_token.is_some() && !self.config().is_leader {
+            return Response::error("unexpected collector authentication token", 400);
+        }
+
+        if let Some(token) = cmd.leader_authentication_token {
+            self.kv_put(
+                KV_KEY_PREFIX_BEARER_TOKEN_LEADER,
+                task_id,
+                BearerToken::from(token),
+            )
+            .await?;
+            Self::evict_cached(&self.isolate_state().leader_bearer_tokens, task_id)?;
+        }
+
+        if let Some(token) = cmd.collector_authentication_token {
+            self.kv_put(
+                KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR,
+                task_id,
+                BearerToken::from(token),
+            )
+            .await?;
+            Self::evict_cached(&self.isolate_state().collector_bearer_tokens, task_id)?;
+        }
+
+        self.kv_put(KV_KEY_PREFIX_TASK_CONFIG, task_id, &task_config)
+            .await?;
+        Self::evict_cached(&self.isolate_state().tasks, task_id)?;
+
+        Response::from_json(&AdminTaskConfig::from(&task_config))
+    }
+
+    /// Delete the given task. See [`Self::delete_task`].
+    pub(crate) async fn admin_delete_task(&self, task_id: &Id) -> Result<Response> {
+        if self.delete_task(task_id).await? {
+            Response::empty()
+        } else {
+            Response::error("task not found", 404)
+        }
+    }
+
+    /// Delete the given task, including its bearer tokens, and remove it from the taskprov index.
+    /// The durable object instances associated with the task are deleted too, but only those known
+    /// to the garbage collector, i.e., only in the "dev" deployment. The same caveat about caching
+    /// applies as for [`Self::admin_update_task`]. Returns `false` if the task was not found.
+    pub(crate) async fn delete_task(&self, task_id: &Id) -> Result<bool> {
+        let task_config: DapTaskConfig =
+            match self.kv_get(KV_KEY_PREFIX_TASK_CONFIG, task_id).await? {
+                Some(task_config) => task_config,
+                None => return Ok(false),
+            };
+
+        for kv_key_prefix in [
+            KV_KEY_PREFIX_TASK_CONFIG,
+            KV_KEY_PREFIX_BEARER_TOKEN_LEADER,
+            KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR,
+        ] {
+            self.kv_delete(kv_key_prefix, task_id).await?;
+        }
+        Self::evict_cached(&self.isolate_state().tasks, task_id)?;
+        Self::evict_cached(&self.isolate_state().leader_bearer_tokens, task_id)?;
+        Self::evict_cached(&self.isolate_state().collector_bearer_tokens, task_id)?;
+
+        self.durable()
+            .post(
+                BINDING_DAP_GARBAGE_COLLECTOR,
+                DURABLE_GARBAGE_COLLECTOR_DELETE_TASK,
+                "garbage_collector".to_string(),
+                task_id,
+            )
+            .await?;
+
+        // Release the task's slot in the peer's taskprov task limit.
+        if task_config.taskprov.is_some()
+            && matches!(
+                self.config().taskprov,
+                Some(ref taskprov) if taskprov.opt_in_policy.max_tasks_per_peer.is_some()
+            )
+        {
+            self.dec_taskprov_peer_task_count(self.config().peer_url(&task_config))
+                .await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Delete each task whose retention period has elapsed (see
+    /// [`DapTaskConfig::retained_until`]). This lists every task in KV, so it is only run on its own
+    /// trigger (see `DAP_TASK_SWEEP_CRON`). Returns the number of tasks deleted.
+    pub(crate) async fn sweep_expired_tasks(&self) -> Result<u64> {
+        let now = now();
+        let prefix = format!("{KV_KEY_PREFIX_TASK_CONFIG}/");
+        let mut cursor = None;
+        let mut deleted = 0;
+        loop {
+            let mut builder = self.kv()?.list().prefix(prefix.clone());
+            if let Some(cursor) = cursor {
+                builder = builder.cursor(cursor);
+            }
+            let list = builder.execute().await?;
+
+            for key in list.keys.iter() {
+                let task_id = match key
+                    .name
+                    .strip_prefix(&prefix)
+                    .and_then(|task_id_hex| hex::decode(task_id_hex).ok())
+                    .and_then(|task_id_data| task_id_data.try_into().ok())
+                {
+                    Some(task_id_data) => Id(task_id_data),
+                    None => {
+                        error!("sweep: skipping malformed KV key {}", key.name);
+                        continue;
+                    }
+                };
+
+                let task_config: DapTaskConfig =
+                    match self.kv_get(KV_KEY_PREFIX_TASK_CONFIG, &task_id).await? {
+                        Some(task_config) => task_config,
+                        None => continue,
+                    };
+
+                if now >= task_config.retained_until(&self.config().global)
+                    && self.delete_task(&task_id).await?
+                {
+                    info!("sweep: deleted expired task {task_id}");
+                    deleted += 1;
+                }
+            }
+
+            if list.list_complete || list.cursor.is_none() {
+                break;
+            }
+            cursor = list.cursor;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Delete each task provisioned via taskprov whose retention period has elapsed (see
+    /// [`Self::put_taskprov_task_garbage`]). Returns the number of tasks deleted.
+    pub(crate) async fn sweep_expired_taskprov_tasks(&self) -> Result<u64> {
+        const LIMIT: usize = 100;
+        let now = now();
+        let mut deleted = 0;
+        loop {
+            let task_ids: Vec<Id> = self
+                .durable()
+                .post(
+                    BINDING_DAP_GARBAGE_COLLECTOR,
+                    DURABLE_GARBAGE_COLLECTOR_GET_EXPIRED_TASKPROV_TASKS,
+                    "garbage_collector".to_string(),
+                    &GetExpiredTaskprovTasks { now, limit: LIMIT },
+                )
+                .await?;
+
+            for task_id in task_ids.iter() {
+                if self.delete_task(task_id).await? {
+                    info!("sweep: deleted expired taskprov task {task_id}");
+                    deleted += 1;
+                } else {
+                    // The task was already deleted, e.g., by the administrator. Remove it from
+                    // the index.
+                    self.durable()
+                        .post(
+                            BINDING_DAP_GARBAGE_COLLECTOR,
+                            DURABLE_GARBAGE_COLLECTOR_DELETE_TASK,
+                            "garbage_collector".to_string(),
+                            task_id,
+                        )
+                        .await?;
+                }
+            }
+
+            if task_ids.len() < LIMIT {
+                break;
+            }
+        }
+
+        Ok(deleted)
+    }
+
     pub(crate) fn extract_version_parameter(&self, req: &Request) -> Result<DapVersion> {
         let url = req.url()?;
         let path = url.path();
@@ -861,6 +2057,15 @@ impl<'srv> DaphneWorker<'srv> {
         let mut r = Cursor::new(payload.as_ref());
         let task_id = Id::decode(&mut r).ok();
 
+        // Record the task ID and link to the sender's trace (if any) in the span for this request.
+        let span = Span::current();
+        if let Some(ref task_id) = task_id {
+            span.record("task_id", field::display(task_id));
+        }
+        if let Some(traceparent) = req.headers().get("traceparent")? {
+            span.record(TRACEPARENT_FIELD, traceparent.as_str());
+        }
+
         Ok(DapRequest {
             version,
             task_id,
@@ -868,6 +2073,7 @@ impl<'srv> DaphneWorker<'srv> {
             url: req.url()?,
             media_type,
             sender_auth,
+            taskprov: req.headers().get(TASKPROV_HEADER)?,
         })
     }
 
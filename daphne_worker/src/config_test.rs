@@ -0,0 +1,182 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::config::{parse_report_shard_count, ReportShardConfig, ReportShardMigration};
+use daphne::{
+    messages::{HpkeKemId, ReportId, ReportMetadata, Time},
+    taskprov::TaskprovVersion,
+    DapGlobalConfig, DapVersion,
+};
+use prio::{codec::Decode, vdaf::prg::Seed};
+use rand::prelude::*;
+
+const TASK_ID_HEX: &str = "f00d";
+const CHANGED_AT: Time = 1_700_000_000;
+
+fn global_config() -> DapGlobalConfig {
+    DapGlobalConfig {
+        report_storage_epoch_duration: 604800,
+        report_storage_max_future_time_skew: 300,
+        max_batch_duration: 360000,
+        min_batch_interval_start: 259200,
+        max_batch_interval_end: 259200,
+        supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
+        allow_taskprov: false,
+        taskprov_version: TaskprovVersion::Draft02,
+        task_expiration_grace_period: Some(604800),
+        reconcile_batch_mismatch: false,
+        max_bulk_upload_reports: 1000,
+    }
+}
+
+fn report_shards(count: u64, migration: Option<ReportShardMigration>) -> ReportShardConfig {
+    ReportShardConfig {
+        key: Seed::get_decoded(&[7; 16]).unwrap(),
+        count,
+        migration,
+    }
+}
+
+/// Generate report metadata with the given timestamp. The report ID is chosen so that the report
+/// maps to the same `ReportsProcessed` instance under both shard configurations if `same` is set,
+/// or to different instances otherwise.
+fn gen_metadata(
+    global_config: &DapGlobalConfig,
+    current: &ReportShardConfig,
+    previous: &ReportShardConfig,
+    time: Time,
+    same: bool,
+) -> ReportMetadata {
+    let mut rng = thread_rng();
+    loop {
+        let metadata = ReportMetadata {
+            id: ReportId(rng.gen()),
+            time,
+            extensions: Vec::default(),
+        };
+        let current_name = current.durable_name_report_store(
+            global_config,
+            &DapVersion::Draft02,
+            TASK_ID_HEX,
+            &metadata,
+        );
+        let previous_name = previous.durable_name_report_store(
+            global_config,
+            &DapVersion::Draft02,
+            TASK_ID_HEX,
+            &metadata,
+        );
+        if (current_name == previous_name) == same {
+            return metadata;
+        }
+    }
+}
+
+#[test]
+fn parse_report_shard_count_rejects_zero() {
+    assert_eq!(
+        parse_report_shard_count("DAP_REPORT_SHARD_COUNT_PREVIOUS", "4").ok(),
+        Some(4)
+    );
+    assert!(parse_report_shard_count("DAP_REPORT_SHARD_COUNT_PREVIOUS", "0").is_err());
+    assert!(parse_report_shard_count("DAP_REPORT_SHARD_COUNT_PREVIOUS", "-1").is_err());
+    assert!(parse_report_shard_count("DAP_REPORT_SHARD_COUNT", "0").is_err());
+}
+
+#[test]
+fn durable_names_report_processed_without_migration() {
+    let global_config = global_config();
+    let current = report_shards(4, None);
+    let metadata = ReportMetadata {
+        id: ReportId([1; 16]),
+        time: CHANGED_AT,
+        extensions: Vec::default(),
+    };
+
+    assert_eq!(
+        current.durable_names_report_processed(
+            &global_config,
+            &DapVersion::Draft02,
+            TASK_ID_HEX,
+            &metadata
+        ),
+        vec![current.durable_name_report_store(
+            &global_config,
+            &DapVersion::Draft02,
+            TASK_ID_HEX,
+            &metadata
+        )]
+    );
+}
+
+#[test]
+fn durable_names_report_processed_during_migration() {
+    let global_config = global_config();
+    let previous = report_shards(2, None);
+    let current = report_shards(
+        4,
+        Some(ReportShardMigration {
+            previous_count: 2,
+            changed_at: CHANGED_AT,
+        }),
+    );
+    let last_time = CHANGED_AT + global_config.report_storage_max_future_time_skew;
+
+    // A report that may have been processed before the change is checked under both mappings.
+    for time in [CHANGED_AT - 3600, CHANGED_AT, last_time] {
+        let metadata = gen_metadata(&global_config, &current, &previous, time, false);
+        assert_eq!(
+            current.durable_names_report_processed(
+                &global_config,
+                &DapVersion::Draft02,
+                TASK_ID_HEX,
+                &metadata
+            ),
+            vec![
+                current.durable_name_report_store(
+                    &global_config,
+                    &DapVersion::Draft02,
+                    TASK_ID_HEX,
+                    &metadata
+                ),
+                previous.durable_name_report_store(
+                    &global_config,
+                    &DapVersion::Draft02,
+                    TASK_ID_HEX,
+                    &metadata
+                ),
+            ]
+        );
+    }
+
+    // If both mappings agree, then the instance is only checked once.
+    let metadata = gen_metadata(&global_config, &current, &previous, CHANGED_AT, true);
+    assert_eq!(
+        current
+            .durable_names_report_processed(
+                &global_config,
+                &DapVersion::Draft02,
+                TASK_ID_HEX,
+                &metadata
+            )
+            .len(),
+        1
+    );
+
+    // A report generated after the change can't have been processed under the previous mapping.
+    let metadata = gen_metadata(&global_config, &current, &previous, last_time + 1, false);
+    assert_eq!(
+        current.durable_names_report_processed(
+            &global_config,
+            &DapVersion::Draft02,
+            TASK_ID_HEX,
+            &metadata
+        ),
+        vec![current.durable_name_report_store(
+            &global_config,
+            &DapVersion::Draft02,
+            TASK_ID_HEX,
+            &metadata
+        )]
+    );
+}
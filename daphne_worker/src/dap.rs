@@ -14,14 +14,19 @@ use crate::{
     dap_err,
     durable::{
         aggregate_store::{
-            DURABLE_AGGREGATE_STORE_CHECK_COLLECTED, DURABLE_AGGREGATE_STORE_GET,
+            AggregateStoreExcludeResp, AggregateStoreMergeReq, AggregateStoreMergeResp,
+            DURABLE_AGGREGATE_STORE_CHECK_COLLECTED, DURABLE_AGGREGATE_STORE_EXCLUDE_REPORTS,
+            DURABLE_AGGREGATE_STORE_GET, DURABLE_AGGREGATE_STORE_GET_REPORT_CHECKSUMS,
             DURABLE_AGGREGATE_STORE_MARK_COLLECTED, DURABLE_AGGREGATE_STORE_MERGE,
         },
         durable_name_agg_store, durable_name_queue, durable_name_task,
         helper_state_store::{
             durable_helper_state_name, DURABLE_HELPER_STATE_GET, DURABLE_HELPER_STATE_PUT,
         },
-        leader_agg_job_queue::DURABLE_LEADER_AGG_JOB_QUEUE_GET,
+        leader_agg_job_queue::{
+            AggJobLease, LeaderAggJobQueueLeaseReq, DURABLE_LEADER_AGG_JOB_QUEUE_ACK,
+            DURABLE_LEADER_AGG_JOB_QUEUE_LEASE,
+        },
         leader_batch_queue::{
             BatchCount, DURABLE_LEADER_BATCH_QUEUE_ASSIGN, DURABLE_LEADER_BATCH_QUEUE_REMOVE,
         },
@@ -29,8 +34,10 @@ use crate::{
             DURABLE_LEADER_COL_JOB_QUEUE_FINISH, DURABLE_LEADER_COL_JOB_QUEUE_GET,
             DURABLE_LEADER_COL_JOB_QUEUE_GET_RESULT, DURABLE_LEADER_COL_JOB_QUEUE_PUT,
         },
+        report_id_hex_from_report,
         reports_pending::{
-            ReportsPendingResult, DURABLE_REPORTS_PENDING_GET, DURABLE_REPORTS_PENDING_PUT,
+            ReportsPendingResult, DURABLE_REPORTS_PENDING_DELETE, DURABLE_REPORTS_PENDING_GET,
+            DURABLE_REPORTS_PENDING_PUT, DURABLE_REPORTS_PENDING_PUT_MULTIPLE,
         },
         reports_processed::DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED,
         BINDING_DAP_AGGREGATE_STORE, BINDING_DAP_HELPER_STATE_STORE,
@@ -38,28 +45,33 @@ use crate::{
         BINDING_DAP_LEADER_COL_JOB_QUEUE, BINDING_DAP_REPORTS_PENDING,
         BINDING_DAP_REPORTS_PROCESSED,
     },
-    now, DaphneWorkerReportSelector,
+    now,
+    tracing_utils::current_trace_context,
+    DaphneWorkerReportSelector,
 };
 use async_trait::async_trait;
 use daphne::{
     auth::{BearerToken, BearerTokenProvider},
     constants,
-    hpke::HpkeDecrypter,
+    hpke::{HpkeDecrypter, HpkeReceiverConfig},
     messages::{
+        taskprov::{DpConfig, TaskConfig},
         BatchSelector, CollectReq, CollectResp, HpkeCiphertext, Id, PartialBatchSelector, Report,
         ReportId, ReportMetadata, TransitionFailure,
     },
     metrics::DaphneMetrics,
     roles::{early_metadata_check, DapAggregator, DapAuthorizedSender, DapHelper, DapLeader},
-    taskprov::{bad_request, get_taskprov_task_config},
-    DapAggregateShare, DapBatchBucket, DapCollectJob, DapError, DapGlobalConfig, DapHelperState,
-    DapOutputShare, DapQueryConfig, DapRequest, DapResponse, DapTaskConfig, DapVersion,
+    taskprov::{bad_request, TASKPROV_HEADER},
+    DapAbort, DapAggregateShare, DapBatchBucket, DapCollectJob, DapError, DapGlobalConfig,
+    DapHelperState, DapOutputShare, DapQueryConfig, DapRequest, DapResponse,
+    DapStatelessHelperConfig, DapTaskConfig, DapVersion,
 };
 use futures::future::try_join_all;
 use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+use rand::{seq::SliceRandom, thread_rng};
 use std::{
     borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
 };
 use tracing::{debug, error, info};
 use worker::*;
@@ -194,6 +206,22 @@ impl<'srv> HpkeDecrypter<'srv> for DaphneWorker<'srv> {
             Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId))
         }
     }
+
+    async fn get_hpke_receiver_config_by_id(
+        &self,
+        task_id: &Id,
+        config_id: u8,
+    ) -> std::result::Result<Option<HpkeReceiverConfig>, DapError> {
+        let version = self.try_get_task_config(task_id).await?.as_ref().version;
+        Ok(self
+            .get_hpke_receiver_config(HpkeReceiverKvKey {
+                version,
+                hpke_config_id: config_id,
+            })
+            .await
+            .map_err(dap_err)?
+            .map(|hpke_receiver_config| hpke_receiver_config.value().clone()))
+    }
 }
 
 #[async_trait(?Send)]
@@ -216,20 +244,32 @@ impl<'srv> BearerTokenProvider<'srv> for DaphneWorker<'srv> {
             .map_err(dap_err)
     }
 
-    fn is_taskprov_leader_bearer_token(&self, token: &BearerToken) -> bool {
-        self.get_global_config().allow_taskprov
-            && match &self.config().taskprov {
-                Some(config) => config.leader_bearer_token == *token,
-                None => false,
-            }
+    fn is_taskprov_leader_bearer_token(
+        &self,
+        taskprov_task_config: &TaskConfig,
+        token: &BearerToken,
+    ) -> std::result::Result<bool, DapError> {
+        if !self.get_global_config().allow_taskprov {
+            return Ok(false);
+        }
+        Ok(self
+            .config()
+            .taskprov_peer_credentials(taskprov_task_config)?
+            .map_or(false, |creds| creds.leader_bearer_token == *token))
     }
 
-    fn is_taskprov_collector_bearer_token(&self, token: &BearerToken) -> bool {
-        self.get_global_config().allow_taskprov
-            && match &self.config().taskprov {
-                Some(config) => config.collector_bearer_token == *token,
-                None => false,
-            }
+    fn is_taskprov_collector_bearer_token(
+        &self,
+        taskprov_task_config: &TaskConfig,
+        token: &BearerToken,
+    ) -> std::result::Result<bool, DapError> {
+        if !self.get_global_config().allow_taskprov {
+            return Ok(false);
+        }
+        Ok(self
+            .config()
+            .taskprov_peer_credentials(taskprov_task_config)?
+            .map_or(false, |creds| creds.collector_bearer_token == *token))
     }
 }
 
@@ -259,30 +299,63 @@ where
     async fn authorized(
         &self,
         req: &DapRequest<BearerToken>,
+        taskprov_task_config: Option<&TaskConfig>,
     ) -> std::result::Result<bool, DapError> {
-        self.bearer_token_authorized(req).await
+        self.bearer_token_authorized(req, taskprov_task_config)
+            .await
     }
 
     fn get_global_config(&self) -> &DapGlobalConfig {
         &self.config().global
     }
 
-    fn taskprov_opt_in_decision(
+    async fn taskprov_opt_in_decision(
         &self,
-        _task_config: &DapTaskConfig,
-    ) -> std::result::Result<bool, DapError> {
-        // For now we always opt-in.
-        Ok(true)
+        task_config: &DapTaskConfig,
+        dp_config: &DpConfig,
+    ) -> std::result::Result<(), DapError> {
+        let policy = &self
+            .config()
+            .taskprov
+            .as_ref()
+            .ok_or_else(|| DapError::fatal("taskprov configuration not found"))?
+            .opt_in_policy;
+
+        let peer_url = self.config().peer_url(task_config);
+
+        let peer_task_count = if policy.max_tasks_per_peer.is_some() {
+            self.get_taskprov_peer_task_count(peer_url)
+                .await
+                .map_err(dap_err)?
+        } else {
+            0
+        };
+
+        policy
+            .check(
+                task_config,
+                dp_config,
+                peer_url,
+                peer_task_count,
+                self.get_current_time(),
+            )
+            .map_err(|reason| {
+                info!("opted out of taskprov task: {reason}");
+                self.metrics().taskprov_opt_out_inc(reason);
+                DapError::Abort(DapAbort::TaskprovOptOut(format!(
+                    "task rejected by opt-in policy: {reason}"
+                )))
+            })
     }
 
     /// Get an existing task (whether an ordinary task or a previously created
-    /// taskprov task).  If we can't find it, see if there is a taskprov extension
-    /// in the report, and if so create the task.
+    /// taskprov task).  If we can't find it, see if a taskprov task configuration
+    /// was advertised, and if so create the task.
     async fn get_task_config_considering_taskprov(
         &'srv self,
         version: DapVersion,
         task_id: Cow<'req, Id>,
-        metadata: Option<&ReportMetadata>,
+        taskprov_task_config: Option<&TaskConfig>,
     ) -> std::result::Result<Option<GuardedDapTaskConfig<'req>>, DapError> {
         let found = self
             .get_task_config(task_id.clone())
@@ -292,17 +365,7 @@ where
             return Ok(found);
         }
         // Not found and no error.
-        if metadata.is_none() {
-            // No report metadata, so we're not going to find anything.
-            return Ok(None);
-        }
-        let metadata_ref = metadata.unwrap();
-        let taskprov_task_config = get_taskprov_task_config(
-            self.config().global.taskprov_version,
-            task_id.as_ref(),
-            metadata_ref,
-        )?;
-        if taskprov_task_config.is_some() {
+        if let Some(taskprov_task_config) = taskprov_task_config {
             let global = self.get_global_config();
             if !global.allow_taskprov {
                 // TODO(bhalleycf) if DAP gets a generic denied error, we should use it here.
@@ -315,37 +378,62 @@ where
                 .ok_or_else(|| DapError::fatal("taskprov configuration not found"))?;
 
             let taskprov_task_id = task_id.as_ref().clone();
+            let dp_config = taskprov_task_config.vdaf_config.dp_config.clone();
+
+            // Use the credentials shared with the peer named by the task configuration. The caller
+            // is expected to have checked that the request was authorized with these credentials.
+            let creds = self
+                .config()
+                .taskprov_peer_credentials(taskprov_task_config)?
+                .ok_or_else(|| DapError::fatal("taskprov configuration not found"))?;
+
             let task_config = DapTaskConfig::try_from_taskprov(
                 version,
                 self.config().global.taskprov_version,
                 &taskprov_task_id,
-                taskprov_task_config.unwrap(),
-                &taskprov.vdaf_verify_key_init,
-                taskprov.hpke_collector_config.as_ref(),
+                taskprov_task_config.clone(),
+                &creds.vdaf_verify_key_init,
+                &creds.hpke_collector_config,
             )?;
 
             // This is the opt-in / opt-out decision point.
-            if !self.taskprov_opt_in_decision(&task_config)? {
-                return Err(DapError::Abort(daphne::DapAbort::InvalidTask));
-            }
+            self.taskprov_opt_in_decision(&task_config, &dp_config)
+                .await?;
 
             // Write the leader bearer token to the KV.  We do this so authorize_with_bearer_token()
-            // finds something.
-            //
-            // TODO(bhalleycf) Note that this is generating KV garbage that will
-            // need collection at some point.
-            self.set_leader_bearer_token(&taskprov_task_id, taskprov.leader_bearer_token.as_ref())
+            // finds something. The token is deleted along with the task once the task expires.
+            self.set_leader_bearer_token(&taskprov_task_id, &creds.leader_bearer_token)
                 .await
                 .map_err(dap_err)?;
 
-            // Write the task config to the KV.
-            //
-            // TODO(bhalleycf) Note that this is generating KV garbage that will
-            // need collection at some point.
-            self.set_task_config(&taskprov_task_id, &task_config)
+            // Likewise, the Leader writes the collector bearer token so that subsequent collect
+            // requests are authorized with the peer's credentials.
+            if self.config().is_leader {
+                self.set_collector_bearer_token(&taskprov_task_id, &creds.collector_bearer_token)
+                    .await
+                    .map_err(dap_err)?;
+            }
+
+            // Write the task config to the KV. The task config is deleted once the task expires.
+            let existing = self
+                .set_task_config(&taskprov_task_id, &task_config)
                 .await
                 .map_err(dap_err)?;
 
+            if existing.is_none() {
+                // Count the task against the peer's limit.
+                if taskprov.opt_in_policy.max_tasks_per_peer.is_some() {
+                    self.inc_taskprov_peer_task_count(self.config().peer_url(&task_config))
+                        .await
+                        .map_err(dap_err)?;
+                }
+
+                // Schedule the task's KV entries and durable objects for deletion.
+                self.put_taskprov_task_garbage(&taskprov_task_id, &task_config)
+                    .await
+                    .map_err(dap_err)?;
+            }
+
             // Do the usual get again so we cache and return the right type.
             self.get_task_config(Cow::Owned(taskprov_task_id))
                 .await
@@ -359,6 +447,10 @@ where
         now()
     }
 
+    fn get_current_time_millis(&self) -> u64 {
+        Date::now().as_millis()
+    }
+
     async fn is_batch_overlapping(
         &self,
         task_id: &Id,
@@ -419,27 +511,61 @@ where
     async fn put_out_shares(
         &self,
         task_id: &Id,
+        agg_job_id: &Id,
         part_batch_sel: &PartialBatchSelector,
         out_shares: Vec<DapOutputShare>,
     ) -> std::result::Result<(), DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
 
         let durable = self.durable();
-        let mut requests = Vec::new();
-        for (bucket, agg_share) in task_config
+        let span = task_config
             .as_ref()
-            .batch_span_for_out_shares(part_batch_sel, out_shares)?
-        {
+            .report_shares_for_out_shares(part_batch_sel, out_shares)?;
+
+        // Check every bucket before merging into any of them, so that the output shares are not
+        // merged into some buckets but not others. Each merge also checks that its bucket has not
+        // been collected, in case the bucket is collected in the meantime.
+        let mut requests = Vec::new();
+        for bucket in span.keys() {
+            let durable_name =
+                durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), bucket);
+            requests.push(durable.get::<bool>(
+                BINDING_DAP_AGGREGATE_STORE,
+                DURABLE_AGGREGATE_STORE_CHECK_COLLECTED,
+                durable_name,
+            ));
+        }
+        let collected = try_join_all(requests).await.map_err(dap_err)?;
+        if collected.into_iter().any(|collected| collected) {
+            return Err(DapError::Abort(DapAbort::BatchOverlap));
+        }
+
+        // If batch reconciliation is enabled, then the output share of each report is stored
+        // along with the aggregate share so that the report can be excluded later.
+        let reconcile = self.config().global.reconcile_batch_mismatch;
+        let mut requests = Vec::new();
+        for (bucket, report_shares) in span {
             let durable_name =
                 durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), &bucket);
-            requests.push(durable.post::<_, ()>(
+            let mut agg_share = DapAggregateShare::default();
+            for report_share in report_shares.iter() {
+                agg_share.merge(report_share.clone())?;
+            }
+            requests.push(durable.post::<_, AggregateStoreMergeResp>(
                 BINDING_DAP_AGGREGATE_STORE,
                 DURABLE_AGGREGATE_STORE_MERGE,
                 durable_name,
-                agg_share,
+                AggregateStoreMergeReq {
+                    agg_job_id: agg_job_id.clone(),
+                    agg_share_delta: agg_share,
+                    report_shares: if reconcile { report_shares } else { Vec::new() },
+                },
             ));
         }
-        try_join_all(requests).await.map_err(dap_err)?;
+        let responses = try_join_all(requests).await.map_err(dap_err)?;
+        if responses.contains(&AggregateStoreMergeResp::AlreadyCollected) {
+            return Err(DapError::Abort(DapAbort::BatchOverlap));
+        }
         Ok(())
     }
 
@@ -470,6 +596,63 @@ where
         Ok(agg_share)
     }
 
+    async fn get_report_checksums(
+        &self,
+        task_id: &Id,
+        batch_sel: &BatchSelector,
+    ) -> std::result::Result<Vec<[u8; 32]>, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+
+        let durable = self.durable();
+        let mut requests = Vec::new();
+        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
+            let durable_name =
+                durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), &bucket);
+            requests.push(durable.get::<Vec<[u8; 32]>>(
+                BINDING_DAP_AGGREGATE_STORE,
+                DURABLE_AGGREGATE_STORE_GET_REPORT_CHECKSUMS,
+                durable_name,
+            ));
+        }
+        let responses = try_join_all(requests).await.map_err(dap_err)?;
+        Ok(responses.into_iter().flatten().collect())
+    }
+
+    async fn exclude_reports(
+        &self,
+        task_id: &Id,
+        batch_sel: &BatchSelector,
+        checksums: &[[u8; 32]],
+    ) -> std::result::Result<u64, DapError> {
+        if checksums.is_empty() {
+            return Ok(0);
+        }
+        let task_config = self.try_get_task_config(task_id).await?;
+
+        let durable = self.durable();
+        let mut requests = Vec::new();
+        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
+            let durable_name =
+                durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), &bucket);
+            requests.push(durable.post::<_, AggregateStoreExcludeResp>(
+                BINDING_DAP_AGGREGATE_STORE,
+                DURABLE_AGGREGATE_STORE_EXCLUDE_REPORTS,
+                durable_name,
+                checksums,
+            ));
+        }
+        let mut excluded = 0;
+        for resp in try_join_all(requests).await.map_err(dap_err)? {
+            match resp {
+                AggregateStoreExcludeResp::Ok(count) => excluded += count,
+                AggregateStoreExcludeResp::AlreadyCollected => {
+                    return Err(DapError::Abort(DapAbort::BatchOverlap))
+                }
+            }
+        }
+        Ok(excluded)
+    }
+
     async fn check_early_reject<'b>(
         &self,
         task_id: &Id,
@@ -495,16 +678,17 @@ where
             ));
             agg_store_request_bucket.push(bucket);
             for metadata in report_meta {
-                let durable_name = self.config().durable_name_report_store(
+                let report_id_hex = hex::encode(metadata.id.get_encoded());
+                for durable_name in self.config().durable_names_report_processed(
                     task_config.as_ref(),
                     &task_id_hex,
                     metadata,
-                );
-                let report_id_hex = hex::encode(metadata.id.get_encoded());
-                let report_id_hex_set = reports_processed_request_data
-                    .entry(durable_name)
-                    .or_default();
-                report_id_hex_set.push(report_id_hex);
+                ) {
+                    reports_processed_request_data
+                        .entry(durable_name)
+                        .or_default()
+                        .push(report_id_hex.clone());
+                }
             }
         }
 
@@ -603,115 +787,272 @@ where
     }
 }
 
-fn task_id_from_report(report: &[u8]) -> std::result::Result<Id, DapError> {
+fn task_id_from_report_hex(report_hex: &str) -> Option<Id> {
     // The task id MUST BE the first 32 bytes of the serialized report; if this
     // needs to change in the future, then we must change the DO serialization
     // format to contain a version.
-    let id = Id(report[..32]
-        .try_into()
-        .map_err(|_| DapError::fatal("serialize report is too short"))?);
-    Ok(id)
+    let task_id_data = hex::decode(report_hex.get(..64)?).ok()?;
+    Some(Id(task_id_data.try_into().ok()?))
 }
 
-#[async_trait(?Send)]
-impl<'srv, 'req> DapLeader<'srv, 'req, BearerToken> for DaphneWorker<'srv>
-where
-    'srv: 'req,
-{
-    type ReportSelector = DaphneWorkerReportSelector;
+/// A report fetched from `ReportsPending` that can't be aggregated.
+#[derive(Debug)]
+pub(crate) struct DroppedReport {
+    /// The task to which the report belongs, if known.
+    pub(crate) task_id: Option<Id>,
 
-    async fn put_report(&self, report: &Report) -> std::result::Result<(), DapError> {
-        let task_config = self.try_get_task_config(&report.task_id).await?;
-        let task_id_hex = report.task_id.to_hex();
-        let report_hex = hex::encode(report.get_encoded_with_param(&task_config.as_ref().version));
-        let res: ReportsPendingResult = self
-            .durable()
-            .post(
+    /// The ID of the report (hex-encoded), if known. This is used to remove the report from
+    /// `ReportsPending`.
+    pub(crate) report_id_hex: Option<String>,
+
+    /// The status under which the report is counted in the report metrics.
+    pub(crate) status: &'static str,
+}
+
+/// Decode hex-encoded reports fetched from `ReportsPending` and group them by task. `versions`
+/// maps the ID of each task to the task's version, or to `None` if the task is not recognized,
+/// e.g., because it was deleted while it still had pending reports. Reports for unrecognized tasks
+/// and reports that can't be decoded are returned separately, so that they can be dropped without
+/// holding up the others.
+pub(crate) fn decode_pending_reports(
+    reports_hex: Vec<String>,
+    versions: &HashMap<Id, Option<DapVersion>>,
+) -> (HashMap<Id, Vec<Report>>, Vec<DroppedReport>) {
+    let mut reports_per_task: HashMap<Id, Vec<Report>> = HashMap::new();
+    let mut dropped = Vec::new();
+    for report_hex in reports_hex.into_iter() {
+        let report_id_hex = report_id_hex_from_report(&report_hex).map(str::to_string);
+        let task_id = task_id_from_report_hex(&report_hex);
+        let version = match task_id.as_ref().map(|task_id| versions.get(task_id)) {
+            Some(Some(Some(version))) => version,
+            Some(_) => {
+                dropped.push(DroppedReport {
+                    task_id,
+                    report_id_hex,
+                    status: "dropped_task_unrecognized",
+                });
+                continue;
+            }
+            None => {
+                dropped.push(DroppedReport {
+                    task_id,
+                    report_id_hex,
+                    status: "dropped_malformed",
+                });
+                continue;
+            }
+        };
+
+        match hex::decode(&report_hex)
+            .ok()
+            .and_then(|report_bytes| Report::get_decoded_with_param(version, &report_bytes).ok())
+        {
+            Some(report) => reports_per_task
+                .entry(report.task_id.clone())
+                .or_default()
+                .push(report),
+            None => dropped.push(DroppedReport {
+                task_id,
+                report_id_hex,
+                status: "dropped_malformed",
+            }),
+        }
+    }
+    (reports_per_task, dropped)
+}
+
+impl DaphneWorker<'_> {
+    /// Store a sequence of reports in `ReportsPending`, bypassing upload rate limits. Return the
+    /// set of reports that were rejected because a report with the same ID is already pending.
+    pub(crate) async fn store_reports(
+        &self,
+        reports: &[Report],
+    ) -> std::result::Result<HashMap<ReportId, TransitionFailure>, DapError> {
+        // Look up the version of each task once, rather than once per report.
+        let mut versions: HashMap<&Id, DapVersion> = HashMap::new();
+        for report in reports.iter() {
+            if !versions.contains_key(&report.task_id) {
+                let task_config = self.try_get_task_config(&report.task_id).await?;
+                versions.insert(&report.task_id, task_config.as_ref().version);
+            }
+        }
+
+        // Group the reports by ReportsPending instance so that each instance is written to once.
+        let mut reports_pending_request_data: HashMap<String, Vec<String>> = HashMap::new();
+        for report in reports.iter() {
+            let version = &versions[&report.task_id];
+            let durable_name = self.config().durable_name_report_store(
+                version,
+                &report.task_id.to_hex(),
+                &report.metadata,
+            );
+            reports_pending_request_data
+                .entry(durable_name)
+                .or_default()
+                .push(hex::encode(report.get_encoded_with_param(version)));
+        }
+
+        let durable = self.durable();
+        let mut reports_pending_requests = Vec::new();
+        for (durable_name, report_hex_set) in reports_pending_request_data.into_iter() {
+            reports_pending_requests.push(durable.post(
                 BINDING_DAP_REPORTS_PENDING,
-                DURABLE_REPORTS_PENDING_PUT,
-                self.config().durable_name_report_store(
-                    task_config.as_ref(),
-                    &task_id_hex,
-                    &report.metadata,
-                ),
-                &report_hex,
-            )
+                DURABLE_REPORTS_PENDING_PUT_MULTIPLE,
+                durable_name,
+                report_hex_set,
+            ));
+        }
+
+        let reports_pending_responses: Vec<Vec<String>> = try_join_all(reports_pending_requests)
             .await
             .map_err(dap_err)?;
+        let mut rejected = HashMap::new();
+        for response in reports_pending_responses.into_iter() {
+            for report_id_hex in response.into_iter() {
+                let report_id = ReportId::get_decoded(&hex::decode(report_id_hex)?)?;
+                rejected.insert(report_id, TransitionFailure::ReportReplayed);
+            }
+        }
+        Ok(rejected)
+    }
 
-        match res {
-            ReportsPendingResult::Ok => Ok(()),
-            ReportsPendingResult::ErrReportExists => {
-                // NOTE This check for report replay is not definitive. It's possible for two
-                // reports with the same ID to appear in two different ReportsPending instances.
-                // The definitive check is performed by DapAggregator::check_early_reject(), which
-                // tracks all report IDs consumed for the task in ReportsProcessed. This check
-                // would be too expensive to do during the upload sub-protocol.
-                Err(DapError::Transition(TransitionFailure::ReportReplayed))
+    /// Leader: Release reports fetched by `get_reports()`. If `delete` is set, then the reports
+    /// are removed from the `ReportsPending` instances they were fetched from. The leases whose
+    /// reports have all been released are acknowledged. Reports that were not fetched under a
+    /// lease, e.g., by a `ReportsPending` alarm, are ignored.
+    async fn release_reports(
+        &self,
+        report_ids_hex: impl IntoIterator<Item = String>,
+        delete: bool,
+    ) -> std::result::Result<(), DapError> {
+        let released = self
+            .state
+            .agg_job_leases
+            .lock()
+            .map_err(|e| DapError::Fatal(e.to_string()))?
+            .release(report_ids_hex);
+
+        if delete {
+            for (reports_pending_id_hex, report_ids_hex) in released.reports.into_iter() {
+                self.durable()
+                    .post_by_id_hex::<_, ()>(
+                        BINDING_DAP_REPORTS_PENDING,
+                        DURABLE_REPORTS_PENDING_DELETE,
+                        reports_pending_id_hex,
+                        &report_ids_hex,
+                    )
+                    .await
+                    .map_err(dap_err)?;
             }
         }
+
+        for (shard, lease) in released.leases.into_iter() {
+            self.ack_agg_job_lease(shard, &lease).await?;
+        }
+        Ok(())
     }
 
-    async fn get_reports(
+    /// Leader: Acknowledge reports fetched from `ReportsPending` that can't be aggregated, so that
+    /// they are removed from storage, and count them in the report metrics.
+    async fn drop_pending_reports(
         &self,
-        report_sel: &DaphneWorkerReportSelector,
-    ) -> std::result::Result<HashMap<Id, HashMap<PartialBatchSelector, Vec<Report>>>, DapError>
-    {
-        let durable = self.durable();
-        // Read at most `report_sel.max_buckets` buckets from the agg job queue. The result is ordered
-        // from oldest to newest.
-        //
-        // NOTE There is only one agg job queue for now (`queue_num == 0`). In the future, work
-        // will be sharded across multiple queues.
-        let res: Vec<String> = durable
+        dropped: Vec<DroppedReport>,
+    ) -> std::result::Result<(), DapError> {
+        let mut report_ids_hex = Vec::with_capacity(dropped.len());
+        for dropped_report in dropped.into_iter() {
+            let DroppedReport {
+                task_id,
+                report_id_hex,
+                status,
+            } = dropped_report;
+            match task_id {
+                Some(ref task_id) => {
+                    debug!("dropping report for task {task_id}: {status}");
+                    self.metrics().report_inc_by(task_id, status, 1);
+                }
+                None => {
+                    error!("dropping report for unknown task: {status}");
+                    self.metrics().report_inc_by_status(status, 1);
+                }
+            }
+            report_ids_hex.extend(report_id_hex);
+        }
+        self.release_reports(report_ids_hex, true).await
+    }
+
+    /// Leader: Acknowledge the lease for an aggregation job taken from the given queue shard.
+    async fn ack_agg_job_lease(
+        &self,
+        shard: u64,
+        lease: &AggJobLease,
+    ) -> std::result::Result<(), DapError> {
+        let held: bool = self
+            .durable()
             .post(
                 BINDING_DAP_LEADER_AGG_JOB_QUEUE,
-                DURABLE_LEADER_AGG_JOB_QUEUE_GET,
-                durable_name_queue(0),
-                &report_sel.max_agg_jobs,
+                DURABLE_LEADER_AGG_JOB_QUEUE_ACK,
+                durable_name_queue(shard),
+                lease,
             )
             .await
             .map_err(dap_err)?;
+        if !held {
+            debug!(
+                "lease for agg job {} was finished or expired before it was acknowledged",
+                lease.agg_job.as_ref()
+            );
+        }
+        Ok(())
+    }
 
-        // Drain at most `report_sel.max_reports` from each ReportsPending instance and group them
-        // by task.
-        //
-        // TODO Figure out if we can safely handle each instance in parallel.
-        let mut reports_per_task: HashMap<Id, Vec<Report>> = HashMap::new();
-        for reports_pending_id_hex in res.into_iter() {
-            let reports_from_durable: Vec<String> = durable
-                .post_by_id_hex(
-                    BINDING_DAP_REPORTS_PENDING,
-                    DURABLE_REPORTS_PENDING_GET,
-                    reports_pending_id_hex,
-                    &report_sel.max_reports,
-                )
-                .await
-                .map_err(dap_err)?;
+    /// Decode a set of hex-encoded reports fetched from `ReportsPending` and group them by task,
+    /// then by partial batch selector. Reports for fixed-size tasks are assigned to batches by
+    /// `LeaderBatchQueue`.
+    ///
+    /// Reports that can't be decoded, or whose task is no longer recognized, are acknowledged so
+    /// that they are removed from storage, and counted in the report metrics with status
+    /// `dropped_malformed` or `dropped_task_unrecognized` respectively.
+    pub(crate) async fn group_reports(
+        &self,
+        reports_hex: Vec<String>,
+    ) -> std::result::Result<HashMap<Id, HashMap<PartialBatchSelector, Vec<Report>>>, DapError>
+    {
+        let durable = self.durable();
 
-            for report_hex in reports_from_durable {
-                let report_bytes = hex::decode(&report_hex).map_err(|_| {
-                    DapError::fatal("response from ReportsPending is not valid hex")
-                })?;
-                let task_id = task_id_from_report(&report_bytes)?;
-                let task_config = self.try_get_task_config(&task_id).await?;
-                let report =
-                    Report::get_decoded_with_param(&task_config.as_ref().version, &report_bytes)?;
-                if let Some(reports) = reports_per_task.get_mut(&report.task_id) {
-                    reports.push(report);
-                } else {
-                    reports_per_task.insert(report.task_id.clone(), vec![report]);
+        // Look up the configuration of each task once, rather than once per report.
+        let mut task_configs: HashMap<Id, Option<GuardedDapTaskConfig<'_>>> = HashMap::new();
+        for report_hex in reports_hex.iter() {
+            if let Some(task_id) = task_id_from_report_hex(report_hex) {
+                if let Entry::Vacant(entry) = task_configs.entry(task_id) {
+                    let task_config = self
+                        .get_task_config(Cow::Owned(entry.key().clone()))
+                        .await
+                        .map_err(dap_err)?;
+                    entry.insert(task_config);
                 }
             }
         }
+        let versions = task_configs
+            .iter()
+            .map(|(task_id, task_config)| {
+                let version = task_config
+                    .as_ref()
+                    .map(|task_config| task_config.as_ref().version);
+                (task_id.clone(), version)
+            })
+            .collect();
+
+        let (reports_per_task, dropped) = decode_pending_reports(reports_hex, &versions);
+        if !dropped.is_empty() {
+            self.drop_pending_reports(dropped).await?;
+        }
 
         let mut reports_per_task_part: HashMap<Id, HashMap<PartialBatchSelector, Vec<Report>>> =
             HashMap::new();
         for (task_id, mut reports) in reports_per_task.into_iter() {
-            let task_config = self
-                .get_task_config(Cow::Owned(task_id))
-                .await
-                .map_err(dap_err)?
+            let task_config = task_configs
+                .get(&task_id)
+                .and_then(Option::as_ref)
                 .ok_or_else(|| DapError::fatal("unrecognized task"))?;
             let task_id_hex = task_config.key().to_hex();
             let reports_per_part = reports_per_task_part
@@ -723,15 +1064,16 @@ where
                 }
                 DapQueryConfig::FixedSize { .. } => {
                     let num_unassigned = reports.len();
-                    let batch_assignments: Vec<BatchCount> = durable
-                        .post(
-                            BINDING_DAP_LEADER_BATCH_QUEUE,
-                            DURABLE_LEADER_BATCH_QUEUE_ASSIGN,
-                            durable_name_task(&task_config.as_ref().version, &task_id_hex),
-                            &(task_config.as_ref().min_batch_size, num_unassigned),
-                        )
-                        .await
-                        .map_err(dap_err)?;
+                    let (batch_assignments, current_report_count): (Vec<BatchCount>, usize) =
+                        durable
+                            .post(
+                                BINDING_DAP_LEADER_BATCH_QUEUE,
+                                DURABLE_LEADER_BATCH_QUEUE_ASSIGN,
+                                durable_name_task(&task_config.as_ref().version, &task_id_hex),
+                                &(task_config.as_ref().min_batch_size, num_unassigned),
+                            )
+                            .await
+                            .map_err(dap_err)?;
                     for batch_count in batch_assignments.into_iter() {
                         let BatchCount {
                             batch_id,
@@ -742,6 +1084,11 @@ where
                             reports.drain(..report_count).collect(),
                         );
                     }
+                    self.metrics().set_batch_fill_level(
+                        task_config.key(),
+                        current_report_count as u64,
+                        task_config.as_ref().min_batch_size,
+                    );
                     if !reports.is_empty() {
                         return Err(DapError::Fatal(
                             format!("LeaderBatchQueue returned the wrong number of reports: got {}; want {}",
@@ -765,6 +1112,218 @@ where
         }
         Ok(reports_per_task_part)
     }
+}
+
+#[async_trait(?Send)]
+impl<'srv, 'req> DapLeader<'srv, 'req, BearerToken> for DaphneWorker<'srv>
+where
+    'srv: 'req,
+{
+    type ReportSelector = DaphneWorkerReportSelector;
+
+    async fn put_report(&self, report: &Report) -> std::result::Result<(), DapError> {
+        let task_config = self.try_get_task_config(&report.task_id).await?;
+        self.acquire_upload_rate_limits(&task_config.as_ref().version, &report.task_id, 1)
+            .await?;
+
+        let task_id_hex = report.task_id.to_hex();
+        let report_hex = hex::encode(report.get_encoded_with_param(&task_config.as_ref().version));
+        let res: ReportsPendingResult = self
+            .durable()
+            .post(
+                BINDING_DAP_REPORTS_PENDING,
+                DURABLE_REPORTS_PENDING_PUT,
+                self.config().durable_name_report_store(
+                    &task_config.as_ref().version,
+                    &task_id_hex,
+                    &report.metadata,
+                ),
+                &report_hex,
+            )
+            .await
+            .map_err(dap_err)?;
+
+        match res {
+            ReportsPendingResult::Ok => Ok(()),
+            ReportsPendingResult::ErrReportExists => {
+                // Return the token, so that replayed reports don't count towards the limits.
+                self.release_upload_rate_limits(&task_config.as_ref().version, &report.task_id, 1)
+                    .await?;
+
+                // NOTE This check for report replay is not definitive. It's possible for two
+                // reports with the same ID to appear in two different ReportsPending instances.
+                // The definitive check is performed by DapAggregator::check_early_reject(), which
+                // tracks all report IDs consumed for the task in ReportsProcessed. This check
+                // would be too expensive to do during the upload sub-protocol.
+                Err(DapError::Transition(TransitionFailure::ReportReplayed))
+            }
+        }
+    }
+
+    async fn put_reports(
+        &self,
+        reports: &[Report],
+    ) -> std::result::Result<HashMap<ReportId, TransitionFailure>, DapError> {
+        let mut report_counts: HashMap<&Id, u64> = HashMap::new();
+        for report in reports.iter() {
+            *report_counts.entry(&report.task_id).or_default() += 1;
+        }
+        let mut versions: HashMap<&Id, DapVersion> = HashMap::with_capacity(report_counts.len());
+        for (task_id, report_count) in report_counts.iter() {
+            let task_config = self.try_get_task_config(task_id).await?;
+            let version = task_config.as_ref().version;
+            if let Err(e) = self
+                .acquire_upload_rate_limits(&version, task_id, *report_count)
+                .await
+            {
+                // The bulk upload is rejected as a whole, so return the tokens taken for the
+                // other tasks.
+                for (task_id, version) in versions.iter() {
+                    self.release_upload_rate_limits(version, task_id, report_counts[task_id])
+                        .await?;
+                }
+                return Err(e);
+            }
+            versions.insert(task_id, version);
+        }
+
+        // NOTE As for `put_report()`, this check for report replay is not definitive.
+        let rejected = self.store_reports(reports).await?;
+
+        // Return the tokens for the reports that were rejected.
+        let mut counted = HashSet::with_capacity(rejected.len());
+        let mut rejected_counts: HashMap<&Id, u64> = HashMap::new();
+        for report in reports.iter() {
+            if rejected.contains_key(&report.metadata.id) && counted.insert(&report.metadata.id) {
+                *rejected_counts.entry(&report.task_id).or_default() += 1;
+            }
+        }
+        for (task_id, rejected_count) in rejected_counts.into_iter() {
+            self.release_upload_rate_limits(&versions[task_id], task_id, rejected_count)
+                .await?;
+        }
+
+        Ok(rejected)
+    }
+
+    async fn put_back_reports(
+        &self,
+        task_id: &Id,
+        part_batch_sel: &PartialBatchSelector,
+        reports: Vec<Report>,
+    ) -> std::result::Result<(), DapError> {
+        if !matches!(part_batch_sel, PartialBatchSelector::TimeInterval) {
+            return Err(DapError::fatal(
+                "cannot put back reports assigned to a fixed-size batch",
+            ));
+        }
+
+        // The reports are still pending, as they are only deleted once they are acknowledged, so
+        // there is nothing to put back. Just release them so that the lease is acknowledged once
+        // the other reports fetched under it have been handled.
+        debug!("put back {} reports for task {task_id}", reports.len());
+        self.release_reports(
+            reports.iter().map(|report| report.metadata.id.to_hex()),
+            false,
+        )
+        .await
+    }
+
+    async fn ack_reports(
+        &self,
+        _task_id: &Id,
+        report_ids: &[ReportId],
+    ) -> std::result::Result<(), DapError> {
+        self.release_reports(report_ids.iter().map(ReportId::to_hex), true)
+            .await
+    }
+
+    async fn get_reports(
+        &self,
+        report_sel: &DaphneWorkerReportSelector,
+    ) -> std::result::Result<HashMap<Id, HashMap<PartialBatchSelector, Vec<Report>>>, DapError>
+    {
+        let durable = self.durable();
+        let shard_count = self.config().agg_job_queue_shard_count;
+        let mut shards = match report_sel.agg_job_queue_shards {
+            Some(ref shards) => {
+                if let Some(shard) = shards.iter().find(|shard| **shard >= shard_count) {
+                    return Err(DapError::Fatal(format!(
+                        "report selector: agg job queue shard {shard} out of range: have {shard_count} shards"
+                    )));
+                }
+                shards.clone()
+            }
+            None => (0..shard_count).collect(),
+        };
+
+        // Lease at most `report_sel.max_agg_jobs` buckets from the agg job queues. The jobs from
+        // each shard are ordered from oldest to newest. The shards are visited in random order so
+        // that a busy shard does not starve the others. Leased jobs are not handed out to other
+        // processors until they are acknowledged or the lease expires.
+        shards.shuffle(&mut thread_rng());
+        let mut res: Vec<(u64, AggJobLease)> = Vec::new();
+        for shard in shards.into_iter() {
+            let max_agg_jobs = report_sel.max_agg_jobs.saturating_sub(res.len() as u64);
+            if max_agg_jobs == 0 {
+                break;
+            }
+            let leases: Vec<AggJobLease> = durable
+                .post(
+                    BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+                    DURABLE_LEADER_AGG_JOB_QUEUE_LEASE,
+                    durable_name_queue(shard),
+                    &LeaderAggJobQueueLeaseReq {
+                        max_agg_jobs,
+                        lease_secs: self.config().agg_job_lease_secs,
+                        agg_job: None,
+                    },
+                )
+                .await
+                .map_err(dap_err)?;
+            res.extend(leases.into_iter().map(|lease| (shard, lease)));
+        }
+
+        // Fetch at most `report_sel.max_reports` from each ReportsPending instance. The reports
+        // are left in storage until they are acknowledged (see `ack_reports()`); the lease is
+        // acknowledged once all of them have been released, so that the job is handed out again
+        // if reports remain.
+        //
+        // TODO Figure out if we can safely handle each instance in parallel.
+        let mut reports_hex = Vec::new();
+        for (shard, lease) in res.into_iter() {
+            let reports_from_durable: Vec<String> = durable
+                .post_by_id_hex(
+                    BINDING_DAP_REPORTS_PENDING,
+                    DURABLE_REPORTS_PENDING_GET,
+                    lease.agg_job.as_ref().clone(),
+                    &report_sel.max_reports,
+                )
+                .await
+                .map_err(dap_err)?;
+            if reports_from_durable.is_empty() {
+                self.ack_agg_job_lease(shard, &lease).await?;
+                continue;
+            }
+
+            let report_ids_hex = reports_from_durable
+                .iter()
+                .map(|report_hex| {
+                    report_id_hex_from_report(report_hex)
+                        .map(str::to_string)
+                        .ok_or_else(|| DapError::fatal("failed to parse report_id from report"))
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            self.state
+                .agg_job_leases
+                .lock()
+                .map_err(|e| DapError::Fatal(e.to_string()))?
+                .insert(shard, lease, report_ids_hex);
+            reports_hex.extend(reports_from_durable);
+        }
+
+        self.group_reports(reports_hex).await
+    }
 
     async fn init_collect_job(
         &self,
@@ -870,10 +1429,10 @@ where
         &self,
         req: DapRequest<BearerToken>,
     ) -> std::result::Result<DapResponse, DapError> {
-        let (payload, url) = (req.payload, req.url);
+        let (payload, url, media_type) = (req.payload, req.url, req.media_type);
 
         let mut headers = reqwest_wasm::header::HeaderMap::new();
-        if let Some(content_type) = req.media_type {
+        if let Some(content_type) = media_type {
             headers.insert(
                 reqwest_wasm::header::CONTENT_TYPE,
                 reqwest_wasm::header::HeaderValue::from_str(content_type)
@@ -889,6 +1448,24 @@ where
             );
         }
 
+        // Advertise the task configuration if the task was provisioned via taskprov.
+        if let Some(ref taskprov) = req.taskprov {
+            headers.insert(
+                reqwest_wasm::header::HeaderName::from_static(TASKPROV_HEADER),
+                reqwest_wasm::header::HeaderValue::from_str(taskprov)
+                    .map_err(|e| DapError::Fatal(e.to_string()))?,
+            );
+        }
+
+        // Propagate the trace context so that the Helper's spans are linked to ours.
+        if let Some(trace_context) = current_trace_context() {
+            headers.insert(
+                reqwest_wasm::header::HeaderName::from_static("traceparent"),
+                reqwest_wasm::header::HeaderValue::from_str(&trace_context.to_traceparent())
+                    .map_err(|e| DapError::Fatal(e.to_string()))?,
+            );
+        }
+
         let reqwest_req = self
             .isolate_state()
             .client
@@ -903,6 +1480,11 @@ where
             .map_err(|e| DapError::Fatal(e.to_string()))?;
         let end = Date::now().as_millis();
         info!("request to {} completed in {}ms", url, end - start);
+        self.state
+            .metrics
+            .helper_request_duration
+            .with_label_values(&[media_type.unwrap_or("none")])
+            .observe(end.saturating_sub(start) as f64 / 1000.0);
         let status = reqwest_resp.status();
         if status == 200 {
             // Translate the reqwest response into a Worker response.
@@ -936,6 +1518,10 @@ impl<'srv, 'req> DapHelper<'srv, 'req, BearerToken> for DaphneWorker<'srv>
 where
     'srv: 'req,
 {
+    fn get_stateless_helper_config(&self) -> Option<&DapStatelessHelperConfig> {
+        self.config().stateless_helper.as_ref()
+    }
+
     async fn put_helper_state(
         &self,
         task_id: &Id,
@@ -0,0 +1,98 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::dap::decode_pending_reports;
+use daphne::{
+    messages::{HpkeCiphertext, Id, Report, ReportId, ReportMetadata},
+    DapVersion,
+};
+use prio::codec::ParameterizedEncode;
+use rand::prelude::*;
+use std::collections::HashMap;
+
+fn gen_report_hex(task_id: &Id, version: DapVersion) -> (ReportId, String) {
+    let mut rng = thread_rng();
+    let report = Report {
+        task_id: task_id.clone(),
+        metadata: ReportMetadata {
+            id: ReportId(rng.gen()),
+            time: 1_637_361_337,
+            extensions: Vec::default(),
+        },
+        public_share: b"public share".to_vec(),
+        encrypted_input_shares: vec![
+            HpkeCiphertext {
+                config_id: 23,
+                enc: b"leader encapsulated key".to_vec(),
+                payload: b"leader ciphertext".to_vec(),
+            },
+            HpkeCiphertext {
+                config_id: 14,
+                enc: b"helper encapsulated key".to_vec(),
+                payload: b"helper ciphertext".to_vec(),
+            },
+        ],
+    };
+    (
+        report.metadata.id.clone(),
+        hex::encode(report.get_encoded_with_param(&version)),
+    )
+}
+
+#[test]
+fn decode_pending_reports_for_deleted_task() {
+    let version = DapVersion::Draft02;
+    let task_id = Id([1; 32]);
+    let deleted_task_id = Id([2; 32]);
+
+    let (report_id, report_hex) = gen_report_hex(&task_id, version);
+    let (deleted_report_id, deleted_report_hex) = gen_report_hex(&deleted_task_id, version);
+
+    // The task was deleted while it still had pending reports.
+    let versions = HashMap::from([
+        (task_id.clone(), Some(version)),
+        (deleted_task_id.clone(), None),
+    ]);
+
+    let (reports_per_task, dropped) =
+        decode_pending_reports(vec![deleted_report_hex, report_hex], &versions);
+
+    assert_eq!(reports_per_task.len(), 1);
+    let reports = reports_per_task.get(&task_id).unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].metadata.id, report_id);
+
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].task_id, Some(deleted_task_id));
+    assert_eq!(dropped[0].report_id_hex, Some(deleted_report_id.to_hex()));
+    assert_eq!(dropped[0].status, "dropped_task_unrecognized");
+}
+
+#[test]
+fn decode_pending_reports_malformed() {
+    let version = DapVersion::Draft02;
+    let task_id = Id([1; 32]);
+
+    let (report_id, report_hex) = gen_report_hex(&task_id, version);
+    let (truncated_report_id, truncated_report_hex) = gen_report_hex(&task_id, version);
+    let truncated_report_hex = truncated_report_hex[..truncated_report_hex.len() - 2].to_string();
+
+    let versions = HashMap::from([(task_id.clone(), Some(version))]);
+
+    let (reports_per_task, dropped) = decode_pending_reports(
+        vec![report_hex, truncated_report_hex, "not a report".to_string()],
+        &versions,
+    );
+
+    let reports = reports_per_task.get(&task_id).unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].metadata.id, report_id);
+
+    assert_eq!(dropped.len(), 2);
+    assert_eq!(dropped[0].task_id, Some(task_id));
+    assert_eq!(dropped[0].report_id_hex, Some(truncated_report_id.to_hex()));
+    assert_eq!(dropped[0].status, "dropped_malformed");
+    assert_eq!(dropped[1].task_id, None);
+    assert_eq!(dropped[1].report_id_hex, None);
+    assert_eq!(dropped[1].status, "dropped_malformed");
+}
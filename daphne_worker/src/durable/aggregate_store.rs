@@ -3,10 +3,16 @@
 
 use crate::{
     config::DaphneWorkerConfig,
-    durable::{state_get_or_default, BINDING_DAP_AGGREGATE_STORE},
+    durable::{
+        state_get_or_default, state_get_versioned_or_default, versioned, DurableStorageHandle,
+        DurableVersioned, Versioned, BINDING_DAP_AGGREGATE_STORE,
+    },
     initialize_tracing, int_err,
 };
-use daphne::DapAggregateShare;
+use daphne::{messages::Id, DapAggregateShare};
+use prio::codec::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io::Cursor};
 use worker::*;
 
 pub(crate) const DURABLE_AGGREGATE_STORE_GET: &str = "/internal/do/aggregate_store/get";
@@ -15,23 +21,151 @@ pub(crate) const DURABLE_AGGREGATE_STORE_MARK_COLLECTED: &str =
     "/internal/do/aggregate_store/mark_collected";
 pub(crate) const DURABLE_AGGREGATE_STORE_CHECK_COLLECTED: &str =
     "/internal/do/aggregate_store/check_collected";
+pub(crate) const DURABLE_AGGREGATE_STORE_GET_REPORT_CHECKSUMS: &str =
+    "/internal/do/aggregate_store/get_report_checksums";
+pub(crate) const DURABLE_AGGREGATE_STORE_EXCLUDE_REPORTS: &str =
+    "/internal/do/aggregate_store/exclude_reports";
+
+impl DurableVersioned for DapAggregateShare {
+    const VERSION: u64 = 1;
+}
+
+/// Request to merge the output shares of an aggregation job into the aggregate share.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AggregateStoreMergeReq {
+    pub(crate) agg_job_id: Id,
+    pub(crate) agg_share_delta: DapAggregateShare,
+
+    /// The output share of each report in the aggregation job, stored so that the report can be
+    /// excluded later. This is empty unless batch reconciliation is enabled.
+    #[serde(default)]
+    pub(crate) report_shares: Vec<DapAggregateShare>,
+}
+
+/// Result of merging the output shares of an aggregation job into the aggregate share.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) enum AggregateStoreMergeResp {
+    /// The output shares were merged.
+    Ok,
+
+    /// The output shares of the aggregation job were merged previously, so nothing was done.
+    AlreadyMerged,
+
+    /// The bucket has been collected, so the output shares were not merged.
+    AlreadyCollected,
+}
+
+/// Result of excluding a set of reports from the aggregate share.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) enum AggregateStoreExcludeResp {
+    /// The given number of reports were excluded.
+    Ok(u64),
+
+    /// The bucket has been collected, so no reports were excluded.
+    AlreadyCollected,
+}
+
+/// Maximum length (in bytes) of the encoded output shares stored under a single key. Values are
+/// limited to 128 KiB and the encoding is stored hex-encoded, so this leaves room for the
+/// versioned envelope.
+pub(crate) const REPORT_SHARES_CHUNK_MAX_LEN: usize = 48 * 1024;
+
+/// Encode the output shares of the reports of an aggregation job and split them into chunks of
+/// whole reports, each at most [`REPORT_SHARES_CHUNK_MAX_LEN`] bytes long. Returns an error if the
+/// output share of a single report exceeds this length.
+pub(crate) fn encode_report_shares_chunked(
+    report_shares: &[DapAggregateShare],
+) -> Result<Vec<Vec<u8>>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    for report_share in report_shares {
+        let encoded = report_share.get_encoded();
+        if encoded.len() > REPORT_SHARES_CHUNK_MAX_LEN {
+            return Err(int_err(format!(
+                "AggregateStore: output share is too large to store ({} bytes)",
+                encoded.len()
+            )));
+        }
+        if chunk.len() + encoded.len() > REPORT_SHARES_CHUNK_MAX_LEN {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.extend_from_slice(&encoded);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+/// Decode a chunk of output shares encoded by [`encode_report_shares_chunked`].
+pub(crate) fn decode_report_shares(chunk: &[u8]) -> Result<Vec<DapAggregateShare>> {
+    let mut r = Cursor::new(chunk);
+    let mut report_shares = Vec::new();
+    while (r.position() as usize) < chunk.len() {
+        report_shares.push(DapAggregateShare::decode(&mut r).map_err(int_err)?);
+    }
+    Ok(report_shares)
+}
+
+/// Convert a chunk of encoded output shares to the value stored under its key.
+fn report_shares_chunk_value(chunk: &[u8]) -> Result<wasm_bindgen::JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&versioned(&hex::encode(
+        chunk,
+    )))?)
+}
+
+/// Fetch each chunk of output shares stored for the aggregation jobs merged into the aggregate
+/// share, as stored under `agg_job_reports/<agg_job_id>/<chunk>`.
+async fn get_all_report_shares(state: &State) -> Result<Vec<(String, Vec<DapAggregateShare>)>> {
+    let iter = state
+        .storage()
+        .list_with_options(ListOptions::new().prefix("agg_job_reports/"))
+        .await?
+        .entries();
+    let mut js_item = iter.next()?;
+    let mut res = Vec::new();
+    while !js_item.done() {
+        let (key, Versioned(chunk_hex)): (String, Versioned<String>) =
+            serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
+        let chunk = hex::decode(chunk_hex).map_err(int_err)?;
+        res.push((key, decode_report_shares(&chunk)?));
+        js_item = iter.next()?;
+    }
+    Ok(res)
+}
 
 /// Durable Object (DO) for storing aggregate shares for a bucket of reports.
 ///
 /// This object defines the following API endpoints:
 ///
 /// - `DURABLE_AGGREGATE_STORE_GET`: Return the current value of the aggregate share.
-/// - `DURABLE_AGGREGATE_STORE_MERGE`: Update the aggregate share.
+/// - `DURABLE_AGGREGATE_STORE_MERGE`: Update the aggregate share with the output shares of an
+///   aggregation job, unless they have already been merged or the bucket has been collected.
 /// - `DURABLE_AGGREGATE_STORE_MARK_COLLECTED`: Mark the bucket as having been collected.
 /// - `DURABLE_AGGREGATE_STORE_CHECK_COLLECTED`: Return a boolean indicating if the bucket has been
 ///   collected.
+/// - `DURABLE_AGGREGATE_STORE_GET_REPORT_CHECKSUMS`: Return the checksum of each report whose
+///   output share is stored.
+/// - `DURABLE_AGGREGATE_STORE_EXCLUDE_REPORTS`: Remove the output shares of a set of reports from
+///   the aggregate share, unless the bucket has been collected.
 ///
 /// The schema for the data stored by this DO is as follows:
 ///
 /// ```text
-/// [Aggregate share] agg_share -> DapAggregateShare
-/// [Collected flag]  collected -> bool
+/// [Aggregate share]     agg_share -> DapAggregateShare
+/// [Collected flag]      collected -> bool
+/// [Merged agg job flag] agg_job/<agg_job_id> -> bool
+/// [Report shares]       agg_job_reports/<agg_job_id>/<chunk> -> String
 /// ```
+///
+/// where `<agg_job_id>` is the hex-encoded ID of an aggregation job whose output shares have been
+/// merged into the aggregate share. The output share of each report is only stored if batch
+/// reconciliation is enabled. The output shares of an aggregation job are encoded (see
+/// [`DapAggregateShare`]'s implementation of `Encode`), split into chunks of whole reports (see
+/// [`encode_report_shares_chunked`]) and stored hex-encoded. Chunks are numbered from 0.
+///
+/// Storage writes that span several keys are limited to 128 keys. Hence, an aggregation job may
+/// have at most 126 chunks per bucket, and an exclusion may change at most 127 chunks.
 #[durable_object]
 pub struct AggregateStore {
     #[allow(dead_code)]
@@ -39,6 +173,9 @@ pub struct AggregateStore {
     env: Env,
     config: DaphneWorkerConfig,
     touched: bool,
+
+    /// Handle to the storage of `state`, used for writes that span several keys.
+    storage: DurableStorageHandle,
 }
 
 #[durable_object]
@@ -47,11 +184,13 @@ impl DurableObject for AggregateStore {
         initialize_tracing(&env);
         let config =
             DaphneWorkerConfig::from_worker_env(&env).expect("failed to load configuration");
+        let (state, storage) = DurableStorageHandle::split(state);
         Self {
             state,
             env,
             config,
             touched: false,
+            storage,
         }
     }
 
@@ -60,22 +199,129 @@ impl DurableObject for AggregateStore {
         ensure_garbage_collected!(req, self, id_hex, BINDING_DAP_AGGREGATE_STORE);
 
         match (req.path().as_ref(), req.method()) {
-            // Merge an aggregate share into the stored aggregate.
+            // Merge the output shares of an aggregation job into the stored aggregate. Each
+            // aggregation job is merged at most once.
             //
-            // Input: `agg_share_dellta: DapAggregateShare`
+            // Input: `merge_req: AggregateStoreMergeReq`
+            // Output: `AggregateStoreMergeResp`
             (DURABLE_AGGREGATE_STORE_MERGE, Method::Post) => {
-                let agg_share_delta = req.json().await?;
+                let merge_req: AggregateStoreMergeReq = req.json().await?;
+                let agg_job_key = format!("agg_job/{}", merge_req.agg_job_id.to_hex());
+
+                // The DO's input gate ensures that no other request is delivered while we are
+                // waiting on storage, so the following reads are not interleaved with another
+                // merge. (The storage transaction API is not exposed by workers-rs.)
+                let collected: bool = state_get_or_default(&self.state, "collected").await?;
+                if collected {
+                    return Response::from_json(&AggregateStoreMergeResp::AlreadyCollected);
+                }
+
+                let merged: bool = state_get_or_default(&self.state, &agg_job_key).await?;
+                if merged {
+                    return Response::from_json(&AggregateStoreMergeResp::AlreadyMerged);
+                }
 
-                // To keep this pair of get and put operations atomic, there should be no await
-                // points between them. See the note below `transaction()` on
-                // https://developers.cloudflare.com/workers/runtime-apis/durable-objects/#transactional-storage-api.
-                // See issue #109.
                 let mut agg_share: DapAggregateShare =
-                    state_get_or_default(&self.state, "agg_share").await?;
-                agg_share.merge(agg_share_delta).map_err(int_err)?;
-                self.state.storage().put("agg_share", agg_share).await?;
+                    state_get_versioned_or_default(&self.state, "agg_share").await?;
+                agg_share
+                    .merge(merge_req.agg_share_delta)
+                    .map_err(int_err)?;
 
-                Response::from_json(&())
+                // The aggregate share, the aggregation job and its output shares are stored with a
+                // single write, so the aggregate share is never updated without also recording the
+                // aggregation job, or vice versa.
+                let chunks = encode_report_shares_chunked(&merge_req.report_shares)?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, chunk)| {
+                        Ok((
+                            format!("agg_job_reports/{}/{i}", merge_req.agg_job_id.to_hex()),
+                            report_shares_chunk_value(chunk)?,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let mut entries = vec![
+                    (
+                        "agg_share",
+                        serde_wasm_bindgen::to_value(&versioned(&agg_share))?,
+                    ),
+                    (agg_job_key.as_str(), serde_wasm_bindgen::to_value(&true)?),
+                ];
+                entries.extend(
+                    chunks
+                        .iter()
+                        .map(|(key, chunk)| (key.as_str(), chunk.clone())),
+                );
+                self.storage.put_multiple(entries).await?;
+
+                Response::from_json(&AggregateStoreMergeResp::Ok)
+            }
+
+            // Get the checksum of each report whose output share is stored.
+            //
+            // Output: `Vec<[u8; 32]>`
+            (DURABLE_AGGREGATE_STORE_GET_REPORT_CHECKSUMS, Method::Get) => {
+                let checksums: Vec<[u8; 32]> = get_all_report_shares(&self.state)
+                    .await?
+                    .into_iter()
+                    .flat_map(|(_key, report_shares)| report_shares)
+                    .map(|report_share| report_share.checksum())
+                    .collect();
+                Response::from_json(&checksums)
+            }
+
+            // Remove the output shares of the reports with the given checksums from the
+            // aggregate share. Checksums of reports whose output shares are not stored are
+            // ignored.
+            //
+            // Input: `checksums: Vec<[u8; 32]>`
+            // Output: `AggregateStoreExcludeResp`
+            (DURABLE_AGGREGATE_STORE_EXCLUDE_REPORTS, Method::Post) => {
+                let checksums: HashSet<[u8; 32]> = req.json().await?;
+
+                let collected: bool = state_get_or_default(&self.state, "collected").await?;
+                if collected {
+                    return Response::from_json(&AggregateStoreExcludeResp::AlreadyCollected);
+                }
+
+                let mut agg_share: DapAggregateShare =
+                    state_get_versioned_or_default(&self.state, "agg_share").await?;
+                let mut excluded = 0;
+                let mut updated = Vec::new();
+                for (key, report_shares) in get_all_report_shares(&self.state).await? {
+                    let (removed, kept): (Vec<_>, Vec<_>) = report_shares
+                        .into_iter()
+                        .partition(|report_share| checksums.contains(&report_share.checksum()));
+                    if removed.is_empty() {
+                        continue;
+                    }
+                    for report_share in removed {
+                        agg_share.exclude(report_share).map_err(int_err)?;
+                        excluded += 1;
+                    }
+                    let kept: Vec<u8> = kept
+                        .iter()
+                        .flat_map(|report_share| report_share.get_encoded())
+                        .collect();
+                    updated.push((key, report_shares_chunk_value(&kept)?));
+                }
+
+                // As with merging, the aggregate share and the remaining report shares are stored
+                // with a single write.
+                if excluded > 0 {
+                    let mut entries = vec![(
+                        "agg_share",
+                        serde_wasm_bindgen::to_value(&versioned(&agg_share))?,
+                    )];
+                    entries.extend(
+                        updated
+                            .iter()
+                            .map(|(key, report_shares)| (key.as_str(), report_shares.clone())),
+                    );
+                    self.storage.put_multiple(entries).await?;
+                }
+
+                Response::from_json(&AggregateStoreExcludeResp::Ok(excluded))
             }
 
             // Get the current aggregate share.
@@ -83,7 +329,7 @@ impl DurableObject for AggregateStore {
             // Output: `DapAggregateShare`
             (DURABLE_AGGREGATE_STORE_GET, Method::Get) => {
                 let agg_share: DapAggregateShare =
-                    state_get_or_default(&self.state, "agg_share").await?;
+                    state_get_versioned_or_default(&self.state, "agg_share").await?;
                 Response::from_json(&agg_share)
             }
 
@@ -3,15 +3,92 @@
 
 use crate::{
     durable,
-    durable::{DurableConnector, DurableOrdered, DurableReference},
+    durable::{
+        state_get_versioned, state_put_versioned, DurableConnector, DurableOrdered,
+        DurableReference, DurableVersioned, Versioned,
+    },
     initialize_tracing, int_err,
 };
+use daphne::messages::{Id, Time};
+use serde::{Deserialize, Serialize};
 use tracing::{error, trace};
 use worker::*;
 
 pub(crate) const DURABLE_GARBAGE_COLLECTOR_PUT: &str = "/internal/do/garbage_collector/put";
+pub(crate) const DURABLE_GARBAGE_COLLECTOR_DELETE_TASK: &str =
+    "/internal/do/garbage_collector/delete_task";
+pub(crate) const DURABLE_GARBAGE_COLLECTOR_PUT_TASKPROV_TASK: &str =
+    "/internal/do/garbage_collector/put_taskprov_task";
+pub(crate) const DURABLE_GARBAGE_COLLECTOR_GET_EXPIRED_TASKPROV_TASKS: &str =
+    "/internal/do/garbage_collector/get_expired_taskprov_tasks";
 
-/// Durable Object (DO) for keeping track of all persistent DO storage.
+/// A task provisioned via taskprov, to be deleted once `delete_after` has passed.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct TaskprovTaskGarbage {
+    pub(crate) task_id: Id,
+    pub(crate) delete_after: Time,
+}
+
+impl DurableVersioned for TaskprovTaskGarbage {
+    const VERSION: u64 = 1;
+}
+
+/// Request for the taskprov tasks that are due for deletion.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct GetExpiredTaskprovTasks {
+    /// The current time.
+    pub(crate) now: Time,
+
+    /// Maximum number of tasks to return.
+    pub(crate) limit: usize,
+}
+
+/// Queue namespace for the DO instances that are not associated with any task.
+const OBJECT_PREFIX: &str = "object";
+
+/// Queue namespace for the DO instances associated with the given task. Keeping these under a
+/// per-task prefix means that deleting a task only needs to list the task's own instances.
+fn object_prefix_for_task(task_id: &Id) -> String {
+    format!("{OBJECT_PREFIX}/task/{}", task_id.to_hex())
+}
+
+/// Queue namespace for the taskprov tasks, ordered by the time after which they are to be deleted.
+const TASKPROV_TASK_PREFIX: &str = "taskprov_task";
+
+/// Key under which the key of the given task's entry in the taskprov queue is stored. This lets the
+/// entry be removed without scanning the queue.
+fn taskprov_task_index_key(task_id: &Id) -> String {
+    format!("{TASKPROV_TASK_PREFIX}/task/{}", task_id.to_hex())
+}
+
+/// Return every DO instance scheduled for deletion, whether or not it is associated with a task.
+///
+/// WARNING: This lists the entire index and is only intended for `DURABLE_DELETE_ALL`.
+async fn get_all_objects(state: &State) -> Result<Vec<DurableReference>> {
+    let key_prefix = format!("{OBJECT_PREFIX}/");
+    let iter = state
+        .storage()
+        .list_with_options(ListOptions::new().prefix(&key_prefix))
+        .await?
+        .entries();
+    let mut js_item = iter.next()?;
+    let mut res = Vec::new();
+    while !js_item.done() {
+        let (_key, Versioned(durable_ref)): (String, Versioned<DurableReference>) =
+            serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
+        res.push(durable_ref);
+        js_item = iter.next()?;
+    }
+    Ok(res)
+}
+
+/// Durable Object (DO) for keeping track of all persistent DO storage. It also keeps track of the
+/// tasks provisioned via taskprov, indexed by the time after which they are to be deleted.
+///
+/// DO instances associated with a task are indexed under `object/task/<task_id>/item/<ordinal>`;
+/// all other instances are indexed under `object/item/<ordinal>`. Each taskprov task is queued under
+/// `taskprov_task/item/<ordinal>`, and the key of its queue entry is stored under
+/// `taskprov_task/task/<task_id>`.
 #[durable_object]
 pub struct GarbageCollector {
     #[allow(dead_code)]
@@ -39,7 +116,8 @@ impl DurableObject for GarbageCollector {
                     | durable::BINDING_DAP_LEADER_AGG_JOB_QUEUE
                     | durable::BINDING_DAP_LEADER_BATCH_QUEUE
                     | durable::BINDING_DAP_LEADER_COL_JOB_QUEUE
-                    | durable::BINDING_DAP_HELPER_STATE_STORE => (),
+                    | durable::BINDING_DAP_HELPER_STATE_STORE
+                    | durable::BINDING_DAP_METRICS_AGGREGATOR => (),
                     s => {
                         let message = format!("GarbageCollector: unrecognized binding: {s}");
                         error!("{}", message);
@@ -47,7 +125,11 @@ impl DurableObject for GarbageCollector {
                     }
                 };
 
-                let queued = DurableOrdered::new_roughly_ordered(durable_ref, "object");
+                let prefix = match durable_ref.task_id {
+                    Some(ref task_id) => object_prefix_for_task(task_id),
+                    None => OBJECT_PREFIX.to_string(),
+                };
+                let queued = DurableOrdered::new_roughly_ordered(durable_ref, &prefix);
                 queued.put(&self.state).await?;
                 trace!(
                     "scheduled {} instance {} for deletion",
@@ -57,6 +139,91 @@ impl DurableObject for GarbageCollector {
                 Response::from_json(&())
             }
 
+            // Schedule a taskprov task for deletion.
+            //
+            // Input: `garbage: TaskprovTaskGarbage`
+            (DURABLE_GARBAGE_COLLECTOR_PUT_TASKPROV_TASK, Method::Post) => {
+                let garbage: TaskprovTaskGarbage = req.json().await?;
+                let delete_after = garbage.delete_after;
+                let index_key = taskprov_task_index_key(&garbage.task_id);
+
+                // If the task was scheduled before, then replace its entry in the queue.
+                if let Some(old_key) =
+                    state_get_versioned::<String>(&self.state, &index_key).await?
+                {
+                    self.state.storage().delete(&old_key).await?;
+                }
+
+                let queued = DurableOrdered::new_ordered_by_time(
+                    garbage,
+                    TASKPROV_TASK_PREFIX,
+                    delete_after,
+                );
+                queued.put(&self.state).await?;
+                state_put_versioned(&self.state, &index_key, &queued.key()).await?;
+                trace!(
+                    "scheduled taskprov task {} for deletion after {}",
+                    queued.as_ref().task_id,
+                    delete_after
+                );
+                Response::from_json(&())
+            }
+
+            // Get the taskprov tasks that are due for deletion, oldest first.
+            //
+            // Input: `get: GetExpiredTaskprovTasks`
+            // Output: `Vec<Id>`
+            (DURABLE_GARBAGE_COLLECTOR_GET_EXPIRED_TASKPROV_TASKS, Method::Post) => {
+                let get: GetExpiredTaskprovTasks = req.json().await?;
+                let queued: Vec<DurableOrdered<TaskprovTaskGarbage>> =
+                    DurableOrdered::get_front(&self.state, TASKPROV_TASK_PREFIX, get.limit).await?;
+                let task_ids: Vec<Id> = queued
+                    .into_iter()
+                    .map(|queued| queued.into_item())
+                    .take_while(|garbage| garbage.delete_after <= get.now)
+                    .map(|garbage| garbage.task_id)
+                    .collect();
+                Response::from_json(&task_ids)
+            }
+
+            // Delete all DO instances associated with the given task and remove the task from
+            // the taskprov index.
+            //
+            // Input: `task_id: Id`
+            (DURABLE_GARBAGE_COLLECTOR_DELETE_TASK, Method::Post) => {
+                let task_id: Id = req.json().await?;
+                let index_key = taskprov_task_index_key(&task_id);
+                if let Some(key) = state_get_versioned::<String>(&self.state, &index_key).await? {
+                    self.state
+                        .storage()
+                        .delete_multiple(vec![key, index_key])
+                        .await?;
+                }
+
+                let queued: Vec<DurableOrdered<DurableReference>> =
+                    DurableOrdered::get_all(&self.state, &object_prefix_for_task(&task_id)).await?;
+                for queued in queued.iter() {
+                    let durable_ref = queued.as_ref();
+                    durable
+                        .post_by_id_hex(
+                            &durable_ref.binding,
+                            durable::DURABLE_DELETE_ALL,
+                            durable_ref.id_hex.clone(),
+                            &(),
+                        )
+                        .await?;
+                    queued.delete(&self.state).await?;
+                    trace!(
+                        "deleted {} instance {} for task {}",
+                        durable_ref.binding,
+                        durable_ref.id_hex,
+                        task_id
+                    );
+                }
+
+                Response::from_json(&())
+            }
+
             // Delete all DO instances.
             //
             // NOTE This method is likely to hit memory and/or time limits when run in a production
@@ -69,9 +236,7 @@ impl DurableObject for GarbageCollector {
             //   replay protection. However, for replay protection in particular, it'll be
             //   important to make sure the Leader rejects reports with old timestamps.
             (durable::DURABLE_DELETE_ALL, Method::Post) => {
-                let queued: Vec<DurableOrdered<DurableReference>> =
-                    DurableOrdered::get_all(&self.state, "object").await?;
-                for durable_ref in queued.iter().map(|queued| queued.as_ref()) {
+                for durable_ref in get_all_objects(&self.state).await?.iter() {
                     durable
                         .post_by_id_hex(
                             &durable_ref.binding,
@@ -3,35 +3,153 @@
 
 use crate::{
     config::DaphneWorkerConfig,
-    durable::{DurableOrdered, BINDING_DAP_LEADER_AGG_JOB_QUEUE},
-    initialize_tracing, int_err,
+    durable::{
+        state_get_versioned, state_put_versioned, DurableOrdered, DurableVersioned, Versioned,
+        BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+    },
+    initialize_tracing, int_err, now,
 };
+use daphne::messages::Time;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use tracing::debug;
 use worker::*;
 
 pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_PUT: &str = "/internal/do/agg_job_queue/put";
-pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_GET: &str = "/internal/do/agg_job_queue/get";
+pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_LEASE: &str = "/internal/do/agg_job_queue/lease";
+pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_ACK: &str = "/internal/do/agg_job_queue/ack";
 pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_FINISH: &str = "/internal/do/agg_job_queue/finish";
 
+/// Request to lease jobs from the front of the queue.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct LeaderAggJobQueueLeaseReq {
+    /// Maximum number of jobs to lease.
+    pub(crate) max_agg_jobs: u64,
+
+    /// Number of seconds after which the lease expires, if the jobs are not acknowledged.
+    pub(crate) lease_secs: u64,
+
+    /// If set, then only this job is leased, provided it is in the queue, i.e., it is not already
+    /// leased to another processor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) agg_job: Option<DurableOrdered<String>>,
+}
+
+/// An aggregation job leased to a processor.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AggJobLease {
+    /// The job. The `String` is the name of the `ReportsPending` instance.
+    pub(crate) agg_job: DurableOrdered<String>,
+
+    /// Random identifier of the lease (hex-encoded).
+    pub(crate) lease_id: String,
+
+    /// Time at which the lease expires.
+    pub(crate) expires_at: Time,
+}
+
+impl DurableVersioned for AggJobLease {
+    const VERSION: u64 = 1;
+}
+
+/// Aggregation jobs leased by a processor, along with the reports it fetched from the
+/// corresponding `ReportsPending` instances. A lease is acknowledged once each of its reports has
+/// been released, i.e., aggregated, dropped, or handed back.
+#[derive(Default)]
+pub(crate) struct AggJobLeases {
+    /// The `ReportsPending` instance from which each report (hex-encoded ID) was fetched.
+    instances: HashMap<String, String>,
+
+    /// For each `ReportsPending` instance, the queue shard and lease of its aggregation job, and
+    /// the number of reports not yet released.
+    leases: HashMap<String, (u64, AggJobLease, usize)>,
+}
+
+impl AggJobLeases {
+    /// Record the lease for an aggregation job taken from the given queue shard, along with the
+    /// IDs (hex-encoded) of the reports fetched for it.
+    pub(crate) fn insert(&mut self, shard: u64, lease: AggJobLease, report_ids_hex: Vec<String>) {
+        let instance = lease.agg_job.as_ref().clone();
+        let report_count = report_ids_hex.len();
+        for report_id_hex in report_ids_hex.into_iter() {
+            self.instances.insert(report_id_hex, instance.clone());
+        }
+        self.leases.insert(instance, (shard, lease, report_count));
+    }
+
+    /// Release the given reports. Reports that were not fetched under a lease are ignored.
+    pub(crate) fn release(
+        &mut self,
+        report_ids_hex: impl IntoIterator<Item = String>,
+    ) -> ReleasedReports {
+        let mut released = ReleasedReports::default();
+        for report_id_hex in report_ids_hex.into_iter() {
+            let instance = match self.instances.remove(&report_id_hex) {
+                Some(instance) => instance,
+                None => continue,
+            };
+            if let Some((_shard, _lease, remaining)) = self.leases.get_mut(&instance) {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    let (shard, lease, _remaining) = self.leases.remove(&instance).unwrap();
+                    released.leases.push((shard, lease));
+                }
+            }
+            released
+                .reports
+                .entry(instance)
+                .or_default()
+                .push(report_id_hex);
+        }
+        released
+    }
+}
+
+/// Reports released by [`AggJobLeases::release`].
+#[derive(Default)]
+pub(crate) struct ReleasedReports {
+    /// IDs (hex-encoded) of the released reports, grouped by the `ReportsPending` instance they
+    /// were fetched from.
+    pub(crate) reports: HashMap<String, Vec<String>>,
+
+    /// Leases, along with their queue shard, whose reports have all been released.
+    pub(crate) leases: Vec<(u64, AggJobLease)>,
+}
+
+fn lease_key(agg_job: &DurableOrdered<String>) -> String {
+    format!("agg_job_lease/{}", agg_job.ordinal())
+}
+
 /// Durable Object (DO) representing an aggregation job queue.
 ///
 /// This object defines the following API endpoints:
 ///
 /// - `DURABLE_LEADER_AGG_JOB_QUEUE_PUT`: Adds a job to the queue. This is called by an instance of
 ///   `ReportsPending`.
-/// - `DURABLE_LEADER_AGG_JOB_QUEUE_PUT`: Fetches the desired number of jobs from the front of the
-///    queue.
-/// - `DURABLE_LEADER_AGG_JOB_QUEUE_FINISH`: Removes the indicated job from the queue.
+/// - `DURABLE_LEADER_AGG_JOB_QUEUE_LEASE`: Leases the desired number of jobs from the front of the
+///    queue, or a specific job (this is used by `ReportsPending` when its alarm fires). Leased
+///    jobs are removed from the queue until they are acknowledged or the lease expires, so that
+///    concurrent processors are handed disjoint sets of jobs.
+/// - `DURABLE_LEADER_AGG_JOB_QUEUE_ACK`: Acknowledges a lease once the processor has aggregated
+///    the reports it fetched, returning the job to the queue so that the remaining reports are
+///    aggregated later. Acknowledging an expired lease has no effect.
+/// - `DURABLE_LEADER_AGG_JOB_QUEUE_FINISH`: Removes the indicated job from the queue, whether or
+///    not it is leased.
+///
+/// Jobs whose lease has expired (for example, because the processor crashed) are returned to the
+/// queue the next time jobs are leased.
 ///
 /// The schemea for data stored in instances of this DO is as follows:
 ///
 /// ```text
 ///     agg_job/item/time/<time>/nonce/<nonce> -> String
+///     agg_job_lease/time/<time>/nonce/<nonce> -> AggJobLease
 /// ```
 ///
 /// where `<time>` and `<nonce>` were generated by the `ReportsPending` instance at creation time.
-/// The value stored is the unique name of the `ReportsPending` instance. Note that this schema
-/// matches the ordinal generated by [`DurableOrdered::new_roughly_ordered`].
+/// The value stored is the unique name of the `ReportsPending` instance, or the lease for it.
+/// Note that this schema matches the ordinal generated by [`DurableOrdered::new_roughly_ordered`].
 #[durable_object]
 pub struct LeaderAggregationJobQueue {
     #[allow(dead_code)]
@@ -41,6 +159,33 @@ pub struct LeaderAggregationJobQueue {
     touched: bool,
 }
 
+impl LeaderAggregationJobQueue {
+    /// Return the jobs whose lease has expired to the queue.
+    async fn reclaim_expired_leases(&self, now: Time) -> Result<()> {
+        let opt = ListOptions::new().prefix("agg_job_lease/");
+        let iter = self.state.storage().list_with_options(opt).await?.entries();
+        let mut item = iter.next()?;
+        let mut expired = Vec::new();
+        while !item.done() {
+            let (key, Versioned(lease)): (String, Versioned<AggJobLease>) =
+                serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
+            if lease.expires_at <= now {
+                expired.push((key, lease));
+            }
+            item = iter.next()?;
+        }
+
+        for (key, lease) in expired.into_iter() {
+            debug!(
+                "LeaderAggregationJobQueue: lease for {} expired",
+                lease.agg_job.as_ref()
+            );
+            lease.agg_job.put(&self.state).await?;
+            self.state.storage().delete(&key).await?;
+        }
+        Ok(())
+    }
+}
 #[durable_object]
 impl DurableObject for LeaderAggregationJobQueue {
     fn new(state: State, env: Env) -> Self {
@@ -74,22 +219,74 @@ impl DurableObject for LeaderAggregationJobQueue {
                 Response::from_json(&())
             }
 
-            // Fetch the aggregation jobs at the fron tf the queue.
+            // Lease the aggregation jobs at the front of the queue.
             //
-            // Input: `max_agg_jobs: usize`,
-            // Output: `Vec<String>` (the names of the `ReportsPending` instances from which to
-            // drain reports)
-            (DURABLE_LEADER_AGG_JOB_QUEUE_GET, Method::Post) => {
-                let max_agg_jobs: usize = req.json().await?;
-                let res: Vec<String> =
-                    DurableOrdered::get_front(&self.state, "agg_job", max_agg_jobs)
+            // Input: `LeaderAggJobQueueLeaseReq`
+            // Output: `Vec<AggJobLease>`
+            (DURABLE_LEADER_AGG_JOB_QUEUE_LEASE, Method::Post) => {
+                let lease_req: LeaderAggJobQueueLeaseReq = req.json().await?;
+                let now = now();
+                self.reclaim_expired_leases(now).await?;
+
+                let agg_jobs: Vec<DurableOrdered<String>> = match lease_req.agg_job {
+                    Some(agg_job) => {
+                        let queued = state_get_versioned::<String>(&self.state, &agg_job.key())
+                            .await?
+                            .is_some();
+                        if queued && lease_req.max_agg_jobs > 0 {
+                            vec![agg_job]
+                        } else {
+                            Vec::new()
+                        }
+                    }
+                    None => {
+                        DurableOrdered::get_front(
+                            &self.state,
+                            "agg_job",
+                            lease_req.max_agg_jobs.try_into().map_err(int_err)?,
+                        )
                         .await?
-                        .into_iter()
-                        .map(|agg_job| agg_job.into_item())
-                        .collect();
+                    }
+                };
+                let mut rng = thread_rng();
+                let mut leases = Vec::with_capacity(agg_jobs.len());
+                for agg_job in agg_jobs.into_iter() {
+                    let lease = AggJobLease {
+                        agg_job,
+                        lease_id: hex::encode(rng.gen::<[u8; 16]>()),
+                        expires_at: now + lease_req.lease_secs,
+                    };
+                    state_put_versioned(&self.state, &lease_key(&lease.agg_job), &lease).await?;
+                    lease.agg_job.delete(&self.state).await?;
+                    leases.push(lease);
+                }
+
+                debug!(
+                    "agg job queue: leased {:?}",
+                    leases
+                        .iter()
+                        .map(|lease| lease.agg_job.as_ref())
+                        .collect::<Vec<_>>()
+                );
+                Response::from_json(&leases)
+            }
 
-                debug!("agg job queue: {:?}", res);
-                Response::from_json(&res)
+            // Acknowledge a lease and return the job to the queue.
+            //
+            // Input: `lease: AggJobLease`
+            // Output: `bool` (indicates whether the lease was still held)
+            (DURABLE_LEADER_AGG_JOB_QUEUE_ACK, Method::Post) => {
+                let lease: AggJobLease = req.json().await?;
+                let key = lease_key(&lease.agg_job);
+                let held = matches!(
+                    state_get_versioned::<AggJobLease>(&self.state, &key).await?,
+                    Some(stored) if stored.lease_id == lease.lease_id
+                );
+                if held {
+                    lease.agg_job.put(&self.state).await?;
+                    self.state.storage().delete(&key).await?;
+                }
+                Response::from_json(&held)
             }
 
             // Remove a job from the queue.
@@ -98,7 +295,10 @@ impl DurableObject for LeaderAggregationJobQueue {
             // `ReportsPending` instance that has become empty)
             (DURABLE_LEADER_AGG_JOB_QUEUE_FINISH, Method::Post) => {
                 let agg_job: DurableOrdered<String> = req.json().await?;
-                agg_job.delete(&self.state).await?;
+                self.state
+                    .storage()
+                    .delete_multiple(vec![agg_job.key(), lease_key(&agg_job)])
+                    .await?;
                 Response::from_json(&())
             }
 
@@ -3,7 +3,10 @@
 
 use crate::{
     config::DaphneWorkerConfig,
-    durable::{state_get, DurableOrdered, BINDING_DAP_LEADER_BATCH_QUEUE},
+    durable::{
+        state_get, state_get_versioned, state_put_versioned, DurableOrdered, DurableVersioned,
+        BINDING_DAP_LEADER_BATCH_QUEUE,
+    },
     initialize_tracing, int_err,
 };
 use daphne::messages::Id;
@@ -26,6 +29,10 @@ pub(crate) struct BatchCount {
     pub(crate) report_count: usize,
 }
 
+impl DurableVersioned for BatchCount {
+    const VERSION: u64 = 1;
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub(crate) enum LeaderBatchQueueResult {
@@ -123,10 +130,11 @@ impl DurableObject for LeaderBatchQueue {
             }
 
             // Assign the requested number of reports to a sequence of batch IDs. For each batch
-            // ID, return the number of reports assigned to the batch.
+            // ID, return the number of reports assigned to the batch. Also return the number of
+            // reports assigned so far to the batch that is currently being filled.
             //
             // Input: `(batch_size, num_unassigned): (usize, usize)`
-            // Output: `Vec<BatchCount>`
+            // Output: `(batch_assignments, current_report_count): (Vec<BatchCount>, usize)`
             (DURABLE_LEADER_BATCH_QUEUE_ASSIGN, Method::Post) => {
                 let (batch_size, mut num_unassigned): (usize, usize) = req.json().await?;
                 if batch_size == 0 {
@@ -135,7 +143,8 @@ impl DurableObject for LeaderBatchQueue {
 
                 // Read the batch that is currently being filled from storage, or, if this is the
                 // first time this LeaderBatchQueue instance has been touched, create a new batch.
-                let mut curr = if let Some(curr) = state_get(&self.state, CURRENT).await? {
+                let mut curr = if let Some(curr) = state_get_versioned(&self.state, CURRENT).await?
+                {
                     curr
                 } else {
                     self.create_batch().await?
@@ -162,8 +171,8 @@ impl DurableObject for LeaderBatchQueue {
                 }
 
                 // Write the current batch to storage.
-                self.state.storage().put(CURRENT, &curr).await?;
-                Response::from_json(&batch_assignments)
+                state_put_versioned(&self.state, CURRENT, &curr).await?;
+                Response::from_json(&(batch_assignments, curr.report_count))
             }
 
             // Remove the indicated batch (i.e., the hex-encoded batch ID) from the queue. This is
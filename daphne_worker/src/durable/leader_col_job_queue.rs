@@ -3,7 +3,10 @@
 
 use crate::{
     config::DaphneWorkerConfig,
-    durable::{state_get, state_get_or_default, DurableOrdered, BINDING_DAP_LEADER_COL_JOB_QUEUE},
+    durable::{
+        state_get, state_get_or_default, state_get_versioned, state_put_versioned, DurableOrdered,
+        DurableVersioned, BINDING_DAP_LEADER_COL_JOB_QUEUE,
+    },
     initialize_tracing, int_err,
 };
 use daphne::{
@@ -26,6 +29,14 @@ pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_FINISH: &str =
 pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_GET_RESULT: &str =
     "/internal/do/leader_col_job_queue/get_result";
 
+impl DurableVersioned for (Id, CollectReq) {
+    const VERSION: u64 = 1;
+}
+
+impl DurableVersioned for CollectResp {
+    const VERSION: u64 = 1;
+}
+
 /// Durable Object (DO) for storing the Leader's state for a given task.
 ///
 /// This object implements the following API endpoints:
@@ -107,8 +118,11 @@ impl DurableObject for LeaderCollectionJobQueue {
                 // If the the request is new, then put it in the job queue.
                 let pending_key = format!("pending/id/{collect_id_hex}");
                 let pending: bool = state_get_or_default(&self.state, &pending_key).await?;
-                let processed: Option<CollectResp> =
-                    state_get(&self.state, &format!("{PROCESSED_PREFIX}/{collect_id_hex}")).await?;
+                let processed: Option<CollectResp> = state_get_versioned(
+                    &self.state,
+                    &format!("{PROCESSED_PREFIX}/{collect_id_hex}"),
+                )
+                .await?;
                 if processed.is_none() && !pending {
                     let queued = DurableOrdered::new_strictly_ordered(
                         &self.state,
@@ -145,7 +159,8 @@ impl DurableObject for LeaderCollectionJobQueue {
                 let (collect_id, collect_resp): (Id, CollectResp) = req.json().await?;
                 let collect_id_hex = collect_id.to_hex();
                 let processed_key = format!("{PROCESSED_PREFIX}/{collect_id_hex}");
-                let processed: Option<CollectResp> = state_get(&self.state, &processed_key).await?;
+                let processed: Option<CollectResp> =
+                    state_get_versioned(&self.state, &processed_key).await?;
                 if processed.is_some() {
                     return Err(int_err(
                         "LeaderCollectionJobQueue: tried to overwrite collect response",
@@ -164,10 +179,7 @@ impl DurableObject for LeaderCollectionJobQueue {
                 let f = storage.delete(&pending_lookup_key);
 
                 // Store the CollectResp.
-                self.state
-                    .storage()
-                    .put(&processed_key, collect_resp)
-                    .await?;
+                state_put_versioned(&self.state, &processed_key, &collect_resp).await?;
 
                 // Remove the lookup key.
                 f.await?;
@@ -186,7 +198,8 @@ impl DurableObject for LeaderCollectionJobQueue {
                     .await?
                     .is_some();
                 let processed_key = format!("{PROCESSED_PREFIX}/{collect_id_hex}");
-                let processed: Option<CollectResp> = state_get(&self.state, &processed_key).await?;
+                let processed: Option<CollectResp> =
+                    state_get_versioned(&self.state, &processed_key).await?;
                 if let Some(collect_resp) = processed {
                     if pending {
                         self.state.storage().delete(&pending_lookup_key).await?;
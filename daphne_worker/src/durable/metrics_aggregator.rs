@@ -0,0 +1,104 @@
+// Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::{
+    config::DaphneWorkerConfig,
+    durable::{
+        state_get_versioned_or_default, state_put_versioned, DurableVersioned,
+        BINDING_DAP_METRICS_AGGREGATOR,
+    },
+    initialize_tracing, int_err,
+    metrics::MetricsFamily,
+};
+use worker::*;
+
+pub(crate) const DURABLE_METRICS_AGGREGATOR_PUT: &str = "/internal/do/metrics_aggregator/put";
+pub(crate) const DURABLE_METRICS_AGGREGATOR_GET: &str = "/internal/do/metrics_aggregator/get";
+
+/// Number of instances of [`MetricsAggregator`]. The metrics collected by each request are merged
+/// into a random instance so that no single instance handles every request.
+pub(crate) const METRICS_AGGREGATOR_SHARD_COUNT: u64 = 16;
+
+/// Name of the given shard of [`MetricsAggregator`].
+pub(crate) fn durable_name_metrics_aggregator(shard: u64) -> String {
+    format!("metrics/shard/{shard}")
+}
+
+const FAMILIES: &str = "families";
+
+impl DurableVersioned for Vec<MetricsFamily> {
+    const VERSION: u64 = 1;
+}
+
+/// Durable Object (DO) for aggregating the metrics collected by each Worker isolate, so that
+/// scrapes of `/internal/metrics` return consistent totals. The totals are sharded across
+/// [`METRICS_AGGREGATOR_SHARD_COUNT`] instances and merged when scraped. Only counters and
+/// histograms are aggregated (see [`MetricsKind`](crate::metrics::MetricsKind)).
+///
+/// This object implements the following API endpoints:
+///
+/// - `DURABLE_METRICS_AGGREGATOR_PUT`: Merge a sequence of metric families into the totals.
+/// - `DURABLE_METRICS_AGGREGATOR_GET`: Return the totals.
+///
+/// The schema for data stored in instances of this DO is as follows:
+///
+/// ```text
+/// [Totals] families -> Vec<MetricsFamily>
+/// ```
+#[durable_object]
+pub struct MetricsAggregator {
+    #[allow(dead_code)]
+    state: State,
+    env: Env,
+    config: DaphneWorkerConfig,
+    touched: bool,
+}
+
+#[durable_object]
+impl DurableObject for MetricsAggregator {
+    fn new(state: State, env: Env) -> Self {
+        initialize_tracing(&env);
+        let config =
+            DaphneWorkerConfig::from_worker_env(&env).expect("failed to load configuration");
+        Self {
+            state,
+            env,
+            config,
+            touched: false,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let id_hex = self.state.id().to_string();
+        ensure_garbage_collected!(req, self, id_hex, BINDING_DAP_METRICS_AGGREGATOR);
+
+        match (req.path().as_ref(), req.method()) {
+            // Merge metrics into the totals by summing them.
+            //
+            // Input: `families: Vec<MetricsFamily>`
+            (DURABLE_METRICS_AGGREGATOR_PUT, Method::Post) => {
+                let others: Vec<MetricsFamily> = req.json().await?;
+                let mut families: Vec<MetricsFamily> =
+                    state_get_versioned_or_default(&self.state, FAMILIES).await?;
+                MetricsFamily::merge_all(&mut families, others);
+                state_put_versioned(&self.state, FAMILIES, &families).await?;
+                Response::from_json(&())
+            }
+
+            // Return the totals.
+            //
+            // Output: `Vec<MetricsFamily>`
+            (DURABLE_METRICS_AGGREGATOR_GET, Method::Get) => {
+                let families: Vec<MetricsFamily> =
+                    state_get_versioned_or_default(&self.state, FAMILIES).await?;
+                Response::from_json(&families)
+            }
+
+            _ => Err(int_err(format!(
+                "MetricsAggregator: unexpected request: method={:?}; path={:?}",
+                req.method(),
+                req.path()
+            ))),
+        }
+    }
+}
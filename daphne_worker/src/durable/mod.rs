@@ -1,10 +1,17 @@
 // Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use crate::{int_err, now};
-use daphne::{messages::Id, DapBatchBucket, DapVersion};
+use crate::{int_err, metrics::DaphneWorkerMetrics, now};
+use daphne::{
+    messages::{Id, Time},
+    DapBatchBucket, DapVersion,
+};
 use rand::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed, Error as _, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{cmp::Ordering, fmt, marker::PhantomData};
 use worker::*;
 
 pub(crate) const DURABLE_DELETE_ALL: &str = "/internal/do/delete_all";
@@ -17,17 +24,31 @@ pub(crate) const BINDING_DAP_LEADER_BATCH_QUEUE: &str = "DAP_LEADER_BATCH_QUEUE"
 pub(crate) const BINDING_DAP_LEADER_COL_JOB_QUEUE: &str = "DAP_LEADER_COL_JOB_QUEUE";
 pub(crate) const BINDING_DAP_HELPER_STATE_STORE: &str = "DAP_HELPER_STATE_STORE";
 pub(crate) const BINDING_DAP_GARBAGE_COLLECTOR: &str = "DAP_GARBAGE_COLLECTOR";
+pub(crate) const BINDING_DAP_METRICS_AGGREGATOR: &str = "DAP_METRICS_AGGREGATOR";
+pub(crate) const BINDING_DAP_UPLOAD_RATE_LIMITER: &str = "DAP_UPLOAD_RATE_LIMITER";
 
 const ERR_NO_VALUE: &str = "No such value in storage.";
 
 /// Used to send HTTP requests to a durable object (DO) instance.
 pub(crate) struct DurableConnector<'a> {
     env: &'a Env,
+
+    /// If set, then the latency of each request is recorded.
+    metrics: Option<&'a DaphneWorkerMetrics>,
 }
 
 impl<'a> DurableConnector<'a> {
     pub(crate) fn new(env: &'a Env) -> Self {
-        DurableConnector { env }
+        DurableConnector { env, metrics: None }
+    }
+
+    /// Like [`Self::new`], except that the latency of each request is recorded in the given
+    /// metrics.
+    pub(crate) fn new_with_metrics(env: &'a Env, metrics: &'a DaphneWorkerMetrics) -> Self {
+        DurableConnector {
+            env,
+            metrics: Some(metrics),
+        }
     }
 
     /// Send a GET request with the given path to the DO instance with the given binding and name.
@@ -40,7 +61,15 @@ impl<'a> DurableConnector<'a> {
     ) -> Result<O> {
         let namespace = self.env.durable_object(durable_binding)?;
         let stub = namespace.id_from_name(&durable_name)?.get_stub()?;
-        durable_request(stub, durable_path, Method::Get, None::<()>).await
+        self.durable_request(
+            stub,
+            durable_binding,
+            Some(&durable_name),
+            durable_path,
+            Method::Get,
+            None::<()>,
+        )
+        .await
     }
 
     /// Send a POST request with the given path to the DO instance with the given binding and name.
@@ -54,7 +83,15 @@ impl<'a> DurableConnector<'a> {
     ) -> Result<O> {
         let namespace = self.env.durable_object(durable_binding)?;
         let stub = namespace.id_from_name(&durable_name)?.get_stub()?;
-        durable_request(stub, durable_path, Method::Post, Some(data)).await
+        self.durable_request(
+            stub,
+            durable_binding,
+            Some(&durable_name),
+            durable_path,
+            Method::Post,
+            Some(data),
+        )
+        .await
     }
 
     /// Send a POST request with the given path to the DO instance with the given binding and hex
@@ -69,27 +106,65 @@ impl<'a> DurableConnector<'a> {
     ) -> Result<O> {
         let namespace = self.env.durable_object(durable_binding)?;
         let stub = namespace.id_from_string(&durable_id_hex)?.get_stub()?;
-        durable_request(stub, durable_path, Method::Post, Some(data)).await
+        self.durable_request(
+            stub,
+            durable_binding,
+            None,
+            durable_path,
+            Method::Post,
+            Some(data),
+        )
+        .await
+    }
+
+    async fn durable_request<I: Serialize, O: for<'b> Deserialize<'b>>(
+        &self,
+        durable_stub: Stub,
+        durable_binding: &str,
+        durable_name: Option<&str>,
+        durable_path: &'static str,
+        method: Method,
+        data: Option<I>,
+    ) -> Result<O> {
+        let start = Date::now().as_millis();
+        let res = durable_request(durable_stub, durable_name, durable_path, method, data).await;
+        if let Some(metrics) = self.metrics {
+            let end = Date::now().as_millis();
+            metrics
+                .durable_request_duration
+                .with_label_values(&[durable_binding])
+                .observe(end.saturating_sub(start) as f64 / 1000.0);
+        }
+        res
     }
 }
 
+/// Send a request to a DO instance. If the name of the instance is known, then it is passed in the
+/// query string so that the instance can tell which task it is associated with. (See
+/// [`task_id_from_durable_request`].)
 async fn durable_request<I: Serialize, O: for<'a> Deserialize<'a>>(
     durable_stub: Stub,
+    durable_name: Option<&str>,
     durable_path: &'static str,
     method: Method,
     data: Option<I>,
 ) -> Result<O> {
+    let mut url = Url::parse(&format!("https://fake-host{durable_path}"))
+        .map_err(|e| Error::RustError(format!("durable_request: {e}")))?;
+    if let Some(durable_name) = durable_name {
+        url.query_pairs_mut().append_pair("name", durable_name);
+    }
+
     let req = match (&method, data) {
         (Method::Post, Some(data)) => Request::new_with_init(
-            &format!("https://fake-host{durable_path}"),
+            url.as_str(),
             RequestInit::new().with_method(Method::Post).with_body(Some(
                 wasm_bindgen::JsValue::from_str(&serde_json::to_string(&data)?),
             )),
         )?,
-        (Method::Get, None) => Request::new_with_init(
-            &format!("https://fake-host{durable_path}"),
-            RequestInit::new().with_method(Method::Get),
-        )?,
+        (Method::Get, None) => {
+            Request::new_with_init(url.as_str(), RequestInit::new().with_method(Method::Get))?
+        }
         _ => {
             return Err(Error::RustError(format!(
                 "durable_request: Unrecognized method: {method:?}",
@@ -126,7 +201,7 @@ macro_rules! ensure_garbage_collected {
                             &crate::durable::DurableReference {
                                 binding: $binding.to_string(),
                                 id_hex: $id,
-                                task_id: None,
+                                task_id: crate::durable::task_id_from_durable_request(&$req),
                             },
                         )
                         .await?;
@@ -192,6 +267,204 @@ pub(crate) async fn state_set_if_not_exists<T: for<'a> Deserialize<'a> + Seriali
     Ok(None)
 }
 
+/// A structured value that is persisted in DO storage in a versioned envelope:
+///
+/// ```text
+///     { "version": <version>, "value": <value> }
+/// ```
+///
+/// Values written before the envelope was introduced are read as version 0. When the encoding of
+/// a type changes, increment its `VERSION` and teach `decode_previous()` to read the older
+/// versions, so that values written by the previous deployment (e.g., by an aggregation job that
+/// is in flight) can still be read. Values written by a later version are rejected.
+///
+/// Every value written with [`state_put_versioned()`] is wrapped, including strings (`String`
+/// implements this trait), such as queue items and index entries. Values written directly with
+/// `storage().put()` are stored as-is. These are scalars (e.g., flags and counters), IDs and
+/// lookup keys, hex-encoded DAP messages, which are encoded according to the DAP version that is
+/// part of the name of the DO instance, and the hex-encoded Helper state, whose encoding carries
+/// its own version (see [`DapHelperState::get_encoded`](daphne::DapHelperState::get_encoded)).
+pub(crate) trait DurableVersioned: Serialize + DeserializeOwned {
+    /// Version of the encoding written by this build.
+    const VERSION: u64;
+
+    /// Decode a value written with an earlier version of the encoding. By default, the earlier
+    /// versions are assumed to be encoded the same way as the current version.
+    fn decode_previous<'de, D: Deserializer<'de>>(
+        version: u64,
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let _ = version;
+        Self::deserialize(deserializer)
+    }
+}
+
+impl DurableVersioned for String {
+    const VERSION: u64 = 1;
+}
+
+/// Wrap a value in its versioned envelope for storage.
+pub(crate) fn versioned<T: DurableVersioned>(value: &T) -> impl Serialize + '_ {
+    #[derive(Serialize)]
+    struct Envelope<'a, T> {
+        version: u64,
+        value: &'a T,
+    }
+
+    Envelope {
+        version: T::VERSION,
+        value,
+    }
+}
+
+/// A value read from its versioned envelope (or written before the envelope was introduced).
+pub(crate) struct Versioned<T>(pub(crate) T);
+
+impl<'de, T: DurableVersioned> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged, bound = "T: DurableVersioned")]
+        enum Stored<T> {
+            Enveloped(Envelope<T>),
+            Unversioned(Unversioned<T>),
+        }
+
+        match Stored::deserialize(deserializer)? {
+            Stored::Enveloped(Envelope(value)) | Stored::Unversioned(Unversioned(value)) => {
+                Ok(Self(value))
+            }
+        }
+    }
+}
+
+struct Envelope<T>(T);
+
+impl<'de, T: DurableVersioned> Deserialize<'de> for Envelope<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_map(EnvelopeVisitor(PhantomData))
+    }
+}
+
+struct EnvelopeVisitor<T>(PhantomData<T>);
+
+impl<'de, T: DurableVersioned> Visitor<'de> for EnvelopeVisitor<T> {
+    type Value = Envelope<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a versioned envelope")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        // The version is written before the value, so we know how to decode the value once we
+        // get to it.
+        if map.next_key::<String>()?.as_deref() != Some("version") {
+            return Err(A::Error::missing_field("version"));
+        }
+        let version: u64 = map.next_value()?;
+        if map.next_key::<String>()?.as_deref() != Some("value") {
+            return Err(A::Error::missing_field("value"));
+        }
+        let value = match version.cmp(&T::VERSION) {
+            Ordering::Equal => map.next_value()?,
+            Ordering::Less => map.next_value_seed(Previous(version, PhantomData))?,
+            Ordering::Greater => {
+                return Err(A::Error::custom(format!(
+                    "unsupported version {version} (the latest is {})",
+                    T::VERSION
+                )))
+            }
+        };
+        Ok(Envelope(value))
+    }
+}
+
+struct Previous<T>(u64, PhantomData<T>);
+
+impl<'de, T: DurableVersioned> DeserializeSeed<'de> for Previous<T> {
+    type Value = T;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<T, D::Error> {
+        T::decode_previous(self.0, deserializer)
+    }
+}
+
+struct Unversioned<T>(T);
+
+impl<'de, T: DurableVersioned> Deserialize<'de> for Unversioned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        T::decode_previous(0, deserializer).map(Unversioned)
+    }
+}
+
+/// Fetch a versioned value from durable storage. (See [`DurableVersioned`].)
+pub(crate) async fn state_get_versioned<T: DurableVersioned>(
+    state: &State,
+    key: &str,
+) -> Result<Option<T>> {
+    Ok(state_get::<Versioned<T>>(state, key)
+        .await?
+        .map(|Versioned(value)| value))
+}
+
+/// Fetch a versioned value from durable storage. If the key/value pair does not exist, then
+/// return the default value.
+pub(crate) async fn state_get_versioned_or_default<T: DurableVersioned + Default>(
+    state: &State,
+    key: &str,
+) -> Result<T> {
+    Ok(state_get_versioned(state, key).await?.unwrap_or_default())
+}
+
+/// Store a value in durable storage in its versioned envelope.
+pub(crate) async fn state_put_versioned<T: DurableVersioned>(
+    state: &State,
+    key: &str,
+    value: &T,
+) -> Result<()> {
+    state.storage().put(key, versioned(value)).await
+}
+
+/// Handle to the storage of a DO instance, used to write key/value pairs whose keys are not known
+/// at compile time with a single write. (`Storage::put_multiple()` only accepts values that
+/// serialize to plain objects, i.e., structs.)
+///
+/// NOTE The handle is taken from the DO state with `State::_inner()`, which workers-rs 0.0.14
+/// exposes only for use by the `durable_object` macro. This is the only use of it; the `worker`
+/// dependency is pinned so that it is checked whenever the dependency is bumped.
+pub(crate) struct DurableStorageHandle {
+    storage: worker_sys::DurableObjectStorage,
+}
+
+impl DurableStorageHandle {
+    /// Return the DO state along with a handle to its storage.
+    pub(crate) fn split(state: State) -> (State, Self) {
+        let inner = state._inner();
+        let storage = inner.storage();
+        (State::from(inner), Self { storage })
+    }
+
+    /// Store the given key/value pairs with a single write, so that either all of them are stored
+    /// or none of them are. Each value is expected to have been converted with
+    /// `serde_wasm_bindgen::to_value()`.
+    pub(crate) async fn put_multiple(
+        &self,
+        entries: Vec<(&str, wasm_bindgen::JsValue)>,
+    ) -> Result<()> {
+        let obj = js_sys::Object::new();
+        for (key, value) in entries {
+            js_sys::Reflect::set(&obj, &key.into(), &value)?;
+        }
+        wasm_bindgen_futures::JsFuture::from(self.storage.put_multiple(obj.into())?).await?;
+        Ok(())
+    }
+}
+
 pub(crate) fn durable_name_queue(shard: u64) -> String {
     format!("queue/{shard}")
 }
@@ -222,6 +495,36 @@ pub(crate) fn durable_name_agg_store(
     )
 }
 
+/// Return the name of the `UploadRateLimiter` instance for the task's limit or, if `client_shard`
+/// is set, for the limits of the Clients in the given shard.
+pub(crate) fn durable_name_upload_rate_limiter(
+    version: &DapVersion,
+    task_id_hex: &str,
+    client_shard: Option<u64>,
+) -> String {
+    let name = format!(
+        "{}/upload_rate_limit",
+        durable_name_task(version, task_id_hex)
+    );
+    if let Some(client_shard) = client_shard {
+        format!("{name}/client_shard/{client_shard}")
+    } else {
+        name
+    }
+}
+
+/// Return the ID of the task with which the DO instance handling the request is associated, if
+/// any. This is inferred from the name of the instance (see [`durable_name_task`]), which is passed
+/// in the query string of the request.
+pub(crate) fn task_id_from_durable_request(req: &Request) -> Option<Id> {
+    let url = req.url().ok()?;
+    let (_key, durable_name) = url.query_pairs().find(|(key, _val)| key == "name")?;
+    let mut parts = durable_name.split('/');
+    parts.find(|part| *part == "task")?;
+    let task_id_data = hex::decode(parts.next()?).ok()?;
+    Some(Id(task_id_data.try_into().ok()?))
+}
+
 pub(crate) fn durable_name_task(version: &DapVersion, task_id_hex: &str) -> String {
     format!("{}/task/{}", version.as_ref(), task_id_hex)
 }
@@ -277,7 +580,10 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
     /// Return the front of a queue stored in the provided DO state. The string `prefix` defines
     /// the queue's namespace, i.e., the prefix of each key for each key/value pair in the queue.
     /// At most `limit` queue elements are returned.
-    pub(crate) async fn get_front(state: &State, prefix: &str, limit: usize) -> Result<Vec<Self>> {
+    pub(crate) async fn get_front(state: &State, prefix: &str, limit: usize) -> Result<Vec<Self>>
+    where
+        T: DurableVersioned,
+    {
         get_front(state, prefix, Some(limit)).await
     }
 
@@ -286,7 +592,10 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
     /// WARNING: If the queue is too long, then this action is likely to cause the Workers runtime
     /// to start rate limiting the Worker. This should only be used when the size of the queue is
     /// strictly controlled.
-    async fn get_all(state: &State, prefix: &str) -> Result<Vec<Self>> {
+    async fn get_all(state: &State, prefix: &str) -> Result<Vec<Self>>
+    where
+        T: DurableVersioned,
+    {
         get_front(state, prefix, None).await
     }
 
@@ -302,8 +611,15 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
     ///
     /// where <time> is the timestamp and <nonce> is a random nonce.
     pub(crate) fn new_roughly_ordered(item: T, prefix: &str) -> Self {
+        Self::new_ordered_by_time(item, prefix, now())
+    }
+
+    /// Create a new element for a queue ordered by the given time. (Use `put()` to store it.)
+    ///
+    /// This is the same as [`Self::new_roughly_ordered`], except that the time is provided by the
+    /// caller.
+    pub(crate) fn new_ordered_by_time(item: T, prefix: &str, time: Time) -> Self {
         let mut rng = thread_rng();
-        let time = now();
         let nonce = rng.gen::<[u8; 16]>();
 
         // Pad the timestamp with 0s to the length of the longest 64-bit integer encoded in
@@ -356,8 +672,11 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
     }
 
     /// Store the item in the provided DO state.
-    pub(crate) async fn put(&self, state: &State) -> Result<()> {
-        state.storage().put(&self.key(), &self.item).await
+    pub(crate) async fn put(&self, state: &State) -> Result<()>
+    where
+        T: DurableVersioned,
+    {
+        state_put_versioned(state, &self.key(), &self.item).await
     }
 
     /// Delete the item from the provided DO state.
@@ -376,6 +695,12 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
         format!("{}/item/{}", self.prefix, self.ordinal)
     }
 
+    /// Return the item's ordinal, i.e., the part of the key that determines its position in the
+    /// queue.
+    pub(crate) fn ordinal(&self) -> &str {
+        &self.ordinal
+    }
+
     pub(crate) fn into_item(self) -> T {
         self.item
     }
@@ -387,7 +712,15 @@ impl<T> AsRef<T> for DurableOrdered<T> {
     }
 }
 
-async fn get_front<T: for<'a> Deserialize<'a> + Serialize>(
+impl<T: Serialize + DeserializeOwned> DurableVersioned for DurableOrdered<T> {
+    const VERSION: u64 = 1;
+}
+
+impl DurableVersioned for DurableReference {
+    const VERSION: u64 = 1;
+}
+
+async fn get_front<T: DurableVersioned>(
     state: &State,
     prefix: &str,
     limit: Option<usize>,
@@ -401,7 +734,7 @@ async fn get_front<T: for<'a> Deserialize<'a> + Serialize>(
     let mut js_item = iter.next()?;
     let mut res = Vec::new();
     while !js_item.done() {
-        let (key, item): (String, T) =
+        let (key, Versioned(item)): (String, Versioned<T>) =
             serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
         if key[..key_prefix.len()] != key_prefix {
             return Err(int_err("queue element key is improperly formatted"));
@@ -423,7 +756,9 @@ pub(crate) mod helper_state_store;
 pub(crate) mod leader_agg_job_queue;
 pub(crate) mod leader_batch_queue;
 pub(crate) mod leader_col_job_queue;
+pub(crate) mod metrics_aggregator;
 #[cfg(test)]
 pub(crate) mod mod_test;
 pub(crate) mod reports_pending;
 pub(crate) mod reports_processed;
+pub(crate) mod upload_rate_limiter;
@@ -2,16 +2,25 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use crate::durable::{
+    aggregate_store::{
+        decode_report_shares, encode_report_shares_chunked, REPORT_SHARES_CHUNK_MAX_LEN,
+    },
     durable_name_agg_store, durable_name_queue, durable_name_report_store,
+    durable_name_upload_rate_limiter,
+    leader_agg_job_queue::{AggJobLease, AggJobLeases},
+    leader_batch_queue::BatchCount,
     report_id_hex_from_report,
+    upload_rate_limiter::{UploadRateLimit, UploadRateLimiterResult, UploadRateLimiterState},
+    versioned, DurableOrdered, DurableVersioned, Versioned,
 };
 use daphne::{
-    messages::{Id, Report, ReportId, ReportMetadata},
-    test_version, test_versions, DapBatchBucket, DapVersion,
+    messages::{CollectReq, CollectResp, Id, Report, ReportId, ReportMetadata},
+    test_version, test_versions, DapAggregateShare, DapBatchBucket, DapVersion,
 };
 use paste::paste;
 use prio::codec::{ParameterizedDecode, ParameterizedEncode};
 use rand::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[test]
 fn durable_name() {
@@ -36,6 +45,262 @@ fn durable_name() {
         durable_name_agg_store(&DapVersion::Draft02, &id1.to_hex(), &DapBatchBucket::TimeInterval{ batch_window: time }),
         "v02/task/1111111111111111111111111111111111111111111111111111111111111111/window/1664850074",
     );
+
+    assert_eq!(
+        durable_name_upload_rate_limiter(&DapVersion::Draft02, &id1.to_hex(), None),
+        "v02/task/1111111111111111111111111111111111111111111111111111111111111111/upload_rate_limit",
+    );
+
+    assert_eq!(
+        durable_name_upload_rate_limiter(&DapVersion::Draft02, &id1.to_hex(), Some(3)),
+        "v02/task/1111111111111111111111111111111111111111111111111111111111111111/upload_rate_limit/client_shard/3",
+    );
+}
+
+#[test]
+fn upload_rate_limiter_rate() {
+    let limit = UploadRateLimit {
+        reports_per_sec: 2.0,
+        burst: 10,
+        daily_quota: None,
+    };
+    let t = 1664850074;
+    let mut state = UploadRateLimiterState::new(&limit, t);
+
+    // The bucket starts out full.
+    assert_eq!(state.acquire(&limit, 8, t), UploadRateLimiterResult::Ok);
+    assert_eq!(
+        state.acquire(&limit, 5, t),
+        UploadRateLimiterResult::Limited { retry_after: 2 }
+    );
+
+    // A rejected request doesn't consume tokens.
+    assert_eq!(state.acquire(&limit, 5, t + 2), UploadRateLimiterResult::Ok);
+    assert_eq!(
+        state.acquire(&limit, 2, t + 2),
+        UploadRateLimiterResult::Limited { retry_after: 1 }
+    );
+
+    // The bucket doesn't fill beyond the burst size.
+    assert_eq!(
+        state.acquire(&limit, 10, t + 100),
+        UploadRateLimiterResult::Ok
+    );
+    assert_eq!(
+        state.acquire(&limit, 1, t + 100),
+        UploadRateLimiterResult::Limited { retry_after: 1 }
+    );
+
+    // A request larger than the burst size is admitted once the bucket is full, after which the
+    // bucket is overdrawn.
+    assert_eq!(
+        state.acquire(&limit, 20, t + 104),
+        UploadRateLimiterResult::Limited { retry_after: 1 }
+    );
+    assert_eq!(
+        state.acquire(&limit, 20, t + 105),
+        UploadRateLimiterResult::Ok
+    );
+    assert_eq!(
+        state.acquire(&limit, 1, t + 105),
+        UploadRateLimiterResult::Limited { retry_after: 6 }
+    );
+}
+
+#[test]
+fn upload_rate_limiter_release() {
+    let limit = UploadRateLimit {
+        reports_per_sec: 1.0,
+        burst: 10,
+        daily_quota: Some(15),
+    };
+    let t = 86400 * 19270;
+    let mut state = UploadRateLimiterState::new(&limit, t);
+
+    // Tokens returned for reports that were not accepted, e.g., because they were rejected as
+    // replays, can be taken again.
+    assert_eq!(state.acquire(&limit, 10, t), UploadRateLimiterResult::Ok);
+    assert_eq!(
+        state.acquire(&limit, 4, t),
+        UploadRateLimiterResult::Limited { retry_after: 4 }
+    );
+    state.release(&limit, 6, t);
+    assert_eq!(state.acquire(&limit, 4, t), UploadRateLimiterResult::Ok);
+
+    // The bucket doesn't fill beyond the burst size and the returned reports don't count towards
+    // the quota.
+    state.release(&limit, 8, t);
+    assert_eq!(state.tokens, 10.0);
+    assert_eq!(state.day_count, 0);
+    assert_eq!(state.acquire(&limit, 10, t), UploadRateLimiterResult::Ok);
+    assert_eq!(
+        state.acquire(&limit, 6, t + 10),
+        UploadRateLimiterResult::Limited {
+            retry_after: 86400 - 10
+        }
+    );
+    assert_eq!(
+        state.acquire(&limit, 5, t + 10),
+        UploadRateLimiterResult::Ok
+    );
+}
+
+#[test]
+fn upload_rate_limiter_daily_quota() {
+    let limit = UploadRateLimit {
+        reports_per_sec: 1000.0,
+        burst: 1000,
+        daily_quota: Some(100),
+    };
+    let t = 86400 * 19270;
+    let mut state = UploadRateLimiterState::new(&limit, t);
+
+    assert_eq!(state.acquire(&limit, 60, t), UploadRateLimiterResult::Ok);
+    assert_eq!(
+        state.acquire(&limit, 60, t + 3600),
+        UploadRateLimiterResult::Limited {
+            retry_after: 86400 - 3600
+        }
+    );
+    assert_eq!(
+        state.acquire(&limit, 40, t + 3600),
+        UploadRateLimiterResult::Ok
+    );
+
+    // The quota is reset at the start of the next day.
+    assert_eq!(
+        state.acquire(&limit, 100, t + 86400),
+        UploadRateLimiterResult::Ok
+    );
+}
+
+#[test]
+fn agg_job_leases_release() {
+    let lease = |instance: &str| AggJobLease {
+        agg_job: DurableOrdered::new_ordered_by_time(instance.to_string(), "agg_job", 1664850074),
+        lease_id: "lease".to_string(),
+        expires_at: 1664850074,
+    };
+    let mut leases = AggJobLeases::default();
+    leases.insert(0, lease("a"), vec!["r1".to_string(), "r2".to_string()]);
+    leases.insert(1, lease("b"), vec!["r3".to_string()]);
+
+    // Reports not fetched under a lease are ignored. A lease is done once all of its reports have
+    // been released.
+    let released = leases.release(["r1".to_string(), "r3".to_string(), "r4".to_string()]);
+    assert_eq!(released.reports.len(), 2);
+    assert_eq!(released.reports["a"], ["r1"]);
+    assert_eq!(released.reports["b"], ["r3"]);
+    assert_eq!(released.leases.len(), 1);
+    assert_eq!(released.leases[0].0, 1);
+    assert_eq!(released.leases[0].1.agg_job.as_ref(), "b");
+
+    // A report is only released once.
+    let released = leases.release(["r1".to_string(), "r2".to_string()]);
+    assert_eq!(released.reports.len(), 1);
+    assert_eq!(released.reports["a"], ["r2"]);
+    assert_eq!(released.leases.len(), 1);
+    assert_eq!(released.leases[0].1.agg_job.as_ref(), "a");
+}
+
+#[test]
+fn durable_ordered_by_time() {
+    let earlier = DurableOrdered::new_ordered_by_time((), "prefix", 999);
+    let later = DurableOrdered::new_ordered_by_time((), "prefix", 1000);
+
+    assert!(earlier
+        .key()
+        .starts_with("prefix/item/time/00000000000000000999/nonce/"));
+    assert!(earlier.key() < later.key());
+}
+
+// Check that a value stored before the versioned envelope was introduced and a value stored in
+// the current envelope are both readable, and that the latter is what we write now. If this fails,
+// then a value written by the previous deployment can no longer be read.
+fn check_versioned<T: DurableVersioned>(unversioned: &str, current: &str) {
+    let Versioned(value) = serde_json::from_str::<Versioned<T>>(unversioned).unwrap();
+    assert_eq!(serde_json::to_string(&versioned(&value)).unwrap(), current);
+
+    let Versioned(value) = serde_json::from_str::<Versioned<T>>(current).unwrap();
+    assert_eq!(serde_json::to_string(&versioned(&value)).unwrap(), current);
+
+    // Values written by a later version are rejected.
+    let later = current.replacen(
+        &format!(r#"{{"version":{}"#, T::VERSION),
+        &format!(r#"{{"version":{}"#, T::VERSION + 1),
+        1,
+    );
+    assert!(serde_json::from_str::<Versioned<T>>(&later).is_err());
+}
+
+#[test]
+fn versioned_previous_encodings() {
+    check_versioned::<BatchCount>(
+        r#"{"batch_id":"1111111111111111111111111111111111111111111111111111111111111111","report_count":3}"#,
+        r#"{"version":1,"value":{"batch_id":"1111111111111111111111111111111111111111111111111111111111111111","report_count":3}}"#,
+    );
+
+    check_versioned::<(Id, CollectReq)>(
+        r#"["2222222222222222222222222222222222222222222222222222222222222222",{"task_id":"1111111111111111111111111111111111111111111111111111111111111111","query":"fixed_size_current_batch","agg_param":[]}]"#,
+        r#"{"version":1,"value":["2222222222222222222222222222222222222222222222222222222222222222",{"task_id":"1111111111111111111111111111111111111111111111111111111111111111","query":"fixed_size_current_batch","agg_param":[]}]}"#,
+    );
+
+    check_versioned::<CollectResp>(
+        r#"{"part_batch_sel":"time_interval","report_count":2,"encrypted_agg_shares":[{"config_id":1,"enc":"0102","payload":"0304"}]}"#,
+        r#"{"version":1,"value":{"part_batch_sel":"time_interval","report_count":2,"encrypted_agg_shares":[{"config_id":1,"enc":"0102","payload":"0304"}]}}"#,
+    );
+
+    check_versioned::<DapAggregateShare>(
+        r#"{"report_count":2,"checksum":[7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7],"data":{"field64":[[0,0,0,0,0,0,0,5],[0,0,0,0,0,0,0,1]]}}"#,
+        r#"{"version":1,"value":{"report_count":2,"checksum":[7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7],"data":{"field64":[[0,0,0,0,0,0,0,5],[0,0,0,0,0,0,0,1]]}}}"#,
+    );
+
+    check_versioned::<AggJobLease>(
+        r#"{"agg_job":{"item":"queue/0","prefix":"agg_job","ordinal":"time/00000000001664850074/nonce/00"},"lease_id":"01","expires_at":1664850134}"#,
+        r#"{"version":1,"value":{"agg_job":{"item":"queue/0","prefix":"agg_job","ordinal":"time/00000000001664850074/nonce/00"},"lease_id":"01","expires_at":1664850134}}"#,
+    );
+
+    check_versioned::<UploadRateLimiterState>(
+        r#"{"tokens":9.5,"updated_at":1664850074,"day":19269,"day_count":1}"#,
+        r#"{"version":1,"value":{"tokens":9.5,"updated_at":1664850074,"day":19269,"day_count":1}}"#,
+    );
+
+    check_versioned::<String>(r#""queue/0""#, r#"{"version":1,"value":"queue/0"}"#);
+}
+
+// A type whose encoding changed: versions before 2 stored the count as a string.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct VersionedCount(u64);
+
+impl DurableVersioned for VersionedCount {
+    const VERSION: u64 = 2;
+
+    fn decode_previous<'de, D: Deserializer<'de>>(
+        version: u64,
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        assert!(version < 2);
+        let count = String::deserialize(deserializer)?;
+        count
+            .parse()
+            .map(VersionedCount)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[test]
+fn versioned_decode_previous() {
+    for (stored, count) in [
+        (r#""23""#, 23),
+        (r#"{"version":1,"value":"23"}"#, 23),
+        (r#"{"version":2,"value":23}"#, 23),
+    ] {
+        let Versioned(got) = serde_json::from_str::<Versioned<VersionedCount>>(stored).unwrap();
+        assert_eq!(got, VersionedCount(count));
+    }
+    assert!(
+        serde_json::from_str::<Versioned<VersionedCount>>(r#"{"version":3,"value":23}"#).is_err()
+    );
 }
 
 // Test that the `report_id_from_report()` method properly extracts the report ID from the
@@ -63,3 +328,60 @@ fn parse_report_id_hex_from_report(version: DapVersion) {
 }
 
 test_versions! {parse_report_id_hex_from_report}
+
+fn report_share_from_json(report_count: u64, data: serde_json::Value) -> DapAggregateShare {
+    serde_json::from_value(serde_json::json!({
+        "report_count": report_count,
+        "checksum": vec![report_count as u8; 32],
+        "data": data,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn report_shares_chunked() {
+    // An aggregation job whose output shares don't fit in a single value.
+    let mut report_shares = (0..100)
+        .map(|i| {
+            report_share_from_json(
+                1,
+                serde_json::json!({ "field128": vec![[i as u8; 16]; 100] }),
+            )
+        })
+        .collect::<Vec<_>>();
+    report_shares.push(report_share_from_json(
+        1,
+        serde_json::json!({ "field64": [[0, 0, 0, 0, 0, 0, 0, 1]] }),
+    ));
+    report_shares.push(report_share_from_json(
+        1,
+        serde_json::json!({ "field_prio2": [[0, 0, 0, 1]] }),
+    ));
+    report_shares.push(report_share_from_json(
+        1,
+        serde_json::json!({ "registered": { "vdaf": "toy", "share": "0102" } }),
+    ));
+    report_shares.push(report_share_from_json(0, serde_json::Value::Null));
+
+    let chunks = encode_report_shares_chunked(&report_shares).unwrap();
+    assert!(chunks.len() > 1);
+    assert!(chunks
+        .iter()
+        .all(|chunk| chunk.len() <= REPORT_SHARES_CHUNK_MAX_LEN));
+
+    let decoded = chunks
+        .iter()
+        .flat_map(|chunk| decode_report_shares(chunk).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        serde_json::to_value(decoded).unwrap(),
+        serde_json::to_value(report_shares).unwrap()
+    );
+
+    // The output share of a single report must fit in a chunk.
+    let too_large = report_share_from_json(
+        1,
+        serde_json::json!({ "field128": vec![[0_u8; 16]; REPORT_SHARES_CHUNK_MAX_LEN / 16] }),
+    );
+    assert!(encode_report_shares_chunked(&[too_large]).is_err());
+}
@@ -2,23 +2,33 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use crate::{
-    config::DaphneWorkerConfig,
+    cached_isolate_state,
+    config::{DaphneWorkerConfig, DaphneWorkerRequestState},
     durable::{
         durable_name_queue,
         leader_agg_job_queue::{
-            DURABLE_LEADER_AGG_JOB_QUEUE_FINISH, DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
+            AggJobLease, LeaderAggJobQueueLeaseReq, DURABLE_LEADER_AGG_JOB_QUEUE_ACK,
+            DURABLE_LEADER_AGG_JOB_QUEUE_FINISH, DURABLE_LEADER_AGG_JOB_QUEUE_LEASE,
+            DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
         },
-        report_id_hex_from_report, state_get, state_set_if_not_exists, DurableConnector,
-        DurableOrdered, BINDING_DAP_LEADER_AGG_JOB_QUEUE, BINDING_DAP_REPORTS_PENDING,
+        report_id_hex_from_report, state_get, state_get_or_default, state_get_versioned,
+        state_put_versioned, state_set_if_not_exists, task_id_from_durable_request,
+        DurableConnector, DurableOrdered, BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+        BINDING_DAP_REPORTS_PENDING,
     },
     initialize_tracing, int_err,
 };
+use daphne::{messages::Id, roles::DapLeader, DapLeaderProcessTelemetry};
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use std::time::Duration;
+use tracing::{debug, error, info_span, Instrument};
 use worker::*;
 
 pub(crate) const DURABLE_REPORTS_PENDING_GET: &str = "/internal/do/reports_pending/get";
+pub(crate) const DURABLE_REPORTS_PENDING_DELETE: &str = "/internal/do/reports_pending/delete";
 pub(crate) const DURABLE_REPORTS_PENDING_PUT: &str = "/internal/do/reports_pending/put";
+pub(crate) const DURABLE_REPORTS_PENDING_PUT_MULTIPLE: &str =
+    "/internal/do/reports_pending/put_multiple";
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -36,20 +46,43 @@ pub(crate) enum ReportsPendingResult {
 ///   `LeaderAggregationJobQueue`. If report is found in this instance with the same ID, then an
 ///   error is returned.
 ///
-/// - `DURABLE_REPORTS_PENDING_GET`: Used to drain reports from storage so that they can be
+/// - `DURABLE_REPORTS_PENDING_PUT_MULTIPLE`: Like `DURABLE_REPORTS_PENDING_PUT`, except that a
+///   sequence of reports is stored. The IDs of reports found in this instance are returned.
+///
+/// - `DURABLE_REPORTS_PENDING_GET`: Used to fetch reports so that they can be aggregated. The
+///   reports are not removed from storage, so that they are not lost if aggregation fails; the
+///   caller is expected to hold the lease for the aggregation job (see
+///   `LeaderAggregationJobQueue`) so that no one else fetches them in the meantime.
+///
+/// - `DURABLE_REPORTS_PENDING_DELETE`: Used to remove reports from storage once they have been
 ///   aggregated. Whenever the instance becomes empty, the aggregation job is removed from
-///   `LeadeerAggregationJobQueue`.
+///   `LeaderAggregationJobQueue`.
+///
+/// If an aggregation job trigger is configured for the task (see `DAP_AGG_JOB_TRIGGERS`), then
+/// the instance also sets an alarm for when it holds enough reports or its oldest report is old
+/// enough. When the alarm fires, the instance leases its aggregation job from
+/// `LeaderAggregationJobQueue` and aggregates the reports in the same way as
+/// `DapLeader::process()`, deleting them only once they have been aggregated; if reports remain
+/// afterwards, then the alarm is set to fire again right away. If the job is leased to a
+/// processor, then the alarm is set to fire again once the lease expires.
 ///
 /// The schema for stored reports is as follows:
 ///
 /// ```text
-/// [Pending report]  pending/<report_id> -> String
-/// [Aggregation job] agg_job -> DurableOrdered<String>
+/// [Pending report]        pending/<report_id> -> String
+/// [Pending report count]  pending_count -> u64
+/// [Aggregation job]       agg_job -> DurableOrdered<String>
+/// [Aggregation job shard] agg_job_shard -> u64
+/// [Task ID]               task_id -> Id
 /// ```
 ///
 /// where `<report_id>` is the ID of the report. The value is the hex-encoded report. The
 /// aggregation job consists of a reference to the name of this DO instance stored in a queue in
-/// `LeaderAggregationJobQueue`.
+/// `LeaderAggregationJobQueue`. The aggregation job shard is the queue to which the job was
+/// dispatched; if missing, the job was dispatched to shard 0. The task ID is recorded when the
+/// alarm is set so that the trigger can be looked up when it fires. Once the instance has been
+/// drained, all of these keys are deleted so that no storage is left behind, e.g., for a task
+/// that has expired.
 #[durable_object]
 pub struct ReportsPending {
     #[allow(dead_code)]
@@ -59,6 +92,246 @@ pub struct ReportsPending {
     touched: bool,
 }
 
+impl ReportsPending {
+    /// Check if processing for this bucket of reports has been scheduled. If not, add this bucket
+    /// to the aggregation job queue.
+    async fn schedule_agg_job(&self, durable: &DurableConnector<'_>, id_hex: String) -> Result<()> {
+        let agg_job: Option<DurableOrdered<String>> =
+            state_get_versioned(&self.state, "agg_job").await?;
+        if agg_job.is_none() {
+            let agg_job_shard = self.config.agg_job_queue_shard(&id_hex);
+            let agg_job = DurableOrdered::new_roughly_ordered(id_hex, "agg_job");
+            durable
+                .post(
+                    BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+                    DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
+                    durable_name_queue(agg_job_shard),
+                    &agg_job,
+                )
+                .await?;
+            state_put_versioned(&self.state, "agg_job", &agg_job).await?;
+            self.state
+                .storage()
+                .put("agg_job_shard", agg_job_shard)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Count `stored` newly stored reports and, if an aggregation job trigger is configured for
+    /// the task, set the alarm accordingly.
+    async fn count_stored(&self, task_id: Option<Id>, stored: u64) -> Result<()> {
+        let pending_count: u64 = state_get_or_default(&self.state, "pending_count").await?;
+        let pending_count = pending_count + stored;
+        self.state
+            .storage()
+            .put("pending_count", pending_count)
+            .await?;
+
+        let (task_id, trigger) = match task_id
+            .as_ref()
+            .and_then(|task_id| Some((task_id, self.config.agg_job_trigger(task_id)?)))
+        {
+            Some(task_trigger) => task_trigger,
+            None => return Ok(()),
+        };
+
+        let alarm = self.state.storage().get_alarm().await?;
+        if trigger.min_reports.map_or(false, |n| pending_count >= n) {
+            if alarm.map_or(true, |alarm| alarm > Date::now().as_millis() as i64) {
+                self.state.storage().set_alarm(Duration::ZERO).await?;
+            }
+        } else if let (Some(max_age), None) = (trigger.max_age, alarm) {
+            self.state
+                .storage()
+                .set_alarm(Duration::from_secs(max_age))
+                .await?;
+        } else {
+            return Ok(());
+        }
+        self.state.storage().put("task_id", task_id).await
+    }
+
+    /// Return the keys and values of at most `reports_requested` reports, without removing them
+    /// from storage.
+    async fn peek(&self, reports_requested: usize) -> Result<(Vec<String>, Vec<String>)> {
+        let opt = ListOptions::new()
+            .prefix("pending/")
+            .limit(reports_requested);
+        let iter = self.state.storage().list_with_options(opt).await?.entries();
+        let mut item = iter.next()?;
+        let mut reports = Vec::with_capacity(reports_requested);
+        let mut keys = Vec::with_capacity(reports_requested);
+        while !item.done() {
+            let (key, report_hex): (String, String) =
+                serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
+            reports.push(report_hex);
+            keys.push(key);
+            item = iter.next()?;
+        }
+        Ok((keys, reports))
+    }
+
+    /// Remove the reports with the given keys from storage. If the instance is now empty, then
+    /// remove the aggregation job from the queue.
+    async fn delete(&self, durable: &DurableConnector<'_>, keys: Vec<String>) -> Result<()> {
+        // NOTE In order to support DAP tasks that require longer batch lifetimes, it will
+        // necessary to check if the lifetime has been reached before removing reports from
+        // storage. We might consider putting reports in KV instead.
+        // Only count the reports that were actually deleted, since a report may be deleted twice
+        // if the lease under which it was fetched expired.
+        let deleted = self.state.storage().delete_multiple(keys).await?;
+        let pending_count: u64 = state_get_or_default(&self.state, "pending_count").await?;
+        self.state
+            .storage()
+            .put(
+                "pending_count",
+                pending_count.saturating_sub(deleted as u64),
+            )
+            .await?;
+
+        // Check if this bucket is now empty, and if so, remove it from the agg job queue.
+        if self.is_empty().await? {
+            let agg_job: Option<DurableOrdered<String>> =
+                state_get_versioned(&self.state, "agg_job").await?;
+            let agg_job_shard: Option<u64> = state_get(&self.state, "agg_job_shard").await?;
+            if let Some(agg_job) = agg_job {
+                // This agg_job delete MUST occur right after the get above, with no
+                // intervening wait on anything other than this DO, in order for us to get
+                // the transactional I/O coalescing workers promises. If some report arrives
+                // before we delete the old agg_job_queue entry, that's ok as it will just
+                // cause a new leader agg job to be created.  There is no race here, as the
+                // new job will have a different name due to the timestamp and nonce that
+                // new_roughly_ordered() adds when constructing the name.
+                self.state
+                    .storage()
+                    .delete_multiple(vec!["agg_job", "agg_job_shard", "pending_count", "task_id"])
+                    .await?;
+                durable
+                    .post(
+                        BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+                        DURABLE_LEADER_AGG_JOB_QUEUE_FINISH,
+                        durable_name_queue(agg_job_shard.unwrap_or(0)),
+                        &agg_job,
+                    )
+                    .await?;
+            }
+        }
+
+        debug!(
+            "deleted {deleted} reports from bucket {}",
+            self.state.id().to_string()
+        );
+        Ok(())
+    }
+
+    async fn is_empty(&self) -> Result<bool> {
+        Ok(self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix("pending/").limit(1))
+            .await?
+            .size()
+            == 0)
+    }
+
+    /// Run aggregation jobs for the pending reports as configured by the trigger for the task.
+    ///
+    /// The aggregation job for this instance is first leased from `LeaderAggregationJobQueue`, as
+    /// is done by `DapLeader::process()`, so that the reports are not handed to a concurrent
+    /// processor. The reports are only removed from storage once they have been aggregated: if
+    /// aggregation fails, then they are aggregated once the lease expires.
+    async fn run_agg_jobs(&self, task_id: &Id) -> Result<AlarmOutcome> {
+        let trigger = match self.config.agg_job_trigger(task_id) {
+            Some(trigger) => trigger,
+            None => return Ok(AlarmOutcome::Idle),
+        };
+        let agg_job: DurableOrdered<String> =
+            match state_get_versioned(&self.state, "agg_job").await? {
+                Some(agg_job) => agg_job,
+                None => return Ok(AlarmOutcome::Idle),
+            };
+        let agg_job_shard: u64 = state_get(&self.state, "agg_job_shard").await?.unwrap_or(0);
+
+        let durable = DurableConnector::new(&self.env);
+        let leases: Vec<AggJobLease> = durable
+            .post(
+                BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+                DURABLE_LEADER_AGG_JOB_QUEUE_LEASE,
+                durable_name_queue(agg_job_shard),
+                &LeaderAggJobQueueLeaseReq {
+                    max_agg_jobs: 1,
+                    lease_secs: self.config.agg_job_lease_secs,
+                    agg_job: Some(agg_job),
+                },
+            )
+            .await?;
+        let lease = match leases.into_iter().next() {
+            Some(lease) => lease,
+            None => return Ok(AlarmOutcome::Leased),
+        };
+
+        let (keys, reports_hex) = self
+            .peek(trigger.max_reports.try_into().map_err(int_err)?)
+            .await?;
+        if !reports_hex.is_empty() {
+            self.aggregate(task_id, reports_hex).await?;
+            self.delete(&durable, keys).await?;
+        }
+
+        // Return the job to the queue so that any remaining reports are aggregated.
+        durable
+            .post::<_, bool>(
+                BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+                DURABLE_LEADER_AGG_JOB_QUEUE_ACK,
+                durable_name_queue(agg_job_shard),
+                &lease,
+            )
+            .await?;
+        Ok(AlarmOutcome::Aggregated)
+    }
+
+    /// Run aggregation jobs for the given reports.
+    async fn aggregate(&self, task_id: &Id, reports_hex: Vec<String>) -> Result<()> {
+        let state = DaphneWorkerRequestState::new(cached_isolate_state(&self.env)?, None)?;
+        let daph = state.handler(&self.env);
+        let reports = daph
+            .group_reports(reports_hex)
+            .await
+            .map_err(|e| int_err(format!("ReportsPending: failed to group reports: {e}")))?;
+        let mut telem = DapLeaderProcessTelemetry::default();
+        daph.run_agg_jobs(reports, None, &mut telem)
+            .instrument(info_span!("run_agg_jobs", task_id = %task_id))
+            .await
+            .map_err(|e| int_err(format!("ReportsPending: failed to run agg jobs: {e}")))?;
+        debug!("{:?}", telem);
+
+        if let Err(e) = state.maybe_push_metrics().await {
+            error!("ReportsPending: failed to push metrics: {e}");
+        }
+        // Durable objects can't defer work past the alarm, so the metrics are merged here. This
+        // is a single request to one shard of the `MetricsAggregator`.
+        if let Some(aggregate_metrics) = state.maybe_aggregate_metrics(&self.env) {
+            if let Err(e) = aggregate_metrics.await {
+                error!("ReportsPending: failed to aggregate metrics: {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of running aggregation jobs when the alarm fires.
+enum AlarmOutcome {
+    /// There was nothing to aggregate.
+    Idle,
+
+    /// Reports were aggregated.
+    Aggregated,
+
+    /// The aggregation job is leased to a processor.
+    Leased,
+}
+
 #[durable_object]
 impl DurableObject for ReportsPending {
     fn new(state: State, env: Env) -> Self {
@@ -79,74 +352,29 @@ impl DurableObject for ReportsPending {
         ensure_garbage_collected!(req, self, id_hex.clone(), BINDING_DAP_REPORTS_PENDING);
 
         match (req.path().as_ref(), req.method()) {
-            // Drain the requested number of reports from storage.
+            // Fetch the requested number of reports, without removing them from storage.
             //
             // Input: `reports_requested: usize`
             // Output: `Vec<String>` (hex-encoded reports)
             (DURABLE_REPORTS_PENDING_GET, Method::Post) => {
                 let reports_requested: usize = req.json().await?;
-                let opt = ListOptions::new()
-                    .prefix("pending/")
-                    .limit(reports_requested);
-                let iter = self.state.storage().list_with_options(opt).await?.entries();
-                let mut item = iter.next()?;
-                let mut reports = Vec::with_capacity(reports_requested);
-                let mut keys = Vec::with_capacity(reports_requested);
-                while !item.done() {
-                    let (key, report_hex): (String, String) =
-                        serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
-                    reports.push(report_hex);
-                    keys.push(key);
-                    item = iter.next()?;
-                }
-
-                // NOTE In order to support DAP tasks that require longer batch lifetimes, it will
-                // necessary to check if the lifetime has been reached before removing reports from
-                // storage. We might consider putting reports in KV instead.
-                self.state.storage().delete_multiple(keys).await?;
-
-                // Check if this bucket is now empty, and if so, remove it from the agg job queue.
-                let empty = self
-                    .state
-                    .storage()
-                    .list_with_options(ListOptions::new().prefix("pending/").limit(1))
-                    .await?
-                    .size()
-                    == 0;
-
-                if empty {
-                    let agg_job: Option<DurableOrdered<String>> =
-                        state_get(&self.state, "agg_job").await?;
-                    if let Some(agg_job) = agg_job {
-                        // This agg_job delete MUST occur right after the get above, with no
-                        // intervening wait on anything other than this DO, in order for us to get
-                        // the transactional I/O coalescing workers promises. If some report arrives
-                        // before we delete the old agg_job_queue entry, that's ok as it will just
-                        // cause a new leader agg job to be created.  There is no race here, as the
-                        // new job will have a different name due to the timestamp and nonce that
-                        // new_roughly_ordered() adds when constructing the name.
-                        self.state.storage().delete("agg_job").await?;
-                        // NOTE There is only one agg job queue for now. In the future, work will
-                        // be sharded across multiple queues.
-                        durable
-                            .post(
-                                BINDING_DAP_LEADER_AGG_JOB_QUEUE,
-                                DURABLE_LEADER_AGG_JOB_QUEUE_FINISH,
-                                durable_name_queue(0),
-                                &agg_job,
-                            )
-                            .await?;
-                    }
-                }
-
-                debug!(
-                    "drained {} reports from bucket {}",
-                    reports.len(),
-                    self.state.id().to_string()
-                );
+                let (_keys, reports) = self.peek(reports_requested).await?;
                 Response::from_json(&reports)
             }
 
+            // Remove reports from storage.
+            //
+            // Input: `Vec<String>` (hex-encoded IDs of the reports)
+            (DURABLE_REPORTS_PENDING_DELETE, Method::Post) => {
+                let report_ids_hex: Vec<String> = req.json().await?;
+                let keys = report_ids_hex
+                    .into_iter()
+                    .map(|report_id_hex| format!("pending/{report_id_hex}"))
+                    .collect();
+                self.delete(&durable, keys).await?;
+                Response::from_json(&())
+            }
+
             // Store a report.
             //
             // Input: `report_hex: String` (hex-encoded report)
@@ -164,27 +392,41 @@ impl DurableObject for ReportsPending {
                     return Response::from_json(&ReportsPendingResult::ErrReportExists);
                 }
 
-                // Check if processing for this bucket of reports has been scheduled. If not, add
-                // this bucket to the aggregation job queue.
-                let agg_job: Option<DurableOrdered<String>> =
-                    state_get(&self.state, "agg_job").await?;
-                if agg_job.is_none() {
-                    let agg_job = DurableOrdered::new_roughly_ordered(id_hex, "agg_job");
-
-                    // TODO Shard the work across multiple job queues rather than just one. (See
-                    // issue #25.) For now there is jsut one job queue.
-                    durable
-                        .post(
-                            BINDING_DAP_LEADER_AGG_JOB_QUEUE,
-                            DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
-                            durable_name_queue(0),
-                            &agg_job,
-                        )
-                        .await?;
-                    self.state.storage().put("agg_job", agg_job).await?;
+                self.schedule_agg_job(&durable, id_hex).await?;
+                self.count_stored(task_id_from_durable_request(&req), 1)
+                    .await?;
+                Response::from_json(&ReportsPendingResult::Ok)
+            }
+
+            // Store a sequence of reports.
+            //
+            // Input: `report_hex_set: Vec<String>` (hex-encoded reports)
+            // Output: `Vec<String>` (hex-encoded IDs of the reports that already exist)
+            (DURABLE_REPORTS_PENDING_PUT_MULTIPLE, Method::Post) => {
+                let report_hex_set: Vec<String> = req.json().await?;
+                let mut exists = Vec::new();
+                let mut stored = 0;
+                for report_hex in report_hex_set.into_iter() {
+                    let report_id_hex = report_id_hex_from_report(&report_hex)
+                        .ok_or_else(|| int_err("failed to parse report_id from report"))?;
+
+                    let key = format!("pending/{report_id_hex}");
+                    if state_set_if_not_exists::<String>(&self.state, &key, &report_hex)
+                        .await?
+                        .is_some()
+                    {
+                        exists.push(report_id_hex.to_string());
+                    } else {
+                        stored += 1;
+                    }
                 }
 
-                Response::from_json(&ReportsPendingResult::Ok)
+                if stored > 0 {
+                    self.schedule_agg_job(&durable, id_hex).await?;
+                    self.count_stored(task_id_from_durable_request(&req), stored)
+                        .await?;
+                }
+                Response::from_json(&exists)
             }
 
             _ => Err(int_err(format!(
@@ -194,4 +436,27 @@ impl DurableObject for ReportsPending {
             ))),
         }
     }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        let task_id: Option<Id> = state_get(&self.state, "task_id").await?;
+        if let Some(ref task_id) = task_id {
+            match self.run_agg_jobs(task_id).await? {
+                AlarmOutcome::Idle => (),
+                // If reports remain, then run another job right away.
+                AlarmOutcome::Aggregated => {
+                    if !self.is_empty().await? {
+                        self.state.storage().set_alarm(Duration::ZERO).await?;
+                    }
+                }
+                // Try again once the lease has expired, in case the processor fails.
+                AlarmOutcome::Leased => {
+                    self.state
+                        .storage()
+                        .set_alarm(Duration::from_secs(self.config.agg_job_lease_secs))
+                        .await?;
+                }
+            }
+        }
+        Response::from_json(&())
+    }
 }
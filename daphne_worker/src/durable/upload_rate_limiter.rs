@@ -0,0 +1,246 @@
+// Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::{
+    config::DaphneWorkerConfig,
+    durable::{
+        state_get_versioned, state_put_versioned, DurableVersioned, BINDING_DAP_UPLOAD_RATE_LIMITER,
+    },
+    initialize_tracing, int_err, now,
+};
+use daphne::messages::Time;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+pub(crate) const DURABLE_UPLOAD_RATE_LIMITER_ACQUIRE: &str =
+    "/internal/do/upload_rate_limiter/acquire";
+pub(crate) const DURABLE_UPLOAD_RATE_LIMITER_RELEASE: &str =
+    "/internal/do/upload_rate_limiter/release";
+
+/// Number of `UploadRateLimiter` instances across which the Client limits of a task are spread.
+pub(crate) const UPLOAD_RATE_LIMITER_CLIENT_SHARD_COUNT: u64 = 64;
+
+const LIMITER_STATE: &str = "limiter_state";
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// Rate limit and quota applied to report uploads.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct UploadRateLimit {
+    /// Average number of reports admitted per second.
+    pub(crate) reports_per_sec: f64,
+
+    /// Maximum number of reports admitted at once.
+    pub(crate) burst: u64,
+
+    /// Maximum number of reports admitted per day (UTC). If not set, then the number of reports
+    /// is only limited by the rate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) daily_quota: Option<u64>,
+}
+
+/// Request to acquire or release the limit for a number of reports.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct UploadRateLimiterReq {
+    pub(crate) limit: UploadRateLimit,
+
+    /// The Client whose limit is acquired or released. If not set, then the request is for the
+    /// task's limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) client: Option<String>,
+
+    pub(crate) report_count: u64,
+}
+
+impl UploadRateLimiterReq {
+    fn state_key(&self) -> String {
+        if let Some(ref client) = self.client {
+            format!("client/{client}")
+        } else {
+            LIMITER_STATE.to_string()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum UploadRateLimiterResult {
+    Ok,
+    Limited { retry_after: u64 },
+}
+
+/// State of a token bucket and daily quota.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct UploadRateLimiterState {
+    /// Number of tokens in the bucket. This is negative if more reports were admitted than the
+    /// bucket held.
+    pub(crate) tokens: f64,
+
+    /// Time at which the bucket was last refilled.
+    pub(crate) updated_at: Time,
+
+    /// The current day, i.e., the number of days since the UNIX epoch.
+    pub(crate) day: u64,
+
+    /// Number of reports admitted during the current day.
+    pub(crate) day_count: u64,
+}
+
+impl DurableVersioned for UploadRateLimiterState {
+    const VERSION: u64 = 1;
+}
+
+impl UploadRateLimiterState {
+    /// Initial state: the bucket is full and no reports have been admitted.
+    pub(crate) fn new(limit: &UploadRateLimit, now: Time) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated_at: now,
+            day: now / SECONDS_PER_DAY,
+            day_count: 0,
+        }
+    }
+
+    /// Refill the bucket and roll over the daily quota as of time `now`.
+    fn refill(&mut self, limit: &UploadRateLimit, now: Time) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens = (self.tokens + elapsed * limit.reports_per_sec).min(limit.burst as f64);
+        self.updated_at = now.max(self.updated_at);
+
+        let day = now / SECONDS_PER_DAY;
+        if day > self.day {
+            self.day = day;
+            self.day_count = 0;
+        }
+    }
+
+    /// Admit `report_count` reports at time `now`, if the daily quota permits and the bucket holds
+    /// enough tokens; if more reports are requested than the burst size, then the bucket only
+    /// needs to be full, after which it is overdrawn. The tokens are taken and the reports are
+    /// counted towards the quota. If the reports are not admitted, then nothing is taken and the
+    /// number of seconds to wait before retrying is returned.
+    pub(crate) fn acquire(
+        &mut self,
+        limit: &UploadRateLimit,
+        report_count: u64,
+        now: Time,
+    ) -> UploadRateLimiterResult {
+        self.refill(limit, now);
+
+        if let Some(daily_quota) = limit.daily_quota {
+            if self.day_count.saturating_add(report_count) > daily_quota {
+                return UploadRateLimiterResult::Limited {
+                    retry_after: (self.day + 1) * SECONDS_PER_DAY - now,
+                };
+            }
+        }
+
+        let required = report_count.min(limit.burst) as f64;
+        if self.tokens < required {
+            return UploadRateLimiterResult::Limited {
+                retry_after: (((required - self.tokens) / limit.reports_per_sec).ceil() as u64)
+                    .max(1),
+            };
+        }
+
+        self.tokens -= report_count as f64;
+        self.day_count = self.day_count.saturating_add(report_count);
+        UploadRateLimiterResult::Ok
+    }
+
+    /// Return the tokens for `report_count` reports admitted by [`Self::acquire`] that were not
+    /// accepted after all, and remove them from the quota.
+    pub(crate) fn release(&mut self, limit: &UploadRateLimit, report_count: u64, now: Time) {
+        self.refill(limit, now);
+        self.tokens = (self.tokens + report_count as f64).min(limit.burst as f64);
+        self.day_count = self.day_count.saturating_sub(report_count);
+    }
+}
+
+/// Durable Object (DO) for enforcing upload rate limits and quotas.
+///
+/// This object defines two API endpoints: `DURABLE_UPLOAD_RATE_LIMITER_ACQUIRE`, which is used to
+/// admit a number of reports subject to the limit passed in the request, taking their tokens if
+/// they are admitted; and `DURABLE_UPLOAD_RATE_LIMITER_RELEASE`, which is used to return the tokens
+/// of admitted reports that were not accepted after all. The limit is passed by the caller so that
+/// configuration changes take effect immediately.
+///
+/// An instance either holds the task's limit or the limits of the Clients that hash to its shard
+/// (see `durable_name_upload_rate_limiter()`). The schema for data stored in instances of this DO
+/// is as follows:
+///
+/// ```text
+/// [State] limiter_state -> UploadRateLimiterState
+/// [State] client/<client> -> UploadRateLimiterState
+/// ```
+#[durable_object]
+pub struct UploadRateLimiter {
+    #[allow(dead_code)]
+    state: State,
+    env: Env,
+    config: DaphneWorkerConfig,
+    touched: bool,
+}
+
+#[durable_object]
+impl DurableObject for UploadRateLimiter {
+    fn new(state: State, env: Env) -> Self {
+        initialize_tracing(&env);
+        let config =
+            DaphneWorkerConfig::from_worker_env(&env).expect("failed to load configuration");
+        Self {
+            state,
+            env,
+            config,
+            touched: false,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let id_hex = self.state.id().to_string();
+        ensure_garbage_collected!(req, self, id_hex, BINDING_DAP_UPLOAD_RATE_LIMITER);
+
+        match (req.path().as_ref(), req.method()) {
+            // Admit a number of reports, taking their tokens if they are admitted.
+            //
+            // Input: `UploadRateLimiterReq`
+            // Output: `UploadRateLimiterResult`
+            (DURABLE_UPLOAD_RATE_LIMITER_ACQUIRE, Method::Post) => {
+                let acquire_req: UploadRateLimiterReq = req.json().await?;
+                let now = now();
+                let state_key = acquire_req.state_key();
+                let mut limiter_state = state_get_versioned(&self.state, &state_key)
+                    .await?
+                    .unwrap_or_else(|| UploadRateLimiterState::new(&acquire_req.limit, now));
+                let result =
+                    limiter_state.acquire(&acquire_req.limit, acquire_req.report_count, now);
+                if result == UploadRateLimiterResult::Ok {
+                    state_put_versioned(&self.state, &state_key, &limiter_state).await?;
+                }
+                Response::from_json(&result)
+            }
+
+            // Return the tokens of a number of admitted reports that were not accepted.
+            //
+            // Input: `UploadRateLimiterReq`
+            // Output: `()`
+            (DURABLE_UPLOAD_RATE_LIMITER_RELEASE, Method::Post) => {
+                let release_req: UploadRateLimiterReq = req.json().await?;
+                let now = now();
+                let state_key = release_req.state_key();
+                let mut limiter_state = state_get_versioned(&self.state, &state_key)
+                    .await?
+                    .unwrap_or_else(|| UploadRateLimiterState::new(&release_req.limit, now));
+                limiter_state.release(&release_req.limit, release_req.report_count, now);
+                state_put_versioned(&self.state, &state_key, &limiter_state).await?;
+                Response::from_json(&())
+            }
+
+            _ => Err(int_err(format!(
+                "UploadRateLimiter: unexpected request: method={:?}; path={:?}",
+                req.method(),
+                req.path()
+            ))),
+        }
+    }
+}
@@ -20,6 +20,11 @@
 //! (DOs)](https://developers.cloudflare.com/workers/learning/using-durable-objects/) for
 //! transactional storage.
 //!
+//! Structured values are written to DO storage in a versioned envelope, so that a release can read
+//! the values written by the previous one (e.g., for aggregation and collection jobs that are in
+//! flight during a deployment). The Helper's state has its own versioned encoding (see
+//! [`DapHelperState::get_encoded()`](daphne::DapHelperState::get_encoded)).
+//!
 //! ## Report Storage (Leader-only)
 //!
 //! The `ReportsPending` DO is used by the Leader to temporarily store reports uploaded by Clients
@@ -38,6 +43,57 @@
 //! integer in range `[0, DAP_REPORT_SHARD_COUNT)`. The shard is determined by applying a
 //! keyed has function to the report's ID. (The key is `DAP_REPORT_SHARD_KEY`.)
 //!
+//! ## Changing the Report Shard Count
+//!
+//! Changing `DAP_REPORT_SHARD_COUNT` changes the `ReportsProcessed` instance that a report maps
+//! to, which would allow a report processed under the old mapping to be replayed. To change the
+//! shard count, set `DAP_REPORT_SHARD_COUNT_PREVIOUS` to the old value and
+//! `DAP_REPORT_SHARD_COUNT_CHANGED_AT` to the time at which the new value is deployed. Reports
+//! with timestamps up to this time (plus `report_storage_max_future_time_skew`) are then checked
+//! for replay under both mappings. Once these reports are older than one report storage epoch
+//! they are rejected regardless, so the old mapping is no longer consulted and the migration
+//! variables can be removed at leisure.
+//!
+//! ## Upload Rate Limiting (Leader-only)
+//!
+//! The Leader may limit the rate at which reports are uploaded for a task, both in total and by
+//! each Client. The limits are configured by `DAP_UPLOAD_RATE_LIMITS`, for example:
+//!
+//! ```text
+//! {
+//!   "default": {
+//!     "task": { "reports_per_sec": 1000, "burst": 5000, "daily_quota": 10000000 },
+//!     "client": { "reports_per_sec": 1, "burst": 100 }
+//!   },
+//!   "tasks": {
+//!     "<task_id>": { "task": { "reports_per_sec": 10, "burst": 100 } }
+//!   }
+//! }
+//! ```
+//!
+//! where `<task_id>` is a base64url-encoded task ID. The limits for a task listed in `tasks`
+//! replace the default limits. Each limit is a token bucket that admits `reports_per_sec` reports
+//! per second on average and up to `burst` at once, plus an optional quota on the number of
+//! reports per day (UTC). Clients are identified by the `CF-Connecting-IP` header.
+//!
+//! The state of each limit is kept by an instance of the `UploadRateLimiter` DO. The limits of the
+//! Clients of a task are spread across a fixed number of instances by a keyed hash of the Client,
+//! so the number of instances doesn't grow with the number of Clients. The naming scheme for
+//! instances of the DO is as follows:
+//!
+//! ```text
+//!     <version>/task/<task_id>/upload_rate_limit
+//!     <version>/task/<task_id>/upload_rate_limit/client_shard/<shard>
+//! ```
+//!
+//! The Client's limit is taken before the task's, with one request to each instance. If the task's
+//! limit is exceeded, then the tokens taken from the Client's limit are returned. Likewise, the
+//! tokens for reports rejected as replays are returned once the reports have been stored, so these
+//! don't count towards either limit. Uploads that are limited are rejected with HTTP status 429, a
+//! `Retry-After` header, and a problem document of type "tooManyRequests"; bulk uploads are
+//! admitted or rejected as a whole. The number of reports rejected is counted by the
+//! `upload_throttled` metric.
+//!
 //! ## Report Metadata Storage (Leader and Helper)
 //!
 //! The `ReportsProcessed` DO is used by the Leader and Helper to keep track of the set of reports
@@ -75,18 +131,71 @@
 //! end-to-end tests.
 //!
 //! The `LeaderAggregationJobQueue` DO is used by the Leader to queue aggregation jobs. There is
-//! just one instance of this DO; once a `ReportsPending` instance becomes non-empty, it sends a
-//! message to `LeaderAggregationJobQueue` indicating when the instance was created.
+//! one instance of this DO per shard, named `queue/<shard>`, where `<shard>` is an integer in
+//! range `[0, DAP_AGG_JOB_QUEUE_SHARD_COUNT)`. Once a `ReportsPending` instance becomes non-empty,
+//! it sends a message to the `LeaderAggregationJobQueue` for its shard indicating when the
+//! instance was created. The shard is determined by applying a keyed hash function to the ID of
+//! the `ReportsPending` instance.
 //!
 //! Aggregation jobs are driven by the Leader's main processing loop (see
 //! [`DapLeader::process()`](daphne::roles::DapLeader::process)). The report selector for
 //! Daphne-Worker, [`DaphneWorkerReportSelector`], indicates the number of jobs to fetch at once
-//! (`max_agg_jobs`) and the number of reports to drain per job (`max_reports`).
+//! (`max_agg_jobs`), the number of reports to drain per job (`max_reports`), and optionally the
+//! shards to fetch jobs from (`agg_job_queue_shards`).
+//!
+//! Jobs are leased to processors rather than simply read from the queue: a leased job is removed
+//! from the queue until the processor acknowledges it or until the lease expires after
+//! `DAP_AGG_JOB_LEASE_SECS`. The processor fetches reports from the `ReportsPending` instance
+//! without removing them; once each report has been aggregated (or dropped because the task has
+//! expired), the processor deletes it from the instance, and once all of the reports it fetched
+//! have been handled, it acknowledges the lease. An acknowledged job goes back in the queue if the
+//! instance still holds reports. A job whose lease expired (for example, because the processor
+//! crashed) is returned to the queue the next time jobs are leased, and the reports that were not
+//! deleted are fetched again. Thus multiple processors may run concurrently, even on the same
+//! shards, and no reports are lost if a processor fails. Processors get disjoint sets of reports
+//! as long as each finishes within `DAP_AGG_JOB_LEASE_SECS`; otherwise, a report may be fetched
+//! by two processors, in which case it is aggregated by one of them and rejected as a replay by
+//! the other.
+//!
+//! The processing loop runs on the cron trigger configured by `DAP_LEADER_PROCESS_SCHEDULE` (see
+//! [`DaphneWorkerRouter::handle_scheduled()`]). It may also be run by sending the report selector
+//! to `POST /internal/process`, which requires the administrator's bearer token unless internal
+//! test endpoints are enabled. A run with no pending work costs one request to each queue shard
+//! that is drained and one to the collection job queue.
+//!
+//! Each run may be bounded in time by `DAP_LEADER_PROCESS_TIME_BUDGET`. Collection jobs whose
+//! batches are ready are completed first, then aggregation jobs are run round-robin across tasks.
+//! Once the budget is spent, the reports that have not been aggregated are returned to
+//! `ReportsPending` and the remaining collection jobs stay queued for the next run. Reports for
+//! fixed-size tasks are always aggregated, since they have already been assigned to a batch.
 //!
 //! Jobs are handled roughly in order of creation (oldest jobs are handled first). The time at
 //! which an aggregation job was created is used determine the order in which it was processed.
 //! Timestamps are truncated to the second; ties are broken by a nonce generated at creation time.
 //!
+//! Aggregation may also be driven by the `ReportsPending` instances themselves, so that the
+//! latency of aggregation doesn't depend on how often the processing loop runs. This is
+//! configured per task by `DAP_AGG_JOB_TRIGGERS`, for example:
+//!
+//! ```text
+//! {
+//!   "default": { "min_reports": 1000, "max_age": 300, "max_reports": 1000 },
+//!   "tasks": {
+//!     "<task_id>": { "max_age": 60, "max_reports": 100 }
+//!   }
+//! }
+//! ```
+//!
+//! where `<task_id>` is a base64url-encoded task ID. Once an instance holds `min_reports` reports
+//! or its oldest report has been waiting for `max_age` seconds, a DO alarm fires that aggregates
+//! up to `max_reports` reports in the same way as the processing loop. If reports remain, then the
+//! alarm fires again right away. A task whose trigger is `null` is only aggregated by the
+//! processing loop. The processing loop may still drain instances that have a trigger: the alarm
+//! leases the instance's aggregation job just like a processor (see below), so the reports are
+//! handed to one of them at a time. If the job is leased to a processor when the alarm fires, then
+//! the alarm fires again once the lease expires. Reports are only deleted by the alarm once they
+//! have been aggregated.
+//!
 //! ## Collection Jobs (Leader-only)
 //!
 //! > NOTE: This scheme is not expected to scale well. Currently it is only suited for driving
@@ -135,6 +244,40 @@
 //! where `<version>` is the DAP version, `<task_id>` is the task ID, and `<agg_job_id>` is the
 //! aggregation job ID.
 //!
+//! If `DAP_HELPER_STATE_KEY` is set, then the Helper is stateless: instead of writing its state to
+//! `HelperStateStore`, it encrypts the state under a key derived from `DAP_HELPER_STATE_KEY`, the
+//! task ID and the aggregation job ID and sends it to the Leader in the `AggregateResp`. The
+//! Leader echoes it back in the `AggregateContinueReq`. State that has been tampered with, that
+//! belongs to another aggregation job, or that is older than `DAP_HELPER_STATE_LIFETIME` is
+//! rejected. (See [`DapStatelessHelperConfig`](daphne::DapStatelessHelperConfig).) Replayed
+//! requests need no extra bookkeeping: the reports of a replayed `AggregateInitializeReq` are
+//! rejected by `ReportsProcessed`, and `AggregateStore` merges the output shares of each
+//! aggregation job only once.
+//!
+//! ## Task Expiration
+//!
+//! Once a task expires, uploads and aggregation jobs for the task are rejected. Reports that were
+//! uploaded before the task expired but not yet aggregated are dropped by the Leader's processing
+//! loop; they are removed from storage and counted in the report metrics with status
+//! `dropped_task_expired`. Reports that are still pending when their task is deleted are dropped in
+//! the same way, with status `dropped_task_unrecognized`. Collection is permitted until the task's grace period has elapsed (see
+//! `task_expiration_grace_period` in [`DapGlobalConfig`](daphne::DapGlobalConfig)). If no grace
+//! period is configured, then collection is permitted for as long as the task exists.
+//!
+//! A task is retained until both the grace period and the report storage epoch (see
+//! `report_storage_epoch_duration`) have elapsed since it expired (see
+//! [`DapTaskConfig::retained_until()`](daphne::DapTaskConfig::retained_until)). After this, the
+//! task is deleted by [`DaphneWorkerRouter::handle_scheduled()`], which is meant to be driven by
+//! cron triggers: the task's configuration and bearer tokens are removed from KV. Tasks
+//! provisioned via taskprov are indexed by the `GarbageCollector` DO by the end of their
+//! retention period, so that they can be found without listing every task. Other tasks are only
+//! deleted if a grace period is configured, on the trigger named by `DAP_TASK_SWEEP_CRON`.
+//!
+//! Durable object instances are only registered with the `GarbageCollector` DO in the "dev"
+//! deployment (see `DAP_DEPLOYMENT`), since registering each instance costs a request to the
+//! `GarbageCollector` DO. In that deployment, deleting a task also deletes the task's instances.
+//! Otherwise, only the task's KV entries are deleted.
+//!
 //! # Environment Variables
 //!
 //! The runtime behavior of Daphne-Worker is controlled by the environment variables defined in the
@@ -149,25 +292,42 @@
 //! | `DAP_COLLECT_ID_KEY` | `String` | yes | Hex-encoded key used to derive the collection job ID from the collect request |
 //! | `DAP_GLOBAL_CONFIG` | [`DapGlobalConfig`](daphne::DapGlobalConfig) | no | DAP global config. |
 //! | `DAP_DEPLOYMENT` | `String` | no | Deployment type, only "prod" for now. |
-//! | `DAP_REPORT_SHARD_COUNT` | `u64` | no | Number of report shards per storage epoch. |
+//! | `DAP_REPORT_SHARD_COUNT` | `u64` | no | Number of report shards per storage epoch. Must be positive. |
 //! | `DAP_REPORT_SHARD_KEY` | `String` | yes | Hex-encoded key used to hash a report into one of the report shards. |
+//! | `DAP_REPORT_SHARD_COUNT_PREVIOUS` | `u64` | no | Report shard count before the most recent change, if it is still being migrated. Must be positive. Requires `DAP_REPORT_SHARD_COUNT_CHANGED_AT`. |
+//! | `DAP_REPORT_SHARD_COUNT_CHANGED_AT` | `u64` | no | Time (UNIX seconds) at which `DAP_REPORT_SHARD_COUNT` was last changed. Requires `DAP_REPORT_SHARD_COUNT_PREVIOUS`. |
+//! | `DAP_AGG_JOB_QUEUE_SHARD_COUNT` | `u64` | no | Leader: Number of aggregation job queue shards. Defaults to 1. |
+//! | `DAP_AGG_JOB_LEASE_SECS` | `u64` | no | Leader: Number of seconds for which an aggregation job is leased to a processor before it is handed out again. Defaults to 60. |
+//! | `DAP_LEADER_PROCESS_SCHEDULE` | `Object` | no | Leader: If set, then reports and collection jobs are processed on a cron trigger (see [`DaphneWorkerRouter::handle_scheduled()`]). The fields are `report_selector`, the [`DaphneWorkerReportSelector`] to process with, and `cron`, the pattern of the trigger to run on. If `cron` is not set, then processing runs on every trigger. |
+//! | `DAP_TASK_SWEEP_CRON` | `String` | no | Cron pattern of the trigger on which tasks whose retention period has elapsed are deleted (see "Task Expiration" above). This lists every task, so the trigger should be infrequent. If not set, then only tasks provisioned via taskprov are deleted. |
+//! | `DAP_LEADER_PROCESS_TIME_BUDGET` | `u64` | no | Leader: Number of seconds a run of the processing loop may take before the remaining work is deferred to the next run. If not set, then each run does all pending work. |
+//! | `DAP_AGG_JOB_TRIGGERS` | `Object` | no | Leader: Conditions under which `ReportsPending` runs aggregation jobs by itself (see "Aggregation Jobs" above). If not set, then reports are only aggregated by the processing loop. |
+//! | `DAP_UPLOAD_RATE_LIMITS` | `Object` | no | Leader: Upload rate limits and daily quotas (see "Upload Rate Limiting" above). If not set, then uploads are not limited. |
+//! | `DAP_HELPER_STATE_KEY` | `String` | yes | Helper: Hex-encoded, 32-byte key used to seal the Helper's state. If set, then the state is sent to the Leader rather than stored in `HelperStateStore`. |
+//! | `DAP_HELPER_STATE_LIFETIME` | `u64` | no | Helper: Number of seconds for which sealed Helper state is accepted. Defaults to 3600. |
+//! | `DAP_TASKPROV_PEERS` | `Object` | yes | Taskprov credentials for specific peer Aggregators, keyed by the peer's URL. Each value has the fields `hpke_collector_config`, `vdaf_verify_key_init` (hex), `leader_bearer_token` and `collector_bearer_token`. Peers without credentials use those configured by the `DAP_TASKPROV_*` variables. |
+//! | `DAP_TASKPROV_OPT_IN_POLICY` | [`TaskprovOptInPolicy`](daphne::taskprov::TaskprovOptInPolicy) | no | Policy used to decide whether to opt in to a task provisioned via taskprov. If not set, then every task is opted in to. |
+//! | `DAP_TRACING_OTLP_URL` | `Url` | no | If set, then spans are exported as OTLP/JSON to the collector at this URL (e.g., `http://collector:4318/v1/traces`). |
+//! | `DAP_METRICS_PULL_ENABLED` | `bool` | no | If "true", then counters and histograms are aggregated by the (sharded) `MetricsAggregator` DOs and served (to the administrator) from `GET /internal/metrics`. Gauges are not aggregated. |
 pub use crate::tracing_utils::initialize_tracing;
 use crate::{
-    config::{DaphneWorkerIsolateState, DaphneWorkerRequestState},
+    config::{DaphneWorker, DaphneWorkerIsolateState, DaphneWorkerRequestState},
     dap::dap_response_to_worker,
 };
 use daphne::{
     auth::BearerToken,
     constants,
-    messages::{decode_base64url, Duration, Id, Time},
+    messages::{decode_base64url, Duration, HpkeConfig, Id, Time},
     roles::{DapAggregator, DapHelper, DapLeader},
-    DapAbort, DapCollectJob, DapError, DapResponse,
+    DapAbort, DapCollectJob, DapError, DapQueryConfig, DapResponse, DapTaskConfig, DapVersion,
+    VdafConfig,
 };
 use once_cell::sync::OnceCell;
 use prio::codec::Encode;
 use serde::{Deserialize, Serialize};
 use std::str;
-use tracing::{debug, error, info_span, Instrument};
+use tracing::{debug, error, field, info_span, Instrument};
+use wasm_bindgen::JsCast;
 use worker::*;
 
 /// Parameters used by the Leader to select a set of reports for aggregation.
@@ -178,6 +338,28 @@ pub struct DaphneWorkerReportSelector {
 
     /// Maximum number of reports to drain for each aggregation job.
     pub max_reports: u64,
+
+    /// Aggregation job queue shards to drain jobs from. If not set, then jobs are drained from
+    /// every shard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agg_job_queue_shards: Option<Vec<u64>>,
+}
+
+/// Create the span for a DAP entry point. The fields are recorded once they are known, either by
+/// `worker_request_to_dap()` or by Daphne. The `traceparent` field links the span to the sender's
+/// trace.
+macro_rules! dap_span {
+    (
+        $name:literal
+    ) => {
+        info_span!(
+            $name,
+            traceparent = field::Empty,
+            task_id = field::Empty,
+            agg_job_id = field::Empty,
+            report_count = field::Empty
+        )
+    };
 }
 
 macro_rules! parse_id {
@@ -211,6 +393,11 @@ pub const DEFAULT_RESPONSE_HTML: &str = "<body>Daphne-Worker</body>";
 
 static ISOLATE_STATE: OnceCell<DaphneWorkerIsolateState> = OnceCell::new();
 
+/// Return the state shared by the requests handled by this isolate, initializing it on first use.
+pub(crate) fn cached_isolate_state(env: &Env) -> Result<&'static DaphneWorkerIsolateState> {
+    ISOLATE_STATE.get_or_try_init(|| DaphneWorkerIsolateState::from_worker_env(env))
+}
+
 impl DaphneWorkerRouter {
     /// HTTP request handler for Daphne-Worker.
     ///
@@ -222,14 +409,17 @@ impl DaphneWorkerRouter {
     /// use worker::*;
     ///
     /// #[event(fetch)]
-    /// pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+    /// pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     ///     let router = DaphneWorkerRouter::default();
-    ///     router.handle_request(req, env).await
+    ///     router.handle_request(req, env, &ctx).await
     /// }
     /// ```
+    ///
+    /// The context is used to do work that the response doesn't depend on, such as aggregating
+    /// metrics, after the response is sent.
     //
     // TODO Document endpoints that aren't defined in the DAP spec
-    pub async fn handle_request(&self, req: Request, env: Env) -> Result<Response> {
+    pub async fn handle_request(&self, req: Request, env: Env, ctx: &Context) -> Result<Response> {
         // Ensure that tracing is initialized. Some callers may choose to initialize earlier,
         // but it's safe and cheap to call initialize_tracing() more than once, and this ensures
         // it's definitely ready for use even if the caller hasn't done anything.
@@ -242,9 +432,10 @@ impl DaphneWorkerRouter {
             uncached_isolate_state = Some(DaphneWorkerIsolateState::from_worker_env(&env)?);
             uncached_isolate_state.as_ref().unwrap()
         } else {
-            ISOLATE_STATE.get_or_try_init(|| DaphneWorkerIsolateState::from_worker_env(&env))?
+            cached_isolate_state(&env)?
         };
-        let state = DaphneWorkerRequestState::new(shared_state)?;
+        let client_addr = req.headers().get("CF-Connecting-IP")?;
+        let state = DaphneWorkerRequestState::new(shared_state, client_addr)?;
 
         let router = Router::with_data(&state)
             .get_async("/:version/hpke_config", |req, ctx| async move {
@@ -261,17 +452,8 @@ impl DaphneWorkerRouter {
             })
             .post_async("/task", |mut req, ctx| async move {
                 let daph = ctx.data.handler(&ctx.env);
-                let admin_token = req
-                    .headers()
-                    .get("X-Daphne-Worker-Admin-Bearer-Token")?
-                    .map(BearerToken::from);
-
-                if daph.config().admin_token.is_none() {
-                    return Response::error("admin not configured", 400);
-                }
-
-                if admin_token.is_none() || admin_token != daph.config().admin_token {
-                    return Response::error("missing or invalid bearer token for admin", 401);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
                 }
 
                 let cmd: InternalTestAddTask = req.json().await?;
@@ -279,43 +461,146 @@ impl DaphneWorkerRouter {
                     .instrument(info_span!("task"))
                     .await?;
                 Response::empty()
+            })
+            .get_async("/task", |req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
+                }
+
+                let url = req.url()?;
+                let mut cursor = None;
+                let mut limit = ADMIN_LIST_TASKS_DEFAULT_LIMIT;
+                for (key, val) in url.query_pairs() {
+                    match key.as_ref() {
+                        "cursor" => cursor = Some(val.into_owned()),
+                        "limit" => match val.parse::<u64>() {
+                            Ok(val) if (1..=ADMIN_LIST_TASKS_MAX_LIMIT).contains(&val) => {
+                                limit = val
+                            }
+                            _ => return Response::error("invalid limit", 400),
+                        },
+                        _ => (),
+                    }
+                }
+
+                let task_list = daph
+                    .admin_list_tasks(cursor, limit)
+                    .instrument(info_span!("list_tasks"))
+                    .await?;
+                Response::from_json(&task_list)
+            })
+            .get_async("/task/:task_id", |req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
+                }
+
+                let task_id = parse_id!(ctx.param("task_id"));
+                daph.admin_get_task(&task_id)
+                    .instrument(info_span!("get_task"))
+                    .await
+            })
+            .patch_async("/task/:task_id", |mut req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
+                }
+
+                let task_id = parse_id!(ctx.param("task_id"));
+                let cmd: AdminUpdateTask = req.json().await?;
+                daph.admin_update_task(&task_id, cmd)
+                    .instrument(info_span!("update_task"))
+                    .await
+            })
+            .post_async("/task/:task_id/expire", |req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
+                }
+
+                let task_id = parse_id!(ctx.param("task_id"));
+                let cmd = AdminUpdateTask {
+                    expiration: Some(now()),
+                    ..Default::default()
+                };
+                daph.admin_update_task(&task_id, cmd)
+                    .instrument(info_span!("expire_task"))
+                    .await
+            })
+            .delete_async("/task/:task_id", |req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
+                }
+
+                let task_id = parse_id!(ctx.param("task_id"));
+                daph.admin_delete_task(&task_id)
+                    .instrument(info_span!("delete_task"))
+                    .await
             });
 
+        let router = if shared_state.config.metrics_pull_enabled {
+            router.get_async("/internal/metrics", |req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                    return Ok(resp);
+                }
+
+                let text = daph
+                    .internal_metrics()
+                    .instrument(info_span!("metrics"))
+                    .await?;
+                let mut headers = Headers::new();
+                headers.set("Content-Type", "text/plain; version=0.0.4")?;
+                Ok(Response::ok(text)?.with_headers(headers))
+            })
+        } else {
+            router
+        };
+
         let router = match env.var("DAP_AGGREGATOR_ROLE")?.to_string().as_ref() {
             "leader" => {
                 router
                     .post_async("/:version/upload", |req, ctx| async move {
                         let daph = ctx.data.handler(&ctx.env);
-                        let req = daph.worker_request_to_dap(req).await?;
+                        async {
+                            let req = daph.worker_request_to_dap(req).await?;
 
-                        match daph
-                            .http_post_upload(&req)
-                            .instrument(info_span!("upload"))
-                            .await
-                        {
-                            Ok(()) => Response::empty(),
-                            Err(e) => abort(e),
+                            if req.media_type == Some(constants::MEDIA_TYPE_BULK_UPLOAD_REQ) {
+                                return match daph.http_post_upload_bulk(&req).await {
+                                    Ok(resp) => dap_response_to_worker(resp),
+                                    Err(e) => abort(e),
+                                };
+                            }
+
+                            match daph.http_post_upload(&req).await {
+                                Ok(()) => Response::empty(),
+                                Err(e) => abort(e),
+                            }
                         }
+                        .instrument(dap_span!("upload"))
+                        .await
                     })
                     .post_async("/:version/collect", |req, ctx| async move {
                         let daph = ctx.data.handler(&ctx.env);
-                        let req = daph.worker_request_to_dap(req).await?;
+                        async {
+                            let req = daph.worker_request_to_dap(req).await?;
 
-                        match daph
-                            .http_post_collect(&req)
-                            .instrument(info_span!("collect"))
-                            .await
-                        {
-                            Ok(collect_uri) => {
-                                let mut headers = Headers::new();
-                                headers.set("Location", collect_uri.as_str())?;
-                                Ok(Response::empty()
-                                    .unwrap()
-                                    .with_status(303)
-                                    .with_headers(headers))
+                            match daph.http_post_collect(&req).await {
+                                Ok(collect_uri) => {
+                                    let mut headers = Headers::new();
+                                    headers.set("Location", collect_uri.as_str())?;
+                                    Ok(Response::empty()
+                                        .unwrap()
+                                        .with_status(303)
+                                        .with_headers(headers))
+                                }
+                                Err(e) => abort(e),
                             }
-                            Err(e) => abort(e),
                         }
+                        .instrument(dap_span!("collect"))
+                        .await
                     })
                     .get_async(
                         "/:version/collect/task/:task_id/req/:collect_id",
@@ -345,76 +630,118 @@ impl DaphneWorkerRouter {
                             }
                         },
                     )
-                    .post_async("/internal/process", |mut req, ctx| async move {
-                        // TODO(cjpatton) Only enable this if `self.enable_internal_test` is set.
-                        let daph = ctx.data.handler(&ctx.env);
-                        let report_sel: DaphneWorkerReportSelector = req.json().await?;
-                        match daph
-                            .process(&report_sel)
-                            .instrument(info_span!("process"))
-                            .await
-                        {
-                            Ok(telem) => {
-                                debug!("{:?}", telem);
-                                Response::from_json(&telem)
-                            }
-                            Err(e) => abort(e),
-                        }
-                    })
-                    .get_async(
-                        "/internal/current_batch/task/:task_id",
-                        |_req, ctx| async move {
-                            // Return the ID of the oldest, not-yet-collecgted batch for the specified
-                            // task. The task ID and batch ID are both encoded in URL-safe base64.
-                            //
-                            // TODO(cjpatton) Only enable this if `self.enable_internal_test` is set.
-                            let task_id = parse_id!(ctx.param("task_id"));
-                            let daph = ctx.data.handler(&ctx.env);
-                            match daph
-                                .internal_current_batch(&task_id)
-                                .instrument(info_span!("current_batch"))
-                                .await
-                            {
-                                Ok(batch_id) => Response::from_bytes(
-                                    batch_id.to_base64url().as_bytes().to_owned(),
-                                ),
-                                Err(e) => abort(e.into()),
-                            }
-                        },
-                    )
             }
 
             "helper" => router
                 .post_async("/:version/aggregate", |req, ctx| async move {
                     let daph = ctx.data.handler(&ctx.env);
-                    let req = daph.worker_request_to_dap(req).await?;
+                    async {
+                        let req = daph.worker_request_to_dap(req).await?;
 
-                    match daph
-                        .http_post_aggregate(&req)
-                        .instrument(info_span!("aggregate"))
-                        .await
-                    {
-                        Ok(resp) => dap_response_to_worker(resp),
-                        Err(e) => abort(e),
+                        match daph.http_post_aggregate(&req).await {
+                            Ok(resp) => dap_response_to_worker(resp),
+                            Err(e) => abort(e),
+                        }
                     }
+                    .instrument(dap_span!("aggregate"))
+                    .await
                 })
                 .post_async("/:version/aggregate_share", |req, ctx| async move {
                     let daph = ctx.data.handler(&ctx.env);
-                    let req = daph.worker_request_to_dap(req).await?;
+                    async {
+                        let req = daph.worker_request_to_dap(req).await?;
 
-                    match daph
-                        .http_post_aggregate_share(&req)
-                        .instrument(info_span!("aggregate_share"))
+                        match daph.http_post_aggregate_share(&req).await {
+                            Ok(resp) => dap_response_to_worker(resp),
+                            Err(e) => abort(e),
+                        }
+                    }
+                    .instrument(dap_span!("aggregate_share"))
+                    .await
+                })
+                .post_async(
+                    "/:version/aggregate_share/reconcile",
+                    |req, ctx| async move {
+                        let daph = ctx.data.handler(&ctx.env);
+                        async {
+                            let req = daph.worker_request_to_dap(req).await?;
+
+                            match daph.http_post_aggregate_share_reconcile(&req).await {
+                                Ok(resp) => dap_response_to_worker(resp),
+                                Err(e) => abort(e),
+                            }
+                        }
+                        .instrument(dap_span!("aggregate_share_reconcile"))
                         .await
-                    {
-                        Ok(resp) => dap_response_to_worker(resp),
-                        Err(e) => abort(e),
+                    },
+                )
+                .post_async("/:version/aggregate_share/exclude", |req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    async {
+                        let req = daph.worker_request_to_dap(req).await?;
+
+                        match daph.http_post_aggregate_share_exclude(&req).await {
+                            Ok(resp) => dap_response_to_worker(resp),
+                            Err(e) => abort(e),
+                        }
                     }
+                    .instrument(dap_span!("aggregate_share_exclude"))
+                    .await
                 }),
 
             _ => return abort(DapError::fatal("unexpected role").into()),
         };
 
+        // Reports and collection jobs are normally processed on a cron trigger (see
+        // `handle_scheduled()`). The Leader may also be asked to process them by the administrator
+        // or, in test mode, by anyone.
+        let is_leader = state.isolate_state.config.is_leader;
+        let router = match internal_access("internal_process", is_leader, self.enable_internal_test)
+        {
+            InternalAccess::Anyone => {
+                router.post_async("/internal/process", |req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    internal_process(&daph, req).await
+                })
+            }
+            InternalAccess::Admin => {
+                router.post_async("/internal/process", |req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    if let Some(resp) = check_admin_bearer_token(&daph, &req)? {
+                        return Ok(resp);
+                    }
+                    internal_process(&daph, req).await
+                })
+            }
+            InternalAccess::NotRouted => router,
+        };
+        let router = match internal_access(
+            "internal_current_batch",
+            is_leader,
+            self.enable_internal_test,
+        ) {
+            InternalAccess::Anyone => router.get_async(
+                "/internal/current_batch/task/:task_id",
+                |_req, ctx| async move {
+                    // Return the ID of the oldest, not-yet-collecgted batch for the specified
+                    // task. The task ID and batch ID are both encoded in URL-safe base64.
+                    let task_id = parse_id!(ctx.param("task_id"));
+                    let daph = ctx.data.handler(&ctx.env);
+                    match daph
+                        .internal_current_batch(&task_id)
+                        .instrument(info_span!("current_batch"))
+                        .await
+                    {
+                        Ok(batch_id) => {
+                            Response::from_bytes(batch_id.to_base64url().as_bytes().to_owned())
+                        }
+                        Err(e) => abort(e.into()),
+                    }
+                },
+            ),
+            InternalAccess::Admin | InternalAccess::NotRouted => router,
+        };
+
         let router = if self.enable_internal_test {
             router
                 .post_async("/internal/delete_all", |_req, ctx| async move {
@@ -496,7 +823,16 @@ impl DaphneWorkerRouter {
         // reports the same times as the span covering the specific API entry point that the
         // router creates. If curious, you can add .instrument(info_span!("http")) just before
         // the await and see.
-        let result = router.run(req, env).await;
+        let endpoint = endpoint_for_path(&req.path());
+        let start = Date::now().as_millis();
+        let result = router.run(req, env.clone().unchecked_into()).await;
+        let end = Date::now().as_millis();
+
+        state
+            .metrics
+            .http_request_duration
+            .with_label_values(&[endpoint])
+            .observe(end.saturating_sub(start) as f64 / 1000.0);
 
         state
             .metrics
@@ -514,8 +850,230 @@ impl DaphneWorkerRouter {
         // in theory, but I don't know if workers-rs supports it.
         state.maybe_push_metrics().await?;
 
+        // Aggregate metrics for scraping, if configured. This is done after responding, so a
+        // failure here is only logged.
+        if let Some(aggregate_metrics) = state.maybe_aggregate_metrics(&env) {
+            ctx.wait_until(async move {
+                if let Err(e) = aggregate_metrics.await {
+                    error!("failed to aggregate metrics: {e}");
+                }
+            });
+        }
+
+        // Export spans to the OTLP collector, if configured.
+        tracing_utils::maybe_export_spans(&state.isolate_state.client).await;
+
         result
     }
+
+    /// Scheduled event handler for Daphne-Worker. Tasks provisioned via taskprov are deleted once
+    /// their retention period has elapsed (see
+    /// [`DapTaskConfig::retained_until()`](daphne::DapTaskConfig::retained_until)). These are
+    /// indexed by the `GarbageCollector` DO, so finding them is cheap.
+    ///
+    /// If `DAP_LEADER_PROCESS_SCHEDULE` is configured, then the Leader also processes reports and
+    /// collection jobs (see [`DapLeader::process()`]). If the schedule names a cron pattern, then
+    /// processing runs only on that trigger and taskprov tasks are only deleted on the others;
+    /// otherwise both run on every trigger.
+    ///
+    /// Other tasks are deleted once their retention period has elapsed only on the trigger named
+    /// by `DAP_TASK_SWEEP_CRON`, and only if a task expiration grace period is configured. This
+    /// sweep lists every task.
+    ///
+    /// This method is typically called from the workers-rs `scheduled` function, which is run
+    /// according to the cron triggers configured for the Worker. For example:
+    ///
+    /// ```ignore
+    /// use daphne_worker::DaphneWorkerRouter;
+    /// use worker::*;
+    ///
+    /// #[event(scheduled)]
+    /// pub async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    ///     let router = DaphneWorkerRouter::default();
+    ///     router.handle_scheduled(&event, env).await.unwrap();
+    /// }
+    /// ```
+    pub async fn handle_scheduled(&self, event: &ScheduledEvent, env: Env) -> Result<()> {
+        initialize_tracing(&env);
+
+        #[allow(unused_assignments)]
+        let mut uncached_isolate_state: Option<DaphneWorkerIsolateState> = None;
+        let shared_state = if env.var("DAP_NO_CACHE").is_ok() {
+            uncached_isolate_state = Some(DaphneWorkerIsolateState::from_worker_env(&env)?);
+            uncached_isolate_state.as_ref().unwrap()
+        } else {
+            cached_isolate_state(&env)?
+        };
+        let state = DaphneWorkerRequestState::new(shared_state, None)?;
+        let daph = state.handler(&env);
+
+        let cron = event.cron();
+        let (process_report_sel, sweep_taskprov) = match daph.config().leader_process_schedule {
+            Some(ref schedule) if daph.config().is_leader => match schedule.cron {
+                Some(ref process_cron) if *process_cron == cron => {
+                    (Some(&schedule.report_selector), false)
+                }
+                Some(..) => (None, true),
+                None => (Some(&schedule.report_selector), true),
+            },
+            _ => (None, true),
+        };
+        let sweep_taskprov = sweep_taskprov && daph.config().global.allow_taskprov;
+        let sweep = daph.config().global.task_expiration_grace_period.is_some()
+            && daph.config().task_sweep_cron.as_ref() == Some(&cron);
+
+        let process_result = if let Some(report_sel) = process_report_sel {
+            let process_result = daph
+                .process(report_sel, daph.leader_process_deadline())
+                .instrument(info_span!("process"))
+                .await;
+            match process_result {
+                Ok(ref telem) => debug!("{:?}", telem),
+                Err(ref e) => error!("failed to process: {e}"),
+            }
+
+            // A failure to report metrics must not prevent the sweep or the export of spans.
+            if let Err(e) = state.maybe_push_metrics().await {
+                error!("failed to push metrics: {e}");
+            }
+            if let Some(aggregate_metrics) = state.maybe_aggregate_metrics(&env) {
+                if let Err(e) = aggregate_metrics.await {
+                    error!("failed to aggregate metrics: {e}");
+                }
+            }
+            process_result
+                .map(|_| ())
+                .map_err(|e| Error::RustError(format!("process: {e}")))
+        } else {
+            Ok(())
+        };
+
+        let result = if sweep {
+            let result = daph
+                .sweep_expired_tasks()
+                .instrument(info_span!("sweep_expired_tasks"))
+                .await;
+            match result {
+                Ok(deleted) => debug!("deleted {deleted} expired tasks"),
+                Err(ref e) => error!("failed to sweep expired tasks: {e}"),
+            }
+            result.map(|_| ())
+        } else {
+            Ok(())
+        };
+
+        let taskprov_result = if sweep_taskprov {
+            let taskprov_result = daph
+                .sweep_expired_taskprov_tasks()
+                .instrument(info_span!("sweep_expired_taskprov_tasks"))
+                .await;
+            match taskprov_result {
+                Ok(deleted) => debug!("deleted {deleted} expired taskprov tasks"),
+                Err(ref e) => error!("failed to sweep expired taskprov tasks: {e}"),
+            }
+            taskprov_result.map(|_| ())
+        } else {
+            Ok(())
+        };
+
+        tracing_utils::maybe_export_spans(&state.isolate_state.client).await;
+
+        process_result.and(result).and(taskprov_result)
+    }
+}
+
+/// Handle a request to process reports and collection jobs. The body of the request is the
+/// [`DaphneWorkerReportSelector`] and the response is the
+/// [`DapLeaderProcessTelemetry`](daphne::DapLeaderProcessTelemetry).
+async fn internal_process(daph: &DaphneWorker<'_>, mut req: Request) -> Result<Response> {
+    let report_sel: DaphneWorkerReportSelector = req.json().await?;
+    match daph
+        .process(&report_sel, daph.leader_process_deadline())
+        .instrument(info_span!("process"))
+        .await
+    {
+        Ok(telem) => {
+            debug!("{:?}", telem);
+            Response::from_json(&telem)
+        }
+        Err(e) => abort(e),
+    }
+}
+
+/// Check that the request carries the administrator's bearer token. If not, then return the
+/// response to send to the client.
+fn check_admin_bearer_token(daph: &DaphneWorker<'_>, req: &Request) -> Result<Option<Response>> {
+    let admin_token = req
+        .headers()
+        .get("X-Daphne-Worker-Admin-Bearer-Token")?
+        .map(BearerToken::from);
+
+    match admin_token_rejection(daph.config().admin_token.as_ref(), admin_token.as_ref()) {
+        Some((msg, status)) => Ok(Some(Response::error(msg, status)?)),
+        None => Ok(None),
+    }
+}
+
+/// Decide whether a request bearing `presented` may access an admin endpoint. If not, return the
+/// error message and status code of the response.
+fn admin_token_rejection(
+    configured: Option<&BearerToken>,
+    presented: Option<&BearerToken>,
+) -> Option<(&'static str, u16)> {
+    match (configured, presented) {
+        (None, _) => Some(("admin not configured", 400)),
+        (Some(configured), Some(presented)) if configured == presented => None,
+        (Some(_), _) => Some(("missing or invalid bearer token for admin", 401)),
+    }
+}
+
+/// Who may call one of the internal endpoints used to drive the Leader.
+#[derive(Debug, PartialEq, Eq)]
+enum InternalAccess {
+    /// The endpoint is not routed.
+    NotRouted,
+
+    /// Only requests bearing the admin bearer token are served.
+    Admin,
+
+    /// All requests are served. This is only the case in test mode.
+    Anyone,
+}
+
+/// Determine access to the internal endpoint labeled `endpoint` (see [`endpoint_for_path`]).
+fn internal_access(endpoint: &str, is_leader: bool, enable_internal_test: bool) -> InternalAccess {
+    match (endpoint, is_leader, enable_internal_test) {
+        ("internal_process" | "internal_current_batch", true, true) => InternalAccess::Anyone,
+        ("internal_process", true, false) => InternalAccess::Admin,
+        _ => InternalAccess::NotRouted,
+    }
+}
+
+/// Map the path of a request to the endpoint used to label latency metrics. The path may contain
+/// task IDs or other identifiers, so using the path itself would lead to unbounded cardinality.
+fn endpoint_for_path(path: &str) -> &'static str {
+    // Strip the version from the path, if any.
+    let path = match path.strip_prefix('/').and_then(|path| path.split_once('/')) {
+        Some((version, rest)) if version.starts_with('v') => rest,
+        _ => path.trim_start_matches('/'),
+    };
+
+    match path {
+        "hpke_config" => "hpke_config",
+        "task" => "task",
+        "upload" => "upload",
+        "collect" => "collect",
+        "aggregate" => "aggregate",
+        "aggregate_share" => "aggregate_share",
+        "aggregate_share/reconcile" => "aggregate_share_reconcile",
+        "aggregate_share/exclude" => "aggregate_share_exclude",
+        "internal/process" => "internal_process",
+        "internal/metrics" => "internal_metrics",
+        _ if path.starts_with("collect/task/") => "collect_poll",
+        _ if path.starts_with("internal/current_batch/") => "internal_current_batch",
+        _ if path.starts_with("internal/") => "internal",
+        _ => "other",
+    }
 }
 
 pub(crate) fn now() -> u64 {
@@ -548,8 +1106,14 @@ fn abort(e: DapAbort) -> Result<Response> {
             debug!("abort: {}", e.to_string());
             let mut headers = Headers::new();
             headers.set("Content-Type", "application/problem+json")?;
+            let status = if let DapAbort::TooManyRequests { retry_after } = e {
+                headers.set("Retry-After", &retry_after.to_string())?;
+                429
+            } else {
+                400
+            };
             Ok(Response::from_json(&e.to_problem_details())?
-                .with_status(400)
+                .with_status(status)
                 .with_headers(headers))
         }
     }
@@ -596,8 +1160,89 @@ pub(crate) struct InternalTestAddTask {
     task_expiration: Time,
 }
 
+/// Number of task IDs returned by `GET /task` if no limit is specified.
+const ADMIN_LIST_TASKS_DEFAULT_LIMIT: u64 = 100;
+
+/// Maximum number of task IDs returned by `GET /task`. This is the maximum supported by KV.
+const ADMIN_LIST_TASKS_MAX_LIMIT: u64 = 1000;
+
+/// Response to `GET /task`. The task IDs are URL-safe base64 encoded. If set, then `cursor` is
+/// passed in the next request in order to fetch the next page.
+#[derive(Serialize)]
+pub(crate) struct AdminTaskList {
+    task_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Response to `GET /task/:task_id`. This is the task's configuration, except that the VDAF verify
+/// key is redacted.
+#[derive(Serialize)]
+pub(crate) struct AdminTaskConfig {
+    version: DapVersion,
+    leader_url: Url,
+    helper_url: Url,
+    time_precision: Duration,
+    expiration: Time,
+    min_batch_size: u64,
+    query: DapQueryConfig,
+    vdaf: VdafConfig,
+    collector_hpke_config: HpkeConfig,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    retired_collector_hpke_configs: Vec<HpkeConfig>,
+}
+
+impl From<&DapTaskConfig> for AdminTaskConfig {
+    fn from(task_config: &DapTaskConfig) -> Self {
+        Self {
+            version: task_config.version,
+            leader_url: task_config.leader_url.clone(),
+            helper_url: task_config.helper_url.clone(),
+            time_precision: task_config.time_precision,
+            expiration: task_config.expiration,
+            min_batch_size: task_config.min_batch_size,
+            query: task_config.query.clone(),
+            vdaf: task_config.vdaf.clone(),
+            collector_hpke_config: task_config.collector_hpke_config.clone(),
+            retired_collector_hpke_configs: task_config.retired_collector_hpke_configs.clone(),
+        }
+    }
+}
+
+/// Request for `PATCH /task/:task_id`. Each field that is set is updated.
+///
+/// Setting `collector_hpke_config` (base64url) rotates the Collector's HPKE configuration: the
+/// previous configuration is retired. Retired configurations whose config ID is listed in
+/// `remove_collector_hpke_config_ids` are removed.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct AdminUpdateTask {
+    #[serde(default)]
+    collector_hpke_config: Option<String>, // base64url
+    #[serde(default)]
+    remove_collector_hpke_config_ids: Option<Vec<u8>>,
+    #[serde(default)]
+    expiration: Option<Time>,
+    #[serde(default)]
+    min_batch_size: Option<u64>,
+    #[serde(default)]
+    leader_authentication_token: Option<String>,
+    #[serde(default)]
+    collector_authentication_token: Option<String>,
+}
+
 mod config;
+#[cfg(test)]
+mod config_test;
 mod dap;
+#[cfg(test)]
+mod dap_test;
 mod durable;
+#[cfg(test)]
+mod lib_test;
 mod metrics;
+#[cfg(test)]
+mod metrics_test;
 mod tracing_utils;
+#[cfg(test)]
+mod tracing_utils_test;
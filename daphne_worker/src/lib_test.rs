@@ -0,0 +1,77 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::{admin_token_rejection, endpoint_for_path, internal_access, InternalAccess};
+use daphne::auth::BearerToken;
+
+#[test]
+fn admin_token_missing() {
+    let configured = BearerToken::from("administrator bearer token");
+    assert_eq!(
+        admin_token_rejection(Some(&configured), None),
+        Some(("missing or invalid bearer token for admin", 401))
+    );
+}
+
+#[test]
+fn admin_token_wrong() {
+    let configured = BearerToken::from("administrator bearer token");
+    let presented = BearerToken::from("not the administrator bearer token");
+    assert_eq!(
+        admin_token_rejection(Some(&configured), Some(&presented)),
+        Some(("missing or invalid bearer token for admin", 401))
+    );
+}
+
+#[test]
+fn admin_token_valid() {
+    let configured = BearerToken::from("administrator bearer token");
+    let presented = BearerToken::from("administrator bearer token");
+    assert_eq!(
+        admin_token_rejection(Some(&configured), Some(&presented)),
+        None
+    );
+}
+
+#[test]
+fn admin_token_not_configured() {
+    let presented = BearerToken::from("administrator bearer token");
+    assert_eq!(
+        admin_token_rejection(None, Some(&presented)),
+        Some(("admin not configured", 400))
+    );
+}
+
+#[test]
+fn internal_process_requires_admin_outside_test_mode() {
+    let endpoint = endpoint_for_path("/internal/process");
+    assert_eq!(
+        internal_access(endpoint, true, false),
+        InternalAccess::Admin
+    );
+    assert_eq!(
+        internal_access(endpoint, true, true),
+        InternalAccess::Anyone
+    );
+    assert_eq!(
+        internal_access(endpoint, false, false),
+        InternalAccess::NotRouted
+    );
+}
+
+#[test]
+fn internal_current_batch_not_routed_outside_test_mode() {
+    let endpoint = endpoint_for_path("/internal/current_batch/task/some_task_id");
+    assert_eq!(
+        internal_access(endpoint, true, false),
+        InternalAccess::NotRouted
+    );
+    assert_eq!(
+        internal_access(endpoint, true, true),
+        InternalAccess::Anyone
+    );
+    assert_eq!(
+        internal_access(endpoint, false, true),
+        InternalAccess::NotRouted
+    );
+}
@@ -5,7 +5,12 @@
 
 use crate::DapError;
 use daphne::metrics::DaphneMetrics;
-use prometheus::{register_int_counter_vec_with_registry, IntCounterVec, Registry};
+use prometheus::{
+    proto::{MetricFamily, MetricType},
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry, HistogramVec,
+    IntCounterVec, Registry,
+};
+use serde::{Deserialize, Serialize};
 
 pub(crate) struct DaphneWorkerMetrics {
     /// Daphne metrics.
@@ -13,6 +18,19 @@ pub(crate) struct DaphneWorkerMetrics {
 
     /// HTTP response status.
     pub(crate) http_status_code: IntCounterVec,
+
+    /// Time (in seconds) taken to handle an HTTP request, labeled by endpoint.
+    pub(crate) http_request_duration: HistogramVec,
+
+    /// Leader: Round trip time (in seconds) of requests sent to the Helper, labeled by media type.
+    pub(crate) helper_request_duration: HistogramVec,
+
+    /// Time (in seconds) taken by requests to durable objects, labeled by binding.
+    pub(crate) durable_request_duration: HistogramVec,
+
+    /// Leader: Number of reports rejected by upload rate limits or quotas, labeled by the limit
+    /// ("task" or "client").
+    pub(crate) upload_throttled: IntCounterVec,
 }
 
 impl DaphneWorkerMetrics {
@@ -30,11 +48,232 @@ impl DaphneWorkerMetrics {
             registry
         )?;
 
+        let http_request_duration = register_histogram_vec_with_registry!(
+            format!("{front}http_request_duration"),
+            "Time (in seconds) taken to handle an HTTP request.",
+            &["endpoint"],
+            registry
+        )?;
+
+        let helper_request_duration = register_histogram_vec_with_registry!(
+            format!("{front}helper_request_duration"),
+            "Round trip time (in seconds) of requests sent to the Helper.",
+            &["media_type"],
+            registry
+        )?;
+
+        let durable_request_duration = register_histogram_vec_with_registry!(
+            format!("{front}durable_request_duration"),
+            "Time (in seconds) taken by requests to durable objects.",
+            &["binding"],
+            registry
+        )?;
+
+        let upload_throttled = register_int_counter_vec_with_registry!(
+            format!("{front}upload_throttled"),
+            "Number of reports rejected by upload rate limits or quotas.",
+            &["limit"],
+            registry
+        )?;
+
         let daphne = DaphneMetrics::register(registry, prefix)?;
 
         Ok(Self {
             daphne,
             http_status_code,
+            http_request_duration,
+            helper_request_duration,
+            durable_request_duration,
+            upload_throttled,
+        })
+    }
+}
+
+/// The type of a metric family.
+///
+/// Gauges are not aggregated: each request has its own registry, so a gauge gathered from it holds
+/// whatever that request last set (or, for a gauge that is incremented and decremented, the net
+/// change made by that request), which says nothing about the deployment as a whole. The variant
+/// is kept so that totals written by earlier versions can still be read; their gauges are dropped
+/// by [`MetricsFamily::merge_all`].
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MetricsKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricsKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+            Self::Histogram => "histogram",
+        }
+    }
+}
+
+/// A serializable snapshot of a metric family. This is used to aggregate the metrics collected by
+/// each request in the `MetricsAggregator` durable object.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct MetricsFamily {
+    pub(crate) name: String,
+    pub(crate) help: String,
+    pub(crate) kind: MetricsKind,
+
+    /// Sequence of samples. Each sample is a series (i.e., the sample name and its labels, as they
+    /// appear in the text exposition format) and a value.
+    pub(crate) samples: Vec<(String, f64)>,
+}
+
+impl MetricsFamily {
+    /// Gather the counters and histograms from the given registry. Only series that were updated
+    /// are included: since each request has its own registry, these hold the increments made while
+    /// handling the request. Gauges are skipped (see [`MetricsKind`]).
+    pub(crate) fn gather(registry: &Registry) -> Vec<Self> {
+        registry
+            .gather()
+            .iter()
+            .filter_map(Self::from_proto)
+            .collect()
+    }
+
+    fn from_proto(proto: &MetricFamily) -> Option<Self> {
+        let name = proto.get_name();
+        let kind = match proto.get_field_type() {
+            MetricType::COUNTER => MetricsKind::Counter,
+            MetricType::HISTOGRAM => MetricsKind::Histogram,
+            MetricType::GAUGE | MetricType::SUMMARY | MetricType::UNTYPED => return None,
+        };
+
+        let mut samples = Vec::new();
+        for metric in proto.get_metric() {
+            let labels: Vec<(&str, String)> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value().to_string()))
+                .collect();
+            if kind == MetricsKind::Counter {
+                let val = metric.get_counter().get_value();
+                if val != 0.0 {
+                    samples.push((series(name, &labels), val));
+                }
+            } else {
+                let histogram = metric.get_histogram();
+                if histogram.get_sample_count() == 0 {
+                    continue;
+                }
+
+                let bucket_name = format!("{name}_bucket");
+                for bucket in histogram.get_bucket() {
+                    let mut bucket_labels = labels.clone();
+                    bucket_labels.push(("le", bucket.get_upper_bound().to_string()));
+                    samples.push((
+                        series(&bucket_name, &bucket_labels),
+                        bucket.get_cumulative_count() as f64,
+                    ));
+                }
+                let mut inf_labels = labels.clone();
+                inf_labels.push(("le", "+Inf".into()));
+                samples.push((
+                    series(&bucket_name, &inf_labels),
+                    histogram.get_sample_count() as f64,
+                ));
+                samples.push((
+                    series(&format!("{name}_sum"), &labels),
+                    histogram.get_sample_sum(),
+                ));
+                samples.push((
+                    series(&format!("{name}_count"), &labels),
+                    histogram.get_sample_count() as f64,
+                ));
+            }
+        }
+
+        if samples.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            name: name.into(),
+            help: proto.get_help().into(),
+            kind,
+            samples,
         })
     }
+
+    /// Merge the samples of `other` into `self` by summing them. If the families have different
+    /// types, then `self` is replaced by `other`.
+    pub(crate) fn merge(&mut self, other: Self) {
+        if self.kind != other.kind {
+            *self = other;
+            return;
+        }
+
+        for (other_series, other_val) in other.samples {
+            match self
+                .samples
+                .iter_mut()
+                .find(|(series, _val)| *series == other_series)
+            {
+                Some((_series, val)) => *val += other_val,
+                None => self.samples.push((other_series, other_val)),
+            }
+        }
+    }
+
+    /// Merge each family of `others` into the matching family of `families`. Gauges are dropped
+    /// from both.
+    pub(crate) fn merge_all(families: &mut Vec<Self>, others: Vec<Self>) {
+        families.retain(|family| family.kind != MetricsKind::Gauge);
+        for other in others {
+            if other.kind == MetricsKind::Gauge {
+                continue;
+            }
+
+            match families.iter_mut().find(|family| family.name == other.name) {
+                Some(family) => family.merge(other),
+                None => families.push(other),
+            }
+        }
+    }
+
+    /// Encode a sequence of metric families in the Prometheus text exposition format.
+    pub(crate) fn encode_text(families: &[Self]) -> String {
+        let mut text = String::new();
+        for family in families {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                family.name,
+                family.help.replace('\\', "\\\\").replace('\n', "\\n"),
+                family.name,
+                family.kind.as_str()
+            ));
+            for (series, val) in family.samples.iter() {
+                text.push_str(&format!("{series} {val}\n"));
+            }
+        }
+        text
+    }
+}
+
+/// Format a series in the text exposition format, e.g., `name{label="value"}`.
+fn series(name: &str, labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return name.into();
+    }
+
+    let labels = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{k}=\"{v}\"")
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{name}{{{labels}}}")
 }
@@ -0,0 +1,112 @@
+// Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::metrics::{DaphneWorkerMetrics, MetricsFamily, MetricsKind};
+use prometheus::{register_int_gauge_vec_with_registry, Registry};
+
+fn gather_one(registry: &Registry, name: &str) -> Option<MetricsFamily> {
+    MetricsFamily::gather(registry)
+        .into_iter()
+        .find(|family| family.name == name)
+}
+
+#[test]
+fn gather_skips_untouched_series() {
+    let registry = Registry::new();
+    let _metrics = DaphneWorkerMetrics::register(&registry, None).unwrap();
+
+    // Nothing has been recorded yet.
+    assert_eq!(MetricsFamily::gather(&registry), Vec::new());
+}
+
+#[test]
+fn gather_counter_and_histogram() {
+    let registry = Registry::new();
+    let metrics = DaphneWorkerMetrics::register(&registry, None).unwrap();
+    metrics
+        .http_status_code
+        .with_label_values(&["200"])
+        .inc_by(2);
+    metrics
+        .durable_request_duration
+        .with_label_values(&["DAP_AGGREGATE_STORE"])
+        .observe(0.5);
+
+    let family = gather_one(&registry, "http_status_code").unwrap();
+    assert_eq!(family.kind, MetricsKind::Counter);
+    assert_eq!(
+        family.samples,
+        vec![(r#"http_status_code{code="200"}"#.to_string(), 2.0)]
+    );
+
+    let family = gather_one(&registry, "durable_request_duration").unwrap();
+    assert_eq!(family.kind, MetricsKind::Histogram);
+    assert!(family.samples.contains(&(
+        r#"durable_request_duration_bucket{binding="DAP_AGGREGATE_STORE",le="0.5"}"#.to_string(),
+        1.0
+    )));
+    assert!(family.samples.contains(&(
+        r#"durable_request_duration_bucket{binding="DAP_AGGREGATE_STORE",le="0.25"}"#.to_string(),
+        0.0
+    )));
+    assert!(family.samples.contains(&(
+        r#"durable_request_duration_count{binding="DAP_AGGREGATE_STORE"}"#.to_string(),
+        1.0
+    )));
+}
+
+#[test]
+fn gather_skips_gauges() {
+    let registry = Registry::new();
+    let gauge = register_int_gauge_vec_with_registry!("gauge", "A gauge.", &[], registry).unwrap();
+    gauge.with_label_values(&[]).set(1);
+
+    assert_eq!(gather_one(&registry, "gauge"), None);
+}
+
+#[test]
+fn merge() {
+    let mut families = vec![
+        MetricsFamily {
+            name: "counter".into(),
+            help: "A counter.".into(),
+            kind: MetricsKind::Counter,
+            samples: vec![("counter".into(), 1.0)],
+        },
+        MetricsFamily {
+            name: "gauge".into(),
+            help: "A gauge.".into(),
+            kind: MetricsKind::Gauge,
+            samples: vec![(r#"gauge{task="a"}"#.into(), 1.0)],
+        },
+    ];
+
+    MetricsFamily::merge_all(
+        &mut families,
+        vec![
+            MetricsFamily {
+                name: "counter".into(),
+                help: "A counter.".into(),
+                kind: MetricsKind::Counter,
+                samples: vec![("counter".into(), 2.0)],
+            },
+            MetricsFamily {
+                name: "gauge".into(),
+                help: "A gauge.".into(),
+                kind: MetricsKind::Gauge,
+                samples: vec![
+                    (r#"gauge{task="a"}"#.into(), 0.5),
+                    (r#"gauge{task="b"}"#.into(), 0.25),
+                ],
+            },
+        ],
+    );
+
+    // Counters are summed and gauges, including those in the totals, are dropped.
+    assert_eq!(
+        MetricsFamily::encode_text(&families),
+        "# HELP counter A counter.\n\
+         # TYPE counter counter\n\
+         counter 3\n"
+    );
+}
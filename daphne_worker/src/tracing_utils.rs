@@ -2,8 +2,15 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use chrono::{SecondsFormat, Utc};
-use std::{fmt::Result as FmtResult, io, str, sync::Once};
-use tracing::{event, Level, Subscriber};
+use once_cell::sync::OnceCell;
+use rand::prelude::*;
+use serde::Serialize;
+use std::{
+    fmt::{Debug, Result as FmtResult},
+    io, str,
+    sync::{Mutex, Once},
+};
+use tracing::{event, field::Field, field::Visit, warn, Level, Span, Subscriber};
 use tracing_core::span;
 use tracing_subscriber::{
     fmt,
@@ -11,8 +18,11 @@ use tracing_subscriber::{
     layer::Context as LayerContext,
     layer::*,
     prelude::*,
-    registry, EnvFilter, Layer,
+    registry,
+    registry::LookupSpan,
+    EnvFilter, Layer, Registry,
 };
+use url::Url;
 use worker::*;
 
 /// WasmTime provides a `tracing_subscriber::fmt::time::FormatTime` implementation that works
@@ -143,6 +153,338 @@ where
     }
 }
 
+/// Name of the span field used to link a span to the trace context propagated by a peer. The
+/// value is the `traceparent` header of the peer's request.
+pub(crate) const TRACEPARENT_FIELD: &str = "traceparent";
+
+/// Maximum number of finished spans that are buffered for export. Spans that finish once the buffer
+/// is full are dropped.
+const MAX_BUFFERED_SPANS: usize = 1024;
+
+/// Trace flag indicating that the trace is sampled.
+const TRACE_FLAG_SAMPLED: u8 = 0x01;
+
+/// The W3C Trace Context (https://www.w3.org/TR/trace-context/) of a span.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TraceContext {
+    pub(crate) trace_id: [u8; 16],
+    pub(crate) span_id: [u8; 8],
+    pub(crate) flags: u8,
+}
+
+impl TraceContext {
+    /// Parse the value of a `traceparent` header. Returns `None` if the value is malformed.
+    pub(crate) fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = decode_hex::<1>(parts.next()?)?;
+        let trace_id = decode_hex::<16>(parts.next()?)?;
+        let span_id = decode_hex::<8>(parts.next()?)?;
+        let flags = decode_hex::<1>(parts.next()?)?;
+
+        // Version 0xff is invalid. Version 0x00 has exactly four parts; future versions may
+        // append more parts, which we ignore.
+        if version[0] == 0xff || (version[0] == 0 && parts.next().is_some()) {
+            return None;
+        }
+
+        // All-zero trace and span IDs are invalid.
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(Self {
+            trace_id,
+            span_id,
+            flags: flags[0],
+        })
+    }
+
+    /// Encode the trace context as the value of a `traceparent` header.
+    pub(crate) fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id),
+            self.flags
+        )
+    }
+}
+
+/// Decode a string of exactly `2 * N` lowercase hex characters.
+fn decode_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N || s.bytes().any(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    hex::decode_to_slice(s, &mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Return the trace context of the current span, if any. This is used to propagate the trace to
+/// the peer in the `traceparent` header of outgoing requests.
+pub(crate) fn current_trace_context() -> Option<TraceContext> {
+    Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            extensions
+                .get::<SpanRecord>()
+                .map(|record| record.context.clone())
+        })
+        .flatten()
+}
+
+/// Per-span state maintained by [`TraceContextLayer`].
+struct SpanRecord {
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    has_local_parent: bool,
+    start_time_unix_nano: u64,
+    attributes: Vec<(String, String)>,
+}
+
+/// Collects the fields of a span as attributes.
+#[derive(Default)]
+struct AttributeVisitor {
+    traceparent: Option<String>,
+    attributes: Vec<(String, String)>,
+}
+
+impl Visit for AttributeVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == TRACEPARENT_FIELD {
+            self.traceparent = Some(value.into());
+        } else {
+            self.attributes.push((field.name().into(), value.into()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+/// TraceContextLayer assigns a [`TraceContext`] to each span. A span inherits the trace of its
+/// parent; a span without a parent starts a new trace, unless it has a `traceparent` field, in
+/// which case it continues the trace propagated by the peer. If spans are exported, then each span
+/// is buffered when it closes until the next call to [`maybe_export_spans`].
+struct TraceContextLayer {
+    export: bool,
+}
+
+fn unix_nano_now() -> u64 {
+    (Date::now().as_millis()).saturating_mul(1_000_000)
+}
+
+impl<S> Layer<S> for TraceContextLayer
+where
+    S: Subscriber + for<'a> registry::LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
+        let span = ctx.span(id).expect("span should exist!");
+        let mut visitor = AttributeVisitor::default();
+        attrs.record(&mut visitor);
+
+        let mut rng = thread_rng();
+        let parent_context = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanRecord>()
+                .map(|record| record.context.clone())
+        });
+        let remote_context = visitor
+            .traceparent
+            .as_deref()
+            .and_then(TraceContext::from_traceparent);
+        let (context, parent_span_id, has_local_parent) = match (parent_context, remote_context) {
+            (Some(parent), _) => (
+                TraceContext {
+                    trace_id: parent.trace_id,
+                    span_id: rng.gen(),
+                    flags: parent.flags,
+                },
+                Some(parent.span_id),
+                true,
+            ),
+            (None, Some(remote)) => (
+                TraceContext {
+                    trace_id: remote.trace_id,
+                    span_id: rng.gen(),
+                    flags: remote.flags,
+                },
+                Some(remote.span_id),
+                false,
+            ),
+            (None, None) => (
+                TraceContext {
+                    trace_id: rng.gen(),
+                    span_id: rng.gen(),
+                    flags: TRACE_FLAG_SAMPLED,
+                },
+                None,
+                false,
+            ),
+        };
+
+        span.extensions_mut().insert(SpanRecord {
+            context,
+            parent_span_id,
+            has_local_parent,
+            start_time_unix_nano: unix_nano_now(),
+            attributes: visitor.attributes,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: LayerContext<'_, S>) {
+        let span = ctx.span(id).expect("span should exist!");
+        let mut visitor = AttributeVisitor::default();
+        values.record(&mut visitor);
+
+        let mut extensions = span.extensions_mut();
+        if let Some(record) = extensions.get_mut::<SpanRecord>() {
+            // A span without a local parent may be linked to the peer's trace after it is
+            // created, e.g., once the request has been parsed. This is only meaningful before any
+            // child span is created.
+            if let Some(remote) = visitor
+                .traceparent
+                .as_deref()
+                .and_then(TraceContext::from_traceparent)
+            {
+                if !record.has_local_parent {
+                    record.context.trace_id = remote.trace_id;
+                    record.context.flags = remote.flags;
+                    record.parent_span_id = Some(remote.span_id);
+                }
+            }
+            for (key, value) in visitor.attributes {
+                match record.attributes.iter_mut().find(|(k, _v)| *k == key) {
+                    Some((_k, v)) => *v = value,
+                    None => record.attributes.push((key, value)),
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: LayerContext<'_, S>) {
+        if !self.export {
+            return;
+        }
+
+        let span = ctx.span(&id).expect("span should exist!");
+        let extensions = span.extensions();
+        if let Some(record) = extensions.get::<SpanRecord>() {
+            if record.context.flags & TRACE_FLAG_SAMPLED == 0 {
+                return;
+            }
+
+            let otlp_span = OtlpSpan {
+                trace_id: hex::encode(record.context.trace_id),
+                span_id: hex::encode(record.context.span_id),
+                parent_span_id: record.parent_span_id.map(hex::encode),
+                name: span.name().into(),
+                kind: OTLP_SPAN_KIND_INTERNAL,
+                start_time_unix_nano: record.start_time_unix_nano.to_string(),
+                end_time_unix_nano: unix_nano_now().to_string(),
+                attributes: record
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| OtlpKeyValue {
+                        key: key.clone(),
+                        value: OtlpAnyValue {
+                            string_value: value.clone(),
+                        },
+                    })
+                    .collect(),
+            };
+
+            let mut finished_spans = FINISHED_SPANS.lock().expect("finished spans: lock failed");
+            if finished_spans.len() < MAX_BUFFERED_SPANS {
+                finished_spans.push(otlp_span);
+            }
+        }
+    }
+}
+
+const OTLP_SPAN_KIND_INTERNAL: u8 = 1;
+
+/// A span in the OTLP/JSON encoding (see
+/// https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OtlpSpan {
+    pub(crate) trace_id: String,
+    pub(crate) span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) parent_span_id: Option<String>,
+    pub(crate) name: String,
+    pub(crate) kind: u8,
+    pub(crate) start_time_unix_nano: String,
+    pub(crate) end_time_unix_nano: String,
+    pub(crate) attributes: Vec<OtlpKeyValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct OtlpKeyValue {
+    pub(crate) key: String,
+    pub(crate) value: OtlpAnyValue,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OtlpAnyValue {
+    pub(crate) string_value: String,
+}
+
+/// Encode a sequence of spans as the body of an OTLP/HTTP export request.
+pub(crate) fn encode_otlp_json(spans: Vec<OtlpSpan>) -> serde_json::Value {
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": "daphne-worker" },
+                }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "daphne" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// Spans that have finished but have not been exported yet.
+static FINISHED_SPANS: Mutex<Vec<OtlpSpan>> = Mutex::new(Vec::new());
+
+/// URL of the OTLP collector to export spans to, if configured.
+static OTLP_COLLECTOR_URL: OnceCell<Url> = OnceCell::new();
+
+/// If configured, export the spans that have finished so far to the OTLP collector. Failure to
+/// export is logged but is otherwise ignored, as tracing is best-effort.
+pub(crate) async fn maybe_export_spans(client: &reqwest_wasm::Client) {
+    let url = match OTLP_COLLECTOR_URL.get() {
+        Some(url) => url,
+        None => return,
+    };
+
+    let spans = std::mem::take(&mut *FINISHED_SPANS.lock().expect("finished spans: lock failed"));
+    if spans.is_empty() {
+        return;
+    }
+
+    match client
+        .post(url.as_str())
+        .json(&encode_otlp_json(spans))
+        .send()
+        .await
+    {
+        Ok(resp) if resp.status().is_success() => (),
+        Ok(resp) => warn!("span export failed with response status {}", resp.status()),
+        Err(err) => warn!("request to OTLP collector failed: {err:?}"),
+    }
+}
+
 static INITIALIZE_TRACING: Once = Once::new();
 
 /// Setup logging.
@@ -152,6 +494,9 @@ static INITIALIZE_TRACING: Once = Once::new();
 ///
 /// Panics if the log handler cannot be installed.
 ///
+/// If DAP_TRACING_OTLP_URL is set, then spans are also exported as OTLP/JSON to
+/// the collector at that URL. See [`maybe_export_spans`].
+///
 /// Logging will only be initialized once, no matter how many times this
 /// function is called.
 pub fn initialize_tracing(env: &Env) {
@@ -162,12 +507,23 @@ pub fn initialize_tracing(env: &Env) {
             Ok(var) => var.to_string(),
             Err(_) => "info".to_string(),
         };
+        if let Ok(var) = env.var("DAP_TRACING_OTLP_URL") {
+            match var.to_string().parse() {
+                Ok(url) => OTLP_COLLECTOR_URL
+                    .set(url)
+                    .expect("OTLP collector URL already set"),
+                Err(err) => console_log!("Failed to parse DAP_TRACING_OTLP_URL: {err}"),
+            }
+        }
         registry()
             .with(
                 fmt::layer()
                     .with_writer(LogWriter::new)
                     .with_timer(WasmTime::new())
-                    .and_then(WasmTimingLayer::new()),
+                    .and_then(WasmTimingLayer::new())
+                    .and_then(TraceContextLayer {
+                        export: OTLP_COLLECTOR_URL.get().is_some(),
+                    }),
             )
             .with(EnvFilter::new(filter))
             .init();
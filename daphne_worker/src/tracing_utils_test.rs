@@ -0,0 +1,116 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::tracing_utils::{encode_otlp_json, OtlpAnyValue, OtlpKeyValue, OtlpSpan, TraceContext};
+use serde_json::json;
+
+const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+#[test]
+fn traceparent_roundtrip() {
+    let trace_context = TraceContext::from_traceparent(TRACEPARENT).unwrap();
+    assert_eq!(
+        trace_context,
+        TraceContext {
+            trace_id: hex::decode("0af7651916cd43dd8448eb211c80319c")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            span_id: hex::decode("b7ad6b7169203331").unwrap().try_into().unwrap(),
+            flags: 0x01,
+        }
+    );
+    assert_eq!(trace_context.to_traceparent(), TRACEPARENT);
+}
+
+#[test]
+fn traceparent_future_version() {
+    // Future versions may append fields, which are ignored.
+    let trace_context = TraceContext::from_traceparent(
+        "cc-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-whatever",
+    )
+    .unwrap();
+    assert_eq!(trace_context.flags, 0x00);
+    assert_eq!(
+        trace_context.to_traceparent(),
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00"
+    );
+}
+
+#[test]
+fn traceparent_malformed() {
+    for traceparent in [
+        "",
+        "00",
+        // Invalid version.
+        "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        // Extra field for version 00.
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-00",
+        // Uppercase hex.
+        "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+        // Wrong length.
+        "00-0af7651916cd43dd8448eb211c80319-b7ad6b7169203331-01",
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333-01",
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-1",
+        // Not hex.
+        "00-0af7651916cd43dd8448eb211c80319x-b7ad6b7169203331-01",
+        // All-zero trace ID or span ID.
+        "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+        "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+    ] {
+        assert_eq!(
+            TraceContext::from_traceparent(traceparent),
+            None,
+            "{traceparent}"
+        );
+    }
+}
+
+#[test]
+fn otlp_json() {
+    let span = OtlpSpan {
+        trace_id: "0af7651916cd43dd8448eb211c80319c".into(),
+        span_id: "00f067aa0ba902b7".into(),
+        parent_span_id: Some("b7ad6b7169203331".into()),
+        name: "aggregate".into(),
+        kind: 1,
+        start_time_unix_nano: "1000000".into(),
+        end_time_unix_nano: "2000000".into(),
+        attributes: vec![OtlpKeyValue {
+            key: "report_count".into(),
+            value: OtlpAnyValue {
+                string_value: "10".into(),
+            },
+        }],
+    };
+
+    assert_eq!(
+        encode_otlp_json(vec![span]),
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": "daphne-worker" },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "daphne" },
+                    "spans": [{
+                        "traceId": "0af7651916cd43dd8448eb211c80319c",
+                        "spanId": "00f067aa0ba902b7",
+                        "parentSpanId": "b7ad6b7169203331",
+                        "name": "aggregate",
+                        "kind": 1,
+                        "startTimeUnixNano": "1000000",
+                        "endTimeUnixNano": "2000000",
+                        "attributes": [{
+                            "key": "report_count",
+                            "value": { "stringValue": "10" },
+                        }],
+                    }],
+                }],
+            }],
+        })
+    );
+}
@@ -17,7 +17,7 @@ fn log_request(req: &Request) {
 }
 
 #[event(fetch, respond_with_errors)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     // Optionally, get more helpful error messages written to the console in the case of a panic.
     utils::set_panic_hook();
 
@@ -31,5 +31,18 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         enable_internal_test: true,
         enable_default_response: false,
     };
-    router.handle_request(req, env).await
+    router.handle_request(req, env, &ctx).await
+}
+
+#[event(scheduled)]
+pub async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    utils::set_panic_hook();
+    initialize_tracing(&env);
+
+    let router = DaphneWorkerRouter {
+        enable_internal_test: true,
+        enable_default_response: false,
+    };
+    // Errors are logged by the handler.
+    let _ = router.handle_scheduled(&event, env).await;
 }
@@ -7,12 +7,14 @@ mod test_runner;
 
 use daphne::{
     async_test_versions, constants,
+    hpke::HpkeReceiverConfig,
     messages::{
+        encode_base64url,
         taskprov::{
             DpConfig, QueryConfig, QueryConfigVar, TaskConfig, UrlBytes, VdafConfig, VdafTypeVar,
         },
-        BatchSelector, CollectReq, CollectResp, Extension, HpkeCiphertext, Id, Interval, Query,
-        Report, ReportId, ReportMetadata,
+        BatchSelector, BulkUploadReq, BulkUploadResp, CollectReq, CollectResp, Extension,
+        HpkeCiphertext, HpkeKemId, Id, Interval, Query, Report, ReportId, ReportMetadata,
     },
     taskprov::{compute_task_id, TaskprovVersion},
     DapAggregateResult, DapMeasurement, DapTaskConfig, DapVersion,
@@ -23,7 +25,9 @@ use prio::codec::{Decode, Encode, ParameterizedEncode};
 use rand::prelude::*;
 use serde::Deserialize;
 use serde_json::json;
-use test_runner::{TestRunner, MIN_BATCH_SIZE, TIME_PRECISION};
+use test_runner::{
+    MockOtlpCollector, TestRunner, AGG_JOB_TRIGGER_TASK_ID, MIN_BATCH_SIZE, TIME_PRECISION,
+};
 use url::Url;
 
 // Redefine async_test_version locally because we want a
@@ -342,6 +346,84 @@ async fn e2e_leader_upload(version: DapVersion) {
 
 async_test_versions! { e2e_leader_upload }
 
+async fn e2e_leader_upload_bulk(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+    let batch_interval = t.batch_interval();
+
+    let mut rng = thread_rng();
+    let mut reports = Vec::new();
+    for _ in 0..3 {
+        let now = rng.gen_range(t.report_interval(&batch_interval));
+        reports.push(
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap(),
+        );
+    }
+
+    // Include the first report twice; the second copy is rejected as a replay.
+    reports.push(reports[0].clone());
+    let bulk_upload_req = BulkUploadReq {
+        task_id: t.task_id.clone(),
+        reports,
+    };
+
+    let resp = client
+        .post(t.leader_url.join("upload").unwrap().as_str())
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            constants::MEDIA_TYPE_BULK_UPLOAD_REQ,
+        )
+        .body(bulk_upload_req.get_encoded_with_param(&version))
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(200, resp.status());
+    assert_eq!(
+        resp.headers().get(reqwest::header::CONTENT_TYPE).unwrap(),
+        constants::MEDIA_TYPE_BULK_UPLOAD_RESP
+    );
+    let bulk_upload_resp = BulkUploadResp::get_decoded(&resp.bytes().await.unwrap()).unwrap();
+    let rejections: Vec<Option<String>> = bulk_upload_resp
+        .results
+        .into_iter()
+        .map(|result| result.rejection)
+        .collect();
+    assert_eq!(
+        rejections,
+        vec![
+            None,
+            None,
+            None,
+            Some("urn:ietf:params:ppm:dap:error:replayedReport".to_string())
+        ]
+    );
+
+    // Each accepted report is aggregated.
+    let agg_telem = t
+        .internal_process(
+            &client,
+            &DaphneWorkerReportSelector {
+                max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
+                max_reports: 100,
+                agg_job_queue_shards: None,
+            },
+        )
+        .await;
+    assert_eq!(agg_telem.reports_aggregated, 3, "reports aggregated");
+}
+
+async_test_versions! { e2e_leader_upload_bulk }
+
 #[tokio::test]
 #[cfg_attr(not(feature = "test_e2e"), ignore)]
 async fn e2e_leader_upload_taskprov() {
@@ -513,6 +595,66 @@ async fn e2e_leader_upload_taskprov() {
     .await;
 }
 
+#[tokio::test]
+#[cfg_attr(not(feature = "test_e2e"), ignore)]
+async fn e2e_leader_upload_taskprov_opt_out() {
+    let version = DapVersion::Draft02;
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+
+    // The task expires too far in the future for the opt-in policy configured in wrangler.toml.
+    let taskprov_task_config = TaskConfig {
+        task_info: "Hi".as_bytes().to_vec(),
+        aggregator_endpoints: vec![
+            UrlBytes {
+                bytes: "https://test1".as_bytes().to_vec(),
+            },
+            UrlBytes {
+                bytes: "https://test2".as_bytes().to_vec(),
+            },
+        ],
+        query_config: QueryConfig {
+            time_precision: 0x01,
+            max_batch_query_count: 128,
+            min_batch_size: 1024,
+            var: QueryConfigVar::FixedSize {
+                max_batch_size: 2048,
+            },
+        },
+        task_expiration: t.now + 86400 * 365,
+        vdaf_config: VdafConfig {
+            dp_config: DpConfig::None,
+            var: VdafTypeVar::Prio3Aes128Count,
+        },
+    };
+    let payload = taskprov_task_config.get_encoded_with_param(&TaskprovVersion::Draft02);
+    let task_id = compute_task_id(TaskprovVersion::Draft02, &payload).unwrap();
+    let extensions = vec![Extension::Taskprov { payload }];
+    let report = t
+        .task_config
+        .vdaf
+        .produce_report_with_extensions(
+            &hpke_config_list,
+            t.now,
+            &task_id,
+            DapMeasurement::U64(23),
+            extensions,
+            version,
+        )
+        .unwrap();
+    t.leader_post_expect_abort(
+        &client,
+        None, // dap_auth_token
+        "upload",
+        constants::MEDIA_TYPE_REPORT,
+        report.get_encoded_with_param(&version),
+        400,
+        "invalidTask",
+    )
+    .await;
+}
+
 async fn e2e_internal_leader_process(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
 
@@ -522,6 +664,7 @@ async fn e2e_internal_leader_process(version: DapVersion) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
         max_reports: t.task_config.min_batch_size,
+        agg_job_queue_shards: None,
     };
 
     let batch_interval = t.batch_interval();
@@ -571,6 +714,265 @@ async fn e2e_internal_leader_process(version: DapVersion) {
 
 async_test_versions! { e2e_internal_leader_process }
 
+// Test that processors draining disjoint sets of agg job queue shards together drain every report.
+async fn e2e_internal_leader_process_by_shard(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+    let batch_interval = t.batch_interval();
+
+    let mut rng = thread_rng();
+    for _ in 0..10 {
+        let now = rng.gen_range(t.report_interval(&batch_interval));
+        t.leader_post_expect_ok(
+            &client,
+            "upload",
+            constants::MEDIA_TYPE_REPORT,
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap()
+                .get_encoded_with_param(&version),
+        )
+        .await;
+    }
+
+    // The Leader is configured with two agg job queue shards.
+    let mut reports_aggregated = 0;
+    for shard in [0, 1] {
+        let report_sel = DaphneWorkerReportSelector {
+            max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
+            max_reports: 100,
+            agg_job_queue_shards: Some(vec![shard]),
+        };
+        let agg_telem = t.internal_process(&client, &report_sel).await;
+        reports_aggregated += agg_telem.reports_aggregated;
+    }
+    assert_eq!(reports_aggregated, 10, "reports aggregated");
+
+    // There should be nothing left to aggregate.
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 100,
+        max_reports: 100,
+        agg_job_queue_shards: None,
+    };
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(agg_telem.reports_processed, 0, "reports processed");
+}
+
+async_test_versions! { e2e_internal_leader_process_by_shard }
+
+// Test that deleting a task that still has pending reports does not hold up processing of the
+// reports for other tasks.
+async fn e2e_internal_leader_process_deleted_task(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let deleted = TestRunner::default_with_version(version).await;
+
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+    let batch_interval = t.batch_interval();
+
+    let mut rng = thread_rng();
+    for t in [&t, &deleted] {
+        for _ in 0..5 {
+            let now = rng.gen_range(t.report_interval(&batch_interval));
+            t.leader_post_expect_ok(
+                &client,
+                "upload",
+                constants::MEDIA_TYPE_REPORT,
+                t.task_config
+                    .vdaf
+                    .produce_report(
+                        &hpke_config_list,
+                        now,
+                        &t.task_id,
+                        DapMeasurement::U64(1),
+                        version,
+                    )
+                    .unwrap()
+                    .get_encoded_with_param(&version),
+            )
+            .await;
+        }
+    }
+
+    // Delete one of the tasks before its reports are aggregated.
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::HeaderName::from_lowercase(b"x-daphne-worker-admin-bearer-token").unwrap(),
+        "administrator bearer token".parse().unwrap(),
+    );
+    let mut task_url = t.leader_url.clone();
+    task_url.set_path(&format!("task/{}", deleted.task_id.to_base64url()));
+    let resp = client
+        .delete(task_url)
+        .headers(headers)
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+
+    // The reports for the remaining task are aggregated.
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
+        max_reports: 100,
+        agg_job_queue_shards: None,
+    };
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(agg_telem.reports_aggregated, 5, "reports aggregated");
+
+    // There should be nothing left to aggregate.
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(agg_telem.reports_processed, 0, "reports processed");
+}
+
+async_test_versions! { e2e_internal_leader_process_deleted_task }
+
+// Test that concurrent processors are handed disjoint sets of reports.
+async fn e2e_internal_leader_process_concurrent(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+    let batch_interval = t.batch_interval();
+
+    let mut rng = thread_rng();
+    for _ in 0..10 {
+        let now = rng.gen_range(t.report_interval(&batch_interval));
+        t.leader_post_expect_ok(
+            &client,
+            "upload",
+            constants::MEDIA_TYPE_REPORT,
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap()
+                .get_encoded_with_param(&version),
+        )
+        .await;
+    }
+
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
+        max_reports: 100,
+        agg_job_queue_shards: None,
+    };
+    let (agg_telem_1, agg_telem_2) = tokio::join!(
+        t.internal_process(&client, &report_sel),
+        t.internal_process(&client, &report_sel),
+    );
+    assert_eq!(
+        agg_telem_1.reports_processed + agg_telem_2.reports_processed,
+        10,
+        "reports processed"
+    );
+    assert_eq!(
+        agg_telem_1.reports_aggregated + agg_telem_2.reports_aggregated,
+        10,
+        "reports aggregated"
+    );
+
+    // There should be nothing left to aggregate.
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(agg_telem.reports_processed, 0, "reports processed");
+}
+
+async_test_versions! { e2e_internal_leader_process_concurrent }
+
+// Test that reports are aggregated by `ReportsPending` when an aggregation job trigger is
+// configured for the task, without running the processing loop.
+async fn e2e_leader_agg_job_trigger(version: DapVersion) {
+    let t = TestRunner::with_task_id(version, AGG_JOB_TRIGGER_TASK_ID).await;
+    let batch_interval = t.batch_interval();
+
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+
+    let mut rng = thread_rng();
+    for _ in 0..t.task_config.min_batch_size {
+        let now = rng.gen_range(t.report_interval(&batch_interval));
+        t.leader_post_expect_ok(
+            &client,
+            "upload",
+            constants::MEDIA_TYPE_REPORT,
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap()
+                .get_encoded_with_param(&version),
+        )
+        .await;
+    }
+
+    let collect_req = CollectReq {
+        task_id: t.task_id.clone(),
+        query: Query::TimeInterval {
+            batch_interval: batch_interval.clone(),
+        },
+        agg_param: Vec::new(),
+    };
+    let collect_uri = t
+        .leader_post_collect(&client, collect_req.get_encoded_with_param(&t.version))
+        .await;
+
+    // Only run collect jobs: the batch becomes ready once the alarms have aggregated the reports.
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 0,
+        max_reports: 0,
+        agg_job_queue_shards: None,
+    };
+    let mut reports_collected = 0;
+    for _ in 0..30 {
+        let agg_telem = t.internal_process(&client, &report_sel).await;
+        assert_eq!(agg_telem.reports_processed, 0, "reports processed");
+        reports_collected += agg_telem.reports_collected;
+        if reports_collected > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    assert_eq!(
+        reports_collected, t.task_config.min_batch_size,
+        "reports collected"
+    );
+
+    let resp = client.get(collect_uri.as_str()).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Nothing is left for the processing loop to aggregate.
+    let agg_telem = t
+        .internal_process(
+            &client,
+            &DaphneWorkerReportSelector {
+                max_agg_jobs: 100,
+                max_reports: 100,
+                agg_job_queue_shards: None,
+            },
+        )
+        .await;
+    assert_eq!(agg_telem.reports_processed, 0, "reports processed");
+}
+
+async_test_versions! { e2e_leader_agg_job_trigger }
+
 // Test that all reports eventually get drained at minimum aggregation rate.
 async fn e2e_leader_process_min_agg_rate(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
@@ -605,6 +1007,7 @@ async fn e2e_leader_process_min_agg_rate(version: DapVersion) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 1,
         max_reports: 1,
+        agg_job_queue_shards: None,
     };
 
     for i in 0..7 {
@@ -619,6 +1022,83 @@ async fn e2e_leader_process_min_agg_rate(version: DapVersion) {
 
 async_test_versions! { e2e_leader_process_min_agg_rate }
 
+async fn e2e_trace_propagation(version: DapVersion) {
+    let collector = MockOtlpCollector::start().await;
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let batch_interval = t.batch_interval();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+
+    let mut rng = thread_rng();
+    let now = rng.gen_range(t.report_interval(&batch_interval));
+    t.leader_post_expect_ok(
+        &client,
+        "upload",
+        constants::MEDIA_TYPE_REPORT,
+        t.task_config
+            .vdaf
+            .produce_report(
+                &hpke_config_list,
+                now,
+                &t.task_id,
+                DapMeasurement::U64(1),
+                version,
+            )
+            .unwrap()
+            .get_encoded_with_param(&version),
+    )
+    .await;
+
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 1,
+        max_reports: 1,
+        agg_job_queue_shards: None,
+    };
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(agg_telem.reports_aggregated, 1);
+
+    let attribute = |span: &serde_json::Value, key: &str| -> Option<String> {
+        span["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attr| attr["key"] == key)
+            .map(|attr| attr["value"]["stringValue"].as_str().unwrap().to_string())
+    };
+
+    // The Leader's aggregation job carries the task ID, aggregation job ID, and report count.
+    let task_id_hex = t.task_id.to_hex();
+    let spans = collector.spans();
+    let agg_job_span = spans
+        .iter()
+        .find(|span| {
+            span["name"] == "run_agg_job"
+                && attribute(span, "task_id").as_deref() == Some(task_id_hex.as_str())
+        })
+        .expect("missing span for aggregation job");
+    assert_eq!(attribute(agg_job_span, "report_count").unwrap(), "1");
+    let agg_job_id = attribute(agg_job_span, "agg_job_id").expect("missing aggregation job ID");
+
+    // The Helper's handling of the initialization and continuation requests belongs to the
+    // Leader's trace.
+    let helper_spans: Vec<_> = spans
+        .iter()
+        .filter(|span| {
+            span["name"] == "aggregate"
+                && attribute(span, "agg_job_id").as_deref() == Some(agg_job_id.as_str())
+        })
+        .collect();
+    assert_eq!(helper_spans.len(), 2);
+    for span in helper_spans {
+        assert_eq!(span["traceId"], agg_job_span["traceId"]);
+        assert_eq!(span["parentSpanId"], agg_job_span["spanId"]);
+        assert_eq!(attribute(span, "task_id").unwrap(), task_id_hex);
+        assert_eq!(attribute(span, "report_count").unwrap(), "1");
+    }
+}
+
+async_test_versions! { e2e_trace_propagation }
+
 async fn e2e_leader_collect_ok(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
     let batch_interval = t.batch_interval();
@@ -673,6 +1153,7 @@ async fn e2e_leader_collect_ok(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                agg_job_queue_shards: None,
             },
         )
         .await;
@@ -777,6 +1258,7 @@ async fn e2e_leader_collect_ok_interleaved(version: DapVersion) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
         max_reports: 100,
+        agg_job_queue_shards: None,
     };
 
     // All reports for the task get processed ...
@@ -857,6 +1339,7 @@ async fn e2e_leader_collect_not_ready_min_batch_size(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                agg_job_queue_shards: None,
             },
         )
         .await;
@@ -1021,6 +1504,7 @@ async fn e2e_leader_collect_abort_overlapping_batch_interval(version: DapVersion
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                agg_job_queue_shards: None,
             },
         )
         .await;
@@ -1078,6 +1562,7 @@ async fn e2e_fixed_size(version: DapVersion, use_current: bool) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
         max_reports: 100,
+        agg_job_queue_shards: None,
     };
 
     let client = t.http_client();
@@ -1344,6 +1829,7 @@ async fn e2e_leader_collect_taskprov_ok(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                agg_job_queue_shards: None,
             },
         )
         .await;
@@ -1439,3 +1925,240 @@ async fn e2e_helper_admin_add_task(version: DapVersion) {
 }
 
 async_test_versions! { e2e_helper_admin_add_task }
+
+async fn e2e_helper_admin_metrics(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let url = Url::parse("http://127.0.0.1:8788/internal/metrics").unwrap();
+
+    // Scraping metrics requires the admin bearer token.
+    let resp = t
+        .http_client()
+        .get(url.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 401);
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::HeaderName::from_lowercase(b"x-daphne-worker-admin-bearer-token").unwrap(),
+        "administrator bearer token".parse().unwrap(),
+    );
+    let resp = t
+        .http_client()
+        .get(url.clone())
+        .headers(headers)
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+
+    // The previous request was rejected, so its status code was aggregated.
+    let text = resp.text().await.unwrap();
+    assert!(
+        text.contains(r#"http_status_code{code="401"}"#),
+        "unexpected metrics: {text}"
+    );
+}
+
+async_test_versions! { e2e_helper_admin_metrics }
+
+async fn e2e_helper_admin_task_lifecycle(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let base_url = Url::parse("http://127.0.0.1:8788/").unwrap();
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::HeaderName::from_lowercase(b"x-daphne-worker-admin-bearer-token").unwrap(),
+        "administrator bearer token".parse().unwrap(),
+    );
+
+    let task_id = Id(thread_rng().gen());
+    let task_id_base64url = task_id.to_base64url();
+    let task_url = base_url.join(&format!("task/{task_id_base64url}")).unwrap();
+
+    // Add the task.
+    let resp = client
+        .post(base_url.join("task").unwrap())
+        .json(&json!({
+            "collector_hpke_config": "kwAgAAEAAQAgAPjfKNRNrnodTEuoCKA5qAOTaWOmVlmNVyAXOL6__20",
+            "leader": format!("http://cool.leader/{}/", version.as_ref()),
+            "helper": format!("https:/awesome.helper.web:8788/{}/", version.as_ref()),
+            "leader_authentication_token": "leader bearer token",
+            "min_batch_size": 10,
+            "query_type": 2,
+            "max_batch_size": 12,
+            "role": "helper",
+            "task_expiration": t.now + 3600,
+            "task_id": task_id_base64url,
+            "time_precision": 3600,
+            "vdaf": {
+                "type": "Prio3Aes128Count"
+            },
+            "verify_key": "y4e6alnJMQ0MZTvdJRJx5Q"
+        }))
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+
+    // Task management requires the admin bearer token.
+    let resp = client
+        .get(task_url.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 401);
+
+    // Get the task. The VDAF verify key is redacted.
+    let task_config: serde_json::Value = client
+        .get(task_url.clone())
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed")
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(task_config["min_batch_size"], 10);
+    assert_eq!(task_config["expiration"], t.now + 3600);
+    assert!(task_config.get("vdaf_verify_key").is_none());
+
+    // The task is listed. Use a small page size to exercise paging.
+    let mut task_ids = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut list_url = base_url.join("task").unwrap();
+        list_url.query_pairs_mut().append_pair("limit", "1");
+        if let Some(ref cursor) = cursor {
+            list_url.query_pairs_mut().append_pair("cursor", cursor);
+        }
+        let task_list: serde_json::Value = client
+            .get(list_url)
+            .headers(headers.clone())
+            .send()
+            .await
+            .expect("request failed")
+            .json()
+            .await
+            .unwrap();
+        for task_id in task_list["task_ids"].as_array().unwrap() {
+            task_ids.push(task_id.as_str().unwrap().to_string());
+        }
+        cursor = task_list
+            .get("cursor")
+            .map(|cursor| cursor.as_str().unwrap().to_string());
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert!(task_ids.contains(&task_id_base64url));
+
+    // Update the task.
+    let resp = client
+        .patch(task_url.clone())
+        .json(&json!({
+            "min_batch_size": 11,
+            "leader_authentication_token": "new leader bearer token",
+        }))
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+    let task_config: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(task_config["min_batch_size"], 11);
+
+    // Rotate the Collector's HPKE config. The previous config is retired.
+    let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+        .unwrap()
+        .config;
+    let collector_hpke_config_base64url = encode_base64url(collector_hpke_config.get_encoded());
+    let resp = client
+        .patch(task_url.clone())
+        .json(&json!({
+            "collector_hpke_config": collector_hpke_config_base64url,
+        }))
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+    let task_config: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(task_config["collector_hpke_config"]["id"], 1);
+    assert_eq!(task_config["retired_collector_hpke_configs"][0]["id"], 147);
+
+    // Remove the retired config.
+    let resp = client
+        .patch(task_url.clone())
+        .json(&json!({ "remove_collector_hpke_config_ids": [147] }))
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+    let task_config: serde_json::Value = resp.json().await.unwrap();
+    assert!(task_config.get("retired_collector_hpke_configs").is_none());
+
+    // Invalid updates are rejected.
+    let reused_collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+        .unwrap()
+        .config;
+    for update in [
+        json!({ "min_batch_size": 0 }),
+        json!({ "min_batch_size": 13 }),
+        json!({ "collector_authentication_token": "collector bearer token" }),
+        json!({ "collector_hpke_config": "not an HPKE config" }),
+        json!({ "collector_hpke_config": encode_base64url(reused_collector_hpke_config.get_encoded()) }),
+        json!({ "remove_collector_hpke_config_ids": [1] }),
+    ] {
+        let resp = client
+            .patch(task_url.clone())
+            .json(&update)
+            .headers(headers.clone())
+            .send()
+            .await
+            .expect("request failed");
+        assert_eq!(resp.status(), 400, "{update}");
+    }
+
+    // Expire the task.
+    let resp = client
+        .post(
+            task_url
+                .join(&format!("{task_id_base64url}/expire"))
+                .unwrap(),
+        )
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+    let task_config: serde_json::Value = resp.json().await.unwrap();
+    assert!(task_config["expiration"].as_u64().unwrap() < t.now + 3600);
+
+    // Delete the task.
+    let resp = client
+        .delete(task_url.clone())
+        .headers(headers.clone())
+        .send()
+        .await
+        .expect("request failed");
+    assert_eq!(resp.status(), 200);
+
+    for resp in [
+        client.get(task_url.clone()),
+        client.patch(task_url.clone()).json(&json!({})),
+        client.delete(task_url.clone()),
+    ] {
+        let resp = resp
+            .headers(headers.clone())
+            .send()
+            .await
+            .expect("request failed");
+        assert_eq!(resp.status(), 404);
+    }
+}
+
+async_test_versions! { e2e_helper_admin_task_lifecycle }
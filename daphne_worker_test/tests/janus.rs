@@ -52,6 +52,7 @@ async fn janus_client() {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be large enough to touch each bucket.
         max_reports: 1,
+        agg_job_queue_shards: None,
     };
     let agg_telem = t.internal_process(&client, &report_sel).await;
     assert_eq!(agg_telem.reports_processed, 1);
@@ -71,6 +72,7 @@ async fn janus_helper() {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be large enough to touch each bucket.
         max_reports: t.task_config.min_batch_size,
+        agg_job_queue_shards: None,
     };
 
     // Upload a number of reports (a few more than the aggregation rate).
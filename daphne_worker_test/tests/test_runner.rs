@@ -24,13 +24,23 @@ use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::ops::Range;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 #[cfg(feature = "test_janus")]
 use tokio::task::JoinHandle;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
 use url::Url;
 
 const VDAF_CONFIG: &VdafConfig = &VdafConfig::Prio3(Prio3Config::Sum { bits: 10 });
 pub(crate) const MIN_BATCH_SIZE: u64 = 10;
+
+/// ID of the task for which the Leader is configured to run aggregation jobs by itself. This needs
+/// to be kept in-sync with `DAP_AGG_JOB_TRIGGERS` in daphne_worker_test/wrangler.toml.
+#[allow(dead_code)]
+pub(crate) const AGG_JOB_TRIGGER_TASK_ID: Id = Id([0x42; 32]);
 pub(crate) const MAX_BATCH_SIZE: u64 = 12;
 pub(crate) const TIME_PRECISION: Duration = 3600; // seconds
 
@@ -77,15 +87,22 @@ impl TestRunner {
         .await
     }
 
+    /// Configure the aggregators with the time-interval task with the given ID.
+    pub async fn with_task_id(version: DapVersion, task_id: Id) -> Self {
+        Self::with_id(version, &DapQueryConfig::TimeInterval, task_id).await
+    }
+
     async fn with(version: DapVersion, query_config: &DapQueryConfig) -> Self {
+        Self::with_id(version, query_config, Id(thread_rng().gen())).await
+    }
+
+    async fn with_id(version: DapVersion, query_config: &DapQueryConfig, task_id: Id) -> Self {
         let mut rng = thread_rng();
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
-        let task_id = Id(rng.gen());
-
         // When running in a local development environment, override the hostname of each
         // aggregator URL with 127.0.0.1.
         let version_path = match version {
@@ -117,8 +134,10 @@ impl TestRunner {
             min_batch_size: MIN_BATCH_SIZE,
             query: query_config.clone(),
             vdaf: VDAF_CONFIG.clone(),
-            vdaf_verify_key: VDAF_CONFIG.gen_verify_key(),
+            vdaf_verify_key: VDAF_CONFIG.gen_verify_key().unwrap(),
             collector_hpke_config: collector_hpke_receiver.config.clone(),
+            retired_collector_hpke_configs: Vec::new(),
+            taskprov: None,
         };
 
         // This block needs to be kept in-sync with daphne_worker_test/wrangler.toml.
@@ -131,6 +150,9 @@ impl TestRunner {
             supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
             allow_taskprov: true,
             taskprov_version: TaskprovVersion::Draft02,
+            task_expiration_grace_period: Some(604800),
+            reconcile_batch_mismatch: false,
+            max_bulk_upload_reports: 1000,
         };
         let taskprov_vdaf_verify_key_init =
             hex::decode("b029a72fa327931a5cb643dcadcaafa098fcbfac07d990cb9e7c9a8675fafb18")
@@ -515,6 +537,93 @@ impl TestRunner {
     }
 }
 
+/// A mock OTLP collector that records the spans exported by the aggregators. It listens on the
+/// port of the `DAP_TRACING_OTLP_URL` configured in daphne_worker_test/wrangler.toml and
+/// docker-compose.yaml.
+pub struct MockOtlpCollector {
+    spans: Arc<Mutex<Vec<serde_json::Value>>>,
+    server_handle: tokio::task::JoinHandle<()>,
+}
+
+#[allow(dead_code)]
+impl MockOtlpCollector {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("0.0.0.0:4318")
+            .await
+            .expect("failed to bind mock OTLP collector");
+        let spans = Arc::new(Mutex::new(Vec::new()));
+
+        let server_spans = spans.clone();
+        let server_handle = tokio::spawn(async move {
+            loop {
+                let (mut stream, _addr) = listener.accept().await.unwrap();
+                let spans = server_spans.clone();
+                tokio::spawn(async move {
+                    let body = read_http_request_body(&mut stream).await;
+                    let export_req: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    for resource_spans in export_req["resourceSpans"].as_array().unwrap() {
+                        for scope_spans in resource_spans["scopeSpans"].as_array().unwrap() {
+                            spans
+                                .lock()
+                                .unwrap()
+                                .extend(scope_spans["spans"].as_array().unwrap().clone());
+                        }
+                    }
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        )
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+
+        Self {
+            spans,
+            server_handle,
+        }
+    }
+
+    /// Return the spans received so far.
+    pub fn spans(&self) -> Vec<serde_json::Value> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockOtlpCollector {
+    fn drop(&mut self) {
+        self.server_handle.abort();
+    }
+}
+
+/// Read an HTTP/1.1 request from the stream and return its body.
+async fn read_http_request_body(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let (header_len, content_len) = loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before end of headers");
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let headers = std::str::from_utf8(&buf[..pos]).unwrap().to_lowercase();
+            let content_len = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map(|len| len.trim().parse::<usize>().unwrap())
+                .unwrap_or(0);
+            break (pos + 4, content_len);
+        }
+    };
+
+    while buf.len() < header_len + content_len {
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before end of body");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    buf[header_len..header_len + content_len].to_vec()
+}
+
 #[cfg(feature = "test_janus")]
 pub struct JanusServerHandle {
     shutdown_sender: Sender<()>,